@@ -0,0 +1,13 @@
+; Copy shader for the pass-through GS, 2 GPRs
+;
+; Runs on the VS stage once for every vertex the GS emitted. R0.x holds the
+; vertex's dword offset in the GSVS ring below the stream number in bits 30
+; and 31. The ring is fetch buffer 1, buffer 0 is the ES's vertex buffer.
+; Exports like solid_vs.asm.
+
+ALU BARRIER
+	0 x: AND_INT R0.x, R0.x, 0x3fffffff
+VC BARRIER
+	FETCH R1.xyzw, R0.x, BUFFER:1 FORMAT:32_32_32_32_FLOAT NUM:SCALED NO_INDEX_OFFSET MFC:15 MEGA_FETCH
+EXPORT_DONE POS0 R1.xyzw BARRIER
+EXPORT_DONE PARAM0 R0.xyzw END_OF_PROGRAM
@@ -0,0 +1,25 @@
+; Geometry shader that emits each triangle unchanged, 3 GPRs
+;
+; R0.x, R0.y and R0.w hold the dword offsets of the three vertices in the
+; ESGS ring, which is fetch buffer 0. Each position goes to the GSVS ring
+; at the dword offset in R2.x, which moves on by the 4 dwords of an output
+; vertex after every EMIT_VERTEX.
+
+ALU BARRIER
+	0 x: MOV R2.x, 0
+VC BARRIER
+	FETCH R1.xyzw, R0.x, BUFFER:0 FORMAT:32_32_32_32_FLOAT NUM:SCALED NO_INDEX_OFFSET MFC:15 MEGA_FETCH
+MEM_RING WRITE_IND R1.xyzw INDEX:R2 ARRAY_SIZE:0xfff ELEM_SIZE:3 BARRIER
+EMIT_VERTEX BARRIER
+ALU BARRIER
+	0 x: ADD_INT R2.x, R2.x, 0x4
+VC BARRIER
+	FETCH R1.xyzw, R0.y, BUFFER:0 FORMAT:32_32_32_32_FLOAT NUM:SCALED NO_INDEX_OFFSET MFC:15 MEGA_FETCH
+MEM_RING WRITE_IND R1.xyzw INDEX:R2 ARRAY_SIZE:0xfff ELEM_SIZE:3 BARRIER
+EMIT_VERTEX BARRIER
+ALU BARRIER
+	0 x: ADD_INT R2.x, R2.x, 0x4
+VC BARRIER
+	FETCH R1.xyzw, R0.w, BUFFER:0 FORMAT:32_32_32_32_FLOAT NUM:SCALED NO_INDEX_OFFSET MFC:15 MEGA_FETCH
+MEM_RING WRITE_IND R1.xyzw INDEX:R2 ARRAY_SIZE:0xfff ELEM_SIZE:3 BARRIER
+EMIT_VERTEX BARRIER END_OF_PROGRAM
@@ -0,0 +1,9 @@
+; Export shader for the pass-through GS, 2 GPRs
+;
+; solid_vs.asm on the ES stage: the position is fetched the same way and
+; written to the ESGS ring as the vertex's only item, 16 bytes, for the GS
+; to read.
+
+VC BARRIER
+	FETCH R1.xy01, R0.x, BUFFER:0 FORMAT:32_32_FLOAT NUM:SCALED SIGNED MFC:7 MEGA_FETCH
+MEM_RING WRITE R1.xyzw ELEM_SIZE:3 BARRIER END_OF_PROGRAM
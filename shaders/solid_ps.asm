@@ -0,0 +1,10 @@
+; Pixel shader for solid fills, 1 GPR
+;
+; Writes the color in constant 0 of the pixel shader's constant buffer 0.
+
+ALU KC0[CB0:0-15] BARRIER
+	0 x: MOV R0.x, KC0[0].x CLAMP
+	  y: MOV R0.y, KC0[0].y CLAMP
+	  z: MOV R0.z, KC0[0].z CLAMP
+	  w: MOV R0.w, KC0[0].w CLAMP
+EXPORT_DONE PIXEL0 R0.xyzw BARRIER END_OF_PROGRAM
@@ -0,0 +1,10 @@
+; Vertex shader for solid fills, 2 GPRs
+;
+; R0.x holds the vertex index. Positions are two floats per vertex in
+; vertex buffer 0 and are exported with z = 0, w = 1. PARAM0 is exported
+; only because the pixel shader expects one parameter.
+
+VC BARRIER
+	FETCH R1.xy01, R0.x, BUFFER:0 FORMAT:32_32_FLOAT NUM:SCALED SIGNED MFC:7 MEGA_FETCH
+EXPORT_DONE POS0 R1.xyzw BARRIER
+EXPORT_DONE PARAM0 R0.xyzw END_OF_PROGRAM
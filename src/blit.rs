@@ -0,0 +1,257 @@
+// 2D operations: copies, textured quads, Porter-Duff compositing and YUV
+// video frames, much like the EXA and Xv paths of the radeon X driver.
+//
+// Every operation draws one quad into a surface with the shaders from
+// shader::library, setting all the state it depends on itself: color
+// buffer, rasterizer, shaders, interpolants, textures, samplers, blending
+// and constants. Only the context set up by the init sequence is assumed.
+// Surfaces live in the BO that bo_reloc refers to, and the color buffer is
+// flushed after each operation so a surface can be sampled right after it
+// was drawn to.
+
+use cs::*;
+use shader::isa::{FMT_8, FMT_8_8_8_8, SEL_0, SEL_1, SEL_X, SEL_Y, SEL_Z, SEL_W};
+use shader::library;
+use shader::object::{PlacedShader, ShaderHeap, ShaderObject, Stage};
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum SurfaceFormat {
+	Rgba8, // sRGB, like the framebuffer
+	R8     // one plane of a YUV frame
+}
+
+// A linear surface, usable as texture and (in Rgba8) as render target
+pub struct Surface {
+	pub offset: u32, // 256 byte aligned
+	pub width: u32,
+	pub height: u32,
+	pub pitch: u32, // in pixels, a multiple of 8
+	pub format: SurfaceFormat
+}
+
+impl Surface {
+	pub fn new(offset: u32, width: u32, height: u32, format: SurfaceFormat) -> Surface {
+		Surface { offset: offset, width: width, height: height, pitch: (width + 7) & !7, format: format }
+	}
+
+	pub fn size(&self) -> u32 {
+		self.pitch * self.height * match self.format { SurfaceFormat::Rgba8 => 4, SurfaceFormat::R8 => 1 }
+	}
+
+	pub fn rect(&self) -> Rect {
+		Rect { x0: 0, y0: 0, x1: self.width, y1: self.height }
+	}
+
+	fn tex_res(&self) -> TexRes {
+		let (data_format, dst_sel, degamma) = match self.format {
+			SurfaceFormat::Rgba8 => (FMT_8_8_8_8, [SEL_X, SEL_Y, SEL_Z, SEL_W], true),
+			SurfaceFormat::R8 => (FMT_8, [SEL_X, SEL_0, SEL_0, SEL_1], false)
+		};
+		TexRes {
+			offset: self.offset,
+			width: self.width,
+			height: self.height,
+			pitch: self.pitch,
+			data_format: data_format,
+			dst_sel: dst_sel,
+			degamma: degamma
+		}
+	}
+
+	fn color_buffer(&self) -> ColorBuffer {
+		assert!(self.format == SurfaceFormat::Rgba8, "can only render to Rgba8 surfaces");
+		assert!(self.offset & 0xff == 0, "surface not 256 byte aligned");
+		ColorBuffer {
+			base: self.offset >> 8,
+			pitch: self.pitch/8-1,
+			slice: self.pitch * ((self.height+7) & !7) / 64 - 1,
+			.. color_buffer(self.width, self.height, false)
+		}
+	}
+}
+
+// Porter-Duff operators, as in the Render extension
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum PictOp { Clear, Src, Dst, Over, OverReverse, In, InReverse, Out, OutReverse, Atop, AtopReverse, Xor, Add }
+
+impl PictOp {
+	// (source factor, destination factor) with premultiplied alpha
+	pub fn blend_factors(self) -> (BlendFactor, BlendFactor) {
+		use cs::BlendFactor::*;
+		match self {
+			PictOp::Clear       => (Zero, Zero),
+			PictOp::Src         => (One, Zero),
+			PictOp::Dst         => (Zero, One),
+			PictOp::Over        => (One, OneMinusSrcAlpha),
+			PictOp::OverReverse => (OneMinusDstAlpha, One),
+			PictOp::In          => (DstAlpha, Zero),
+			PictOp::InReverse   => (Zero, SrcAlpha),
+			PictOp::Out         => (OneMinusDstAlpha, Zero),
+			PictOp::OutReverse  => (Zero, OneMinusSrcAlpha),
+			PictOp::Atop        => (DstAlpha, OneMinusSrcAlpha),
+			PictOp::AtopReverse => (OneMinusDstAlpha, SrcAlpha),
+			PictOp::Xor         => (OneMinusDstAlpha, OneMinusSrcAlpha),
+			PictOp::Add         => (One, One)
+		}
+	}
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum YuvMatrix { Bt601, Bt709 }
+
+impl YuvMatrix {
+	// Limited range YUV to RGB as the columns c0..c3 yuv_ps expects
+	pub fn columns(self) -> [[f32; 4]; 4] {
+		let (rv, gu, gv, bu) = match self {
+			YuvMatrix::Bt601 => (1.596, -0.391, -0.813, 2.018),
+			YuvMatrix::Bt709 => (1.793, -0.213, -0.533, 2.112)
+		};
+		let ys = 255.0 / 219.0;
+		let y0 = -16.0 / 219.0;
+		[
+			[ys, ys, ys, 0.0],
+			[0.0, gu, bu, 0.0],
+			[rv, gv, 0.0, 0.0],
+			[y0 - 0.5 * rv, y0 - 0.5 * (gu + gv), y0 - 0.5 * bu, 1.0]
+		]
+	}
+}
+
+// I420: full resolution Y, U and V subsampled by two in both directions
+pub struct YuvFrame {
+	pub y: Surface,
+	pub u: Surface,
+	pub v: Surface,
+	pub matrix: YuvMatrix
+}
+
+impl YuvFrame {
+	// The three planes one after the other, starting at offset
+	pub fn i420(offset: u32, width: u32, height: u32, matrix: YuvMatrix) -> YuvFrame {
+		let align = |o: u32| (o + 255) & !255;
+		let y = Surface::new(offset, width, height, SurfaceFormat::R8);
+		let u = Surface::new(align(y.offset + y.size()), (width+1)/2, (height+1)/2, SurfaceFormat::R8);
+		let v = Surface::new(align(u.offset + u.size()), u.width, u.height, SurfaceFormat::R8);
+		YuvFrame { y: y, u: u, v: v, matrix: matrix }
+	}
+
+	pub fn size(&self) -> u32 {
+		self.v.offset + self.v.size() - self.y.offset
+	}
+}
+
+pub struct BlitShaders {
+	pub vs: PlacedShader,
+	pub copy_ps: PlacedShader,
+	pub texture_ps: PlacedShader,
+	pub yuv_ps: PlacedShader,
+	pub base: u32 // BO offset of the heap
+}
+
+impl BlitShaders {
+	pub fn new(heap: &mut ShaderHeap, base: u32) -> BlitShaders {
+		BlitShaders {
+			vs: heap.add(ShaderObject::new(Stage::Vs, &library::quad_vs())),
+			copy_ps: heap.add(ShaderObject::new(Stage::Ps, &library::copy_ps())),
+			texture_ps: heap.add(ShaderObject::new(Stage::Ps, &library::texture_ps())),
+			yuv_ps: heap.add(ShaderObject::new(Stage::Ps, &library::yuv_ps())),
+			base: base
+		}
+	}
+
+	fn addr(&self, shader: &PlacedShader) -> u32 {
+		self.base + shader.offset as u32
+	}
+}
+
+const QUAD: DrawInfo<'static> = DrawInfo {
+	prim_type: DI_PT_TRISTRIP,
+	indirect: false,
+	indexed: true,
+	index_size: 4,
+	instance_count: 1,
+	user_buffer: Some(&[0, 1, 2, 3])
+};
+
+// VS constants mapping quad corners to dst_rect in NDC and to src_rect in
+// normalized texture coordinates
+fn quad_consts(src: &Surface, src_rect: &Rect, dst: &Surface, dst_rect: &Rect) -> [f32; 8] {
+	let (dw, dh) = (dst.width as f32, dst.height as f32);
+	let (sw, sh) = (src.width as f32, src.height as f32);
+	[
+		(dst_rect.x1 - dst_rect.x0) as f32 / dw * 2.0,
+		-((dst_rect.y1 - dst_rect.y0) as f32) / dh * 2.0,
+		(src_rect.x1 - src_rect.x0) as f32 / sw,
+		(src_rect.y1 - src_rect.y0) as f32 / sh,
+		dst_rect.x0 as f32 / dw * 2.0 - 1.0,
+		1.0 - dst_rect.y0 as f32 / dh * 2.0,
+		src_rect.x0 as f32 / sw,
+		src_rect.y0 as f32 / sh
+	]
+}
+
+fn draw_quad(cs: &mut CS, ring: &mut ConstRing, shaders: &BlitShaders, ps: &PlacedShader, vs_consts: &[f32],
+             ps_consts: &[f32], dst: &Surface, blend: Option<(BlendFactor, BlendFactor)>, bo_reloc: &Fn(&mut CS) -> ()) {
+	setup_fb_cb(cs, &dst.color_buffer(), bo_reloc);
+	setup_blend(cs, blend);
+	setup_rasterizer(cs, &RasterizerState::viewport(dst.width, dst.height));
+	setup_depth(cs);
+
+	evergreen_vs_setup(cs, &shaders.vs.object, shaders.addr(&shaders.vs), bo_reloc);
+	evergreen_ps_setup(cs, &ps.object, shaders.addr(ps), bo_reloc);
+	setup_interpolants(cs, 1);
+
+	ring.bind(cs, ShaderStage::Vs, 0, vs_consts, bo_reloc);
+	if !ps_consts.is_empty() {
+		ring.bind(cs, ShaderStage::Ps, 0, ps_consts, bo_reloc);
+	}
+
+	vbo(cs, QUAD);
+	surface_sync(cs, CB_ACTION_ENA_bit | CB0_DEST_BASE_ENA_bit, dst.size(), dst.offset, 7, bo_reloc);
+}
+
+// Copies src_rect of src to dst with its top left corner at (dst_x, dst_y)
+pub fn copy_rect(cs: &mut CS, ring: &mut ConstRing, shaders: &BlitShaders, src: &Surface, src_rect: &Rect,
+                 dst: &Surface, dst_x: u32, dst_y: u32, bo_reloc: &Fn(&mut CS) -> ()) {
+	cs.write_label("copy rect");
+	let dst_rect = Rect {
+		x0: dst_x,
+		y0: dst_y,
+		x1: dst_x + src_rect.x1 - src_rect.x0,
+		y1: dst_y + src_rect.y1 - src_rect.y0
+	};
+	set_texture_resource(cs, FETCH_RESOURCE_OFFSET_PS, &src.tex_res(), bo_reloc);
+	set_sampler(cs, SAMPLER_OFFSET_PS, TexFilter::Point);
+	draw_quad(cs, ring, shaders, &shaders.copy_ps, &quad_consts(src, src_rect, dst, &dst_rect), &[], dst, None, bo_reloc);
+}
+
+// Draws src_rect of src, multiplied by tint, stretched over dst_rect of dst
+// and combined with what's there by op
+pub fn composite(cs: &mut CS, ring: &mut ConstRing, shaders: &BlitShaders, op: PictOp, src: &Surface, src_rect: &Rect,
+                 tint: [f32; 4], dst: &Surface, dst_rect: &Rect, bo_reloc: &Fn(&mut CS) -> ()) {
+	cs.write_label("composite");
+	set_texture_resource(cs, FETCH_RESOURCE_OFFSET_PS, &src.tex_res(), bo_reloc);
+	set_sampler(cs, SAMPLER_OFFSET_PS, TexFilter::Bilinear);
+	let blend = if op == PictOp::Src { None } else { Some(op.blend_factors()) };
+	draw_quad(cs, ring, shaders, &shaders.texture_ps, &quad_consts(src, src_rect, dst, dst_rect), &tint, dst, blend, bo_reloc);
+}
+
+// A textured quad: composite with PictOp::Src
+pub fn draw_texture(cs: &mut CS, ring: &mut ConstRing, shaders: &BlitShaders, src: &Surface, src_rect: &Rect,
+                    tint: [f32; 4], dst: &Surface, dst_rect: &Rect, bo_reloc: &Fn(&mut CS) -> ()) {
+	composite(cs, ring, shaders, PictOp::Src, src, src_rect, tint, dst, dst_rect, bo_reloc);
+}
+
+// Converts the whole frame to RGB, scaled to dst_rect of dst
+pub fn draw_yuv_frame(cs: &mut CS, ring: &mut ConstRing, shaders: &BlitShaders, frame: &YuvFrame,
+                      dst: &Surface, dst_rect: &Rect, bo_reloc: &Fn(&mut CS) -> ()) {
+	cs.write_label("draw yuv frame");
+	for (i, plane) in [&frame.y, &frame.u, &frame.v].iter().enumerate() {
+		set_texture_resource(cs, FETCH_RESOURCE_OFFSET_PS + i as u32, &plane.tex_res(), bo_reloc);
+	}
+	set_sampler(cs, SAMPLER_OFFSET_PS, TexFilter::Bilinear);
+	let columns = frame.matrix.columns();
+	let ps_consts: Vec<f32> = columns.iter().flat_map(|c| c.iter().cloned()).collect();
+	let vs_consts = quad_consts(&frame.y, &frame.y.rect(), dst, dst_rect);
+	draw_quad(cs, ring, shaders, &shaders.yuv_ps, &vs_consts, &ps_consts, dst, None, bo_reloc);
+}
@@ -0,0 +1,308 @@
+// Ioctl captures: every call the program makes on the DRM device, with what
+// it returned, so a run can be attached to a bug report and resubmitted on
+// another machine. A file is a header of little endian dwords followed by
+// records, written as the calls happen so a capture survives a hang.
+//
+//     "EGCP" version
+//     tag payload_size payload
+//     ...
+//
+// Payloads, errno is 0 for success and 64-bit values are lo hi:
+//
+//     1  OPEN                -                         a new fd, handles start over
+//     2  GEM_CREATE          size domain errno handle bo_size
+//     3  GEM_MMAP            handle size errno
+//     4  GEM_INFO            errno gart_size vram_size vram_visible
+//     5  GEM_WAIT_IDLE       handle errno
+//     6  GEM_BUSY            handle errno              EBUSY while busy
+//     7  GEM_CLOSE           handle errno bytes        contents before the close
+//     8  CS                  errno ib_dw ib num_relocs num_relocs * (handle read_domains write_domain flags)
+//                            flags_dw flags num_bos num_bos * (handle bytes)   contents at submit
+//     9  INFO                request errno value
+//     10 PRIME_HANDLE_TO_FD  handle errno
+//
+// bytes is a byte count followed by the data, padded to a dword.
+
+use std::fs;
+use std::io::{self, Write};
+use drm_radeon_ioctl::DrmRadeonCsReloc;
+
+pub const CAPTURE_MAGIC: u32 = 0x50434745; // "EGCP"
+pub const CAPTURE_VERSION: u32 = 1;
+
+const TAG_OPEN: u32 = 1;
+const TAG_GEM_CREATE: u32 = 2;
+const TAG_GEM_MMAP: u32 = 3;
+const TAG_GEM_INFO: u32 = 4;
+const TAG_GEM_WAIT_IDLE: u32 = 5;
+const TAG_GEM_BUSY: u32 = 6;
+const TAG_GEM_CLOSE: u32 = 7;
+const TAG_CS: u32 = 8;
+const TAG_INFO: u32 = 9;
+const TAG_PRIME_HANDLE_TO_FD: u32 = 10;
+
+pub enum Record {
+	Open,
+	GemCreate { size: u64, domain: u32, errno: i32, handle: u32, bo_size: u64 },
+	GemMmap { handle: u32, size: u64, errno: i32 },
+	GemInfo { errno: i32, gart_size: u64, vram_size: u64, vram_visible: u64 },
+	GemWaitIdle { handle: u32, errno: i32 },
+	GemBusy { handle: u32, errno: i32 },
+	GemClose { handle: u32, errno: i32, contents: Vec<u8> },
+	Cs { errno: i32, ib: Vec<u32>, relocs: Vec<DrmRadeonCsReloc>, flags: Vec<u32>, bos: Vec<(u32, Vec<u8>)> },
+	Info { request: u32, errno: i32, value: u64 },
+	PrimeHandleToFd { handle: u32, errno: i32 }
+}
+
+fn push_u32(out: &mut Vec<u8>, v: u32) {
+	out.extend_from_slice(&[v as u8, (v >> 8) as u8, (v >> 16) as u8, (v >> 24) as u8]);
+}
+
+fn push_u64(out: &mut Vec<u8>, v: u64) {
+	push_u32(out, v as u32);
+	push_u32(out, (v >> 32) as u32);
+}
+
+fn push_bytes(out: &mut Vec<u8>, bytes: &[u8]) {
+	push_u32(out, bytes.len() as u32);
+	out.extend_from_slice(bytes);
+	while !out.len().is_multiple_of(4) { out.push(0) }
+}
+
+fn read_u32(bytes: &[u8], at: &mut usize) -> Result<u32, String> {
+	if *at + 4 > bytes.len() {
+		return Err("record truncated".to_owned())
+	}
+	let b = &bytes[*at..*at+4];
+	*at += 4;
+	Ok(b[0] as u32 | (b[1] as u32) << 8 | (b[2] as u32) << 16 | (b[3] as u32) << 24)
+}
+
+fn read_u64(bytes: &[u8], at: &mut usize) -> Result<u64, String> {
+	let lo = read_u32(bytes, at)? as u64;
+	Ok(lo | (read_u32(bytes, at)? as u64) << 32)
+}
+
+fn read_u32s(bytes: &[u8], at: &mut usize) -> Result<Vec<u32>, String> {
+	let n = read_u32(bytes, at)?;
+	(0..n).map(|_| read_u32(bytes, at)).collect()
+}
+
+fn read_bytes(bytes: &[u8], at: &mut usize) -> Result<Vec<u8>, String> {
+	let n = read_u32(bytes, at)? as usize;
+	if *at + n > bytes.len() {
+		return Err("record truncated".to_owned())
+	}
+	let data = bytes[*at..*at+n].to_vec();
+	*at += (n + 3) & !3;
+	Ok(data)
+}
+
+impl Record {
+	pub fn name(&self) -> &'static str {
+		match *self {
+			Record::Open => "OPEN",
+			Record::GemCreate {..} => "GEM_CREATE",
+			Record::GemMmap {..} => "GEM_MMAP",
+			Record::GemInfo {..} => "GEM_INFO",
+			Record::GemWaitIdle {..} => "GEM_WAIT_IDLE",
+			Record::GemBusy {..} => "GEM_BUSY",
+			Record::GemClose {..} => "GEM_CLOSE",
+			Record::Cs {..} => "CS",
+			Record::Info {..} => "INFO",
+			Record::PrimeHandleToFd {..} => "PRIME_HANDLE_TO_FD"
+		}
+	}
+
+	pub fn to_bytes(&self) -> Vec<u8> {
+		let mut p = Vec::new();
+		let tag = match *self {
+			Record::Open => TAG_OPEN,
+			Record::GemCreate { size, domain, errno, handle, bo_size } => {
+				push_u64(&mut p, size);
+				push_u32(&mut p, domain);
+				push_u32(&mut p, errno as u32);
+				push_u32(&mut p, handle);
+				push_u64(&mut p, bo_size);
+				TAG_GEM_CREATE
+			},
+			Record::GemMmap { handle, size, errno } => {
+				push_u32(&mut p, handle);
+				push_u64(&mut p, size);
+				push_u32(&mut p, errno as u32);
+				TAG_GEM_MMAP
+			},
+			Record::GemInfo { errno, gart_size, vram_size, vram_visible } => {
+				push_u32(&mut p, errno as u32);
+				push_u64(&mut p, gart_size);
+				push_u64(&mut p, vram_size);
+				push_u64(&mut p, vram_visible);
+				TAG_GEM_INFO
+			},
+			Record::GemWaitIdle { handle, errno } => {
+				push_u32(&mut p, handle);
+				push_u32(&mut p, errno as u32);
+				TAG_GEM_WAIT_IDLE
+			},
+			Record::GemBusy { handle, errno } => {
+				push_u32(&mut p, handle);
+				push_u32(&mut p, errno as u32);
+				TAG_GEM_BUSY
+			},
+			Record::GemClose { handle, errno, ref contents } => {
+				push_u32(&mut p, handle);
+				push_u32(&mut p, errno as u32);
+				push_bytes(&mut p, contents);
+				TAG_GEM_CLOSE
+			},
+			Record::Cs { errno, ref ib, ref relocs, ref flags, ref bos } => {
+				push_u32(&mut p, errno as u32);
+				push_u32(&mut p, ib.len() as u32);
+				for &dw in ib { push_u32(&mut p, dw) }
+				push_u32(&mut p, relocs.len() as u32);
+				for r in relocs {
+					for &v in &[r.handle, r.read_domains, r.write_domain, r.flags] { push_u32(&mut p, v) }
+				}
+				push_u32(&mut p, flags.len() as u32);
+				for &dw in flags { push_u32(&mut p, dw) }
+				push_u32(&mut p, bos.len() as u32);
+				for &(handle, ref contents) in bos {
+					push_u32(&mut p, handle);
+					push_bytes(&mut p, contents);
+				}
+				TAG_CS
+			},
+			Record::Info { request, errno, value } => {
+				push_u32(&mut p, request);
+				push_u32(&mut p, errno as u32);
+				push_u64(&mut p, value);
+				TAG_INFO
+			},
+			Record::PrimeHandleToFd { handle, errno } => {
+				push_u32(&mut p, handle);
+				push_u32(&mut p, errno as u32);
+				TAG_PRIME_HANDLE_TO_FD
+			}
+		};
+		let mut out = Vec::with_capacity(p.len() + 8);
+		push_u32(&mut out, tag);
+		push_u32(&mut out, p.len() as u32);
+		out.extend_from_slice(&p);
+		out
+	}
+
+	fn from_payload(tag: u32, p: &[u8]) -> Result<Record, String> {
+		let at = &mut 0;
+		Ok(match tag {
+			TAG_OPEN => Record::Open,
+			TAG_GEM_CREATE => Record::GemCreate {
+				size: read_u64(p, at)?,
+				domain: read_u32(p, at)?,
+				errno: read_u32(p, at)? as i32,
+				handle: read_u32(p, at)?,
+				bo_size: read_u64(p, at)?
+			},
+			TAG_GEM_MMAP => Record::GemMmap {
+				handle: read_u32(p, at)?,
+				size: read_u64(p, at)?,
+				errno: read_u32(p, at)? as i32
+			},
+			TAG_GEM_INFO => Record::GemInfo {
+				errno: read_u32(p, at)? as i32,
+				gart_size: read_u64(p, at)?,
+				vram_size: read_u64(p, at)?,
+				vram_visible: read_u64(p, at)?
+			},
+			TAG_GEM_WAIT_IDLE => Record::GemWaitIdle { handle: read_u32(p, at)?, errno: read_u32(p, at)? as i32 },
+			TAG_GEM_BUSY => Record::GemBusy { handle: read_u32(p, at)?, errno: read_u32(p, at)? as i32 },
+			TAG_GEM_CLOSE => Record::GemClose {
+				handle: read_u32(p, at)?,
+				errno: read_u32(p, at)? as i32,
+				contents: read_bytes(p, at)?
+			},
+			TAG_CS => {
+				let errno = read_u32(p, at)? as i32;
+				let ib = read_u32s(p, at)?;
+				let num_relocs = read_u32(p, at)?;
+				let mut relocs = Vec::new();
+				for _ in 0..num_relocs {
+					relocs.push(DrmRadeonCsReloc {
+						handle: read_u32(p, at)?,
+						read_domains: read_u32(p, at)?,
+						write_domain: read_u32(p, at)?,
+						flags: read_u32(p, at)?
+					});
+				}
+				let flags = read_u32s(p, at)?;
+				let num_bos = read_u32(p, at)?;
+				let mut bos = Vec::new();
+				for _ in 0..num_bos {
+					let handle = read_u32(p, at)?;
+					bos.push((handle, read_bytes(p, at)?));
+				}
+				Record::Cs { errno, ib, relocs, flags, bos }
+			},
+			TAG_INFO => Record::Info {
+				request: read_u32(p, at)?,
+				errno: read_u32(p, at)? as i32,
+				value: read_u64(p, at)?
+			},
+			TAG_PRIME_HANDLE_TO_FD => Record::PrimeHandleToFd { handle: read_u32(p, at)?, errno: read_u32(p, at)? as i32 },
+			_ => return Err(format!("unknown record tag {}", tag))
+		})
+	}
+}
+
+pub fn header() -> Vec<u8> {
+	let mut out = Vec::new();
+	push_u32(&mut out, CAPTURE_MAGIC);
+	push_u32(&mut out, CAPTURE_VERSION);
+	out
+}
+
+// A record cut short at the end, as left behind by a hang or a crash, ends
+// the capture instead of failing it
+pub fn parse(bytes: &[u8]) -> Result<Vec<Record>, String> {
+	let at = &mut 0;
+	if read_u32(bytes, at).ok() != Some(CAPTURE_MAGIC) {
+		return Err("not a capture".to_owned())
+	}
+	let version = read_u32(bytes, at)?;
+	if version != CAPTURE_VERSION {
+		return Err(format!("capture version {} unsupported", version))
+	}
+	let mut records = Vec::new();
+	while *at < bytes.len() {
+		let start = *at;
+		let size = match (read_u32(bytes, at), read_u32(bytes, at)) {
+			(Ok(tag), Ok(size)) if *at + size as usize <= bytes.len() => {
+				let payload = &bytes[*at..*at + size as usize];
+				records.push(Record::from_payload(tag, payload).map_err(|e| format!("record {} at {}: {}", records.len(), start, e))?);
+				size as usize
+			},
+			_ => {
+				println!("capture truncated after {} records", records.len());
+				break
+			}
+		};
+		*at += size;
+	}
+	Ok(records)
+}
+
+pub struct CaptureWriter {
+	file: fs::File
+}
+
+impl CaptureWriter {
+	pub fn create(path: &str) -> io::Result<CaptureWriter> {
+		let mut file = fs::File::create(path)?;
+		file.write_all(&header())?;
+		Ok(CaptureWriter { file })
+	}
+
+	// Unbuffered, a record is on disk before the next ioctl can hang
+	pub fn write(&mut self, record: &Record) -> io::Result<()> {
+		self.file.write_all(&record.to_bytes())
+	}
+}
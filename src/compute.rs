@@ -0,0 +1,169 @@
+// Compute dispatch, see evergreen_compute.c in mesa
+//
+// Compute shaders run on the LS hardware stage with VGT_GS_MODE in compute
+// mode. Inputs come from fetch resources in the CS range, outputs go
+// through RATs (random access targets), which are color buffer slots with
+// the RAT bit set. Register writes for a dispatch have the compute bit set
+// in their packet header.
+
+use cs::*;
+use r600_pci_ids::RadeonFamily;
+
+pub const MAX_RATS: u32 = 8;
+
+const EVENT_TYPE_CS_PARTIAL_FLUSH: u32 = 7;
+
+pub struct ComputeLimits {
+	pub num_threads: u32,
+	pub num_stack_entries: u32,
+	pub num_temp_gprs: u32,
+	pub num_pipes: u32
+}
+
+impl ComputeLimits {
+	pub fn for_family(family: &RadeonFamily) -> ComputeLimits {
+		use r600_pci_ids::RadeonFamily::*;
+		let (num_threads, num_stack_entries, num_pipes) = match *family {
+			CEDAR | PALM | SUMO => (96, 256, 2),
+			SUMO2 => (96, 512, 2),
+			REDWOOD | TURKS | CAICOS => (128, 256, 4),
+			_ => (128, 512, 8)
+		};
+		ComputeLimits {
+			num_threads: num_threads,
+			num_stack_entries: num_stack_entries,
+			num_temp_gprs: 4,
+			num_pipes: num_pipes
+		}
+	}
+}
+
+pub struct ComputeShader {
+	pub shader_addr: u32,
+	pub shader_size: u32,
+	pub num_gprs: u32,
+	pub stack_size: u32,
+	pub lds_dwords: u32,
+	pub block: [u32; 3] // thread group size
+}
+
+// Hands all threads, stack entries, GPRs and LDS to the LS stage, so this
+// can't be mixed with rendering in the same CS.
+pub fn setup_compute(cs: &mut CS, limits: &ComputeLimits) {
+	cs.write_label("setup compute");
+	cs.write(&[packet3(Packet3::CONTEXT_CONTROL, 1, 0), 0x80000000, 0x80000000]);
+	cs.write(&[packet3(Packet3::EVENT_WRITE, 0, 0), EVENT_TYPE_CS_PARTIAL_FLUSH | event_index(4)]);
+
+	cs.set_reg(0x8958, DI_PT_POINTLIST); // VGT_PRIMITIVE_TYPE
+
+	cs.set_reg_n(0x8c04, 3);
+	cs.emit(limits.num_temp_gprs << 28);                 // SQ_GPR_RESOURCE_MGMT_1
+	cs.emit(0);                                          // SQ_GPR_RESOURCE_MGMT_2
+	cs.emit((248 - limits.num_temp_gprs) << 16);         // SQ_GPR_RESOURCE_MGMT_3 NUM_LS_GPRS
+	cs.set_reg_n(0x8c18, 5);
+	cs.emit(0);                                          // SQ_THREAD_RESOURCE_MGMT_1
+	cs.emit(limits.num_threads << 8);                    // SQ_THREAD_RESOURCE_MGMT_2 NUM_LS_THREADS
+	cs.emit(0);                                          // SQ_STACK_RESOURCE_MGMT_1
+	cs.emit(0);                                          // SQ_STACK_RESOURCE_MGMT_2
+	cs.emit(limits.num_stack_entries << 16);             // SQ_STACK_RESOURCE_MGMT_3 NUM_LS_STACK_ENTRIES
+	cs.set_reg(0x8e2c, 8192 << 16);                      // SQ_LDS_RESOURCE_MGMT NUM_LS_LDS
+
+	// dynamic GPR allocation is broken, limits must be 240 instead of 0
+	cs.set_reg(0x28838, 0x1e | (0x1e<<5) | (0x1e<<10) | (0x1e<<15) | (0x1e<<20) | (0x1e<<25)); // SQ_DYN_GPR_RESOURCE_LIMIT_1
+
+	cs.set_reg(0x28a40, VGT_GS_MODE_COMPUTE_MODE | VGT_GS_MODE_PARTIAL_THD_AT_EOI); // VGT_GS_MODE
+	cs.set_reg(0x28b54, 2); // VGT_SHADER_STAGES_EN // CS_ON
+	cs.set_reg(0x286e8, 1 /* TID_IN_GROUP_ENA */ | (1<<1) /* TGID_ENA */ | (1<<2) /* DISABLE_INDEX_PACK */); // SPI_COMPUTE_INPUT_CNTL
+
+	// loops are terminated by the shader, let the hardware counter run to 4095
+	cs.set_reg(0x3a200 + 160*4, 0x1000fff); // SQ_LOOP_CONST_160
+}
+
+pub fn evergreen_cs_setup(cs: &mut CS, conf: &ComputeShader, limits: &ComputeLimits, bo_reloc: &Fn(&mut CS) -> ()) {
+	cs.write_label("evergreen_cs_setup");
+
+	let threads = conf.block[0] * conf.block[1] * conf.block[2];
+	let num_waves = (threads + 16 * limits.num_pipes - 1) / (16 * limits.num_pipes);
+	assert!(threads > 0 && threads <= 256, "thread group size out of range");
+	assert!(conf.lds_dwords < (1<<14), "LDS allocation too large");
+
+	surface_sync(cs, SH_ACTION_ENA_bit, conf.shader_size, conf.shader_addr, 3, bo_reloc);
+
+	cs.set_reg_compute(0x288d0, conf.shader_addr >> 8); // SQ_PGM_START_LS
+	bo_reloc(cs);
+	cs.set_reg_compute(0x288d4, conf.num_gprs | (conf.stack_size << 8) | (1<<21) /* DX10_CLAMP */); // SQ_PGM_RESOURCES_LS
+	cs.set_reg_compute(0x288d8, 0); // SQ_PGM_RESOURCES_2_LS
+
+	cs.set_reg_compute(0x286ec, conf.block[0]); // SPI_COMPUTE_NUM_THREAD_X
+	cs.set_reg_compute(0x286f0, conf.block[1]); // SPI_COMPUTE_NUM_THREAD_Y
+	cs.set_reg_compute(0x286f4, conf.block[2]); // SPI_COMPUTE_NUM_THREAD_Z
+	cs.set_reg(0x8970, threads); // VGT_NUM_INDICES
+	cs.set_reg_compute(0x288e8, conf.lds_dwords | (num_waves << 14)); // SQ_LDS_ALLOC
+}
+
+// Input buffer `id` as seen by the fetch instructions of a compute shader
+pub fn set_compute_buffer(cs: &mut CS, id: u32, offset: u32, size: u32, stride: u32, bo_reloc: &Fn(&mut CS) -> ()) {
+	let res = VtxRes {
+		byteoffset: offset,
+		bytesize: size,
+		stride: stride,
+		vtxcount: size / stride
+	};
+	set_buffer_resource(cs, FETCH_RESOURCE_OFFSET_CS + id, &res, bo_reloc);
+	bo_reloc(cs);
+}
+
+// A linear buffer of dwords for MEM_RAT instructions to write to, the
+// index GPR addresses dwords. The buffer needs to be 256 byte aligned.
+pub fn rat_buffer(offset: u32, size: u32) -> ColorBuffer {
+	assert!(offset & 0xff == 0, "RAT buffer not 256 byte aligned");
+	let pitch = (size / 4 + 63) & !63;
+	ColorBuffer {
+		base: offset >> 8,
+		pitch: pitch/8-1,
+		slice: pitch/64-1,
+		view: 0,
+		info: (0xd/*COLOR_32*/<<2) + (4/*UINT*/<<12) + (1<<26) /* RAT */,
+		attrib: 16 /* NON_DISP_TILING_ORDER */,
+		dim: pitch-1,
+		cmask: offset >> 8,
+		cmask_slice: 0,
+		fmask: offset >> 8,
+		fmask_slice: 0,
+		clear_word0: 0,
+		clear_word1: 0,
+		clear_word2: 0,
+		clear_word3: 0
+	}
+}
+
+pub fn set_rat(cs: &mut CS, id: u32, rat: &ColorBuffer, bo_reloc: &Fn(&mut CS) -> ()) {
+	assert!(id < MAX_RATS, "RAT id out of range");
+	set_color_buffer(cs, id, rat, bo_reloc);
+}
+
+// rats is a bitmask of the RAT ids in use
+pub fn dispatch(cs: &mut CS, rats: u32, x: u32, y: u32, z: u32) {
+	let mut target_mask = 0;
+	for i in 0..MAX_RATS {
+		if rats & (1 << i) != 0 { target_mask |= 0xf << (i*4); }
+	}
+	cs.set_reg_compute(0x28238, target_mask); // CB_TARGET_MASK
+	cs.set_reg_compute(0x28808, cb_color_control(CB_MODE_NORMAL)); // CB_COLOR_CONTROL
+
+	cs.write_label("dispatch");
+	cs.write(&[
+		packet3(Packet3::DISPATCH_DIRECT, 3, 0) | PACKET3_COMPUTE_MODE,
+		x,
+		y,
+		z,
+		1 // COMPUTE_SHADER_EN
+	]);
+}
+
+// Waits for the dispatch and flushes RAT writes, so the CPU can read the
+// results after the CS completes.
+pub fn compute_flush(cs: &mut CS, offset: u32, size: u32, bo_reloc: &Fn(&mut CS) -> ()) {
+	cs.write(&[packet3(Packet3::EVENT_WRITE, 0, 0), EVENT_TYPE_CS_PARTIAL_FLUSH | event_index(4)]);
+	surface_sync(cs, CB_ACTION_ENA_bit | CB0_DEST_BASE_ENA_bit, size, offset, 6, bo_reloc);
+}
@@ -1,5 +1,8 @@
 use std::collections::hash_map::HashMap;
+use std::io;
+use device::{CsChunk, Device};
 use drm_radeon_ioctl::*;
+use shader::object::ShaderObject;
 
 #[allow(dead_code)]
 #[allow(non_camel_case_types)]
@@ -70,16 +73,42 @@ pub enum Packet3 {
 	EVENT_WRITE_7E = 0x7E, // disregard this
 }
 
-const RADEON_CHUNK_ID_RELOCS: u32 = 0x01;
-const RADEON_CHUNK_ID_IB: u32 = 0x02;
-const RADEON_CHUNK_ID_FLAGS: u32 = 0x03;
-// const RADEON_CHUNK_ID_CONST_IB: u32 = 0x04;
+// A labeled part of the IB, [start, end), with a marker after it
+pub struct MarkerSection {
+	pub label: String,
+	pub start: usize,
+	pub end: usize
+}
+
+// Markers are EOPs around the sections of a CS. Sections are the parts
+// between top-level labels, indented ones like reloc NOPs belong to the
+// section they're in.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum MarkerKind {
+	// After every section the section's number plus one, to its own dword,
+	// so what the GPU got through can be read back after a hang
+	Hang,
+	// The GPU clock counter as 64 bits, in slot 0 before the first section
+	// and in slot n + 1 after section n
+	Timestamp
+}
+
+struct Markers {
+	kind: MarkerKind,
+	handle: u32,
+	domain: u32,
+	offset: u32,
+	max: usize,
+	open: Option<(usize, String)>,
+	sections: Vec<MarkerSection>
+}
 
 #[derive(Default)]
 pub struct CS {
 	ib: Vec<u32>,
 	relocs: Vec<DrmRadeonCsReloc>,
-	labels: HashMap<usize, String>
+	labels: HashMap<usize, String>,
+	markers: Option<Markers>
 }
 
 impl CS {
@@ -90,8 +119,80 @@ impl CS {
 		self.ib.extend_from_slice(data);
 	}
 	pub fn write_label(&mut self, label: &str) {
+		if self.markers.is_some() && !label.starts_with(' ') {
+			self.close_section();
+			let first = {
+				let m = self.markers.as_ref().unwrap();
+				m.kind == MarkerKind::Timestamp && m.sections.is_empty() && m.open.is_none()
+			};
+			if first {
+				self.write_marker(0);
+			}
+			self.markers.as_mut().unwrap().open = Some((self.ib.len(), label.to_owned()));
+		}
 		self.labels.insert(self.ib.len(), label.to_owned());
 	}
+	// Markers go to max slots at offset in the BO handle, dwords for hang
+	// markers and qwords for timestamps; sections past that are left out
+	pub fn enable_markers(&mut self, kind: MarkerKind, handle: u32, domain: u32, offset: u32, max: usize) {
+		self.markers = Some(Markers { kind: kind, handle: handle, domain: domain, offset: offset, max: max, open: None, sections: Vec::new() });
+	}
+	// Writes the marker of the last section
+	pub fn finish_markers(&mut self) {
+		self.close_section();
+	}
+	pub fn marker_sections(&self) -> &[MarkerSection] {
+		self.markers.as_ref().map_or(&[], |m| &m.sections)
+	}
+	fn close_section(&mut self) {
+		let (start, label) = match self.markers.as_mut().and_then(|m| m.open.take()) {
+			Some(open) => open,
+			None => return
+		};
+		let end = self.ib.len();
+		if start == end {
+			return
+		}
+		let slot = {
+			let m = self.markers.as_mut().unwrap();
+			let slot = match m.kind {
+				MarkerKind::Hang => m.sections.len(),
+				MarkerKind::Timestamp => m.sections.len() + 1
+			};
+			if slot >= m.max {
+				return
+			}
+			m.sections.push(MarkerSection { label: label, start: start, end: end });
+			slot as u32
+		};
+		self.write_marker(slot);
+	}
+	fn write_marker(&mut self, slot: u32) {
+		let (kind, handle, domain, offset) = {
+			let m = self.markers.as_ref().unwrap();
+			(m.kind, m.handle, m.domain, m.offset)
+		};
+		let (label, addr, data) = match kind {
+			MarkerKind::Hang => ("hang marker", offset + slot * 4, data_sel(1)),
+			MarkerKind::Timestamp => ("timestamp", offset + slot * 8, data_sel(3))
+		};
+		self.labels.insert(self.ib.len(), format!("  {} {}", label, slot));
+		self.write(&[
+			packet3(Packet3::EVENT_WRITE_EOP, 4, 0),
+			BOTTOM_OF_PIPE_TS | event_index(5),
+			addr & !3,
+			data | int_sel(0),
+			slot + 1,
+			0
+		]);
+		self.write_reloc_nop(handle, 0, domain);
+	}
+	pub fn ib(&self) -> &[u32] {
+		&self.ib
+	}
+	pub fn label_at(&self, index: usize) -> Option<&str> {
+		self.labels.get(&index).map(|l| l.as_str())
+	}
 	pub fn write_reloc(&mut self, handle: u32, read_domains: u32, write_domain: u32, flags: u32) {
 		self.relocs.push(DrmRadeonCsReloc{
 			handle: handle,
@@ -100,10 +201,25 @@ impl CS {
 			flags: flags
 		})
 	}
+	// The NOP payload is the dword offset of the reloc entry it refers to,
+	// so relocs against a second BO need to point past the first entry.
+	pub fn write_reloc_nop(&mut self, handle: u32, read_domains: u32, write_domain: u32) {
+		let index = self.relocs.len() as u32 * 4;
+		self.write(&[packet3(Packet3::NOP, 0, 0), index]);
+		self.write_reloc(handle, read_domains, write_domain, 0);
+	}
 	pub fn set_reg(&mut self, reg: u32, value: u32) {
 		self.set_reg_n(reg, 1);
 		self.emit(value);
 	}
+	// Register writes for dispatches carry the compute bit in the header,
+	// they're all context registers
+	pub fn set_reg_compute(&mut self, reg: u32, value: u32) {
+		assert!(reg >= SET_CONTEXT_REG__OFFSET && reg < SET_CONTEXT_REG__END, "{:#x} isn't a context register", reg);
+		self.emit(packet3(Packet3::SET_CONTEXT_REG, 1, 0) | PACKET3_COMPUTE_MODE);
+		self.emit((reg - SET_CONTEXT_REG__OFFSET) >> 2);
+		self.emit(value);
+	}
 	pub fn set_reg_n(&mut self, reg: u32, num: u32) {
 		if reg >= SET_CONFIG_REG__OFFSET && reg < SET_CONFIG_REG__END {
 			self.emit(packet3(Packet3::SET_CONFIG_REG, num, 0));
@@ -130,7 +246,7 @@ impl CS {
 			self.emit((num << 8) + (reg >> 2));
 		};
 	}
-	pub fn submit(&self, fd: i32) {
+	pub fn submit(&self, dev: &Device) -> io::Result<()> {
 		// Three chunks: instruction buffer, relocations and flags.
 		let flags: [u32; 2] = [0, 0];
 		if true {
@@ -141,26 +257,7 @@ impl CS {
 				println!("[{:2}] = {:08x}", i, word);
 			}
 		}
-		let chunk0 = DrmRadeonCsChunk {
-			chunk_id: RADEON_CHUNK_ID_IB,
-			length_dw: self.ib.len() as u32,
-			chunk_data: if self.ib.is_empty() {0} else {&self.ib[0] as *const u32 as u64}
-		};
-		let chunk1 = DrmRadeonCsChunk {
-			chunk_id: RADEON_CHUNK_ID_RELOCS,
-			length_dw: self.relocs.len() as u32 * 4,
-			chunk_data: if self.relocs.is_empty() {0} else {&self.relocs[0] as *const DrmRadeonCsReloc as u64}
-		};
-		let chunk2 = DrmRadeonCsChunk {
-			chunk_id: RADEON_CHUNK_ID_FLAGS,
-			length_dw: 2,
-			chunk_data: &flags[0] as *const u32 as u64
-		};
-		let chunks: [DrmRadeonCsChunk; 3] = [chunk0, chunk1, chunk2];
-		// Finally, fill in the arguments for the ioctl.
-		let ahh = U64PtrSlice::new(&chunks);
-		let mut cs = DrmRadeonCs::new(&ahh);
-		unsafe { drm_ioctl_radeon_cs(fd, &mut cs) };
+		dev.cs(&[CsChunk::Ib(&self.ib), CsChunk::Relocs(&self.relocs), CsChunk::Flags(&flags)])
 	}
 }
 
@@ -179,6 +276,20 @@ const SET_LOOP_CONST__END: u32     = 0x0003a500;
 const SET_BOOL_CONST__OFFSET: u32  = 0x0003a500;
 const SET_BOOL_CONST__END: u32     = 0x0003a518;
 
+// The register the offset of a SET_* packet counts from, for decoding
+pub fn set_reg_base(op: &Packet3) -> Option<u32> {
+	match *op {
+		Packet3::SET_CONFIG_REG => Some(SET_CONFIG_REG__OFFSET),
+		Packet3::SET_CONTEXT_REG => Some(SET_CONTEXT_REG__OFFSET),
+		Packet3::SET_RESOURCE => Some(SET_RESOURCE__OFFSET),
+		Packet3::SET_SAMPLER => Some(SET_SAMPLER__OFFSET),
+		Packet3::SET_CTL_CONST => Some(SET_CTL_CONST__OFFSET),
+		Packet3::SET_LOOP_CONST => Some(SET_LOOP_CONST__OFFSET),
+		Packet3::SET_BOOL_CONST => Some(SET_BOOL_CONST__OFFSET),
+		_ => None
+	}
+}
+
 pub struct ColorBuffer {
 	pub base:        u32,
 	pub pitch:       u32,
@@ -187,21 +298,24 @@ pub struct ColorBuffer {
 	pub info:        u32,
 	pub attrib:      u32,
 	pub dim:         u32,
-	// pub cmask:       u32,
-	// pub cmask_slice: u32,
-	// pub fmask:       u32,
-	// pub fmask_slice: u32,
+	pub cmask:       u32,
+	pub cmask_slice: u32,
+	pub fmask:       u32,
+	pub fmask_slice: u32,
 	pub clear_word0: u32,
 	pub clear_word1: u32,
 	pub clear_word2: u32,
 	pub clear_word3: u32
 }
 
+pub const PACKET3_COMPUTE_MODE: u32 = 1 << 1;
+
 pub fn packet3(op: Packet3, n: u32, c: u32) -> u32 {
 	(/*RADEON_PACKET_TYPE*/3 << 30) | ((op as u32 & 0xFF) << 8) | ((n & 0x3FFF) << 16) | (if c != 0 {1} else {0})
 }
 pub const CACHE_FLUSH_AND_INV_EVENT_TS: u32 = (0x14 << 0);
 pub const CACHE_FLUSH_AND_INV_EVENT: u32 = (0x16 << 0);
+pub const BOTTOM_OF_PIPE_TS: u32 = (0x28 << 0);
 const VGT_INDEX_16: u32 = 0;
 const VGT_INDEX_32: u32 = 1;
 const VGT_DMA_SWAP_NONE: u32 = (0 << 2);
@@ -210,7 +324,9 @@ const VGT_DMA_SWAP_32_BIT: u32 = (2 << 2);
 const VGT_DMA_SWAP_WORD: u32 = (3 << 2);
 const V_0287F0_DI_SRC_SEL_IMMEDIATE: u32 = 1;
 const R_008958_VGT_PRIMITIVE_TYPE: u32 = 0x8958;
-const V_008958_DI_PT_TRILIST: u32 = 0x0004;
+pub const DI_PT_POINTLIST: u32 = 0x0001;
+pub const DI_PT_TRILIST: u32 = 0x0004;
+pub const DI_PT_TRISTRIP: u32 = 0x0006;
 pub fn event_index(n: u32) -> u32 { n << 8 }
 pub fn data_sel(n: u32) -> u32 { n << 29 }
 pub fn int_sel(n: u32) -> u32 { n << 24 }
@@ -218,16 +334,6 @@ pub fn int_sel(n: u32) -> u32 { n << 24 }
 const NUM_GPRS_shift: u32 = 0;
 const STACK_SIZE_shift: u32 = 8;
 
-pub struct ShaderConfig {
-	pub shader_addr: u32,
-	pub shader_size: u32,
-	pub num_gprs: u32,
-	pub stack_size: u32,
-	//bo: u32
-	pub export_mode: u32, // for PS
-	pub clamp_consts: u32 // for PS
-}
-
 pub struct VtxRes {
 	pub byteoffset: u32,
 	pub bytesize: u32,
@@ -235,6 +341,7 @@ pub struct VtxRes {
 	pub vtxcount: u32,
 }
 pub struct DrawInfo<'a> {
+	pub prim_type: u32,
 	pub indirect: bool,
 	pub indexed: bool,
 	pub index_size: u32,
@@ -248,27 +355,221 @@ pub fn setup_depth(cs: &mut CS) {
 	// cs.set_reg(0x28780, 0x40000001); // CB_BLEND0_CONTROL // RT0: enable and dst' = src * 1 + dst * 0
 }
 
-pub fn setup_scissors(cs: &mut CS, w: u32, h: u32) {
+// Rasterizer state
+//
+// With a viewport the vertex shader outputs clip space positions which the
+// clipper divides by W and the VTE maps to window coordinates. Without one,
+// positions are taken as window coordinates (pixels) as they are.
+
+#[derive(Clone, Copy)]
+pub struct Viewport {
+	pub x: f32,
+	pub y: f32,
+	pub width: f32,
+	pub height: f32,
+	pub min_depth: f32,
+	pub max_depth: f32
+}
+
+// Pixel rectangle, x1 and y1 are exclusive
+#[derive(Clone, Copy)]
+pub struct Rect {
+	pub x0: u32,
+	pub y0: u32,
+	pub x1: u32,
+	pub y1: u32
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum CullMode { None, Front, Back, FrontAndBack }
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum FrontFace { Ccw, Cw }
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum PolygonMode { Point = 0, Line = 1, Fill = 2 }
+
+pub struct RasterizerState {
+	pub width: u32,  // of the render target, for the screen and window scissors
+	pub height: u32,
+	pub viewport: Option<Viewport>,
+	pub scissors: Vec<Rect>, // up to four, a pixel passes if it's in any of them
+	pub cull_mode: CullMode,
+	pub front_face: FrontFace,
+	pub polygon_mode_front: PolygonMode,
+	pub polygon_mode_back: PolygonMode,
+	pub line_width: f32,
+	pub point_size: f32,
+	pub clip: bool,
+	pub guard_band: bool,
+	pub discard: bool // kill all primitives after stream-out
+}
+
+impl RasterizerState {
+	// What this program always did: no clipping, positions in pixels.
+	pub fn pixel_space(w: u32, h: u32) -> RasterizerState {
+		RasterizerState {
+			width: w,
+			height: h,
+			viewport: None,
+			scissors: vec![],
+			cull_mode: CullMode::None,
+			front_face: FrontFace::Ccw,
+			polygon_mode_front: PolygonMode::Fill,
+			polygon_mode_back: PolygonMode::Fill,
+			line_width: 1.0,
+			point_size: 1.0,
+			clip: false,
+			guard_band: false,
+			discard: false
+		}
+	}
+
+	// Normalized device coordinates: (-1, 1) is the top left corner of the
+	// target, (1, -1) the bottom right one, Z is in [0, 1].
+	pub fn viewport(w: u32, h: u32) -> RasterizerState {
+		RasterizerState {
+			viewport: Some(Viewport {
+				x: 0.0,
+				y: 0.0,
+				width: w as f32,
+				height: h as f32,
+				min_depth: 0.0,
+				max_depth: 1.0
+			}),
+			clip: true,
+			guard_band: true,
+			.. RasterizerState::pixel_space(w, h)
+		}
+	}
+}
+
+const MAX_CLIPRECTS: usize = 4;
+
+fn scissor_xy(x: u32, y: u32) -> u32 {
+	(x & 0x7fff) | ((y & 0x7fff) << 16)
+}
+
+// Line width and point size are programmed as half sizes in 12.4 fixed point
+fn pack_half_12p4(size: f32) -> u32 {
+	std::cmp::min((size * 8.0) as u32, 0xffff)
+}
+
+pub fn setup_rasterizer(cs: &mut CS, rs: &RasterizerState) {
+	cs.write_label("setup rasterizer");
+	assert!(rs.scissors.len() <= MAX_CLIPRECTS, "too many scissor rectangles");
+
+	let full = Rect { x0: 0, y0: 0, x1: rs.width, y1: rs.height };
+	let generic = if rs.scissors.len() == 1 { rs.scissors[0] } else { full };
+
 	cs.set_reg_n(0x28240, 2); // PA_SC_GENERIC_SCISSOR_TL
-	cs.emit(0);
-	cs.emit(w + (h << 14));
+	cs.emit(scissor_xy(generic.x0, generic.y0));
+	cs.emit(scissor_xy(generic.x1, generic.y1));
 	cs.set_reg_n(0x28030, 2); // PA_SC_SCREEN_SCISSOR_TL
-	cs.emit(0);
-	cs.emit(w + (h << 14));
+	cs.emit(scissor_xy(full.x0, full.y0));
+	cs.emit(scissor_xy(full.x1, full.y1));
 	cs.set_reg_n(0x28204, 2); // PA_SC_WINDOW_SCISSOR_TL
-	cs.emit(0);
-	cs.emit(w + (h << 14));
+	cs.emit(scissor_xy(full.x0, full.y0));
+	cs.emit(scissor_xy(full.x1, full.y1));
+
+	// Each bit of the cliprect rule is the outcome for one combination of
+	// inside/outside the four cliprects. For a union, every combination
+	// with at least one of the used rects pass.
+	let used = (1u32 << rs.scissors.len()) - 1;
+	let rule = if rs.scissors.len() > 1 {
+		(0..16).filter(|i| i & used != 0).fold(0, |r, i| r | (1<<i))
+	} else {
+		0xffff
+	};
+	cs.set_reg(0x2820c, rule); // PA_SC_CLIPRECT_RULE
+	if rs.scissors.len() > 1 {
+		cs.set_reg_n(0x28210, 2 * rs.scissors.len() as u32); // PA_SC_CLIPRECT_0_TL
+		for r in &rs.scissors {
+			cs.emit(scissor_xy(r.x0, r.y0));
+			cs.emit(scissor_xy(r.x1, r.y1));
+		}
+	}
+
+	let (scale, offset, zmin, zmax) = if let Some(vp) = rs.viewport {
+		([vp.width / 2.0, -vp.height / 2.0, vp.max_depth - vp.min_depth],
+		 [vp.x + vp.width / 2.0, vp.y + vp.height / 2.0, vp.min_depth],
+		 vp.min_depth.min(vp.max_depth),
+		 vp.min_depth.max(vp.max_depth))
+	} else {
+		([1.0, 1.0, 1.0], [0.0, 0.0, 0.0], 0.0, 1.0)
+	};
+	cs.set_reg_n(0x2843c, 6); // PA_CL_VPORT_XSCALE_0
+	cs.emit(/*0x2843c,*/ scale[0].to_bits());  // PA_CL_VPORT_XSCALE_0
+	cs.emit(/*0x28440,*/ offset[0].to_bits()); // PA_CL_VPORT_XOFFSET_0
+	cs.emit(/*0x28444,*/ scale[1].to_bits());  // PA_CL_VPORT_YSCALE_0
+	cs.emit(/*0x28448,*/ offset[1].to_bits()); // PA_CL_VPORT_YOFFSET_0
+	cs.emit(/*0x2844c,*/ scale[2].to_bits());  // PA_CL_VPORT_ZSCALE_0
+	cs.emit(/*0x28450,*/ offset[2].to_bits()); // PA_CL_VPORT_ZOFFSET_0
+	cs.set_reg_n(0x282d0, 2); // PA_SC_VPORT_ZMIN_0
+	cs.emit(zmin.to_bits());
+	cs.emit(zmax.to_bits());
+
+	cs.set_reg(0x28818, if rs.viewport.is_some() { // PA_CL_VTE_CNTL
+		0x3f /* X,Y,Z SCALE and OFFSET_ENA */ | (1<<10) /* VTX_W0_FMT */
+	} else {
+		1<<8 /* VTX_XY_FMT */
+	});
+	cs.set_reg(0x28810, (if rs.clip { // PA_CL_CLIP_CNTL
+		(1<<19) /* DX_CLIP_SPACE_DEF */ | (1<<24) /* DX_LINEAR_ATTR_CLIP_ENA */
+	} else {
+		1<<16 /* CLIP_DISABLE */
+	}) | (if rs.discard { 1<<22 /* DX_RASTERIZATION_KILL */ } else { 0 }));
+
+	// How far outside the viewport (in multiples of its size) primitives
+	// can reach before they actually have to be clipped.
+	let (gb_x, gb_y) = match (rs.guard_band, rs.viewport) {
+		(true, Some(_)) => {
+			let max_range = 8192.0;
+			let gb = |s: f32, o: f32| ((max_range - o) / s.abs()).min((max_range + o) / s.abs());
+			(gb(scale[0], offset[0]), gb(scale[1], offset[1]))
+		},
+		_ => (1.0, 1.0)
+	};
+	cs.set_reg_n(0x28c0c, 4); // PA_CL_GB_VERT_CLIP_ADJ
+	cs.emit(/*0x28c0c,*/ gb_y.to_bits()); // PA_CL_GB_VERT_CLIP_ADJ
+	cs.emit(/*0x28c10,*/ 1.0f32.to_bits()); // PA_CL_GB_VERT_DISC_ADJ
+	cs.emit(/*0x28c14,*/ gb_x.to_bits()); // PA_CL_GB_HORZ_CLIP_ADJ
+	cs.emit(/*0x28c18,*/ 1.0f32.to_bits()); // PA_CL_GB_HORZ_DISC_ADJ
 
-	cs.set_reg(0x28810, 0x00010000); // PA_CL_CLIP_CNTL
-	cs.set_reg(0x28818, 0x00000100); // PA_CL_VTE_CNTL
+	let dual_mode = rs.polygon_mode_front != PolygonMode::Fill || rs.polygon_mode_back != PolygonMode::Fill;
+	cs.set_reg(0x28814, // PA_SU_SC_MODE_CNTL
+		(if rs.cull_mode == CullMode::Front || rs.cull_mode == CullMode::FrontAndBack {1<<0} else {0}) |
+		(if rs.cull_mode == CullMode::Back || rs.cull_mode == CullMode::FrontAndBack {1<<1} else {0}) |
+		(if rs.front_face == FrontFace::Cw {1<<2} else {0}) |
+		(if dual_mode {1<<3} else {0}) |
+		((rs.polygon_mode_front as u32) << 5) |
+		((rs.polygon_mode_back as u32) << 8));
+
+	let point = pack_half_12p4(rs.point_size);
+	cs.set_reg_n(0x28a00, 2); // PA_SU_POINT_SIZE
+	cs.emit(/*0x28a00,*/ point | (point << 16)); // PA_SU_POINT_SIZE
+	cs.emit(/*0x28a04,*/ 0xffff << 16); // PA_SU_POINT_MINMAX
+	cs.set_reg(0x28a08, pack_half_12p4(rs.line_width)); // PA_SU_LINE_CNTL
+	cs.set_reg(0x28c08, 1 /* PIX_CENTER at .5 */ | (5<<3) /* QUANT_MODE 1/256th */); // PA_SU_VTX_CNTL
 }
 
-pub fn setup_fb(cs: &mut CS, w: u32, h: u32, tiled: bool, bo_reloc: &Fn(&mut CS) -> ()) {
+pub const CB_MODE_DISABLE: u32 = 0;
+pub const CB_MODE_NORMAL: u32 = 1;
+pub const CB_MODE_ELIMINATE_FAST_CLEAR: u32 = 2;
+pub const CB_MODE_RESOLVE: u32 = 3;
+pub const CB_MODE_DECOMPRESS: u32 = 4;
+pub const CB_MODE_FMASK_DECOMPRESS: u32 = 5;
+
+pub fn cb_color_control(mode: u32) -> u32 {
+	(0xcc << 16) /* ROP3 copy */ | (mode << 4)
+}
+
+pub fn color_buffer(w: u32, h: u32, tiled: bool) -> ColorBuffer {
 
 	let w8 = (w+7)/8;
 	let h8 = (h+7)/8;
 
-	let cb = ColorBuffer {
+	ColorBuffer {
 		base:  0, // offset_of!(BOLayout=>cb) as u32 >> 8,
 		pitch: w8-1,
 		slice: w8*h8-1,
@@ -282,47 +583,328 @@ pub fn setup_fb(cs: &mut CS, w: u32, h: u32, tiled: bool, bo_reloc: &Fn(&mut CS)
 			16 /* NON_DISP_TILING_ORDER */
 		},
 		dim: ((h-1)<<16) + (w-1),
+		cmask: 0,
+		cmask_slice: 0,
+		fmask: 0,
+		fmask_slice: 0,
 		clear_word0: 0,
 		clear_word1: 0,
 		clear_word2: 0,
 		clear_word3: 0
-	};
+	}
+}
 
+// CB_COLOR1..7 follow CB_COLOR0 with a stride of 15 registers. CB_COLOR8..11
+// are elsewhere with a stride of 7 and have no CMASK or FMASK, they aren't
+// handled here.
+pub fn set_color_buffer(cs: &mut CS, index: u32, cb: &ColorBuffer, bo_reloc: &Fn(&mut CS) -> ()) {
+	assert!(index < 8, "CB_COLOR{} isn't supported", index);
+	let r = 0x28c60 + index * 0x3c;
 
-	cs.set_reg(0x28c60, cb.base);        // CB_COLOR0_BASE
+	cs.set_reg(r+0x00, cb.base);        // CB_COLOR0_BASE
 	bo_reloc(cs); // CB_COLOR0_BASE
-	cs.set_reg(0x28c7c, 0 /*cb.cmask*/); // CB_COLOR0_CMASK
+	cs.set_reg(r+0x1c, cb.cmask);       // CB_COLOR0_CMASK
 	bo_reloc(cs); // CB_COLOR0_CMASK
-	cs.set_reg(0x28c84, 0 /*cb.fmask*/); // CB_COLOR0_FMASK
+	cs.set_reg(r+0x24, cb.fmask);       // CB_COLOR0_FMASK
 	bo_reloc(cs); // CB_COLOR0_FMASK
-	cs.set_reg(0x28c74, cb.attrib);      // CB_COLOR0_ATTRIB
+	cs.set_reg(r+0x14, cb.attrib);      // CB_COLOR0_ATTRIB
 	bo_reloc(cs); // CB_COLOR0_ATTRIB
-	cs.set_reg(0x28c70, cb.info);        // CB_COLOR0_INFO
+	cs.set_reg(r+0x10, cb.info);        // CB_COLOR0_INFO
 	bo_reloc(cs); // CB_COLOR0_INFO
-	cs.set_reg(0x28c64, cb.pitch);       // CB_COLOR0_PITCH
-	cs.set_reg(0x28c68, cb.slice);       // CB_COLOR0_SLICE
-	cs.set_reg(0x28c6c, cb.view);        // CB_COLOR0_VIEW
-	cs.set_reg(0x28c78, cb.dim);         // CB_COLOR0_DIM
-	cs.set_reg(0x28c80, 0 /*cb.cmask_slice*/); // CB_COLOR0_CMASK_SLICE
-	cs.set_reg(0x28c88, 0 /*cb.fmask_slice*/); // CB_COLOR0_FMASK_SLICE
-	cs.set_reg_n(0x28c8c, 4);
+	cs.set_reg(r+0x04, cb.pitch);       // CB_COLOR0_PITCH
+	cs.set_reg(r+0x08, cb.slice);       // CB_COLOR0_SLICE
+	cs.set_reg(r+0x0c, cb.view);        // CB_COLOR0_VIEW
+	cs.set_reg(r+0x18, cb.dim);         // CB_COLOR0_DIM
+	cs.set_reg(r+0x20, cb.cmask_slice); // CB_COLOR0_CMASK_SLICE
+	cs.set_reg(r+0x28, cb.fmask_slice); // CB_COLOR0_FMASK_SLICE
+	cs.set_reg_n(r+0x2c, 4);
 	cs.emit(/*0x28c8c, */ cb.clear_word0); // CB_COLOR0_CLEAR_WORD0
 	cs.emit(/*0x28c90, */ cb.clear_word1); // CB_COLOR0_CLEAR_WORD1
 	cs.emit(/*0x28c94, */ cb.clear_word2); // CB_COLOR0_CLEAR_WORD2
 	cs.emit(/*0x28c98, */ cb.clear_word3); // CB_COLOR0_CLEAR_WORD3
+}
+
+pub fn setup_fb(cs: &mut CS, w: u32, h: u32, tiled: bool, bo_reloc: &Fn(&mut CS) -> ()) {
+	setup_fb_cb(cs, &color_buffer(w, h, tiled), bo_reloc);
+}
+
+pub fn setup_fb_cb(cs: &mut CS, cb: &ColorBuffer, bo_reloc: &Fn(&mut CS) -> ()) {
+	set_color_buffer(cs, 0, cb, bo_reloc);
 	cs.set_reg(0x28238, 15); // 0xCB_TARGET_MASK
-	cs.set_reg(0x28808, 0x00cc0010); // CB_COLOR_CONTROL
+	cs.set_reg(0x28808, cb_color_control(CB_MODE_NORMAL)); // CB_COLOR_CONTROL
 	cs.set_reg(0x28780, 0); // CB_BLEND0_CONTROL
 }
 
+#[derive(Clone, Copy, PartialEq)]
+pub enum BlendFactor {
+	Zero = 0,
+	One = 1,
+	SrcColor = 2,
+	OneMinusSrcColor = 3,
+	SrcAlpha = 4,
+	OneMinusSrcAlpha = 5,
+	DstAlpha = 6,
+	OneMinusDstAlpha = 7,
+	DstColor = 8,
+	OneMinusDstColor = 9
+}
+
+// dst' = src * src_factor + dst * dst_factor, for color and alpha alike
+pub fn cb_blend_control(src: BlendFactor, dst: BlendFactor) -> u32 {
+	(1 << 30) /* ENABLE */ | (src as u32) | ((dst as u32) << 8) | ((src as u32) << 16) | ((dst as u32) << 24)
+}
+
+// None disables blending on RT0
+pub fn setup_blend(cs: &mut CS, factors: Option<(BlendFactor, BlendFactor)>) {
+	cs.set_reg(0x28780, factors.map_or(0, |(src, dst)| cb_blend_control(src, dst))); // CB_BLEND0_CONTROL
+}
+
+// Multisampling
+//
+// A multisampled color buffer lives in its own BO next to its CMASK and
+// FMASK. FMASK maps each sample to one of the fragments stored in the color
+// buffer, CMASK tracks per 8×8 tile whether FMASK is compressed (and, later,
+// whether the tile is fast cleared). Sizes follow r600_texture.c in mesa.
+
+const PIPE_INTERLEAVE_BYTES: u32 = 256;
+
+pub struct MsaaSurface {
+	pub width: u32,
+	pub height: u32,
+	pub samples: u32,
+	pub pitch: u32, // in pixels, multiple of 8
+	pub color_offset: u32,
+	pub cmask_offset: u32,
+	pub cmask_size: u32,
+	pub cmask_slice_tile_max: u32,
+	pub fmask_offset: u32,
+	pub fmask_size: u32,
+	pub fmask_slice_tile_max: u32,
+	pub size: u32 // of the whole BO
+}
+
+fn align(n: u32, a: u32) -> u32 { (n + a - 1) / a * a }
+
+pub const MSAA_SAMPLE_COUNTS: [u32; 3] = [2, 4, 8];
+
+impl MsaaSurface {
+	pub fn new(w: u32, h: u32, samples: u32, num_pipes: u32) -> MsaaSurface {
+		assert!(MSAA_SAMPLE_COUNTS.contains(&samples), "unsupported sample count");
+
+		let pitch = align(w, 8);
+		let height = align(h, 8);
+		let color_offset = 0;
+		let color_size = align(pitch * height * 4 * samples, 256);
+
+		// CMASK: 4 bits per 8×8 tile, padded to the cmask cache macro tile
+		let elements_per_macro_tile = (1024 / 4) * num_pipes;
+		let pixels_per_macro_tile = elements_per_macro_tile * 64;
+		let macro_tile_width = ((pixels_per_macro_tile as f64).sqrt() as u32).next_power_of_two();
+		let macro_tile_height = pixels_per_macro_tile / macro_tile_width;
+		let cmask_pitch = align(w, macro_tile_width);
+		let cmask_height = align(h, macro_tile_height);
+		let base_align = std::cmp::max(256, num_pipes * PIPE_INTERLEAVE_BYTES);
+		let cmask_offset = align(color_offset + color_size, base_align);
+		let cmask_size = align((cmask_pitch * cmask_height * 4 + 7) / 8 / 64, base_align);
+		let cmask_slice_tile_max = cmask_pitch * cmask_height / (128*128) - 1;
+
+		// FMASK: mesa allocates it like a 4 bytes per pixel surface for all
+		// sample counts (1 byte × 4 "samples" for 2x and 4x, 4 bytes for 8x)
+		let fmask_offset = align(cmask_offset + cmask_size, 256);
+		let fmask_size = align(pitch * height * 4, 256);
+		let fmask_slice_tile_max = pitch * height / 64 - 1;
+
+		MsaaSurface {
+			width: w,
+			height: h,
+			samples: samples,
+			pitch: pitch,
+			color_offset: color_offset,
+			cmask_offset: cmask_offset,
+			cmask_size: cmask_size,
+			cmask_slice_tile_max: cmask_slice_tile_max,
+			fmask_offset: fmask_offset,
+			fmask_size: fmask_size,
+			fmask_slice_tile_max: fmask_slice_tile_max,
+			size: fmask_offset + fmask_size
+		}
+	}
+
+	pub fn log_samples(&self) -> u32 {
+		self.samples.trailing_zeros()
+	}
+
+	pub fn color_buffer(&self) -> ColorBuffer {
+		let w8 = self.pitch/8;
+		let h8 = (self.height+7)/8;
+		let log_samples = self.log_samples();
+		ColorBuffer {
+			base: self.color_offset >> 8,
+			pitch: w8-1,
+			slice: w8*h8-1,
+			view: 0,
+			info: (26/*RGBA*/<<2) + (6/*SRGB*/<<12)
+				+ (2<<8) /* ARRAY_1D_TILED_THIN1, MSAA surfaces can't be linear */
+				+ (1<<18) /* COMPRESSION, use FMASK */,
+			attrib: (log_samples<<24) /* NUM_SAMPLES */ + (log_samples<<27) /* NUM_FRAGMENTS */,
+			dim: ((self.height-1)<<16) + (self.width-1),
+			cmask: self.cmask_offset >> 8,
+			cmask_slice: self.cmask_slice_tile_max,
+			fmask: self.fmask_offset >> 8,
+			fmask_slice: self.fmask_slice_tile_max,
+			clear_word0: 0,
+			clear_word1: 0,
+			clear_word2: 0,
+			clear_word3: 0
+		}
+	}
+}
+
+// Sample positions are signed 4-bit offsets in 1/16th pixels. Evergreen has
+// eight PA_SC_AA_SAMPLE_LOCS registers, each holding four x/y pairs.
+fn fill_sreg(locs: [(i32, i32); 4]) -> u32 {
+	let mut r = 0;
+	for (i, &(x, y)) in locs.iter().enumerate() {
+		r |= ((x as u32 & 0xf) | ((y as u32 & 0xf) << 4)) << (8*i);
+	}
+	r
+}
+
+pub fn setup_msaa(cs: &mut CS, samples: u32) {
+	cs.write_label("setup msaa");
+
+	let (locs, max_dist): (Vec<u32>, u32) = match samples {
+		2 => (vec![fill_sreg([(-4, 4), (4, -4), (-4, 4), (4, -4)]); 4], 4),
+		4 => (vec![fill_sreg([(-2, -2), (2, 2), (-6, 6), (6, -6)]); 4], 6),
+		8 => ((0..8).map(|i| if i % 2 == 0 {
+				fill_sreg([(-1, 1), (1, 5), (3, -5), (5, 3)])
+			} else {
+				fill_sreg([(-7, -1), (-3, -7), (7, -3), (-5, 7)])
+			}).collect(), 7),
+		_ => (vec![], 0)
+	};
+
+	if !locs.is_empty() {
+		cs.set_reg_n(0x28c1c, locs.len() as u32); // PA_SC_AA_SAMPLE_LOCS_0
+		cs.write(&locs);
+	}
+
+	cs.set_reg_n(0x28c00, 2);
+	if samples > 1 {
+		cs.emit(/*0x28c00,*/ (1<<10) | (1<<9)); // PA_SC_LINE_CNTL // LAST_PIXEL, EXPAND_LINE_WIDTH
+		cs.emit(/*0x28c04,*/ samples.trailing_zeros() | (max_dist<<13)); // PA_SC_AA_CONFIG
+	} else {
+		cs.emit(/*0x28c00,*/ 1<<10); // PA_SC_LINE_CNTL // LAST_PIXEL
+		cs.emit(/*0x28c04,*/ 0);     // PA_SC_AA_CONFIG
+	}
+	cs.set_reg(0x28c3c, 0xffffffff); // PA_SC_AA_MASK
+}
+
+// In resolve mode the CB reads the multisampled CB0 and writes the averaged
+// fragments to CB1 for every pixel covered by the next draw. The shader
+// output is ignored, so any rectangle covering the target will do.
+pub fn setup_resolve(cs: &mut CS, src: &ColorBuffer, dst: &ColorBuffer,
+                     src_reloc: &Fn(&mut CS) -> (), dst_reloc: &Fn(&mut CS) -> ()) {
+	cs.write_label("setup resolve");
+	set_color_buffer(cs, 0, src, src_reloc);
+	set_color_buffer(cs, 1, dst, dst_reloc);
+	cs.set_reg(0x28238, 0xff); // CB_TARGET_MASK
+	cs.set_reg(0x28808, cb_color_control(CB_MODE_RESOLVE)); // CB_COLOR_CONTROL
+}
+
+// Clears
+//
+// Surfaces with a CMASK can be cleared without touching the color data:
+// setting every CMASK element to 0 marks the tile as fast cleared, and the
+// CB substitutes CB_COLORn_CLEAR_WORD0..3 when reading it. Before anything
+// but the CB looks at the surface (or before a resolve), an eliminate pass
+// writes the clear value into the tiles that are still marked. Linear
+// surfaces have no CMASK and get cleared by drawing a rectangle.
+
+pub const EVENT_TYPE_VGT_FLUSH: u32 = 0x24;
+pub const EVENT_TYPE_FLUSH_AND_INV_CB_META: u32 = 46;
+pub const CB_ACTION_ENA_bit: u32 = 1 << 25;
+pub const CB0_DEST_BASE_ENA_bit: u32 = 1 << 6;
+const CP_DMA_MAX_BYTE_COUNT: u32 = (1 << 21) - 8;
+
+fn linear_to_srgb(c: f32) -> f32 {
+	let c = c.max(0.0).min(1.0);
+	if c <= 0.0031308 { c * 12.92 } else { 1.055 * c.powf(1.0/2.4) - 0.055 }
+}
+
+// CLEAR_WORD0 holds the clear value in the surface format, which here is
+// always COLOR_8_8_8_8 with NUMBER_SRGB.
+pub fn clear_words_rgba8_srgb(rgba: [f32; 4]) -> [u32; 4] {
+	let mut word = 0;
+	for i in 0..4 {
+		let c = if i < 3 { linear_to_srgb(rgba[i]) } else { rgba[i].max(0.0).min(1.0) };
+		word |= ((c * 255.0 + 0.5) as u32) << (8*i);
+	}
+	[word, 0, 0, 0]
+}
+
+impl ColorBuffer {
+	pub fn set_fast_clear(&mut self, rgba: [f32; 4]) {
+		let words = clear_words_rgba8_srgb(rgba);
+		self.info |= 1<<17; // FAST_CLEAR
+		self.clear_word0 = words[0];
+		self.clear_word1 = words[1];
+		self.clear_word2 = words[2];
+		self.clear_word3 = words[3];
+	}
+}
+
+pub fn event_write(cs: &mut CS, event_type: u32) {
+	cs.write(&[
+		packet3(Packet3::EVENT_WRITE, 0, 0),
+		event_type | event_index(0)
+	]);
+}
+
+// Fill a dword aligned range of a BO with a constant, without going
+// through the CPU mapping.
+pub fn cp_dma_fill(cs: &mut CS, offset: u32, size: u32, value: u32, bo_reloc: &Fn(&mut CS) -> ()) {
+	cs.write_label("cp dma fill");
+	let mut done = 0;
+	while done < size {
+		let count = std::cmp::min(size - done, CP_DMA_MAX_BYTE_COUNT);
+		let last = done + count == size;
+		cs.write(&[
+			packet3(Packet3::CP_DMA, 4, 0),
+			value,                                       // DATA
+			(if last {1<<31} else {0}) /* CP_SYNC */ | (2<<29) /* SRC_SEL data */,
+			offset + done,                               // DST_ADDR_LO
+			0,                                           // DST_ADDR_HI
+			count                                        // BYTE_COUNT
+		]);
+		bo_reloc(cs);
+		done += count;
+	}
+}
+
+pub fn fast_clear_cmask(cs: &mut CS, surf: &MsaaSurface, msaa_reloc: &Fn(&mut CS) -> ()) {
+	cs.write_label("fast clear");
+	event_write(cs, EVENT_TYPE_FLUSH_AND_INV_CB_META);
+	cp_dma_fill(cs, surf.cmask_offset, surf.cmask_size, 0, msaa_reloc);
+	surface_sync(cs, CB_ACTION_ENA_bit | CB0_DEST_BASE_ENA_bit, surf.cmask_size, surf.cmask_offset, 5, msaa_reloc);
+}
+
+// The next draw writes the clear value into all fast cleared tiles it
+// covers and marks them as expanded again.
+pub fn setup_eliminate_fast_clear(cs: &mut CS, cb: &ColorBuffer, bo_reloc: &Fn(&mut CS) -> ()) {
+	cs.write_label("setup eliminate fast clear");
+	set_color_buffer(cs, 0, cb, bo_reloc);
+	cs.set_reg(0x28238, 15); // CB_TARGET_MASK
+	cs.set_reg(0x28808, cb_color_control(CB_MODE_ELIMINATE_FAST_CLEAR)); // CB_COLOR_CONTROL
+}
+
 pub fn setup_spi<'a>(cs: &'a mut CS) {
 	cs.write_label("setting up spi");
 	if false { // already done above
 		cs.set_reg(0x28644, 0x00000401); /* SPI_PS_INPUT_CNTL_0 */ /* 4=flat shader 1=semantic 1?? */
-	} else {
-		cs.set_reg(0x286C4, 0); /* SPI_VS_OUT_CONFIG */
 	}
-	
+	// SPI_VS_OUT_CONFIG is set by evergreen_vs_setup
+
 	cs.set_reg_n(0x286cc, 3);
 	if true {
 		cs.emit(/*0x286cc,*/ 0x20000000); /* SPI_PS_IN_CONTROL_0 */ /* do it like radeondemo */
@@ -343,8 +925,25 @@ pub fn setup_spi<'a>(cs: &'a mut CS) {
     cs.set_reg(0x286e4, 0x00000000); /* SPI_PS_IN_CONTROL_2 */
 }
 
-const TC_ACTION_ENA_bit: u32 = 1 << 23;
-const SH_ACTION_ENA_bit: u32 = 1 << 27;
+// Routes VS PARAM n to PS input n for the first num_params parameters.
+// They are interpolated with the perspective barycentrics, which is what
+// the INTERP instructions of builder shaders read from R0.xy.
+pub fn setup_interpolants(cs: &mut CS, num_params: u32) {
+	assert!(num_params <= 32, "too many interpolants");
+	cs.write_label("setting up interpolants");
+	for n in 0..(num_params+3)/4 {
+		let ids = (0..4).fold(0, |ids, i| ids | (4*n + i) << (8*i));
+		cs.set_reg(0x2861c + 4*n, ids); // SPI_VS_OUT_ID_n
+	}
+	for n in 0..num_params {
+		cs.set_reg(0x28644 + 4*n, n); // SPI_PS_INPUT_CNTL_n // SEMANTIC
+	}
+	cs.set_reg(0x286cc, num_params | (1<<28)); /* SPI_PS_IN_CONTROL_0 */ /* NUM_INTERP, PERSP_GRADIENT_ENA */
+	cs.set_reg(0x286e0, 0x00000001); /* SPI_BARYC_CNTL */ /* PERSP_CENTER_ENA */
+}
+
+pub const TC_ACTION_ENA_bit: u32 = 1 << 23;
+pub const SH_ACTION_ENA_bit: u32 = 1 << 27;
 
 // surface_sync(cs, SH_ACTION_ENA_bit, , );
 pub fn surface_sync(cs: &mut CS, sync_type: u32, cp_coher_size: u32, mc_addr: u32, number: u64, bo_reloc: &Fn(&mut CS) -> ()) {
@@ -361,16 +960,108 @@ pub fn surface_sync(cs: &mut CS, sync_type: u32, cp_coher_size: u32, mc_addr: u3
 	bo_reloc(cs);
 }
 
-pub fn set_ps_const_buffer(cs: &mut CS, offset: u32, bo_reloc: &Fn(&mut CS) -> ()) {
-	let size = 1;
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum ShaderStage { Ps, Vs, Gs, Hs, Ls }
+
+pub const MAX_CONST_BUFFERS: u32 = 16;
+
+// (SQ_ALU_CONST_BUFFER_SIZE_*_0, SQ_ALU_CONST_CACHE_*_0)
+fn const_buffer_regs(stage: ShaderStage) -> (u32, u32) {
+	match stage {
+		ShaderStage::Ps => (0x28140, 0x28940),
+		ShaderStage::Vs => (0x28180, 0x28980),
+		ShaderStage::Gs => (0x281c0, 0x289c0),
+		ShaderStage::Hs => (0x28f80, 0x28f00),
+		ShaderStage::Ls => (0x28fc0, 0x28f40)
+	}
+}
+
+// Both the address and the size are in units of 256 bytes (16 vec4s), so
+// constant buffers need to be 256 byte aligned.
+pub fn set_const_buffer(cs: &mut CS, stage: ShaderStage, slot: u32, offset: u32, size: u32, bo_reloc: &Fn(&mut CS) -> ()) {
+	assert!(slot < MAX_CONST_BUFFERS, "constant buffer slot out of range");
+	assert!(offset & 0xff == 0, "constant buffer not 256 byte aligned");
+	let (size_reg, cache_reg) = const_buffer_regs(stage);
 	surface_sync(cs, SH_ACTION_ENA_bit, size, offset, 1, bo_reloc);
-	cs.set_reg(0x28140, 1);         // SQ_ALU_CONST_BUFFER_SIZE_PS_0
-	cs.set_reg(0x28940, offset>>8); // SQ_ALU_CONST_CACHE_PS_0
+	cs.set_reg(size_reg + 4*slot, (size+255)>>8); // SQ_ALU_CONST_BUFFER_SIZE_*_n
+	cs.set_reg(cache_reg + 4*slot, offset>>8);    // SQ_ALU_CONST_CACHE_*_n
 	bo_reloc(cs);
 }
 
+// Suballocates constant buffers from a region of a BO, so that every draw
+// can have its own constants. The contents are collected on the CPU while
+// the CS is built and copied into the BO before submission. Allocations
+// wrap around at the end of the region, but never into data of the current
+// frame.
+pub struct ConstRing {
+	base: u32,
+	data: Vec<u8>,
+	head: u32,
+	frame_start: u32,
+	frame_used: u32
+}
+
+impl ConstRing {
+	pub fn new(base: u32, size: u32) -> ConstRing {
+		assert!(base & 0xff == 0 && size & 0xff == 0, "constant ring not 256 byte aligned");
+		ConstRing { base: base, data: vec![0; size as usize], head: 0, frame_start: 0, frame_used: 0 }
+	}
+
+	pub fn begin_frame(&mut self) {
+		self.frame_start = self.head;
+		self.frame_used = 0;
+	}
+
+	// Returns the BO offset of the copy
+	pub fn push(&mut self, consts: &[f32]) -> u32 {
+		let size = ((consts.len() as u32 * 4) + 255) & !255;
+		let len = self.data.len() as u32;
+		let start = if self.head + size > len { 0 } else { self.head };
+		let skipped = if start == self.head { 0 } else { len - self.head };
+		assert!(self.frame_used + skipped + size <= len, "constant ring overflow within one frame");
+
+		for (i, c) in consts.iter().enumerate() {
+			let o = start as usize + i*4;
+			self.data[o..o+4].copy_from_slice(&c.to_bits().to_le_bytes());
+		}
+		self.head = start + size;
+		self.frame_used += skipped + size;
+		self.base + start
+	}
+
+	pub fn bind(&mut self, cs: &mut CS, stage: ShaderStage, slot: u32, consts: &[f32], bo_reloc: &Fn(&mut CS) -> ()) {
+		let offset = self.push(consts);
+		set_const_buffer(cs, stage, slot, offset, consts.len() as u32 * 4, bo_reloc);
+	}
+
+	// dst is the ring region of the mapped BO
+	pub fn upload(&self, dst: &mut [u8]) {
+		let len = self.data.len() as u32;
+		let mut o = self.frame_start;
+		let mut left = self.frame_used;
+		while left > 0 {
+			let n = std::cmp::min(left, len - o);
+			dst[o as usize..(o+n) as usize].copy_from_slice(&self.data[o as usize..(o+n) as usize]);
+			o = (o + n) % len;
+			left -= n;
+		}
+	}
+}
+
+// First fetch resource of each stage, fetch instructions index relative to it
+pub const FETCH_RESOURCE_OFFSET_PS: u32 = 0;
+pub const FETCH_RESOURCE_OFFSET_VS: u32 = 176;
+pub const FETCH_RESOURCE_OFFSET_GS: u32 = 336;
+pub const FETCH_RESOURCE_OFFSET_HS: u32 = 496;
+pub const FETCH_RESOURCE_OFFSET_LS: u32 = 656;
+pub const FETCH_RESOURCE_OFFSET_CS: u32 = 816;
+
 pub fn set_vtx_resource<'a>(cs: &'a mut CS, vtxres: &VtxRes, bo_reloc: &Fn(&mut CS) -> ()) {
-	let base: u32 = 0x30000 + 8 * 4 * 176;
+	set_buffer_resource(cs, FETCH_RESOURCE_OFFSET_VS, vtxres, bo_reloc);
+}
+
+pub fn set_buffer_resource(cs: &mut CS, resource: u32, vtxres: &VtxRes, bo_reloc: &Fn(&mut CS) -> ()) {
+	let base: u32 = 0x30000 + 8 * 4 * resource;
 	surface_sync(cs, TC_ACTION_ENA_bit, vtxres.bytesize, vtxres.byteoffset, 2, bo_reloc);
 
 	cs.write_label("setting up vtx resource");
@@ -385,6 +1076,65 @@ pub fn set_vtx_resource<'a>(cs: &'a mut CS, vtxres: &VtxRes, bo_reloc: &Fn(&mut
 	cs.emit(3<<30); // valid buffer
 }
 
+// A linear 2D texture with a single mip level
+pub struct TexRes {
+	pub offset: u32, // 256 byte aligned
+	pub width: u32,
+	pub height: u32,
+	pub pitch: u32, // in pixels, a multiple of 8
+	pub data_format: u32, // FMT_*
+	pub dst_sel: [u32; 4], // SEL_* per returned component
+	pub degamma: bool // for sRGB data
+}
+
+pub fn set_texture_resource(cs: &mut CS, resource: u32, tex: &TexRes, bo_reloc: &Fn(&mut CS) -> ()) {
+	assert!(tex.offset & 0xff == 0, "texture not 256 byte aligned");
+	assert!(tex.pitch & 7 == 0 && tex.pitch >= tex.width, "bad texture pitch");
+	let base: u32 = 0x30000 + 8 * 4 * resource;
+	let size = tex.pitch * tex.height * format_bytes(tex.data_format);
+	surface_sync(cs, TC_ACTION_ENA_bit, size, tex.offset, 2, bo_reloc);
+
+	cs.write_label("setting up tex resource");
+	cs.set_reg_n(base, 8);
+	cs.emit(1 /* SQ_TEX_DIM_2D */ | ((tex.pitch/8-1) << 6) | ((tex.width-1) << 18)); // SQ_TEX_RESOURCE_WORD0
+	cs.emit(tex.height-1); // SQ_TEX_RESOURCE_WORD1 // ARRAY_LINEAR_GENERAL
+	cs.emit(tex.offset >> 8); // SQ_TEX_RESOURCE_WORD2 // BASE_ADDRESS
+	cs.emit(tex.offset >> 8); // SQ_TEX_RESOURCE_WORD3 // MIP_ADDRESS
+	cs.emit((if tex.degamma {1<<11} else {0}) | (tex.dst_sel[0] << 16) | (tex.dst_sel[1] << 19) |
+		(tex.dst_sel[2] << 22) | (tex.dst_sel[3] << 25)); // SQ_TEX_RESOURCE_WORD4
+	cs.emit(0); // SQ_TEX_RESOURCE_WORD5 // levels and slices 0 to 0
+	cs.emit(0); // SQ_TEX_RESOURCE_WORD6
+	cs.emit(tex.data_format | (2<<30)); // SQ_TEX_RESOURCE_WORD7 // valid texture
+	bo_reloc(cs); // BASE_ADDRESS
+	bo_reloc(cs); // MIP_ADDRESS
+}
+
+fn format_bytes(data_format: u32) -> u32 {
+	match data_format {
+		1 /* FMT_8 */ => 1,
+		7 /* FMT_8_8 */ => 2,
+		26 /* FMT_8_8_8_8 */ => 4,
+		_ => panic!("unsupported texture format {}", data_format)
+	}
+}
+
+// First sampler of each stage, like the fetch resources
+pub const SAMPLER_OFFSET_PS: u32 = 0;
+pub const SAMPLER_OFFSET_VS: u32 = 18;
+pub const SAMPLER_OFFSET_GS: u32 = 36;
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum TexFilter { Point = 0, Bilinear = 1 }
+
+// Clamps to the edge texels and never leaves mip level 0
+pub fn set_sampler(cs: &mut CS, sampler: u32, filter: TexFilter) {
+	let clamp = 2; // SQ_TEX_CLAMP_LAST_TEXEL
+	cs.set_reg_n(0x3c000 + 3 * 4 * sampler, 3);
+	cs.emit(clamp | (clamp << 3) | (clamp << 6) | ((filter as u32) << 9) | ((filter as u32) << 11)); // SQ_TEX_SAMPLER_WORD0
+	cs.emit(0); // SQ_TEX_SAMPLER_WORD1 // MIN_LOD = MAX_LOD = 0
+	cs.emit(1 << 31); // SQ_TEX_SAMPLER_WORD2 // TYPE
+}
+
 // ES = export shader
 // FS = fetch shader
 // GS = geometry shader
@@ -393,37 +1143,39 @@ pub fn set_vtx_resource<'a>(cs: &'a mut CS, vtxres: &VtxRes, bo_reloc: &Fn(&mut
 // PS = pixel shader
 // VS = vertex shader
 
-pub fn evergreen_vs_setup(cs: &mut CS, vs_conf: &ShaderConfig, bo_reloc: &Fn(&mut CS) -> ()) {
+pub fn evergreen_vs_setup(cs: &mut CS, vs: &ShaderObject, shader_addr: u32, bo_reloc: &Fn(&mut CS) -> ()) {
 	cs.write_label("evergreen_vs_setup");
 
 	let mut sq_pgm_resources: u32;
 	let mut sq_pgm_resources_2: u32 = 0;
 
-	sq_pgm_resources =  (vs_conf.num_gprs << NUM_GPRS_shift) |
-						(vs_conf.stack_size << STACK_SIZE_shift);
+	sq_pgm_resources =  (vs.num_gprs << NUM_GPRS_shift) |
+						(vs.stack_size << STACK_SIZE_shift);
 
 	/* flush SQ cache */
 	// evergreen_cp_set_surface_sync(radeon, SH_ACTION_ENA_bit,
 	// 			  vs_conf.shader_size, vs_conf->shader_addr,
 	// 			  vs_conf.bo, domain, 0);
-	surface_sync(cs, SH_ACTION_ENA_bit, vs_conf.shader_size, vs_conf.shader_addr, 3, bo_reloc);
+	surface_sync(cs, SH_ACTION_ENA_bit, vs.size(), shader_addr, 3, bo_reloc);
 
-	cs.set_reg(0x2885c, vs_conf.shader_addr >> 8); // SQ_PGM_START_VS
+	cs.set_reg(0x2885c, shader_addr >> 8); // SQ_PGM_START_VS
 	bo_reloc(cs);
 
 	cs.set_reg_n(0x28860, 2); // SQ_PGM_RESOURCES_VS
 	cs.emit(sq_pgm_resources);
 	cs.emit(sq_pgm_resources_2);
+
+	cs.set_reg(0x286c4, vs.vs_export_count() << 1); // SPI_VS_OUT_CONFIG
 }
 
-pub fn evergreen_ps_setup(cs: &mut CS, ps_conf: &ShaderConfig, bo_reloc: &Fn(&mut CS) -> ()) {
+pub fn evergreen_ps_setup(cs: &mut CS, ps: &ShaderObject, shader_addr: u32, bo_reloc: &Fn(&mut CS) -> ()) {
 	cs.write_label("evergreen_ps_setup");
 
 	let sq_pgm_resources: u32;
 	let sq_pgm_resources_2: u32 = 0;
 
-	sq_pgm_resources = (ps_conf.num_gprs << NUM_GPRS_shift) |
-					   (ps_conf.stack_size << STACK_SIZE_shift);
+	sq_pgm_resources = (ps.num_gprs << NUM_GPRS_shift) |
+					   (ps.stack_size << STACK_SIZE_shift);
 
 	/*if (ps_conf->dx10_clamp)
 	sq_pgm_resources |= DX10_CLAMP_bit;
@@ -448,18 +1200,149 @@ pub fn evergreen_ps_setup(cs: &mut CS, ps_conf: &ShaderConfig, bo_reloc: &Fn(&mu
 	// evergreen_cp_set_surface_sync(radeon, SH_ACTION_ENA_bit,
 	// 			  ps_conf->shader_size, ps_conf->shader_addr,
 	// 			  ps_conf->bo, domain, 0);
-	surface_sync(cs, SH_ACTION_ENA_bit, ps_conf.shader_size, ps_conf.shader_addr, 4, bo_reloc);
+	surface_sync(cs, SH_ACTION_ENA_bit, ps.size(), shader_addr, 4, bo_reloc);
 
-	cs.set_reg(0x28840, ps_conf.shader_addr >> 8); // SQ_PGM_START_FS
+	cs.set_reg(0x28840, shader_addr >> 8); // SQ_PGM_START_FS
 	bo_reloc(cs);
 
 	cs.set_reg_n(0x28844, 3); // SQ_PGM_RESOURCES_PS
 	cs.emit(sq_pgm_resources);    // SQ_PGM_RESOURCES_PS
 	cs.emit(sq_pgm_resources_2);  // SQ_PGM_RESOURCES_2_PS
-	cs.emit(ps_conf.export_mode); // SQ_PGM_EXPORTS_PS
+	cs.emit(ps.ps_exports());     // SQ_PGM_EXPORTS_PS
 }
 
 
+// ES and GS programs are set up like the VS, each with its own
+// SQ_PGM_START/SQ_PGM_RESOURCES/SQ_PGM_RESOURCES_2 triple.
+fn pgm_setup(cs: &mut CS, label: &str, start_reg: u32, shader: &ShaderObject, shader_addr: u32, number: u64, bo_reloc: &Fn(&mut CS) -> ()) {
+	cs.write_label(label);
+	surface_sync(cs, SH_ACTION_ENA_bit, shader.size(), shader_addr, number, bo_reloc);
+
+	cs.set_reg(start_reg, shader_addr >> 8); // SQ_PGM_START_*
+	bo_reloc(cs);
+
+	cs.set_reg_n(start_reg + 4, 2);
+	cs.emit((shader.num_gprs << NUM_GPRS_shift) | (shader.stack_size << STACK_SIZE_shift)); // SQ_PGM_RESOURCES_*
+	cs.emit(0); // SQ_PGM_RESOURCES_2_*
+}
+
+pub fn evergreen_gs_setup(cs: &mut CS, gs: &ShaderObject, shader_addr: u32, bo_reloc: &Fn(&mut CS) -> ()) {
+	pgm_setup(cs, "evergreen_gs_setup", 0x28874, gs, shader_addr, 3, bo_reloc); // SQ_PGM_START_GS
+}
+
+pub fn evergreen_es_setup(cs: &mut CS, es: &ShaderObject, shader_addr: u32, bo_reloc: &Fn(&mut CS) -> ()) {
+	pgm_setup(cs, "evergreen_es_setup", 0x2888c, es, shader_addr, 3, bo_reloc); // SQ_PGM_START_ES
+}
+
+// Pipeline stages, see evergreen_emit_shader_stages in mesa
+//
+// Without a GS, the VS runs on the VS stage. A GS moves the vertex shader
+// to the ES stage, which writes its outputs to the ESGS ring. The GS reads
+// them from there, writes its vertices to the GSVS ring, and a copy shader
+// on the VS stage reads them back and exports them. Tessellation (LS, HS
+// and the domain shader) isn't set up.
+
+pub const VGT_GS_MODE_COMPUTE_MODE: u32 = 1 << 14;
+pub const VGT_GS_MODE_PARTIAL_THD_AT_EOI: u32 = 1 << 17;
+const GS_SCENARIO_G: u32 = 3;
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum GsOutPrim { Points = 0, LineStrip = 1, TriStrip = 2 }
+
+pub struct GsState {
+	pub max_vert_out: u32,
+	pub out_prim: GsOutPrim,
+	pub esgs_itemsize: u32, // bytes the ES writes per vertex
+	pub gsvs_itemsize: u32, // bytes the GS writes per output vertex
+	pub prim_id: bool       // the GS reads the primitive id
+}
+
+// Both rings live in the BO, sizes are multiples of 256 bytes
+pub struct GsRings {
+	pub esgs_offset: u32,
+	pub esgs_size: u32,
+	pub gsvs_offset: u32,
+	pub gsvs_size: u32
+}
+
+pub fn setup_shader_stages(cs: &mut CS, gs: Option<&GsState>) {
+	cs.write_label("setup shader stages");
+	let mut stages = 0;
+	let mut gs_mode = 0;
+	if let Some(gs) = gs {
+		// GS_CUT_MODE sizes the cut buffer for the vertices a GS emits
+		let cut_mode = match gs.max_vert_out {
+			0 ..= 128 => 3,
+			129 ..= 256 => 2,
+			257 ..= 512 => 1,
+			_ => 0
+		};
+		stages |= (2<<3) /* ES_EN real */ | (1<<5) /* GS_EN */ | (2<<6) /* VS_EN copy shader */;
+		gs_mode = GS_SCENARIO_G | (cut_mode << 3);
+	}
+	cs.set_reg(0x28b54, stages);  // VGT_SHADER_STAGES_EN
+	cs.set_reg(0x28a40, gs_mode); // VGT_GS_MODE
+	cs.set_reg(0x28a84, if gs.map(|gs| gs.prim_id).unwrap_or(false) {1} else {0}); // VGT_PRIMITIVEID_EN
+}
+
+// see evergreen_update_gs_state in mesa
+pub fn setup_gs(cs: &mut CS, gs: &GsState) {
+	cs.write_label("setup gs");
+	let gsvs_vertex = gs.gsvs_itemsize >> 2;
+	let gsvs_prim = (gs.gsvs_itemsize * gs.max_vert_out) >> 2;
+	cs.set_reg(0x28b38, gs.max_vert_out); // VGT_GS_MAX_VERT_OUT
+	cs.set_reg(0x28a6c, gs.out_prim as u32); // VGT_GS_OUT_PRIM_TYPE
+	cs.set_reg_n(0x2891c, 4); // SQ_GS_VERT_ITEMSIZE
+	cs.emit(gsvs_vertex); // SQ_GS_VERT_ITEMSIZE
+	cs.emit(0);           // SQ_GS_VERT_ITEMSIZE_1
+	cs.emit(0);           // SQ_GS_VERT_ITEMSIZE_2
+	cs.emit(0);           // SQ_GS_VERT_ITEMSIZE_3
+	cs.set_reg(0x28900, gs.esgs_itemsize >> 2); // SQ_ESGS_RING_ITEMSIZE
+	cs.set_reg(0x28904, gsvs_prim); // SQ_GSVS_RING_ITEMSIZE
+	cs.set_reg_n(0x2892c, 3); // SQ_GSVS_RING_OFFSET_1 // only stream 0 is used
+	cs.emit(gsvs_prim);
+	cs.emit(gsvs_prim);
+	cs.emit(gsvs_prim);
+	cs.set_reg_n(0x28a54, 3); // VGT_GS_PER_ES
+	cs.emit(0x80);  // VGT_GS_PER_ES
+	cs.emit(0x100); // VGT_ES_PER_GS
+	cs.emit(0x2);   // VGT_GS_PER_VS
+}
+
+// The GS reads the ESGS ring and the copy shader the GSVS ring through
+// fetch resources, see set_ring_resource.
+pub fn setup_gs_rings(cs: &mut CS, rings: Option<&GsRings>, bo_reloc: &Fn(&mut CS) -> ()) {
+	cs.write_label("setup gs rings");
+	cs.set_reg(0x8040, 1<<15); // WAIT_UNTIL // WAIT_3D_IDLE
+	if let Some(r) = rings {
+		assert!((r.esgs_offset | r.esgs_size | r.gsvs_offset | r.gsvs_size) & 0xff == 0, "GS rings not 256 byte aligned");
+		cs.set_reg(0x8c40, r.esgs_offset >> 8); // SQ_ESGS_RING_BASE
+		bo_reloc(cs);
+		cs.set_reg(0x8c44, r.esgs_size >> 8);   // SQ_ESGS_RING_SIZE
+		cs.set_reg(0x8c48, r.gsvs_offset >> 8); // SQ_GSVS_RING_BASE
+		bo_reloc(cs);
+		cs.set_reg(0x8c4c, r.gsvs_size >> 8);   // SQ_GSVS_RING_SIZE
+	} else {
+		cs.set_reg(0x8c44, 0); // SQ_ESGS_RING_SIZE
+		cs.set_reg(0x8c4c, 0); // SQ_GSVS_RING_SIZE
+	}
+	cs.set_reg(0x8040, 1<<15); // WAIT_UNTIL // WAIT_3D_IDLE
+	event_write(cs, EVENT_TYPE_VGT_FLUSH);
+}
+
+// A ring as a buffer for fetch instructions of the stage starting at
+// resource_base, e.g. FETCH_RESOURCE_OFFSET_GS + n for BUFFER_ID n in the GS.
+pub fn set_ring_resource(cs: &mut CS, resource: u32, offset: u32, size: u32, bo_reloc: &Fn(&mut CS) -> ()) {
+	let res = VtxRes {
+		byteoffset: offset,
+		bytesize: size,
+		stride: 4,
+		vtxcount: size / 4
+	};
+	set_buffer_resource(cs, resource, &res, bo_reloc);
+	bo_reloc(cs);
+}
+
 pub fn vbo<'a>(cs: &'a mut CS, info: DrawInfo) {
 	// see r600_draw_vbo in mesa
 
@@ -477,8 +1360,7 @@ pub fn vbo<'a>(cs: &'a mut CS, info: DrawInfo) {
 		// not used in radeondemo
 		cs.set_reg(/*R_*/0x03CFF4/*_SQ_VTX_START_INST_LOC*/, 0);
 	}
-	const V_008958_DI_PT_TRILIST: u32 = 4;
-	cs.set_reg(R_008958_VGT_PRIMITIVE_TYPE, V_008958_DI_PT_TRILIST);
+	cs.set_reg(R_008958_VGT_PRIMITIVE_TYPE, info.prim_type);
 
 	if info.indexed {
 		let r600_big_endian = false;
@@ -0,0 +1,231 @@
+// Reading what the radeon kernel driver shows in debugfs after a hang:
+// /sys/kernel/debug/dri/<n>/radeon_ring_gfx and radeon_fence_info.
+//
+// The ring file has the pointers and a listing of the ring from 32 dwords
+// before the read pointer up to past the write pointer:
+//
+//     wptr: 0x00000140 [  320]
+//     rptr: 0x00000129 [  297]
+//     rptr next(0x850c): 0x00000129 [  297]
+//     ...
+//     r[  297]=0xc0034300 * #
+//
+// '*' marks rptr, '#' the rptr the kernel had the CP write to a scratch
+// register just before its last INDIRECT_BUFFER. The listing usually
+// starts inside a packet, so decoding starts at the first dword from which
+// the packets chain up to wptr exactly.
+
+use std::fmt::Write;
+use num;
+use cs::Packet3;
+
+pub struct RingDump {
+	pub wptr: u32,
+	pub rptr: u32,
+	pub rptr_next: Option<u32>,
+	// (ring index, value) in listing order, which wraps around the ring
+	pub dwords: Vec<(u32, u32)>
+}
+
+pub struct FenceInfo {
+	pub ring: u32,
+	pub last_signaled: u64,
+	pub last_emitted: u64
+}
+
+// A packet in the listing
+pub struct RingPacket {
+	pub start: usize, // into RingDump::dwords
+	pub len: usize
+}
+
+pub struct Summary {
+	// ring index decoding starts at
+	pub first_packet: Option<u32>,
+	// ring index of the packet rptr is in, None when the CP caught up with wptr
+	pub stopped_at: Option<u32>,
+	// the IB the CP was executing: address and size in dwords
+	pub in_ib: Option<(u64, u32)>
+}
+
+fn parse_number(s: &str) -> Result<u64, String> {
+	let s = s.trim();
+	let r = if s.starts_with("0x") { u64::from_str_radix(&s[2..], 16) } else { s.parse() };
+	r.map_err(|_| format!("bad number '{}'", s))
+}
+
+// "0x00000140 [  320]"
+fn parse_pointer(s: &str) -> Result<u32, String> {
+	parse_number(s.split('[').next().unwrap()).map(|v| v as u32)
+}
+
+pub fn parse_ring(text: &str) -> Result<RingDump, String> {
+	let (mut wptr, mut rptr, mut rptr_next) = (None, None, None);
+	let mut dwords = Vec::new();
+	for (n, line) in text.lines().enumerate() {
+		let line = line.trim();
+		let err = |e: String| format!("line {}: {}", n + 1, e);
+		if line.starts_with("wptr:") {
+			wptr = Some(parse_pointer(&line[5..]).map_err(&err)?);
+		} else if line.starts_with("rptr:") {
+			rptr = Some(parse_pointer(&line[5..]).map_err(&err)?);
+		} else if line.starts_with("rptr next") {
+			let value = line.splitn(2, "):").nth(1).ok_or(err("no value".to_owned()))?;
+			rptr_next = Some(parse_pointer(value).map_err(&err)?);
+		} else if line.starts_with("r[") {
+			let close = line.find("]=").ok_or(err("no ]=".to_owned()))?;
+			let index = parse_number(&line[2..close]).map_err(&err)? as u32;
+			let value = line[close+2..].split_whitespace().next().ok_or(err("no value".to_owned()))?;
+			dwords.push((index, parse_number(value).map_err(&err)? as u32));
+		}
+		// the driver's wptr, semaphore addresses and dword counts aren't needed
+	}
+	Ok(RingDump {
+		wptr: wptr.ok_or("no wptr")?,
+		rptr: rptr.ok_or("no rptr")?,
+		rptr_next: rptr_next,
+		dwords: dwords
+	})
+}
+
+pub fn parse_fences(text: &str) -> Result<Vec<FenceInfo>, String> {
+	let mut fences: Vec<FenceInfo> = Vec::new();
+	for (n, line) in text.lines().enumerate() {
+		let line = line.trim();
+		let err = |e: String| format!("line {}: {}", n + 1, e);
+		if line.starts_with("--- ring ") {
+			let ring = parse_number(line[9..].trim_end_matches('-')).map_err(&err)?;
+			fences.push(FenceInfo { ring: ring as u32, last_signaled: 0, last_emitted: 0 });
+		} else if line.starts_with("Last signaled fence") || line.starts_with("Last emitted") {
+			let value = parse_number(line.split_whitespace().last().unwrap()).map_err(&err)?;
+			let fence = fences.last_mut().ok_or(err("fence before a ring".to_owned()))?;
+			if line.starts_with("Last signaled") { fence.last_signaled = value } else { fence.last_emitted = value }
+		}
+	}
+	Ok(fences)
+}
+
+fn opcode(header: u32) -> Option<Packet3> {
+	num::FromPrimitive::from_u32((header >> 8) & 0xff)
+}
+
+// Length of the packet starting with header; the kernel only puts type 2
+// fillers and type 3 packets it knows into the ring
+fn packet_len(header: u32) -> Option<usize> {
+	match header >> 30 {
+		2 => Some(1),
+		3 if opcode(header).is_some() => Some(((header >> 16) & 0x3fff) as usize + 2),
+		_ => None
+	}
+}
+
+pub fn decode(dump: &RingDump) -> Vec<RingPacket> {
+	let end = dump.dwords.iter().position(|&(i, _)| i == dump.wptr).unwrap_or(dump.dwords.len());
+	for first in 0..end {
+		let mut packets = Vec::new();
+		let mut at = first;
+		while at < end {
+			match packet_len(dump.dwords[at].1) {
+				Some(len) => { packets.push(RingPacket { start: at, len: len }); at += len },
+				None => break
+			}
+		}
+		if at == end {
+			return packets
+		}
+	}
+	Vec::new()
+}
+
+fn ib_of(dump: &RingDump, p: &RingPacket) -> Option<(u64, u32)> {
+	let d = |k: usize| dump.dwords.get(p.start + k).map(|&(_, v)| v).unwrap_or(0);
+	match opcode(d(0)) {
+		Some(Packet3::INDIRECT_BUFFER) if d(0) >> 30 == 3 =>
+			Some(((d(2) as u64 & 0xff) << 32 | (d(1) & !3) as u64, d(3))),
+		_ => None
+	}
+}
+
+pub fn summarize(dump: &RingDump, packets: &[RingPacket]) -> Summary {
+	let index = |p: &RingPacket| dump.dwords[p.start].0;
+	let at_rptr = packets.iter().position(|p| (p.start..p.start+p.len).any(|k| dump.dwords[k].0 == dump.rptr));
+	Summary {
+		first_packet: packets.first().map(&index),
+		stopped_at: at_rptr.map(|k| index(&packets[k])),
+		// rptr right after an INDIRECT_BUFFER means the CP is still in that IB
+		in_ib: at_rptr.and_then(|k| if k > 0 { ib_of(dump, &packets[k - 1]) } else { None })
+	}
+}
+
+// What a packet means for the hang, if anything
+fn note(dump: &RingDump, p: &RingPacket, gfx_fence: Option<&FenceInfo>) -> String {
+	let d = |k: usize| dump.dwords.get(p.start + k).map(|&(_, v)| v).unwrap_or(0);
+	if let Some((addr, size)) = ib_of(dump, p) {
+		return format!("IB at {:#x}, {} dwords", addr, size)
+	}
+	match opcode(d(0)) {
+		Some(Packet3::EVENT_WRITE_EOP) if d(0) >> 30 == 3 && p.len >= 5 => match gfx_fence {
+			Some(f) if (d(4) as u64) <= (f.last_signaled & 0xffffffff) => format!("fence {:#x}, signaled", d(4)),
+			Some(_) => format!("fence {:#x}, not signaled", d(4)),
+			None => format!("fence {:#x}", d(4))
+		},
+		_ => String::new()
+	}
+}
+
+pub fn print(dump: &RingDump, fences: &[FenceInfo]) {
+	let packets = decode(dump);
+	let summary = summarize(dump, &packets);
+	let gfx_fence = fences.iter().find(|f| f.ring == 0);
+	let marks = |k: usize| {
+		let mut m = String::new();
+		if dump.dwords[k].0 == dump.rptr { m.push_str(" <- rptr") }
+		if Some(dump.dwords[k].0) == dump.rptr_next { m.push_str(" <- rptr next") }
+		m
+	};
+
+	println!("wptr {} rptr {}{}", dump.wptr, dump.rptr,
+		dump.rptr_next.map_or(String::new(), |n| format!(" rptr next {}", n)));
+	let first = packets.first().map_or(dump.dwords.len(), |p| p.start);
+	for k in 0..first {
+		println!("r[{:6}] {:08x}   part of an earlier packet{}", dump.dwords[k].0, dump.dwords[k].1, marks(k));
+	}
+	for p in &packets {
+		let (index, header) = dump.dwords[p.start];
+		let mut line = String::new();
+		if header >> 30 == 2 {
+			write!(line, "r[{:6}] {:08x} type 2 filler", index, header).unwrap();
+		} else {
+			write!(line, "r[{:6}] {:08x} {:?}", index, header, opcode(header).unwrap()).unwrap();
+			let n = note(dump, p, gfx_fence);
+			if !n.is_empty() { write!(line, "   {}", n).unwrap() }
+		}
+		println!("{}{}", line, marks(p.start));
+		for k in p.start+1..p.start+p.len {
+			println!("r[{:6}] {:08x}{}", dump.dwords[k].0, dump.dwords[k].1, marks(k));
+		}
+	}
+	let end = packets.last().map_or(first, |p| p.start + p.len);
+	for k in end..dump.dwords.len() {
+		println!("r[{:6}] {:08x}   past wptr{}", dump.dwords[k].0, dump.dwords[k].1, marks(k));
+	}
+
+	println!("");
+	if packets.is_empty() {
+		println!("the listing doesn't decode into packets up to wptr");
+	} else if dump.rptr == dump.wptr {
+		println!("the CP caught up with the ring, it isn't stuck on a ring packet");
+	} else if let Some((addr, size)) = summary.in_ib {
+		println!("the CP stopped in the IB at {:#x} ({} dwords), look there", addr, size);
+	} else if let Some(at) = summary.stopped_at {
+		let p = packets.iter().find(|p| dump.dwords[p.start].0 == at).unwrap();
+		println!("the CP stopped at r[{}], {:?}", at, opcode(dump.dwords[p.start].1));
+	}
+	if let Some(f) = gfx_fence {
+		if f.last_signaled + 1 == f.last_emitted {
+			println!("gfx fence {:#x} hasn't signaled", f.last_emitted);
+		} else if f.last_signaled < f.last_emitted {
+			println!("gfx fences {:#x} to {:#x} haven't signaled", f.last_signaled + 1, f.last_emitted);
+		}
+	}
+}
@@ -0,0 +1,119 @@
+// The real thing: ioctls on an open /dev/dri node
+
+use std::fs;
+use std::io;
+use std::os::unix::io::{AsRawFd, RawFd};
+use libc;
+use nix;
+use drm_radeon_ioctl::*;
+use super::{CsChunk, Device, Mapping};
+
+pub struct DrmDevice {
+	file: fs::File
+}
+
+impl DrmDevice {
+	pub fn open(path: &str) -> io::Result<DrmDevice> {
+		let file = fs::OpenOptions::new().read(true).write(true).open(path)?;
+		Ok(DrmDevice { file: file })
+	}
+
+	fn raw_fd(&self) -> RawFd {
+		self.file.as_raw_fd()
+	}
+}
+
+fn check(r: nix::Result<libc::c_int>) -> io::Result<()> {
+	r.map(|_| ()).map_err(|e| io::Error::from_raw_os_error(e as i32))
+}
+
+impl Device for DrmDevice {
+	fn gem_create(&self, size: u64, domain: u32) -> io::Result<(u32, u64)> {
+		let mut create = DrmRadeonGemCreate {
+			size: size,
+			alignment: 0,
+			handle: 0,
+			initial_domain: domain,
+			flags: 0
+		};
+		check(unsafe { drm_ioctl_radeon_gem_create(self.raw_fd(), &mut create) })?;
+		Ok((create.handle, create.size))
+	}
+
+	fn gem_mmap(&self, handle: u32, size: u64) -> io::Result<Mapping> {
+		let mut mmap_args = DrmRadeonGemMmap {
+			handle: handle,
+			pad: 0,
+			offset: 0,
+			size: size,
+			addr_ptr: 0
+		};
+		check(unsafe { drm_ioctl_radeon_gem_mmap(self.raw_fd(), &mut mmap_args) })?;
+		Mapping::new(self.raw_fd(), mmap_args.addr_ptr, mmap_args.size as usize)
+	}
+
+	fn gem_info(&self) -> io::Result<DrmRadeonGemInfo> {
+		let mut info = DrmRadeonGemInfo::default();
+		check(unsafe { drm_ioctl_radeon_gem_info(self.raw_fd(), &mut info) })?;
+		Ok(info)
+	}
+
+	fn gem_wait_idle(&self, handle: u32) -> io::Result<()> {
+		let waitidle = DrmRadeonGemWaitIdle { handle: handle, pad: 0 };
+		check(unsafe { drm_ioctl_radeon_gem_wait_idle(self.raw_fd(), &waitidle) })
+	}
+
+	fn gem_busy(&self, handle: u32) -> io::Result<bool> {
+		let mut busy = DrmRadeonGemBusy { handle: handle, domain: 0 };
+		match check(unsafe { drm_ioctl_radeon_gem_busy(self.raw_fd(), &mut busy) }) {
+			Ok(()) => Ok(false),
+			Err(ref e) if e.raw_os_error() == Some(libc::EBUSY) => Ok(true),
+			Err(e) => Err(e)
+		}
+	}
+
+	fn gem_close(&self, handle: u32) -> io::Result<()> {
+		let close = DrmGemClose {
+			handle: handle,
+			pad: 0
+		};
+		check(unsafe { drm_ioctl_gem_close(self.raw_fd(), &close) })
+	}
+
+	fn cs(&self, chunks: &[CsChunk]) -> io::Result<()> {
+		let chunks: Vec<DrmRadeonCsChunk> = chunks.iter().map(|chunk| {
+			let (id, length_dw, data) = match *chunk {
+				CsChunk::Ib(ib) => (RADEON_CHUNK_ID_IB, ib.len() as u32, ib.as_ptr() as u64),
+				CsChunk::Relocs(relocs) => (RADEON_CHUNK_ID_RELOCS, relocs.len() as u32 * 4, relocs.as_ptr() as u64),
+				CsChunk::Flags(flags) => (RADEON_CHUNK_ID_FLAGS, flags.len() as u32, flags.as_ptr() as u64)
+			};
+			DrmRadeonCsChunk {
+				chunk_id: id,
+				length_dw: length_dw,
+				chunk_data: if length_dw == 0 {0} else {data}
+			}
+		}).collect();
+		// Finally, fill in the arguments for the ioctl.
+		let ahh = U64PtrSlice::new(&chunks);
+		let mut cs = DrmRadeonCs::new(&ahh);
+		check(unsafe { drm_ioctl_radeon_cs(self.raw_fd(), &mut cs) })
+	}
+
+	fn info(&self, request: u32) -> io::Result<u64> {
+		let mut value: u64 = 0;
+		let mut info = DrmRadeonInfo { request: request, pad: 0, value: &mut value as *mut u64 as u64 };
+		check(unsafe { drm_ioctl_radeon_info(self.raw_fd(), &mut info) })?;
+		Ok(value)
+	}
+
+	fn prime_handle_to_fd(&self, handle: u32) -> io::Result<RawFd> {
+		let mut ph = DrmPrimeHandle::default();
+		ph.handle = handle;
+		check(unsafe { drm_ioctl_prime_handle_to_fd(self.raw_fd(), &mut ph) })?;
+		Ok(ph.fd)
+	}
+
+	fn fd(&self) -> Option<RawFd> {
+		Some(self.raw_fd())
+	}
+}
@@ -0,0 +1,203 @@
+// A device in the same process: BOs are memfds, so mappings and prime fds
+// share their pages like with the kernel, and submissions are checked the
+// way the CS ioctl would before they optionally run on the reference
+// renderer. The GPU is never busy, everything completes at submit time,
+// unless a hang is asked for: then that submission doesn't run, BOs stay
+// busy and the next GEM_WAIT_IDLE resets the GPU like the kernel's lockup
+// detection would.
+
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+use std::ffi::CString;
+use std::io;
+use std::os::unix::io::RawFd;
+use libc;
+use drm_radeon_ioctl::*;
+use reference::Renderer;
+use shader::isa::Chip;
+use super::{CsChunk, Device, Mapping, RADEON_INFO_CLOCK_CRYSTAL_FREQ, RADEON_INFO_DEVICE_ID, RADEON_INFO_GPU_RESET_COUNTER, RADEON_INFO_NUM_TILE_PIPES};
+
+const PAGE_SIZE: u64 = 4096;
+
+struct MockBO {
+	memfd: RawFd,
+	size: u64,
+	mapping: Mapping // for the renderer
+}
+
+impl Drop for MockBO {
+	fn drop(&mut self) {
+		unsafe { libc::close(self.memfd); }
+	}
+}
+
+pub struct MockDevice {
+	pub device_id: u32,   // what RADEON_INFO_DEVICE_ID returns, a Cedar by default
+	pub num_tile_pipes: u32,
+	pub clock_crystal_freq: u32, // kHz; the renderer's clock counts dwords
+	pub execute: bool,    // run IBs on the reference renderer
+	pub submissions: Cell<u32>,
+	pub hang_at: Option<u32>, // the submission, counted from 1, that hangs
+	pub resets: Cell<u64>,
+	hung: Cell<bool>,
+	bos: RefCell<HashMap<u32, MockBO>>,
+	next_handle: Cell<u32>
+}
+
+fn errno(e: i32) -> io::Error {
+	io::Error::from_raw_os_error(e)
+}
+
+impl MockDevice {
+	pub fn new(execute: bool) -> MockDevice {
+		MockDevice {
+			device_id: 0x68e0,
+			num_tile_pipes: 2,
+			clock_crystal_freq: 27000,
+			execute: execute,
+			submissions: Cell::new(0),
+			hang_at: None,
+			resets: Cell::new(0),
+			hung: Cell::new(false),
+			bos: RefCell::new(HashMap::new()),
+			next_handle: Cell::new(1)
+		}
+	}
+
+	// Runs the IB with the first BO the relocs name as memory; the reference
+	// renderer only knows one address space
+	fn run(&self, ib: &[u32], relocs: &[DrmRadeonCsReloc]) -> io::Result<()> {
+		let mut bos = self.bos.borrow_mut();
+		let mut handles: Vec<u32> = Vec::new();
+		for r in relocs {
+			if !handles.contains(&r.handle) { handles.push(r.handle) }
+		}
+		if handles.len() > 1 {
+			println!("mock: the IB references {} BOs, only BO {} is visible to the renderer", handles.len(), handles[0]);
+		}
+		let mut empty = [];
+		let mem = match handles.first() {
+			Some(handle) => bos.get_mut(handle).unwrap().mapping.bytes(),
+			None => &mut empty[..]
+		};
+		let mut renderer = Renderer::new(mem, Chip::Evergreen);
+		let result = renderer.execute_ib(ib, &|_| None);
+		for w in &renderer.warnings {
+			println!("mock: {}", w);
+		}
+		result.map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))
+	}
+}
+
+impl Device for MockDevice {
+	fn gem_create(&self, size: u64, _domain: u32) -> io::Result<(u32, u64)> {
+		if size == 0 {
+			return Err(errno(libc::EINVAL))
+		}
+		let size = (size + PAGE_SIZE - 1) & !(PAGE_SIZE - 1);
+		let name = CString::new("mock bo").unwrap();
+		let memfd = unsafe { libc::memfd_create(name.as_ptr(), libc::MFD_CLOEXEC) };
+		if memfd < 0 {
+			return Err(io::Error::last_os_error())
+		}
+		if unsafe { libc::ftruncate(memfd, size as libc::off_t) } < 0 {
+			let e = io::Error::last_os_error();
+			unsafe { libc::close(memfd); }
+			return Err(e)
+		}
+		let mapping = Mapping::new(memfd, 0, size as usize).map_err(|e| { unsafe { libc::close(memfd); } e })?;
+		let handle = self.next_handle.get();
+		self.next_handle.set(handle + 1);
+		self.bos.borrow_mut().insert(handle, MockBO { memfd: memfd, size: size, mapping: mapping });
+		Ok((handle, size))
+	}
+
+	fn gem_mmap(&self, handle: u32, size: u64) -> io::Result<Mapping> {
+		let bos = self.bos.borrow();
+		let bo = bos.get(&handle).ok_or(errno(libc::ENOENT))?;
+		if size > bo.size {
+			return Err(errno(libc::EINVAL))
+		}
+		Mapping::new(bo.memfd, 0, size as usize)
+	}
+
+	fn gem_info(&self) -> io::Result<DrmRadeonGemInfo> {
+		Ok(DrmRadeonGemInfo {
+			gart_size: 512 << 20,
+			vram_size: 256 << 20,
+			vram_visible: 256 << 20
+		})
+	}
+
+	fn gem_wait_idle(&self, handle: u32) -> io::Result<()> {
+		self.bos.borrow().get(&handle).ok_or(errno(libc::ENOENT))?;
+		if self.hung.get() {
+			self.hung.set(false);
+			self.resets.set(self.resets.get() + 1);
+			return Err(errno(libc::EDEADLK))
+		}
+		Ok(())
+	}
+
+	fn gem_busy(&self, handle: u32) -> io::Result<bool> {
+		self.bos.borrow().get(&handle).map(|_| self.hung.get()).ok_or(errno(libc::ENOENT))
+	}
+
+	fn gem_close(&self, handle: u32) -> io::Result<()> {
+		self.bos.borrow_mut().remove(&handle).map(|_| ()).ok_or(errno(libc::EINVAL))
+	}
+
+	// Like radeon_cs_parser_init: one IB, the relocs and the flags, every
+	// reloc naming a BO that exists
+	fn cs(&self, chunks: &[CsChunk]) -> io::Result<()> {
+		let (mut ib, mut relocs) = (None, None);
+		for chunk in chunks {
+			match *chunk {
+				CsChunk::Ib(data) => {
+					if ib.is_some() { return Err(errno(libc::EINVAL)) }
+					ib = Some(data);
+				},
+				CsChunk::Relocs(data) => relocs = Some(data),
+				CsChunk::Flags(data) => if data.len() > 2 { return Err(errno(libc::EINVAL)) }
+			}
+		}
+		let ib = ib.ok_or(errno(libc::EINVAL))?;
+		let relocs = relocs.unwrap_or(&[]);
+		if ib.is_empty() || relocs.iter().any(|r| !self.bos.borrow().contains_key(&r.handle)) {
+			return Err(errno(libc::EINVAL))
+		}
+		self.submissions.set(self.submissions.get() + 1);
+		if self.hung.get() {
+			return Err(errno(libc::EDEADLK))
+		}
+		if Some(self.submissions.get()) == self.hang_at {
+			self.hung.set(true);
+			return Ok(())
+		}
+		if self.execute {
+			self.run(ib, relocs)?;
+		}
+		Ok(())
+	}
+
+	fn info(&self, request: u32) -> io::Result<u64> {
+		match request {
+			RADEON_INFO_DEVICE_ID => Ok(self.device_id as u64),
+			RADEON_INFO_NUM_TILE_PIPES => Ok(self.num_tile_pipes as u64),
+			RADEON_INFO_CLOCK_CRYSTAL_FREQ => Ok(self.clock_crystal_freq as u64),
+			RADEON_INFO_GPU_RESET_COUNTER => Ok(self.resets.get()),
+			_ => Err(errno(libc::EINVAL))
+		}
+	}
+
+	fn prime_handle_to_fd(&self, handle: u32) -> io::Result<RawFd> {
+		let bos = self.bos.borrow();
+		let bo = bos.get(&handle).ok_or(errno(libc::ENOENT))?;
+		let fd = unsafe { libc::dup(bo.memfd) };
+		if fd < 0 { Err(io::Error::last_os_error()) } else { Ok(fd) }
+	}
+
+	fn fd(&self) -> Option<RawFd> {
+		None
+	}
+}
@@ -0,0 +1,165 @@
+// The radeon DRM interface the program uses, as a trait, so that everything
+// above it can run on a real /dev/dri node or on a mock without a GPU.
+// Methods follow the ioctls of the same name and return the errno the
+// kernel would.
+
+pub mod drm;
+pub mod mock;
+pub mod record;
+
+pub use self::drm::DrmDevice;
+pub use self::mock::MockDevice;
+pub use self::record::RecordingDevice;
+
+use std::io;
+use std::os::unix::io::RawFd;
+use std::thread;
+use std::time::{Duration, Instant};
+use libc;
+use drm_radeon_ioctl::{DrmRadeonCsReloc, DrmRadeonGemInfo};
+
+pub const RADEON_INFO_DEVICE_ID: u32 = 0x00;
+pub const RADEON_INFO_CLOCK_CRYSTAL_FREQ: u32 = 0x09; // kHz, what the GPU clock counter runs at
+pub const RADEON_INFO_NUM_TILE_PIPES: u32 = 0x0b;
+pub const RADEON_INFO_GPU_RESET_COUNTER: u32 = 0x26;
+
+// The chunks of a DRM_RADEON_CS submission
+pub enum CsChunk<'a> {
+	Ib(&'a [u32]),
+	Relocs(&'a [DrmRadeonCsReloc]),
+	Flags(&'a [u32])
+}
+
+pub trait Device {
+	// Returns the handle and the size, rounded up to pages
+	fn gem_create(&self, size: u64, domain: u32) -> io::Result<(u32, u64)>;
+	// Maps the first size bytes of a BO, the kernel ignores the offset
+	// argument of DRM_RADEON_GEM_MMAP so there's none here
+	fn gem_mmap(&self, handle: u32, size: u64) -> io::Result<Mapping>;
+	fn gem_info(&self) -> io::Result<DrmRadeonGemInfo>;
+	fn gem_wait_idle(&self, handle: u32) -> io::Result<()>;
+	// true while the GPU still uses the BO
+	fn gem_busy(&self, handle: u32) -> io::Result<bool>;
+	fn gem_close(&self, handle: u32) -> io::Result<()>;
+	fn cs(&self, chunks: &[CsChunk]) -> io::Result<()>;
+	// RADEON_INFO_* request
+	fn info(&self, request: u32) -> io::Result<u64>;
+	fn prime_handle_to_fd(&self, handle: u32) -> io::Result<RawFd>;
+	// The DRM fd, for the display backends; None for devices without one
+	fn fd(&self) -> Option<RawFd>;
+}
+
+pub struct Mapping {
+	pub ptr: *mut libc::c_void,
+	size: usize
+}
+
+impl Mapping {
+	// Maps size bytes of fd at offset, shared and writable
+	pub fn new(fd: RawFd, offset: u64, size: usize) -> io::Result<Mapping> {
+		let ptr = unsafe {
+			libc::mmap(0 as *mut libc::c_void, size, libc::PROT_READ|libc::PROT_WRITE, libc::MAP_SHARED, fd, offset as i64)
+		};
+		if ptr == libc::MAP_FAILED {
+			return Err(io::Error::last_os_error())
+		}
+		Ok(Mapping { ptr: ptr, size: size })
+	}
+
+	pub fn size(&self) -> usize {
+		self.size
+	}
+
+	pub fn bytes(&mut self) -> &mut [u8] {
+		unsafe { ::std::slice::from_raw_parts_mut(self.ptr as *mut u8, self.size) }
+	}
+}
+
+impl Drop for Mapping {
+	fn drop(&mut self) {
+		unsafe { libc::munmap(self.ptr, self.size); }
+	}
+}
+
+// A BO that is closed when it goes out of scope
+pub struct BO<'d> {
+	pub handle: u32,
+	pub size: u64,
+	dev: &'d Device
+}
+
+impl<'d> Drop for BO<'d> {
+	fn drop(&mut self) {
+		let _ = self.dev.gem_close(self.handle);
+	}
+}
+
+pub fn gem_create(dev: &Device, size: u64, domain: u32) -> BO {
+	let (handle, size) = dev.gem_create(size, domain).expect("gem_create failed");
+	BO { handle: handle, size: size, dev: dev }
+}
+
+pub fn bomap(dev: &Device, handle: u32, size: u64) -> Mapping {
+	dev.gem_mmap(handle, size).expect("gem_mmap failed")
+}
+
+pub fn radeon_info(dev: &Device, request: u32) -> u64 {
+	dev.info(request).unwrap_or(0)
+}
+
+// How waiting for the GPU ended
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Wait {
+	Idle,
+	TimedOut,
+	// The GPU was reset, BOs may have lost their contents and the device
+	// should be opened again
+	Reset
+}
+
+// None on kernels without the counter
+pub fn reset_counter(dev: &Device) -> Option<u64> {
+	dev.info(RADEON_INFO_GPU_RESET_COUNTER).ok()
+}
+
+// The kernel returns EDEADLK when it finds the GPU locked up. If it manages
+// to reset it right away the caller sees EAGAIN instead, so the counter has
+// to be compared as well.
+pub fn is_reset<T>(dev: &Device, r: &io::Result<T>, resets_before: Option<u64>) -> bool {
+	let deadlk = match *r {
+		Err(ref e) => e.raw_os_error() == Some(libc::EDEADLK),
+		Ok(_) => false
+	};
+	deadlk || reset_since(dev, resets_before)
+}
+
+pub fn reset_since(dev: &Device, resets_before: Option<u64>) -> bool {
+	resets_before.is_some() && reset_counter(dev) != resets_before
+}
+
+// Polls until the GPU is done with the BO, a hung GPU shows up as TimedOut
+// after timeout instead of blocking until the kernel notices. resets_before
+// is the reset counter from before the submission.
+pub fn wait_idle(dev: &Device, handle: u32, timeout: Duration, resets_before: Option<u64>) -> Wait {
+	let start = Instant::now();
+	loop {
+		let busy = dev.gem_busy(handle);
+		if is_reset(dev, &busy, resets_before) {
+			return Wait::Reset
+		}
+		if !busy.unwrap_or(false) {
+			return Wait::Idle
+		}
+		if start.elapsed() > timeout {
+			return Wait::TimedOut
+		}
+		thread::sleep(Duration::from_millis(1));
+	}
+}
+
+// After a timeout: GEM_WAIT_IDLE blocks until the kernel's lockup detection
+// has reset the GPU, or until the GPU finishes after all
+pub fn wait_for_reset(dev: &Device, handle: u32, resets_before: Option<u64>) -> Wait {
+	let r = dev.gem_wait_idle(handle);
+	if is_reset(dev, &r, resets_before) { Wait::Reset } else { Wait::Idle }
+}
@@ -0,0 +1,307 @@
+// Capturing a device and playing captures back.
+//
+// RecordingDevice wraps another device and logs every call to a capture
+// file. The display backends use the raw fd from fd() for KMS and prime
+// ioctls of their own, those aren't recorded.
+//
+// replay() resubmits the records on any device. Handles are translated to
+// the ones the device hands out and BOs get their recorded contents before
+// every CS, so each submission sees the memory it saw when it was recorded.
+// What comes back differently is reported: results of the calls and the
+// contents of BOs when they are closed. dump() lists them instead.
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::io;
+use std::os::unix::io::RawFd;
+use std::rc::Rc;
+use libc;
+use capture::{CaptureWriter, Record};
+use drm_radeon_ioctl::*;
+use pm4;
+use super::{CsChunk, Device, Mapping};
+
+pub struct RecordingDevice {
+	inner: Box<Device>,
+	out: Rc<RefCell<CaptureWriter>>,
+	sizes: RefCell<HashMap<u32, u64>>
+}
+
+fn errno_of<T>(r: &io::Result<T>) -> i32 {
+	match *r {
+		Ok(_) => 0,
+		Err(ref e) => e.raw_os_error().unwrap_or(libc::EIO)
+	}
+}
+
+impl RecordingDevice {
+	// The writer is shared so that several devices opened one after another
+	// end up in one capture, each starting with an OPEN record
+	pub fn new(inner: Box<Device>, out: Rc<RefCell<CaptureWriter>>) -> RecordingDevice {
+		let dev = RecordingDevice { inner: inner, out: out, sizes: RefCell::new(HashMap::new()) };
+		dev.log(Record::Open);
+		dev
+	}
+
+	fn log(&self, record: Record) {
+		if let Err(e) = self.out.borrow_mut().write(&record) {
+			println!("capture: {}", e);
+		}
+	}
+
+	fn contents(&self, handle: u32) -> Vec<u8> {
+		let size = match self.sizes.borrow().get(&handle) {
+			Some(&size) => size,
+			None => return Vec::new()
+		};
+		match self.inner.gem_mmap(handle, size) {
+			Ok(mut mapping) => mapping.bytes().to_vec(),
+			Err(_) => Vec::new()
+		}
+	}
+}
+
+impl Device for RecordingDevice {
+	fn gem_create(&self, size: u64, domain: u32) -> io::Result<(u32, u64)> {
+		let r = self.inner.gem_create(size, domain);
+		let (handle, bo_size) = *r.as_ref().unwrap_or(&(0, 0));
+		if r.is_ok() {
+			self.sizes.borrow_mut().insert(handle, bo_size);
+		}
+		self.log(Record::GemCreate { size: size, domain: domain, errno: errno_of(&r), handle: handle, bo_size: bo_size });
+		r
+	}
+
+	fn gem_mmap(&self, handle: u32, size: u64) -> io::Result<Mapping> {
+		let r = self.inner.gem_mmap(handle, size);
+		self.log(Record::GemMmap { handle: handle, size: size, errno: errno_of(&r) });
+		r
+	}
+
+	fn gem_info(&self) -> io::Result<DrmRadeonGemInfo> {
+		let r = self.inner.gem_info();
+		let record = match r {
+			Ok(ref info) => Record::GemInfo { errno: 0, gart_size: info.gart_size, vram_size: info.vram_size, vram_visible: info.vram_visible },
+			Err(_) => Record::GemInfo { errno: errno_of(&r), gart_size: 0, vram_size: 0, vram_visible: 0 }
+		};
+		self.log(record);
+		r
+	}
+
+	fn gem_wait_idle(&self, handle: u32) -> io::Result<()> {
+		let r = self.inner.gem_wait_idle(handle);
+		self.log(Record::GemWaitIdle { handle: handle, errno: errno_of(&r) });
+		r
+	}
+
+	fn gem_busy(&self, handle: u32) -> io::Result<bool> {
+		let r = self.inner.gem_busy(handle);
+		let errno = match r { Ok(true) => libc::EBUSY, _ => errno_of(&r) };
+		self.log(Record::GemBusy { handle: handle, errno: errno });
+		r
+	}
+
+	fn gem_close(&self, handle: u32) -> io::Result<()> {
+		let contents = self.contents(handle);
+		let r = self.inner.gem_close(handle);
+		self.sizes.borrow_mut().remove(&handle);
+		self.log(Record::GemClose { handle: handle, errno: errno_of(&r), contents: contents });
+		r
+	}
+
+	fn cs(&self, chunks: &[CsChunk]) -> io::Result<()> {
+		let (mut ib, mut relocs, mut flags) = (Vec::new(), Vec::new(), Vec::new());
+		for chunk in chunks {
+			match *chunk {
+				CsChunk::Ib(data) => ib.extend_from_slice(data),
+				CsChunk::Relocs(data) => relocs.extend_from_slice(data),
+				CsChunk::Flags(data) => flags.extend_from_slice(data)
+			}
+		}
+		// BO contents are taken before the submission, afterwards the GPU
+		// is already writing to them
+		let mut bos: Vec<(u32, Vec<u8>)> = Vec::new();
+		for r in &relocs {
+			if !bos.iter().any(|&(h, _)| h == r.handle) {
+				bos.push((r.handle, self.contents(r.handle)));
+			}
+		}
+		let mut record = Record::Cs { errno: 0, ib: ib, relocs: relocs, flags: flags, bos: bos };
+		let r = self.inner.cs(chunks);
+		if let Record::Cs { ref mut errno, .. } = record {
+			*errno = errno_of(&r);
+		}
+		self.log(record);
+		r
+	}
+
+	fn info(&self, request: u32) -> io::Result<u64> {
+		let r = self.inner.info(request);
+		self.log(Record::Info { request: request, errno: errno_of(&r), value: *r.as_ref().unwrap_or(&0) });
+		r
+	}
+
+	fn prime_handle_to_fd(&self, handle: u32) -> io::Result<RawFd> {
+		let r = self.inner.prime_handle_to_fd(handle);
+		self.log(Record::PrimeHandleToFd { handle: handle, errno: errno_of(&r) });
+		r
+	}
+
+	fn fd(&self) -> Option<RawFd> {
+		self.inner.fd()
+	}
+}
+
+fn errno_str(errno: i32) -> String {
+	if errno == 0 { "success".to_owned() } else { format!("{}", io::Error::from_raw_os_error(errno)) }
+}
+
+fn copy_into(dev: &Device, handle: u32, size: u64, contents: &[u8]) -> io::Result<()> {
+	let mut mapping = dev.gem_mmap(handle, size)?;
+	let n = contents.len().min(mapping.size());
+	mapping.bytes()[..n].copy_from_slice(&contents[..n]);
+	Ok(())
+}
+
+// Returns the number of differences from the capture; Err if the capture
+// can't be played back on this device at all
+pub fn replay(records: &[Record], open: &Fn() -> Box<Device>) -> Result<u32, String> {
+	let mut dev: Option<Box<Device>> = None;
+	let mut handles: HashMap<u32, (u32, u64)> = HashMap::new();
+	let mut differences = 0;
+	let mut submissions = 0;
+
+	for (i, record) in records.iter().enumerate() {
+		if let Record::Open = *record {
+			dev = Some(open());
+			handles.clear();
+			continue
+		}
+		if dev.is_none() {
+			dev = Some(open());
+		}
+		let dev: &Device = &**dev.as_ref().unwrap();
+		let lookup = |handles: &HashMap<u32, (u32, u64)>, handle: u32| handles.get(&handle).cloned()
+			.ok_or_else(|| format!("record {} ({}): BO {} wasn't created in the capture", i, record.name(), handle));
+
+		let (recorded, errno) = match *record {
+			Record::Open => unreachable!(),
+			Record::GemCreate { size, domain, errno, handle, .. } => {
+				let r = dev.gem_create(size, domain);
+				if let Ok(bo) = r {
+					handles.insert(handle, bo);
+				}
+				(errno, errno_of(&r))
+			},
+			Record::GemMmap { handle, size, errno } => {
+				// nothing to do with the mapping, this only checks it works
+				let (new, _) = lookup(&handles, handle)?;
+				(errno, errno_of(&dev.gem_mmap(new, size)))
+			},
+			Record::GemInfo { errno, gart_size, vram_size, vram_visible } => {
+				let r = dev.gem_info();
+				if let Ok(ref info) = r {
+					if errno == 0 && (info.gart_size, info.vram_size, info.vram_visible) != (gart_size, vram_size, vram_visible) {
+						println!("record {} (GEM_INFO): the device reports gart {} vram {} visible {}, the capture has {} {} {}",
+							i, info.gart_size, info.vram_size, info.vram_visible, gart_size, vram_size, vram_visible);
+					}
+				}
+				(errno, errno_of(&r))
+			},
+			Record::GemWaitIdle { handle, errno } => {
+				let (new, _) = lookup(&handles, handle)?;
+				(errno, errno_of(&dev.gem_wait_idle(new)))
+			},
+			Record::GemBusy { handle, .. } => {
+				// whether it's still busy depends on timing, not worth reporting
+				let (new, _) = lookup(&handles, handle)?;
+				let _ = dev.gem_busy(new);
+				(0, 0)
+			},
+			Record::GemClose { handle, errno, ref contents } => {
+				let (new, size) = lookup(&handles, handle)?;
+				let _ = dev.gem_wait_idle(new);
+				if let Ok(mut mapping) = dev.gem_mmap(new, size) {
+					let now = mapping.bytes();
+					let n = contents.len().min(now.len());
+					let differing = (0..n).filter(|&j| now[j] != contents[j]).count();
+					if differing > 0 {
+						let first = (0..n).find(|&j| now[j] != contents[j]).unwrap();
+						println!("record {} (GEM_CLOSE): BO {} differs from the capture in {} bytes, first at {:#x}", i, handle, differing, first);
+						differences += 1;
+					}
+				}
+				handles.remove(&handle);
+				(errno, errno_of(&dev.gem_close(new)))
+			},
+			Record::Cs { errno, ref ib, ref relocs, ref flags, ref bos } => {
+				for &(handle, ref contents) in bos {
+					let (new, size) = lookup(&handles, handle)?;
+					copy_into(dev, new, size, contents)
+						.map_err(|e| format!("record {} (CS): can't restore BO {}: {}", i, handle, e))?;
+				}
+				let mut translated = Vec::new();
+				for r in relocs {
+					let mut r = *r;
+					r.handle = lookup(&handles, r.handle)?.0;
+					translated.push(r);
+				}
+				submissions += 1;
+				(errno, errno_of(&dev.cs(&[CsChunk::Ib(ib), CsChunk::Relocs(&translated), CsChunk::Flags(flags)])))
+			},
+			Record::Info { request, errno, value } => {
+				let r = dev.info(request);
+				if let Ok(v) = r {
+					if errno == 0 && v != value {
+						println!("record {} (INFO): request {:#x} is {:#x} on this device, {:#x} in the capture", i, request, v, value);
+					}
+				}
+				(errno, errno_of(&r))
+			},
+			Record::PrimeHandleToFd { handle, errno } => {
+				let (new, _) = lookup(&handles, handle)?;
+				let r = dev.prime_handle_to_fd(new);
+				if let Ok(fd) = r {
+					unsafe { libc::close(fd); }
+				}
+				(errno, errno_of(&r))
+			}
+		};
+		if errno != recorded {
+			println!("record {} ({}): {}, the capture has {}", i, record.name(), errno_str(errno), errno_str(recorded));
+			differences += 1;
+		}
+	}
+	println!("replayed {} records, {} submissions, {} differences", records.len(), submissions, differences);
+	Ok(differences)
+}
+
+// Lists the records of a capture with the command streams decoded
+pub fn dump(records: &[Record]) {
+	for (i, record) in records.iter().enumerate() {
+		match *record {
+			Record::Open => println!("{}: OPEN", i),
+			Record::GemCreate { size, domain, errno, handle, bo_size } =>
+				println!("{}: GEM_CREATE size {} domain {:#x} -> errno {} handle {} size {}", i, size, domain, errno, handle, bo_size),
+			Record::GemMmap { handle, size, errno } => println!("{}: GEM_MMAP handle {} size {} -> errno {}", i, handle, size, errno),
+			Record::GemInfo { errno, gart_size, vram_size, vram_visible } =>
+				println!("{}: GEM_INFO -> errno {} gart {} vram {} visible {}", i, errno, gart_size, vram_size, vram_visible),
+			Record::GemWaitIdle { handle, errno } => println!("{}: GEM_WAIT_IDLE handle {} -> errno {}", i, handle, errno),
+			Record::GemBusy { handle, errno } => println!("{}: GEM_BUSY handle {} -> errno {}", i, handle, errno),
+			Record::GemClose { handle, errno, ref contents } =>
+				println!("{}: GEM_CLOSE handle {} ({} bytes) -> errno {}", i, handle, contents.len(), errno),
+			Record::Cs { errno, ref ib, ref relocs, ref flags, ref bos } => {
+				println!("{}: CS {} dwords, flags {:x?} -> errno {}", i, ib.len(), flags, errno);
+				for (j, r) in relocs.iter().enumerate() {
+					println!("  reloc {}: handle {} read {:#x} write {:#x} flags {:#x}", j, r.handle, r.read_domains, r.write_domain, r.flags);
+				}
+				for &(handle, ref contents) in bos {
+					println!("  BO {}: {} bytes", handle, contents.len());
+				}
+				pm4::split_and_print(ib);
+			},
+			Record::Info { request, errno, value } => println!("{}: INFO {:#x} -> errno {} value {:#x}", i, request, errno, value),
+			Record::PrimeHandleToFd { handle, errno } => println!("{}: PRIME_HANDLE_TO_FD handle {} -> errno {}", i, handle, errno)
+		}
+	}
+}
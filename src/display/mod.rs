@@ -14,13 +14,8 @@ pub const H: u32 = 768;
 //pub const H: u32 = 480;
 pub const L_CB_SIZE: usize = (W*H*4) as usize;
 pub const L_DB_SIZE: usize = (W*H*4) as usize;
-pub const L_SHADERBLOB_SIZE: usize = 4096;
+pub const L_SHADERBLOB_SIZE: usize = 8192;
 pub const L_VERTEXBUFFER_SIZE: usize = 4*4*4;
-pub const SH_SOLID_VS_OFFSET: usize = 0;
-pub const SH_SOLID_PS_OFFSET: usize = 512;
-// pub const SH_COPY_VS_OFFSET: usize = 1024;
-// pub const SH_COPY_PS_OFFSET: usize = 1536;
-// pub const SH_COMP_VS_OFFSET: usize = 2048;
-// pub const SH_COMP_PS_OFFSET: usize = 2560;
-// pub const SH_XV_VS_OFFSET: usize = 3072;
-// pub const SH_XV_PS_OFFSET: usize = 3584;
+pub const L_CONSTRING_SIZE: usize = 65536;
+pub const L_STREAMOUT_SIZE: usize = 4096;
+pub const L_YUV_SIZE: usize = 8192;
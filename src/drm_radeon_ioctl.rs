@@ -1,32 +1,30 @@
 // using the ioctls directly was just done out of curiousity
 // using libdrm is probably more portable
 
-use std;
 use std::marker::PhantomData;
-use nix::sys::ioctl;
 
 pub const RADEON_GEM_DOMAIN_CPU: u32 = 0x1;
 pub const RADEON_GEM_DOMAIN_GTT: u32 = 0x2;
 pub const RADEON_GEM_DOMAIN_VRAM: u32 = 0x4;
 
-const DRM_IOCTL_BASE: u32 = b'd' as u32; // 0x64
-
-const DRM_COMMAND_BASE: u32 = b'@' as u32; // 0x40;
-const DRM_RADEON_GEM_INFO: u32			= 0x1c;
-const DRM_RADEON_GEM_CREATE: u32		= 0x1d;
-const DRM_RADEON_GEM_MMAP: u32			= 0x1e;
-const DRM_RADEON_GEM_PREAD: u32			= 0x21;
-const DRM_RADEON_GEM_PWRITE: u32		= 0x22;
-const DRM_RADEON_GEM_SET_DOMAIN: u32	= 0x23;
-const DRM_RADEON_GEM_WAIT_IDLE: u32		= 0x24;
-const DRM_RADEON_CS: u32				= 0x26;
-const DRM_RADEON_INFO: u32				= 0x27;
-const DRM_RADEON_GEM_SET_TILING: u32	= 0x28;
-const DRM_RADEON_GEM_GET_TILING: u32	= 0x29;
-const DRM_RADEON_GEM_BUSY: u32			= 0x2a;
-const DRM_RADEON_GEM_VA: u32			= 0x2b;
-const DRM_RADEON_GEM_OP: u32			= 0x2c;
-const DRM_RADEON_UCODE_UPDATE: u32		= 0x2e;
+pub const DRM_IOCTL_BASE: u32 = b'd' as u32; // 0x64
+
+pub const DRM_COMMAND_BASE: u32 = b'@' as u32; // 0x40;
+pub const DRM_RADEON_GEM_INFO: u32			= 0x1c;
+pub const DRM_RADEON_GEM_CREATE: u32		= 0x1d;
+pub const DRM_RADEON_GEM_MMAP: u32			= 0x1e;
+pub const DRM_RADEON_GEM_PREAD: u32			= 0x21;
+pub const DRM_RADEON_GEM_PWRITE: u32		= 0x22;
+pub const DRM_RADEON_GEM_SET_DOMAIN: u32	= 0x23;
+pub const DRM_RADEON_GEM_WAIT_IDLE: u32		= 0x24;
+pub const DRM_RADEON_CS: u32				= 0x26;
+pub const DRM_RADEON_INFO: u32				= 0x27;
+pub const DRM_RADEON_GEM_SET_TILING: u32	= 0x28;
+pub const DRM_RADEON_GEM_GET_TILING: u32	= 0x29;
+pub const DRM_RADEON_GEM_BUSY: u32			= 0x2a;
+pub const DRM_RADEON_GEM_VA: u32			= 0x2b;
+pub const DRM_RADEON_GEM_OP: u32			= 0x2c;
+pub const DRM_RADEON_UCODE_UPDATE: u32		= 0x2e;
 
 ioctl_readwrite!(drm_ioctl_radeon_cs, DRM_IOCTL_BASE, DRM_COMMAND_BASE + DRM_RADEON_CS, DrmRadeonCs);
 ioctl_readwrite!(drm_ioctl_radeon_info, DRM_IOCTL_BASE, DRM_COMMAND_BASE + DRM_RADEON_INFO, DrmRadeonInfo);
@@ -65,11 +63,16 @@ pub struct U64PtrSlice<'a, T: 'a> {
 impl<'a, T> U64PtrSlice<'a, T> {
 	pub fn new(items: &'a [T]) -> Self {
 		let ptrs = items.iter().map(|r| r as *const T as u64).collect::<Vec<_>>();
-		U64PtrSlice { ptrs: ptrs, phantom: PhantomData }
+		U64PtrSlice { ptrs, phantom: PhantomData }
 	}
 	pub fn ptrs(&self) -> &[u64] { &self.ptrs[..] }
 }
 
+pub const RADEON_CHUNK_ID_RELOCS: u32 = 0x01;
+pub const RADEON_CHUNK_ID_IB: u32 = 0x02;
+pub const RADEON_CHUNK_ID_FLAGS: u32 = 0x03;
+// pub const RADEON_CHUNK_ID_CONST_IB: u32 = 0x04;
+
 #[repr(C)]
 #[derive(Default)]
 pub struct DrmRadeonCsChunk {
@@ -81,7 +84,7 @@ pub struct DrmRadeonCsChunk {
 /* DrmRadeonCsReloc.flags */
 
 #[repr(C)]
-#[derive(Default)]
+#[derive(Default, Clone, Copy)]
 pub struct DrmRadeonCsReloc {
 	pub handle: u32,
 	pub read_domains: u32,
@@ -91,10 +94,10 @@ pub struct DrmRadeonCsReloc {
 
 #[repr(C)]
 pub struct DrmRadeonCs<'a> {
-	num_chunks: u32,
+	pub num_chunks: u32,
 	pub cs_id: u32,
 	/* this points to uint64_t * which point to cs chunks */
-	chunks: u64,
+	pub chunks: u64,
 	/* updates to the limits after this CS ioctl */
 	pub gart_limit: u64,
 	pub vram_limit: u64,
@@ -0,0 +1,113 @@
+// Fences on EVENT_WRITE_EOP
+//
+// Fence slots are 64-bit words in a BO, a BO of their own or a region of
+// another one. A timeline owns a slot and counts up: emitting a fence puts
+// an EOP into the CS that writes the next sequence number to the slot once
+// everything before it has left the pipeline, and the fence has signaled
+// when the slot has reached its number. The CPU polls the slot, so frames
+// in flight can be tracked one by one instead of waiting for a whole BO to
+// go idle.
+//
+// With an interrupt the CP also raises one when the write has landed. That
+// wakes the kernel's fence processing, so GEM_BUSY and GEM_WAIT_IDLE notice
+// the end of the submission sooner; polling the slot doesn't need it.
+
+use std::ptr;
+use std::thread;
+use std::time::{Duration, Instant};
+use cs::*;
+use device::{reset_since, Device, Mapping, Wait};
+
+pub struct FenceSlots {
+	handle: u32,
+	domain: u32,
+	offset: u32, // of slot 0 in the BO
+	// the last sequence number emitted to each slot, and whether a timeline has it
+	slots: Vec<(u64, bool)>
+}
+
+// A slot and the sequence number last emitted to it
+pub struct Timeline {
+	slot: u32,
+	last: u64
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Fence {
+	pub slot: u32,
+	pub seq: u64
+}
+
+impl Timeline {
+	// The latest fence, signaled once everything emitted on the timeline is
+	pub fn last(&self) -> Fence {
+		Fence { slot: self.slot, seq: self.last }
+	}
+}
+
+impl FenceSlots {
+	// The slots have to be zeroed before the first submission that uses them
+	pub fn new(handle: u32, domain: u32, offset: u32, count: usize) -> FenceSlots {
+		assert!(offset & 7 == 0, "fence slots not 8 byte aligned");
+		FenceSlots { handle: handle, domain: domain, offset: offset, slots: vec![(0, false); count] }
+	}
+
+	pub fn timeline(&mut self) -> Option<Timeline> {
+		let slot = self.slots.iter().position(|&(_, used)| !used)?;
+		self.slots[slot].1 = true;
+		Some(Timeline { slot: slot as u32, last: self.slots[slot].0 })
+	}
+
+	// Only once the timeline's last fence has signaled, the next owner of the
+	// slot carries on from its sequence number
+	pub fn release(&mut self, timeline: Timeline) {
+		self.slots[timeline.slot as usize] = (timeline.last, false);
+	}
+
+	pub fn emit(&self, cs: &mut CS, timeline: &mut Timeline, interrupt: bool) -> Fence {
+		timeline.last += 1;
+		let addr = self.offset + timeline.slot * 8;
+		cs.write_label("fence");
+		cs.write(&[
+			packet3(Packet3::EVENT_WRITE_EOP, 4, 0),
+			CACHE_FLUSH_AND_INV_EVENT_TS | event_index(5),
+			addr & !3,
+			data_sel(2) | int_sel(if interrupt { 2 } else { 0 }),
+			timeline.last as u32,
+			(timeline.last >> 32) as u32
+		]);
+		cs.write_reloc_nop(self.handle, 0, self.domain);
+		timeline.last()
+	}
+
+	// What the GPU has written to the slot so far; the mapping starts at the
+	// beginning of the BO
+	pub fn value(&self, mapping: &Mapping, slot: u32) -> u64 {
+		let at = (self.offset + slot * 8) as usize;
+		assert!(at + 8 <= mapping.size(), "fence slot outside the mapping");
+		unsafe { ptr::read_volatile((mapping.ptr as *const u8).offset(at as isize) as *const u64) }
+	}
+
+	pub fn signaled(&self, mapping: &Mapping, fence: Fence) -> bool {
+		self.value(mapping, fence.slot) >= fence.seq
+	}
+
+	// Polls until the fence has signaled, backing off from 10µs to 1ms
+	// between looks; resets_before is the reset counter from before the
+	// submission, as for device::wait_idle
+	pub fn wait(&self, dev: &Device, mapping: &Mapping, fence: Fence, timeout: Duration, resets_before: Option<u64>) -> Wait {
+		let start = Instant::now();
+		let mut sleep = Duration::from_micros(10);
+		while !self.signaled(mapping, fence) {
+			if reset_since(dev, resets_before) {
+				return Wait::Reset
+			}
+			if start.elapsed() > timeout {
+				return Wait::TimedOut
+			}
+			thread::sleep(sleep);
+			sleep = (sleep * 2).min(Duration::from_millis(1));
+		}
+		Wait::Idle
+	}
+}
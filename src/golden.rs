@@ -0,0 +1,135 @@
+// Golden image tests: a rendered scene is compared with golden/<name>.png,
+// channel by channel with a tolerance. golden/<name>.mask.png, if there is
+// one, leaves out the pixels that are black in it, for parts of a scene
+// that are allowed to differ. On a mismatch the rendered image and a diff
+// image are written to the output directory: differing pixels red, masked
+// pixels blue and the rest a darkened golden. Blessing writes the rendered
+// image over the golden instead.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use image;
+
+pub struct Image {
+	pub width: u32,
+	pub height: u32,
+	pub rgba: Vec<u8>
+}
+
+impl Image {
+	pub fn new(width: u32, height: u32, rgba: &[u8]) -> Image {
+		Image { width: width, height: height, rgba: rgba[..(width * height * 4) as usize].to_vec() }
+	}
+
+	pub fn load(path: &Path) -> Result<Image, String> {
+		let img = image::open(path).map_err(|e| format!("{}: {}", path.display(), e))?.to_rgba();
+		Ok(Image { width: img.width(), height: img.height(), rgba: img.into_raw() })
+	}
+
+	pub fn save(&self, path: &Path) -> Result<(), String> {
+		image::save_buffer(path, &self.rgba, self.width, self.height, image::RGBA(8))
+			.map_err(|e| format!("{}: {}", path.display(), e))
+	}
+}
+
+pub struct Mismatch {
+	pub pixels: usize,
+	pub first: (u32, u32),
+	pub max_difference: u8
+}
+
+fn masked(mask: Option<&Image>, i: usize) -> bool {
+	mask.map_or(false, |m| m.rgba[i*4..i*4+3] == [0, 0, 0])
+}
+
+fn difference(a: &[u8], b: &[u8]) -> u8 {
+	a.iter().zip(b).map(|(&x, &y)| (x as i32 - y as i32).abs() as u8).max().unwrap_or(0)
+}
+
+pub fn compare(actual: &Image, golden: &Image, mask: Option<&Image>, tolerance: u8) -> Result<Option<Mismatch>, String> {
+	if (actual.width, actual.height) != (golden.width, golden.height) {
+		return Err(format!("rendered {}x{}, the golden is {}x{}", actual.width, actual.height, golden.width, golden.height))
+	}
+	if let Some(m) = mask {
+		if (m.width, m.height) != (golden.width, golden.height) {
+			return Err(format!("the mask is {}x{}, the golden {}x{}", m.width, m.height, golden.width, golden.height))
+		}
+	}
+	let mut mismatch: Option<Mismatch> = None;
+	for i in 0..(golden.width * golden.height) as usize {
+		let d = difference(&actual.rgba[i*4..i*4+4], &golden.rgba[i*4..i*4+4]);
+		if d <= tolerance || masked(mask, i) {
+			continue
+		}
+		let m = mismatch.get_or_insert(Mismatch {
+			pixels: 0,
+			first: (i as u32 % golden.width, i as u32 / golden.width),
+			max_difference: 0
+		});
+		m.pixels += 1;
+		m.max_difference = m.max_difference.max(d);
+	}
+	Ok(mismatch)
+}
+
+pub fn diff_image(actual: &Image, golden: &Image, mask: Option<&Image>, tolerance: u8) -> Image {
+	let mut rgba = Vec::with_capacity(golden.rgba.len());
+	for i in 0..(golden.width * golden.height) as usize {
+		let g = &golden.rgba[i*4..i*4+4];
+		if masked(mask, i) {
+			rgba.extend_from_slice(&[0, 0, 96, 255]);
+		} else if difference(&actual.rgba[i*4..i*4+4], g) > tolerance {
+			rgba.extend_from_slice(&[255, 0, 0, 255]);
+		} else {
+			rgba.extend_from_slice(&[g[0] / 4, g[1] / 4, g[2] / 4, 255]);
+		}
+	}
+	Image { width: golden.width, height: golden.height, rgba: rgba }
+}
+
+pub fn golden_path(dir: &Path, name: &str) -> PathBuf {
+	dir.join(format!("{}.png", name))
+}
+
+pub fn load_mask(dir: &Path, name: &str) -> Result<Option<Image>, String> {
+	let path = dir.join(format!("{}.mask.png", name));
+	if path.exists() { Image::load(&path).map(Some) } else { Ok(None) }
+}
+
+// Checks the scene name against the golden of golden_name, scenes that are
+// expected to look the same can share one. Ok(true) if the image matches or
+// was blessed.
+pub fn check(actual: &Image, name: &str, golden_name: &str, dir: &Path, out_dir: &Path, tolerance: u8, bless: bool) -> Result<bool, String> {
+	let path = golden_path(dir, golden_name);
+	if bless {
+		if golden_name != name {
+			println!("{}: not blessed, the golden belongs to {}", name, golden_name);
+			return Ok(true)
+		}
+		fs::create_dir_all(dir).map_err(|e| format!("{}: {}", dir.display(), e))?;
+		actual.save(&path)?;
+		println!("{}: blessed {}", name, path.display());
+		return Ok(true)
+	}
+	if !path.exists() {
+		return Err(format!("{} doesn't exist, bless {} to create it", path.display(), golden_name))
+	}
+	let golden = Image::load(&path)?;
+	let mask = load_mask(dir, golden_name)?;
+	match compare(actual, &golden, mask.as_ref(), tolerance)? {
+		None => {
+			println!("{}: ok", name);
+			Ok(true)
+		},
+		Some(m) => {
+			fs::create_dir_all(out_dir).map_err(|e| format!("{}: {}", out_dir.display(), e))?;
+			let actual_path = out_dir.join(format!("{}.png", name));
+			let diff_path = out_dir.join(format!("{}.diff.png", name));
+			actual.save(&actual_path)?;
+			diff_image(actual, &golden, mask.as_ref(), tolerance).save(&diff_path)?;
+			println!("{}: {} pixels differ by up to {} (tolerance {}), the first at {:?}; see {} and {}",
+				name, m.pixels, m.max_difference, tolerance, m.first, actual_path.display(), diff_path.display());
+			Ok(false)
+		}
+	}
+}
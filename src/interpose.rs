@@ -0,0 +1,317 @@
+// A library to preload into other programs (Mesa's r600g, radeondemo, the X
+// driver) that captures what they submit:
+//
+//     EVERGREEN_CAPTURE=/tmp/gears LD_PRELOAD=target/release/libevergreen_capture.so glxgears
+//
+// Every DRM fd the program uses for radeon ioctls gets a capture file,
+// /tmp/gears.<pid>.<n>.cap, in the format of capture.rs, which --replay and
+// --dump-capture read. The prefix defaults to /tmp/evergreen.
+//
+// ioctl and close are replaced. DRM ioctls on radeon fds are passed on and
+// recorded, one at a time, so a multithreaded driver loses some of its
+// parallelism while it's being captured. BOs the program didn't create
+// itself (GEM_OPEN, PRIME_FD_TO_HANDLE) are recorded as GEM_CREATE with
+// their size so that a replay has something to put their contents in.
+
+extern crate libc;
+#[macro_use]
+extern crate nix;
+
+#[allow(dead_code)]
+#[path = "capture.rs"]
+mod capture;
+#[allow(dead_code)]
+#[path = "drm_radeon_ioctl.rs"]
+mod drm_radeon_ioctl;
+
+use std::collections::HashMap;
+use std::collections::hash_map::Entry;
+use std::ffi::CStr;
+use std::ptr;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use libc::{c_char, c_int, c_ulong, c_void};
+use capture::{CaptureWriter, Record};
+use drm_radeon_ioctl::*;
+
+const DRM_GEM_CLOSE: u32 = 0x09;
+const DRM_GEM_OPEN: u32 = 0x0b;
+const DRM_PRIME_HANDLE_TO_FD: u32 = 0x2d;
+const DRM_PRIME_FD_TO_HANDLE: u32 = 0x2e;
+
+// RADEON_INFO requests that write 64 bits, the others write 32
+const RADEON_INFO_64BIT: [u32; 3] = [0x11 /* TIMESTAMP */, 0x1e /* VRAM_USAGE */, 0x1f /* GTT_USAGE */];
+
+#[repr(C)]
+struct DrmVersion {
+	version_major: c_int,
+	version_minor: c_int,
+	version_patchlevel: c_int,
+	name_len: usize,
+	name: *mut c_char,
+	date_len: usize,
+	date: *mut c_char,
+	desc_len: usize,
+	desc: *mut c_char
+}
+
+#[repr(C)]
+struct DrmGemOpen {
+	name: u32,
+	handle: u32,
+	size: u64
+}
+
+type IoctlFn = unsafe extern "C" fn(c_int, c_ulong, *mut c_void) -> c_int;
+type CloseFn = unsafe extern "C" fn(c_int) -> c_int;
+
+static REAL_IOCTL: AtomicUsize = AtomicUsize::new(0);
+static REAL_CLOSE: AtomicUsize = AtomicUsize::new(0);
+static NEXT_CAPTURE: AtomicUsize = AtomicUsize::new(0);
+
+// None for fds that turned out not to be radeon
+static FDS: Mutex<Option<HashMap<c_int, Option<FdCapture>>>> = Mutex::new(None);
+
+struct FdCapture {
+	out: CaptureWriter,
+	sizes: HashMap<u32, u64>
+}
+
+unsafe fn next_symbol(cache: &AtomicUsize, name: &[u8]) -> usize {
+	let mut f = cache.load(Ordering::Relaxed);
+	if f == 0 {
+		f = libc::dlsym(libc::RTLD_NEXT, name.as_ptr() as *const c_char) as usize;
+		cache.store(f, Ordering::Relaxed);
+	}
+	f
+}
+
+unsafe fn real_ioctl(fd: c_int, request: c_ulong, arg: *mut c_void) -> c_int {
+	let f: IoctlFn = std::mem::transmute(next_symbol(&REAL_IOCTL, b"ioctl\0"));
+	f(fd, request, arg)
+}
+
+unsafe fn errno() -> c_int {
+	*libc::__errno_location()
+}
+
+unsafe fn set_errno(e: c_int) {
+	*libc::__errno_location() = e;
+}
+
+unsafe fn is_radeon(fd: c_int) -> bool {
+	let mut name = [0 as c_char; 32];
+	let mut version = DrmVersion {
+		version_major: 0, version_minor: 0, version_patchlevel: 0,
+		name_len: name.len() - 1, name: name.as_mut_ptr(),
+		date_len: 0, date: ptr::null_mut(),
+		desc_len: 0, desc: ptr::null_mut()
+	};
+	let request = request_code_readwrite!(DRM_IOCTL_BASE, 0x00, std::mem::size_of::<DrmVersion>());
+	if real_ioctl(fd, request as c_ulong, &mut version as *mut DrmVersion as *mut c_void) != 0 {
+		return false
+	}
+	CStr::from_ptr(name.as_ptr()).to_bytes() == b"radeon"
+}
+
+unsafe fn open_capture(fd: c_int) -> Option<FdCapture> {
+	if !is_radeon(fd) {
+		return None
+	}
+	let prefix = std::env::var("EVERGREEN_CAPTURE").unwrap_or("/tmp/evergreen".to_owned());
+	let path = format!("{}.{}.{}.cap", prefix, libc::getpid(), NEXT_CAPTURE.fetch_add(1, Ordering::Relaxed));
+	match CaptureWriter::create(&path) {
+		Ok(mut out) => {
+			let _ = out.write(&Record::Open);
+			Some(FdCapture { out, sizes: HashMap::new() })
+		},
+		Err(e) => {
+			eprintln!("evergreen_capture: {}: {}", path, e);
+			None
+		}
+	}
+}
+
+// Contents of a BO through a mapping of its own, empty if the size is
+// unknown or it can't be mapped
+unsafe fn bo_contents(fd: c_int, sizes: &HashMap<u32, u64>, handle: u32) -> Vec<u8> {
+	let size = match sizes.get(&handle) { Some(&size) => size, None => return Vec::new() };
+	let mut mmap_args = DrmRadeonGemMmap { handle, pad: 0, offset: 0, size, addr_ptr: 0 };
+	let request = request_code_readwrite!(DRM_IOCTL_BASE, DRM_COMMAND_BASE + DRM_RADEON_GEM_MMAP, std::mem::size_of::<DrmRadeonGemMmap>());
+	if real_ioctl(fd, request as c_ulong, &mut mmap_args as *mut DrmRadeonGemMmap as *mut c_void) != 0 {
+		return Vec::new()
+	}
+	let ptr = libc::mmap(ptr::null_mut(), size as usize, libc::PROT_READ, libc::MAP_SHARED, fd, mmap_args.addr_ptr as i64);
+	if ptr == libc::MAP_FAILED {
+		return Vec::new()
+	}
+	let contents = std::slice::from_raw_parts(ptr as *const u8, size as usize).to_vec();
+	libc::munmap(ptr, size as usize);
+	contents
+}
+
+unsafe fn cs_record(fd: c_int, sizes: &HashMap<u32, u64>, cs: &DrmRadeonCs) -> Record {
+	let (mut ib, mut relocs, mut flags) = (Vec::new(), Vec::new(), Vec::new());
+	let chunk_ptrs = std::slice::from_raw_parts(cs.chunks as usize as *const u64, cs.num_chunks as usize);
+	for &p in chunk_ptrs {
+		let chunk = &*(p as usize as *const DrmRadeonCsChunk);
+		if chunk.length_dw == 0 {
+			continue
+		}
+		let data = std::slice::from_raw_parts(chunk.chunk_data as usize as *const u32, chunk.length_dw as usize);
+		match chunk.chunk_id {
+			RADEON_CHUNK_ID_IB => ib.extend_from_slice(data),
+			RADEON_CHUNK_ID_RELOCS => relocs.extend_from_slice(std::slice::from_raw_parts(
+				data.as_ptr() as *const DrmRadeonCsReloc, data.len() / 4)),
+			RADEON_CHUNK_ID_FLAGS => flags.extend_from_slice(data),
+			_ => {}
+		}
+	}
+	let mut bos: Vec<(u32, Vec<u8>)> = Vec::new();
+	for r in &relocs {
+		if !bos.iter().any(|&(h, _)| h == r.handle) {
+			bos.push((r.handle, bo_contents(fd, sizes, r.handle)));
+		}
+	}
+	Record::Cs { errno: 0, ib, relocs, flags, bos }
+}
+
+// Passes the ioctl on and returns its result and errno along with the
+// records for it
+unsafe fn capture(c: &mut FdCapture, fd: c_int, nr: u32, request: c_ulong, arg: *mut c_void) -> (c_int, c_int, Vec<Record>) {
+	let radeon = |n: u32| DRM_COMMAND_BASE + n;
+
+	// what has to be read before the kernel gets to it
+	let mut before = match nr {
+		n if n == radeon(DRM_RADEON_CS) => Some(cs_record(fd, &c.sizes, &*(arg as *const DrmRadeonCs))),
+		DRM_GEM_CLOSE => {
+			let handle = (*(arg as *const DrmGemClose)).handle;
+			Some(Record::GemClose { handle, errno: 0, contents: bo_contents(fd, &c.sizes, handle) })
+		},
+		_ => None
+	};
+
+	let ret = real_ioctl(fd, request, arg);
+	let e = if ret == 0 { 0 } else { errno() };
+
+	let records = match nr {
+		n if n == radeon(DRM_RADEON_CS) => {
+			if let Some(Record::Cs { ref mut errno, .. }) = before { *errno = e }
+			vec![before.take().unwrap()]
+		},
+		DRM_GEM_CLOSE => {
+			if let Some(Record::GemClose { handle, ref mut errno, .. }) = before {
+				*errno = e;
+				c.sizes.remove(&handle);
+			}
+			vec![before.take().unwrap()]
+		},
+		n if n == radeon(DRM_RADEON_GEM_CREATE) => {
+			let create = &*(arg as *const DrmRadeonGemCreate);
+			if e == 0 { c.sizes.insert(create.handle, create.size); }
+			vec![Record::GemCreate { size: create.size, domain: create.initial_domain, errno: e, handle: create.handle, bo_size: create.size }]
+		},
+		DRM_GEM_OPEN if e == 0 => {
+			let open = &*(arg as *const DrmGemOpen);
+			c.sizes.insert(open.handle, open.size);
+			vec![Record::GemCreate { size: open.size, domain: RADEON_GEM_DOMAIN_GTT, errno: 0, handle: open.handle, bo_size: open.size }]
+		},
+		DRM_PRIME_FD_TO_HANDLE if e == 0 => {
+			let prime = &*(arg as *const DrmPrimeHandle);
+			let size = libc::lseek(prime.fd, 0, libc::SEEK_END);
+			if size > 0 {
+				c.sizes.insert(prime.handle, size as u64);
+				vec![Record::GemCreate { size: size as u64, domain: RADEON_GEM_DOMAIN_GTT, errno: 0, handle: prime.handle, bo_size: size as u64 }]
+			} else {
+				vec![]
+			}
+		},
+		n if n == radeon(DRM_RADEON_GEM_MMAP) => {
+			let mmap_args = &*(arg as *const DrmRadeonGemMmap);
+			vec![Record::GemMmap { handle: mmap_args.handle, size: mmap_args.size, errno: e }]
+		},
+		n if n == radeon(DRM_RADEON_GEM_INFO) => {
+			let info = &*(arg as *const DrmRadeonGemInfo);
+			vec![Record::GemInfo { errno: e, gart_size: info.gart_size, vram_size: info.vram_size, vram_visible: info.vram_visible }]
+		},
+		n if n == radeon(DRM_RADEON_GEM_WAIT_IDLE) =>
+			vec![Record::GemWaitIdle { handle: (*(arg as *const DrmRadeonGemWaitIdle)).handle, errno: e }],
+		n if n == radeon(DRM_RADEON_GEM_BUSY) =>
+			vec![Record::GemBusy { handle: (*(arg as *const DrmRadeonGemBusy)).handle, errno: e }],
+		n if n == radeon(DRM_RADEON_INFO) => {
+			let info = &*(arg as *const DrmRadeonInfo);
+			let value = if e != 0 || info.value == 0 { 0 }
+				else if RADEON_INFO_64BIT.contains(&info.request) { *(info.value as usize as *const u64) }
+				else { *(info.value as usize as *const u32) as u64 };
+			vec![Record::Info { request: info.request, errno: e, value }]
+		},
+		DRM_PRIME_HANDLE_TO_FD =>
+			vec![Record::PrimeHandleToFd { handle: (*(arg as *const DrmPrimeHandle)).handle, errno: e }],
+		_ => vec![]
+	};
+	(ret, e, records)
+}
+
+/// Replaces libc's ioctl.
+///
+/// # Safety
+///
+/// The same as for libc's: arg has to point to what the request expects.
+#[no_mangle]
+pub unsafe extern "C" fn ioctl(fd: c_int, request: c_ulong, arg: *mut c_void) -> c_int {
+	let ty = ((request >> 8) & 0xff) as u32;
+	let nr = (request & 0xff) as u32;
+	let interesting = nr >= DRM_COMMAND_BASE || [DRM_GEM_CLOSE, DRM_GEM_OPEN, DRM_PRIME_HANDLE_TO_FD, DRM_PRIME_FD_TO_HANDLE].contains(&nr);
+	if ty != DRM_IOCTL_BASE || !interesting {
+		return real_ioctl(fd, request, arg)
+	}
+
+	// The capture file is opened without holding the lock: if that fails
+	// the file is closed again, through close below, which takes the lock.
+	// For the same reason a capture opened meanwhile by another thread is
+	// dropped after the lock is released.
+	let known = FDS.lock().unwrap_or_else(|e| e.into_inner()).as_ref().is_some_and(|fds| fds.contains_key(&fd));
+	if !known {
+		let c = open_capture(fd);
+		let spare = match FDS.lock().unwrap_or_else(|e| e.into_inner()).get_or_insert_with(HashMap::new).entry(fd) {
+			Entry::Vacant(v) => { v.insert(c); None },
+			Entry::Occupied(_) => c
+		};
+		drop(spare);
+	}
+
+	let mut guard = FDS.lock().unwrap_or_else(|e| e.into_inner());
+	let fds = guard.get_or_insert_with(HashMap::new);
+	match fds.get_mut(&fd) {
+		Some(&mut Some(ref mut c)) => {
+			let (ret, e, records) = capture(c, fd, nr, request, arg);
+			for record in &records {
+				let _ = c.out.write(record);
+			}
+			// what was done since mustn't change what the caller sees
+			if ret != 0 {
+				set_errno(e);
+			}
+			ret
+		},
+		_ => real_ioctl(fd, request, arg)
+	}
+}
+
+/// Replaces libc's close.
+///
+/// # Safety
+///
+/// The same as for libc's.
+#[no_mangle]
+pub unsafe extern "C" fn close(fd: c_int) -> c_int {
+	// taken out under the lock but dropped after it, dropping closes the
+	// capture file, which comes back here
+	let finished = match FDS.lock() {
+		Ok(mut guard) => guard.as_mut().and_then(|fds| fds.remove(&fd)),
+		Err(_) => None
+	};
+	drop(finished);
+	let f: CloseFn = std::mem::transmute(next_symbol(&REAL_CLOSE, b"close\0"));
+	f(fd)
+}
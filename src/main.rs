@@ -12,21 +12,44 @@ extern crate xcb;
 extern crate wayland_client;
 extern crate wayland_protocols;
 
+mod blit;
+mod capture;
+mod compute;
 mod cs;
+mod debugfs;
+mod fence;
+mod device;
 #[macro_use]
 mod display;
 mod drm_radeon_ioctl;
+mod golden;
 mod initseq;
 mod libdrm;
 mod pm4;
+mod profile;
+mod query;
 mod r600_pci_ids;
-
+mod reference;
+mod shader;
+mod streamout;
+
+use blit::*;
+use compute::*;
+use shader::object::{PlacedShader, ShaderHeap, ShaderObject, Stage};
+use shader::builder::ShaderBuilder;
 use cs::*;
+use device::*;
+use fence::{Fence, FenceSlots, Timeline};
+use query::{QueryPool, QueryResult, QueryType};
+use streamout::*;
 use drm_radeon_ioctl::*;
 use initseq::INITSEQ;
+use std::cell::RefCell;
 use std::env;
 use std::fs;
-use std::os::unix::io::AsRawFd;
+use std::io::Read;
+use std::rc::Rc;
+use std::time::Duration;
 use display::*;
 use getopts::Options;
 
@@ -36,21 +59,46 @@ pub struct BOLayout {
 	pub db: [u8; L_DB_SIZE],
 	pub sh: [u8; L_SHADERBLOB_SIZE],
 	pub vx: [f32; L_VERTEXBUFFER_SIZE/4],
+	pub vx_fullscreen: [f32; L_VERTEXBUFFER_SIZE/4],
 	pub timestamps: [u64; 4],
-	pub align_to_256: [u8; 256-8*4-L_VERTEXBUFFER_SIZE],
-	pub cl: [f32; 4]
+	pub so_filled_size: [u32; 4],
+	pub align_to_256: [u8; 256-8*4-4*4-2*L_VERTEXBUFFER_SIZE],
+	pub consts: [u8; L_CONSTRING_SIZE],
+	pub so: [f32; L_STREAMOUT_SIZE/4],
+	pub yuv: [u8; L_YUV_SIZE],
+	pub esgs_ring: [u8; L_GS_RING_SIZE],
+	pub gsvs_ring: [u8; L_GS_RING_SIZE],
+	pub markers: [u32; MAX_MARKERS],
+	pub section_times: [u64; MAX_MARKERS],
+	pub fences: [u64; MAX_FENCES],
+	pub queries: [u64; L_QUERIES_SIZE/8]
+}
+
+// Marker slots for --locate-hang and --profile, about one per labeled section
+const MAX_MARKERS: usize = 256;
+const MAX_FENCES: usize = 16;
+// For the square's queries with --check-queries
+const L_QUERIES_SIZE: usize = 512;
+// Each of the two rings of the pass-through GS
+const L_GS_RING_SIZE: usize = 65536;
+
+const COMPUTE_N: usize = 4096;
+
+#[repr(C)]
+pub struct ComputeBOLayout {
+	pub sh: [u8; L_SHADERBLOB_SIZE],
+	pub a: [f32; COMPUTE_N],
+	pub b: [f32; COMPUTE_N],
+	pub c: [f32; COMPUTE_N]
 }
 
 macro_rules! offset_of {
-	($t:ty => $m:ident) => (unsafe {
-		let np = 0 as *const $t;
-		let mp = (&(*np).$m) as *const _;
-		mp as usize
-	})
+	($t:ty => $m:ident) => (::std::mem::offset_of!($t, $m))
 }
 
 
 const THEDRAW: DrawInfo<'static> = DrawInfo {
+	prim_type: DI_PT_TRILIST,
 	indirect: false,
 	indexed: true,
 	index_size: 4,
@@ -58,101 +106,73 @@ const THEDRAW: DrawInfo<'static> = DrawInfo {
 	user_buffer: Some(&[0, 2, 1, 1, 2, 3])
 };
 
-fn bomap(fd: i32, handle: u32, offset: u64, size: u64) -> Mapping {
-	let mut mmap_args = DrmRadeonGemMmap {
-		handle: handle,
-		pad: 0,
-		offset: offset,
-		size: size,
-		addr_ptr: 0
-	};
-	let addr;
-	unsafe {
-		/*let r =*/
-		drm_ioctl_radeon_gem_mmap(fd, &mut mmap_args);
-		// println!("mmap ioctl -> {}", r);
-		// println!("{:?}", std::io::Error::last_os_error());
-		addr = libc::mmap(0 as *mut libc::c_void, mmap_args.size as usize, libc::PROT_READ|libc::PROT_WRITE, libc::MAP_SHARED, fd, mmap_args.addr_ptr as i64);
-	};
-	// println!("{:?}", std::io::Error::last_os_error());
-	Mapping {ptr: addr, size: mmap_args.size as usize}
-}
+const BO_DOMAIN: u32 = RADEON_GEM_DOMAIN_VRAM;
 
-struct Mapping {
-	pub ptr: *mut libc::c_void,
-	size: usize
-}
+// How long a render may take before the GPU is taken to be hung
+const DEFAULT_TIMEOUT_MS: u64 = 2000;
 
-impl Drop for Mapping {
-	fn drop(&mut self) {
-		unsafe { libc::munmap(self.ptr, self.size); }
-	}
+// The multisampled color buffer, its CMASK and its FMASK share a second BO.
+struct MsaaTarget<'d> {
+	bo: BO<'d>,
+	surface: MsaaSurface
 }
 
-struct BO {
-	pub handle: u32,
-	pub size: u64,
-	fd: i32
+fn msaa_target(dev: &Device, samples: u32) -> MsaaTarget {
+	let num_pipes = radeon_info(dev, RADEON_INFO_NUM_TILE_PIPES) as u32;
+	let surface = MsaaSurface::new(W, H, samples, std::cmp::max(num_pipes, 1));
+	let bo = gem_create(dev, surface.size as u64, BO_DOMAIN);
+
+	let mapping = bomap(dev, bo.handle, bo.size);
+	let data = unsafe { std::slice::from_raw_parts_mut(mapping.ptr as *mut u8, surface.size as usize) };
+	let cmask = surface.cmask_offset as usize .. (surface.cmask_offset + surface.cmask_size) as usize;
+	let fmask = surface.fmask_offset as usize .. (surface.fmask_offset + surface.fmask_size) as usize;
+	// a CMASK nibble of 0xc is compressed but not fast cleared, so the FMASK
+	// is read, and an FMASK of 0 maps every sample to fragment 0
+	for b in &mut data[cmask] { *b = 0xcc; }
+	for b in &mut data[fmask] { *b = 0; }
+
+	MsaaTarget { bo: bo, surface: surface }
 }
 
-impl Drop for BO {
-	fn drop(&mut self) {
-		gem_close(self.fd, self.handle);
-	}
-}
+// The pass-through GS: a position in and out per vertex, one triangle per
+// invocation
+const PASSTHROUGH_GS: GsState = GsState {
+	max_vert_out: 3,
+	out_prim: GsOutPrim::TriStrip,
+	esgs_itemsize: 16,
+	gsvs_itemsize: 16,
+	prim_id: false
+};
 
-fn gem_create(fd: i32, size: u64, domain: u32) -> BO {
-	let mut create = DrmRadeonGemCreate {
-		size: size,
-		alignment: 0,
-		handle: 0,
-		initial_domain: domain,
-		flags: 0
-	};
-	unsafe { drm_ioctl_radeon_gem_create(fd, &mut create) };
-	BO {
-		handle: create.handle,
-		size: create.size,
-		fd: fd
+fn setup_shaders(cs: &mut CS, shaders: &Shaders, bo_reloc: &Fn(&mut CS) -> (), streamout: bool, gs: bool) {
+	let sh = offset_of!(BOLayout=>sh);
+	if gs {
+		let rings = GsRings {
+			esgs_offset: offset_of!(BOLayout=>esgs_ring) as u32,
+			esgs_size: L_GS_RING_SIZE as u32,
+			gsvs_offset: offset_of!(BOLayout=>gsvs_ring) as u32,
+			gsvs_size: L_GS_RING_SIZE as u32
+		};
+		evergreen_es_setup(cs, &shaders.solid_es.object, (sh + shaders.solid_es.offset) as u32, bo_reloc);
+		evergreen_gs_setup(cs, &shaders.passthrough_gs.object, (sh + shaders.passthrough_gs.offset) as u32, bo_reloc);
+		evergreen_vs_setup(cs, &shaders.gs_copy_vs.object, (sh + shaders.gs_copy_vs.offset) as u32, bo_reloc);
+		setup_shader_stages(cs, Some(&PASSTHROUGH_GS));
+		setup_gs(cs, &PASSTHROUGH_GS);
+		setup_gs_rings(cs, Some(&rings), bo_reloc);
+		// the ES reads the vertex buffer, fetch buffer 0 of the VS, so the
+		// copy shader reads the GSVS ring as buffer 1
+		set_ring_resource(cs, FETCH_RESOURCE_OFFSET_GS, rings.esgs_offset, rings.esgs_size, bo_reloc);
+		set_ring_resource(cs, FETCH_RESOURCE_OFFSET_VS + 1, rings.gsvs_offset, rings.gsvs_size, bo_reloc);
+	} else {
+		let vs = if streamout { &shaders.solid_vs_streamout } else { &shaders.solid_vs };
+		evergreen_vs_setup(cs, &vs.object, (sh + vs.offset) as u32, bo_reloc);
 	}
-}
-
-fn gem_close(fd: i32, handle: u32) {
-	let close = DrmGemClose {
-		handle: handle,
-		pad: 0
-	};
-	unsafe { drm_ioctl_gem_close(fd, &close) };
-}
-
-const BO_DOMAIN: u32 = RADEON_GEM_DOMAIN_VRAM;
-
-fn setup_shaders(cs: &mut CS, bo_reloc: &Fn(&mut CS) -> ()) {
-	let vs_conf = ShaderConfig {
-		shader_addr  : (offset_of!(BOLayout=>sh) + SH_SOLID_VS_OFFSET) as u32,
-		shader_size  : 512,
-		num_gprs     : 2,
-		stack_size   : 0,
-		clamp_consts : 0, // N/A
-		export_mode  : 0  // N/A
-	};
-	evergreen_vs_setup(cs, &vs_conf, bo_reloc);
-
-	let ps_conf = ShaderConfig {
-		shader_addr  : (offset_of!(BOLayout=>sh) + SH_SOLID_PS_OFFSET) as u32,
-		shader_size  : 512,
-		num_gprs     : 1,
-		stack_size   : 0,
-		clamp_consts : 0,
-		export_mode  : 2,
-	};
-	evergreen_ps_setup(cs, &ps_conf, bo_reloc);
+	evergreen_ps_setup(cs, &shaders.solid_ps.object, (sh + shaders.solid_ps.offset) as u32, bo_reloc);
 }
 
 fn write_number(cs: &mut CS, number: u64) {
 	// Fence, write 64-bit data.
-	let offset = unsafe { (&(*(0 as *const BOLayout)).timestamps[0]) as *const u64 as usize };
-	// or offset_of!(BOLayout=>timestamps) as u32
+	let offset = offset_of!(BOLayout=>timestamps);
 
 	cs.write_label("write number");
 	//cs.write(&[ /* set r4 within ME to right value */
@@ -171,53 +191,198 @@ fn write_number(cs: &mut CS, number: u64) {
 	]);
 }
 
-const SHADERBIN: &'static [u8; 4096] = include_bytes!("../evergreen_shader.bin");
+const CLEAR_COLOR: [f32; 4] = [0.0, 0.0, 0.0, 1.0];
+const SQUARE_COLOR: [f32; 4] = [0.0, 1.0, 0.0, 1.0];
+
+const SOLID_VS_ASM: &'static str = include_str!("../shaders/solid_vs.asm");
+const SOLID_PS_ASM: &'static str = include_str!("../shaders/solid_ps.asm");
+const SOLID_ES_ASM: &'static str = include_str!("../shaders/solid_es.asm");
+const PASSTHROUGH_GS_ASM: &'static str = include_str!("../shaders/passthrough_gs.asm");
+const GS_COPY_VS_ASM: &'static str = include_str!("../shaders/gs_copy_vs.asm");
+
+// Square vertices as the draw sees them, two triangles from THEDRAW's indices
+const SQUARE_CORNERS: [(u32, u32); 4] = [(10, 10), (10, 90), (90, 10), (90, 90)];
+
+// pixel position to normalized device coordinates
+fn ndc(x: u32, y: u32) -> (f32, f32) {
+	(x as f32 / W as f32 * 2.0 - 1.0, 1.0 - y as f32 / H as f32 * 2.0)
+}
+
+fn assemble_shader(name: &str, stage: Stage, source: &str) -> ShaderObject {
+	let program = shader::asm::assemble(source, shader::isa::Chip::Evergreen)
+		.unwrap_or_else(|e| panic!("{}: {}", name, e));
+	ShaderObject::new(stage, &program)
+}
+
+// The shaders in BOLayout.sh
+struct Shaders {
+	solid_vs: PlacedShader,
+	solid_vs_streamout: PlacedShader,
+	solid_ps: PlacedShader,
+	solid_es: PlacedShader,
+	passthrough_gs: PlacedShader,
+	gs_copy_vs: PlacedShader,
+	blit: BlitShaders
+}
+
+fn load_shaders() -> (ShaderHeap, Shaders) {
+	let mut heap = ShaderHeap::default();
+	let shaders = Shaders {
+		solid_vs: heap.add(assemble_shader("solid_vs.asm", Stage::Vs, SOLID_VS_ASM)),
+		solid_vs_streamout: heap.add(ShaderObject::new(Stage::Vs, &shader::library::solid_vs_streamout())),
+		solid_ps: heap.add(assemble_shader("solid_ps.asm", Stage::Ps, SOLID_PS_ASM)),
+		solid_es: heap.add(assemble_shader("solid_es.asm", Stage::Es, SOLID_ES_ASM)),
+		passthrough_gs: heap.add(assemble_shader("passthrough_gs.asm", Stage::Gs, PASSTHROUGH_GS_ASM)),
+		gs_copy_vs: heap.add(assemble_shader("gs_copy_vs.asm", Stage::Vs, GS_COPY_VS_ASM)),
+		blit: BlitShaders::new(&mut heap, offset_of!(BOLayout=>sh) as u32)
+	};
+	assert!(heap.code.len() <= L_SHADERBLOB_SIZE, "shaders don't fit in BOLayout.sh");
+	for &(name, shader) in [
+		("solid VS", &shaders.solid_vs), ("solid VS with stream-out", &shaders.solid_vs_streamout),
+		("solid PS", &shaders.solid_ps), ("solid ES", &shaders.solid_es), ("pass-through GS", &shaders.passthrough_gs),
+		("GS copy VS", &shaders.gs_copy_vs), ("quad VS", &shaders.blit.vs), ("copy PS", &shaders.blit.copy_ps),
+		("texture PS", &shaders.blit.texture_ps), ("YUV PS", &shaders.blit.yuv_ps)
+	].iter() {
+		validate_shader(name, &shader.object);
+	}
+	(heap, shaders)
+}
+
+fn validate_shader(name: &str, object: &ShaderObject) {
+	let problems = shader::validate::validate_object(object, shader::isa::Chip::Evergreen);
+	assert!(problems.is_empty(), "{}: {}", name, problems.join(", "));
+}
 
-fn init_bo(bo: &mut BOLayout) {
-	// let mut f = fs::File::open("evergreen_shader.bin").unwrap();
-	// f.read_exact(&mut bo.sh).unwrap();
-	bo.sh = *SHADERBIN;
+fn init_bo(bo: &mut BOLayout, shaders: &ShaderHeap) {
+	bo.sh[..shaders.code.len()].copy_from_slice(&shaders.code);
 
 	bo.timestamps[0] = 0xcdcdcdcdcdcdcdcd;
 	bo.timestamps[1] = 0xc1c1c1c1c1c1c1c1;
 	bo.timestamps[2] = 0xc2c2c2c2c2c2c2c2;
 	bo.timestamps[3] = 0xc3c3c3c3c3c3c3c3;
 
-	bo.vx[ 0] = 10.0;
-	bo.vx[ 1] = 10.0;
-	bo.vx[ 2] = 10.0;
-	bo.vx[ 3] = 1.0;
+	for (i, &(x, y)) in SQUARE_CORNERS.iter().enumerate() {
+		let (x, y) = ndc(x, y);
+		bo.vx[i*4+0] = x;
+		bo.vx[i*4+1] = y;
+		bo.vx[i*4+2] = 0.0;
+		bo.vx[i*4+3] = 1.0;
+	}
+
+	// covers the whole framebuffer, for clears and resolves
+	for (i, &(x, y)) in [(0, 0), (0, H), (W, 0), (W, H)].iter().enumerate() {
+		let (x, y) = ndc(x, y);
+		bo.vx_fullscreen[i*4+0] = x;
+		bo.vx_fullscreen[i*4+1] = y;
+		bo.vx_fullscreen[i*4+2] = 0.0;
+		bo.vx_fullscreen[i*4+3] = 1.0;
+	}
 
-	bo.vx[ 4] = 10.0;
-	bo.vx[ 5] = 90.0;
-	bo.vx[ 6] = 10.0;
-	bo.vx[ 7] = 1.0;
+	let frame = yuv_frame();
+	let base = offset_of!(BOLayout=>yuv) as u32;
+	for (plane, bars) in [&frame.y, &frame.u, &frame.v].iter().zip(color_bars_yuv().iter()) {
+		for y in 0..plane.height {
+			for x in 0..plane.width {
+				let o = (plane.offset - base + y * plane.pitch + x) as usize;
+				bo.yuv[o] = bars[(x * bars.len() as u32 / plane.width) as usize];
+			}
+		}
+	}
 
-	bo.vx[ 8] = 90.0;
-	bo.vx[ 9] = 10.0;
-	bo.vx[10] = 10.0;
-	bo.vx[11] = 1.0;
+	for m in bo.markers.iter_mut() { *m = 0; }
+	for t in bo.section_times.iter_mut() { *t = profile::NOT_WRITTEN; }
+	for q in bo.queries.iter_mut() { *q = 0; }
+}
 
-	bo.vx[12] = 90.0;
-	bo.vx[13] = 90.0;
-	bo.vx[14] = 10.0;
-	bo.vx[15] = 1.0;
+// For --blit: a YUV frame of eight vertical color bars
+const YUV_W: u32 = 64;
+const YUV_H: u32 = 48;
 
-	bo.cl[0] = 0.0;
-	bo.cl[1] = 1.0;
-	bo.cl[2] = 0.0;
-	bo.cl[3] = 1.0;
+fn yuv_frame() -> YuvFrame {
+	let frame = YuvFrame::i420(offset_of!(BOLayout=>yuv) as u32, YUV_W, YUV_H, YuvMatrix::Bt601);
+	assert!(frame.size() as usize <= L_YUV_SIZE, "YUV frame doesn't fit in BOLayout.yuv");
+	frame
 }
 
-fn build_cs(bo_handle: u32, initseq: &[u32]) -> CS{
+// Y, U and V of white, yellow, cyan, green, magenta, red, blue and black
+fn color_bars_yuv() -> [[u8; 8]; 3] {
+	let mut planes = [[0; 8]; 3];
+	for i in 0..8 {
+		let r = if i & 2 == 0 { 1.0 } else { 0.0 };
+		let g = if i < 4 { 1.0 } else { 0.0 };
+		let b = if i & 1 == 0 { 1.0 } else { 0.0 };
+		let y = 0.299 * r + 0.587 * g + 0.114 * b;
+		planes[0][i] = (16.0 + 219.0 * y) as u8;
+		planes[1][i] = (128.0 + 224.0 * (b - y) / 1.772) as u8;
+		planes[2][i] = (128.0 + 224.0 * (r - y) / 1.402) as u8;
+	}
+	planes
+}
+
+// Copies the square to its right, composites a half transparent copy over
+// that, draws a small darker copy further right, and draws the YUV frame
+// below, once as the BT.601 it was encoded with and once as BT.709
+fn blit_demo(cs: &mut CS, ring: &mut ConstRing, shaders: &BlitShaders, bo_reloc: &Fn(&mut CS) -> ()) {
+	let fb = Surface::new(offset_of!(BOLayout=>cb) as u32, W, H, SurfaceFormat::Rgba8);
+	let square = Rect { x0: 10, y0: 10, x1: 90, y1: 90 };
+	copy_rect(cs, ring, shaders, &fb, &square, &fb, 110, 10, bo_reloc);
+	composite(cs, ring, shaders, PictOp::Over, &fb, &square, [0.5; 4], &fb, &Rect { x0: 150, y0: 50, x1: 270, y1: 170 }, bo_reloc);
+	draw_texture(cs, ring, shaders, &fb, &square, [0.5, 0.5, 0.5, 1.0], &fb, &Rect { x0: 290, y0: 10, x1: 330, y1: 50 }, bo_reloc);
+	draw_yuv_frame(cs, ring, shaders, &yuv_frame(), &fb, &Rect { x0: 10, y0: 190, x1: 266, y1: 382 }, bo_reloc);
+	let bt709 = YuvFrame { matrix: YuvMatrix::Bt709, .. yuv_frame() };
+	draw_yuv_frame(cs, ring, shaders, &bt709, &fb, &Rect { x0: 286, y0: 190, x1: 542, y1: 382 }, bo_reloc);
+}
+
+// What goes into a frame; the default is the plain square with the
+// baseline init sequence
+#[derive(Clone, Copy, Default)]
+struct RenderOptions<'a> {
+	initseq: &'a [u32], // sent before the setup, for the minimizer
+	msaa: Option<&'a MsaaTarget<'a>>,
+	streamout: bool,
+	blit: bool,
+	gs: bool, // draw through the pass-through GS
+	markers: Option<MarkerKind>,
+	queries: bool // wrap the square's draw in queries
+}
+
+fn build_cs(bo_handle: u32, options: &RenderOptions, ring: &mut ConstRing, shaders: &Shaders, mut queries: Option<&mut QueryPool>) -> CS{
+	let RenderOptions { initseq, msaa, streamout, blit, gs, markers, .. } = *options;
 
 	let mut cs = CS::default();
+	ring.begin_frame();
+	match markers {
+		Some(MarkerKind::Hang) => cs.enable_markers(MarkerKind::Hang, bo_handle, BO_DOMAIN, offset_of!(BOLayout=>markers) as u32, MAX_MARKERS),
+		Some(MarkerKind::Timestamp) => cs.enable_markers(MarkerKind::Timestamp, bo_handle, BO_DOMAIN, offset_of!(BOLayout=>section_times) as u32, MAX_MARKERS),
+		None => {}
+	}
 
 	let bo_reloc = |cs: &mut CS| {
 		cs.write_label("  reloc nop");
 		cs.write(&[packet3(cs::Packet3::NOP, 0, 0), 0x00000000]);
 		cs.write_reloc(bo_handle, 0, BO_DOMAIN, 0);
 	};
+	let msaa_reloc = |cs: &mut CS| {
+		cs.write_label("  reloc nop (msaa)");
+		cs.write_reloc_nop(msaa.unwrap().bo.handle, 0, BO_DOMAIN);
+	};
+	let msaa_cb = msaa.map(|m| {
+		let mut cb = m.surface.color_buffer();
+		cb.set_fast_clear(CLEAR_COLOR);
+		cb
+	});
+	let so_buffers = [StreamoutBuffer {
+		offset: offset_of!(BOLayout=>so) as u32,
+		size: L_STREAMOUT_SIZE as u32,
+		stride: 4 * 4,
+		filled_size_offset: offset_of!(BOLayout=>so_filled_size) as u32
+	}];
+	let fullscreen = VtxRes {
+		byteoffset: offset_of!(BOLayout=>vx_fullscreen) as u32,
+		bytesize:   4 * 4 * 4,
+		stride:     4 * 4,
+		vtxcount:   4
+	};
 
 	write_number(&mut cs, 1);
 	bo_reloc(&mut cs);
@@ -226,8 +391,11 @@ fn build_cs(bo_handle: u32, initseq: &[u32]) -> CS{
 		//initseq_send(&mut cs);
 		cs.write(initseq);
 
-		cs.write_label("setup scissors");
-		setup_scissors(&mut cs, W, H);
+		setup_rasterizer(&mut cs, &RasterizerState {
+			discard: streamout,
+			.. RasterizerState::viewport(W, H)
+		});
+		setup_msaa(&mut cs, msaa.map(|m| m.surface.samples).unwrap_or(1));
 
 	write_number(&mut cs, 2);
 	bo_reloc(&mut cs);
@@ -237,19 +405,32 @@ fn build_cs(bo_handle: u32, initseq: &[u32]) -> CS{
 	write_number(&mut cs, 3);
 	bo_reloc(&mut cs);
 
-		setup_shaders(&mut cs, &bo_reloc);
+		setup_shaders(&mut cs, shaders, &bo_reloc, streamout, gs);
 
 	write_number(&mut cs, 4);
 	bo_reloc(&mut cs);
 
 		cs.write_label("setup framebuffer");
-		setup_fb(&mut cs, W, H, TILED, &bo_reloc);
+		if let (Some(m), Some(cb)) = (msaa, msaa_cb.as_ref()) {
+			fast_clear_cmask(&mut cs, &m.surface, &msaa_reloc);
+			setup_fb_cb(&mut cs, cb, &msaa_reloc);
+		} else {
+			setup_fb(&mut cs, W, H, TILED, &bo_reloc);
+		}
 
 	write_number(&mut cs, 5);
 	bo_reloc(&mut cs);
 
 		setup_spi(&mut cs);
 
+		if msaa.is_none() && !streamout {
+			cs.write_label("clear");
+			ring.bind(&mut cs, ShaderStage::Ps, 0, &CLEAR_COLOR, &bo_reloc);
+			set_vtx_resource(&mut cs, &fullscreen, &bo_reloc);
+			bo_reloc(&mut cs);
+			vbo(&mut cs, THEDRAW);
+		}
+
 	write_number(&mut cs, 6);
 	bo_reloc(&mut cs);
 
@@ -259,7 +440,7 @@ fn build_cs(bo_handle: u32, initseq: &[u32]) -> CS{
 			stride:     4 * 4,
 			vtxcount:   4
 		};
-		set_ps_const_buffer(&mut cs, offset_of!(BOLayout=>cl) as u32, &bo_reloc);
+		ring.bind(&mut cs, ShaderStage::Ps, 0, &SQUARE_COLOR, &bo_reloc);
 
 	write_number(&mut cs, 7);
 	bo_reloc(&mut cs);
@@ -270,47 +451,568 @@ fn build_cs(bo_handle: u32, initseq: &[u32]) -> CS{
 	write_number(&mut cs, 8);
 	bo_reloc(&mut cs);
 
+		let square_queries = queries.as_mut().map(|pool| [
+			pool.begin(&mut cs, QueryType::Occlusion, "square occlusion"),
+			pool.begin(&mut cs, QueryType::PipelineStats, "square pipeline statistics")
+		]);
+		if streamout {
+			streamout_begin(&mut cs, &so_buffers, &bo_reloc);
+		}
+		let so_query = queries.as_mut().filter(|_| streamout).map(|pool|
+			pool.begin(&mut cs, QueryType::Streamout, "square stream-out"));
 		vbo(&mut cs, THEDRAW);
+		if let (Some(pool), Some(query)) = (queries.as_ref(), so_query.as_ref()) {
+			pool.end(&mut cs, query);
+		}
+		if streamout {
+			streamout_end(&mut cs, &so_buffers, &bo_reloc);
+		}
+		if let (Some(pool), Some(square)) = (queries.as_ref(), square_queries.as_ref()) {
+			for query in square.iter().rev() {
+				pool.end(&mut cs, query);
+			}
+		}
+		if blit {
+			blit_demo(&mut cs, ring, &shaders.blit, &bo_reloc);
+		}
+
+	if let Some(cb) = msaa_cb.as_ref() {
+		write_number(&mut cs, 9);
+		bo_reloc(&mut cs);
+
+			setup_eliminate_fast_clear(&mut cs, cb, &msaa_reloc);
+			set_vtx_resource(&mut cs, &fullscreen, &bo_reloc);
+			bo_reloc(&mut cs);
+			vbo(&mut cs, THEDRAW);
+
+			setup_resolve(&mut cs, cb, &color_buffer(W, H, TILED), &msaa_reloc, &bo_reloc);
+			vbo(&mut cs, THEDRAW);
+	}
+
 		cs.write_label("end");
+		cs.finish_markers();
 
 	cs
 }
 
-fn render(fd: i32, bo_handle: u32, bo_size: u64, initseq: &[u32]) {
-	let mut waitidle = DrmRadeonGemWaitIdle::default();
-	waitidle.handle = bo_handle;
+// The BO everything renders into, kept for as long as the device so that
+// its fence slots carry on from one submission to the next
+struct FrameBo<'d> {
+	bo: BO<'d>,
+	fences: FenceSlots
+}
+
+impl<'d> FrameBo<'d> {
+	fn new(dev: &'d Device) -> FrameBo<'d> {
+		let bo = gem_create(dev, std::mem::size_of::<BOLayout>() as u64, BO_DOMAIN);
+		{
+			let mapping = bomap(dev, bo.handle, bo.size);
+			let bo_data = unsafe {&mut (*(mapping.ptr as *mut BOLayout))};
+			for f in bo_data.fences.iter_mut() { *f = 0; }
+		}
+		let fences = FenceSlots::new(bo.handle, BO_DOMAIN, offset_of!(BOLayout=>fences) as u32, MAX_FENCES);
+		FrameBo { bo: bo, fences: fences }
+	}
+}
+
+// A submitted CS with the fence at its end and the GPU reset counter from
+// before it, so that waiting for it can tell whether the GPU was reset since
+struct Submission {
+	cs: CS,
+	timeline: Option<Timeline>, // given back to the frame BO once the fence has signaled
+	fence: Fence,
+	queries: Option<QueryPool>, // read back once the fence has signaled
+	resets_before: Option<u64>,
+	submitted: bool, // false if the kernel refused it, then there's no fence to wait for
+	reset: bool // the CS ioctl already found the GPU locked up
+}
+
+// Fills the BO and submits the CS without waiting for it
+fn submit(dev: &Device, frame: &mut FrameBo, options: &RenderOptions) -> Submission {
+	let bo_handle = frame.bo.handle;
+	let mut ring = ConstRing::new(offset_of!(BOLayout=>consts) as u32, L_CONSTRING_SIZE as u32);
+	let (heap, shaders) = load_shaders();
+	let mut pool = if options.queries {
+		Some(QueryPool::new(bo_handle, BO_DOMAIN, offset_of!(BOLayout=>queries) as u32, L_QUERIES_SIZE as u32))
+	} else {
+		None
+	};
+	let mut cs = build_cs(bo_handle, options, &mut ring, &shaders, pool.as_mut());
+	let mut timeline = frame.fences.timeline().expect("all fence slots in use");
+	let fence = frame.fences.emit(&mut cs, &mut timeline, false);
 
 	{
-		// println!("BO handle = {:?}  size = {:?}", bo_handle, bo_size);
-		let mapping = bomap(fd, bo_handle, 0, bo_size);
+		// println!("BO handle = {:?}  size = {:?}", bo_handle, frame.bo.size);
+		let mapping = bomap(dev, bo_handle, frame.bo.size);
 		let p = mapping.ptr;
 
 		//println!("p = {:?}", p);
 
 		let bo = unsafe { &mut *(p as *mut BOLayout) };
-		init_bo(bo);
+		init_bo(bo, &heap);
+		ring.upload(&mut bo.consts);
 
 		//println!("BO unmapped");
 	}
 
-	unsafe { drm_ioctl_radeon_gem_wait_idle(fd, &mut waitidle) }; // println!("BO waited");
-
-	let cs = build_cs(bo_handle, initseq);
-	cs.submit(fd);
+	let resets_before = reset_counter(dev);
+	let r = cs.submit(dev);
+	if let Err(ref e) = r {
+		println!("CS submission failed: {}", e);
+	}
 	//println!("CS submitted");
+	let reset = is_reset(dev, &r, resets_before);
+	Submission {
+		cs: cs,
+		timeline: Some(timeline),
+		fence: fence,
+		queries: pool,
+		resets_before: resets_before,
+		submitted: r.is_ok(),
+		reset: reset
+	}
+}
+
+// Waits for the fence of a submission; if it takes longer than timeout the
+// GPU is taken to be hung and this waits on for the kernel to reset it.
+// Once the fence has signaled its timeline goes back to the frame BO, after
+// a hang the slot stays taken.
+fn finish(dev: &Device, frame: &mut FrameBo, submission: &mut Submission, timeout: Duration) -> Wait {
+	if submission.reset {
+		return Wait::Reset
+	}
+	let wait = if submission.submitted {
+		let mapping = bomap(dev, frame.bo.handle, frame.bo.size);
+		frame.fences.wait(dev, &mapping, submission.fence, timeout, submission.resets_before)
+	} else {
+		Wait::Idle
+	};
+	match wait {
+		Wait::Idle => {
+			if let Some(timeline) = submission.timeline.take() {
+				frame.fences.release(timeline);
+			}
+			Wait::Idle
+		},
+		Wait::TimedOut => {
+			println!("GPU still busy after {:?}, waiting for the kernel to reset it", timeout);
+			match wait_for_reset(dev, frame.bo.handle, submission.resets_before) {
+				Wait::Reset => Wait::Reset,
+				_ => Wait::TimedOut
+			}
+		},
+		w => w
+	}
+}
+
+// Idle if the BO can be looked at afterwards
+fn render(dev: &Device, frame: &mut FrameBo, options: &RenderOptions, timeout: Duration) -> Wait {
+	let mut submission = submit(dev, frame, options);
+	let wait = finish(dev, frame, &mut submission, timeout);
+	if wait == Wait::Reset {
+		println!("the GPU was reset");
+	}
+	wait
+}
+
+// Renders with a hang marker after every labeled section and reads the
+// markers back once the GPU is idle or has been reset. Sections finish in
+// order, so the first marker that wasn't written is the section the GPU got
+// stuck in. False if there is one.
+fn locate_hang(dev: &Device, frame: &mut FrameBo, options: &RenderOptions, timeout: Duration) -> bool {
+	let mut submission = submit(dev, frame, &RenderOptions { markers: Some(MarkerKind::Hang), .. *options });
+	let wait = finish(dev, frame, &mut submission, timeout);
+	let idle = wait == Wait::Idle;
+	if !idle {
+		println!("{}", if wait == Wait::Reset { "the GPU was reset" } else { "the GPU didn't finish" });
+	}
+
+	let cs = &submission.cs;
+	let sections = cs.marker_sections();
+	let mapping = bomap(dev, frame.bo.handle, frame.bo.size);
+	let bo_data = unsafe {&(*(mapping.ptr as *const BOLayout))};
+	let first_incomplete = (0..sections.len()).find(|&n| bo_data.markers[n] != n as u32 + 1);
+	let n = match first_incomplete {
+		Some(n) => n,
+		None => {
+			println!("all {} sections completed{}", sections.len(), if idle { "" } else { ", the hang is past the last marker" });
+			return idle
+		}
+	};
+	match n {
+		0 => println!("no section completed"),
+		_ => println!("last completed: {} (section {})", sections[n - 1].label, n)
+	}
+	println!("first incomplete: {} (section {}, marker {:#x})", sections[n].label, n + 1, bo_data.markers[n]);
+	for (k, s) in sections.iter().enumerate().skip(n + 1) {
+		if bo_data.markers[k] == k as u32 + 1 {
+			println!("but the marker of {} (section {}) was written, something else is wrong", s.label, k + 1);
+			break
+		}
+	}
+	println!("");
+	pm4::print_labeled(cs, sections[n].start, sections[n].end);
+	false
+}
+
+// Renders with a timestamp around every labeled section and prints how long
+// each took; with trace, also writes them there as Chrome trace JSON
+fn profile(dev: &Device, frame: &mut FrameBo, options: &RenderOptions, timeout: Duration, trace: Option<&str>) -> bool {
+	let freq = radeon_info(dev, RADEON_INFO_CLOCK_CRYSTAL_FREQ);
+	if freq == 0 {
+		println!("the kernel doesn't report the GPU clock frequency");
+		return false
+	}
+	let mut submission = submit(dev, frame, &RenderOptions { markers: Some(MarkerKind::Timestamp), .. *options });
+	if finish(dev, frame, &mut submission, timeout) != Wait::Idle {
+		println!("the GPU didn't finish, --locate-hang shows where it stopped");
+		return false
+	}
+
+	let mapping = bomap(dev, frame.bo.handle, frame.bo.size);
+	let bo_data = unsafe {&(*(mapping.ptr as *const BOLayout))};
+	let times = profile::section_times(submission.cs.marker_sections(), &bo_data.section_times, freq);
+	println!("GPU clock {} kHz", freq);
+	profile::print_table(&times);
+	if let Some(path) = trace {
+		if let Err(e) = fs::write(path, profile::chrome_trace(&times)) {
+			println!("{}: {}", path, e);
+			return false
+		}
+		println!("wrote {}", path);
+	}
+	true
+}
+
+// Renders the square inside an occlusion, a pipeline statistics and with
+// streamout a stream-out statistics query, and checks their results once
+// the fence at the end has signaled. Stream-out turns rasterization off, so
+// then nothing reaches the PS.
+fn check_queries(dev: &Device, frame: &mut FrameBo, streamout: bool, timeout: Duration) -> bool {
+	let mut submission = submit(dev, frame, &RenderOptions { streamout: streamout, queries: true, .. RenderOptions::default() });
+	if finish(dev, frame, &mut submission, timeout) != Wait::Idle {
+		println!("the GPU didn't finish, --locate-hang shows where it stopped");
+		return false
+	}
+
+	let (x0, y0) = SQUARE_CORNERS[0];
+	let (x1, y1) = SQUARE_CORNERS[3];
+	let pixels = if streamout { 0 } else { ((x1 - x0) * (y1 - y0)) as u64 };
+	let indices = THEDRAW.user_buffer.unwrap().len() as u64;
+	let mapping = bomap(dev, frame.bo.handle, frame.bo.size);
+	let pool = submission.queries.as_ref().unwrap();
+	let mut ok = true;
+	for query in pool.queries() {
+		let (got, good) = match pool.result(&mapping, query, &frame.fences, submission.fence) {
+			Some(QueryResult::Occlusion(passed)) => (format!("{} samples passed", passed), passed == pixels),
+			Some(QueryResult::PipelineStats(s)) => (
+				format!("{} vertices, {} primitives, {} VS, {} clipper in, {} clipper out, {} PS",
+					s.ia_vertices, s.ia_primitives, s.vs_invocations, s.c_invocations, s.c_primitives, s.ps_invocations),
+				(s.ia_vertices, s.ia_primitives, s.ps_invocations) == (indices, indices / 3, pixels)),
+			Some(QueryResult::Streamout { written, needed }) => (
+				format!("{} primitives written, {} needed", written, needed),
+				(written, needed) == (indices / 3, indices / 3)),
+			None => ("the fence didn't signal".to_owned(), false)
+		};
+		println!("  {}: {} {}", query.name, got, if good { "ok" } else { "MISMATCH" });
+		ok &= good;
+	}
+	ok
+}
+
+// Like render, but the CS runs on the reference renderer with a BO in host
+// memory, which can't have an MSAA target. Returns the BO contents, to be
+// looked at as a BOLayout.
+fn render_reference(options: &RenderOptions) -> Result<Vec<u64>, String> {
+	assert!(options.msaa.is_none(), "the reference renderer can't do MSAA");
+	let size = std::mem::size_of::<BOLayout>();
+	let mut mem = vec![0u64; (size + 7) / 8];
+
+	let mut ring = ConstRing::new(offset_of!(BOLayout=>consts) as u32, L_CONSTRING_SIZE as u32);
+	let (heap, shaders) = load_shaders();
+	let cs = build_cs(0, options, &mut ring, &shaders, None);
+	{
+		let bo = unsafe { &mut *(mem.as_mut_ptr() as *mut BOLayout) };
+		init_bo(bo, &heap);
+		ring.upload(&mut bo.consts);
+	}
+
+	let bytes = unsafe { std::slice::from_raw_parts_mut(mem.as_mut_ptr() as *mut u8, size) };
+	let mut renderer = reference::Renderer::new(bytes, shader::isa::Chip::Evergreen);
+	let result = renderer.execute(&cs);
+	for w in &renderer.warnings {
+		println!("reference renderer: {}", w);
+	}
+	result.map(|_| mem)
+}
+
+// Scenes for the golden image tests. Goldens are in GOLDEN_DIR; the MSAA
+// square has its edges on pixel boundaries, so it resolves to the same
+// image as the plain one.
+struct GoldenScene {
+	name: &'static str,
+	golden: &'static str,
+	msaa: Option<u32>,
+	blit: bool,
+	gs: bool,
+	tolerance: u8 // per channel, filtering and blending may round differently
+}
 
-	unsafe { drm_ioctl_radeon_gem_wait_idle(fd, &mut waitidle) }; // println!("BO waited");
+const GOLDEN_DIR: &'static str = "golden";
+
+const GOLDEN_SCENES: [GoldenScene; 4] = [
+	GoldenScene { name: "square", golden: "square", msaa: None, blit: false, gs: false, tolerance: 0 },
+	GoldenScene { name: "msaa4", golden: "square", msaa: Some(4), blit: false, gs: false, tolerance: 0 },
+	GoldenScene { name: "blit", golden: "blit", msaa: None, blit: true, gs: false, tolerance: 2 },
+	GoldenScene { name: "gs", golden: "square", msaa: None, blit: false, gs: true, tolerance: 0 }
+];
+
+fn render_scene(dev: &Device, frame: &mut FrameBo, scene: &GoldenScene, timeout: Duration) -> Result<golden::Image, String> {
+	let msaa = scene.msaa.map(|n| msaa_target(dev, n));
+	match render(dev, frame, &RenderOptions { msaa: msaa.as_ref(), blit: scene.blit, gs: scene.gs, .. RenderOptions::default() }, timeout) {
+		Wait::Idle => {},
+		Wait::TimedOut => return Err(format!("the GPU didn't finish in {:?}", timeout)),
+		Wait::Reset => return Err("the GPU hung and was reset".to_owned())
+	}
+	let mapping = bomap(dev, frame.bo.handle, frame.bo.size);
+	let bo_data = unsafe {&(*(mapping.ptr as *const BOLayout))};
+	Ok(golden::Image::new(W, H, &bo_data.cb))
+}
+
+fn render_scene_reference(scene: &GoldenScene) -> Result<golden::Image, String> {
+	let mem = render_reference(&RenderOptions { blit: scene.blit, gs: scene.gs, .. RenderOptions::default() })?;
+	let bo_data = unsafe { &*(mem.as_ptr() as *const BOLayout) };
+	Ok(golden::Image::new(W, H, &bo_data.cb))
+}
+
+// Renders the scenes named, or all of them, and checks them against their
+// goldens; a device of None means the reference renderer
+fn run_golden(dev: Option<&Device>, names: &[String], out_dir: &str, tolerance: Option<u8>, bless: bool, timeout: Duration) -> bool {
+	let mut ok = true;
+	for name in names {
+		if !GOLDEN_SCENES.iter().any(|s| s.name == name) {
+			println!("{}: no such scene", name);
+			ok = false;
+		}
+	}
+	// all scenes render into the same frame BO
+	let mut device = dev.map(|dev| (dev, FrameBo::new(dev)));
+	for scene in GOLDEN_SCENES.iter().filter(|s| names.is_empty() || names.iter().any(|n| n == s.name)) {
+		let image = match device {
+			Some((dev, ref mut frame)) => render_scene(dev, frame, scene, timeout),
+			None if scene.msaa.is_some() => {
+				println!("{}: skipped, the reference renderer can't do MSAA", scene.name);
+				continue
+			},
+			None => render_scene_reference(scene)
+		};
+		let result = image.and_then(|image| golden::check(&image, scene.name, scene.golden,
+			std::path::Path::new(GOLDEN_DIR), std::path::Path::new(out_dir), tolerance.unwrap_or(scene.tolerance), bless));
+		match result {
+			Ok(good) => ok &= good,
+			Err(e) => { println!("{}: {}", scene.name, e); ok = false; }
+		}
+	}
+	ok
+}
+
+// Prints the captured vertices next to the square's; false if any is wrong
+// or missing
+fn check_streamout(bo: &BOLayout) -> bool {
+	let filled = bo.so_filled_size[0] as usize;
+	println!("stream-out buffer 0: {} bytes", filled);
+	let mut ok = true;
+	for (v, index) in THEDRAW.user_buffer.unwrap().iter().enumerate() {
+		let (x, y) = SQUARE_CORNERS[*index as usize];
+		let (x, y) = ndc(x, y);
+		let expected = [x, y, 0.0, 1.0];
+		if (v + 1) * 16 > filled {
+			println!("  vertex {}: missing", v);
+			ok = false;
+			continue
+		}
+		let got = &bo.so[v*4..v*4+4];
+		println!("  vertex {}: {:?} {}", v, got, if got == expected { "ok" } else { "MISMATCH" });
+		ok &= got == expected;
+	}
+	ok
+}
+
+// Runs the solid shaders on the CPU interpreter with the square's inputs
+fn check_shaders() -> bool {
+	use shader::interp::*;
+	let chip = shader::isa::Chip::Evergreen;
+	let mut vx = Vec::new();
+	for &(x, y) in SQUARE_CORNERS.iter() {
+		let (x, y) = ndc(x, y);
+		for v in &[x, y, 0.0, 1.0] {
+			vx.extend_from_slice(&v.to_le_bytes());
+		}
+	}
+	let vs = shader::asm::assemble(SOLID_VS_ASM, chip).unwrap();
+	let vs_streamout = shader::library::solid_vs_streamout();
+	let ps = shader::asm::assemble(SOLID_PS_ASM, chip).unwrap();
+
+	// the same shaders written with the builder
+	let mut b = ShaderBuilder::new(Stage::Vs);
+	let position = b.vertex_input(0, 0, shader::isa::FMT_32_32_FLOAT, 2);
+	b.export_position(position);
+	let built_vs = b.program().unwrap();
+	let mut b = ShaderBuilder::new(Stage::Ps);
+	let color = b.uniform(0, 0);
+	b.export_color(0, color);
+	let built_ps = b.program().unwrap();
+
+	let mut ok = true;
+	let mut check = |what: String, got: Result<Option<[f32; 4]>, String>, expected: [f32; 4]| {
+		match got {
+			Ok(Some(v)) => {
+				println!("  {}: {:?} {}", what, v, if v == expected { "ok" } else { "MISMATCH" });
+				ok &= v == expected;
+			},
+			Ok(None) => { println!("  {}: missing", what); ok = false; },
+			Err(e) => { println!("  {}: {}", what, e); ok = false; }
+		}
+	};
+	for (i, &(x, y)) in SQUARE_CORNERS.iter().enumerate() {
+		let (x, y) = ndc(x, y);
+		let run = |program| run_vs(program, chip, vec![VertexBuffer { data: &vx, stride: 4 * 4 }], vec![], i as u32);
+		check(format!("solid VS, vertex {} POS0", i), run(&vs).map(|o| o.pos(0)), [x, y, 0.0, 1.0]);
+		check(format!("built solid VS, vertex {} POS0", i), run(&built_vs).map(|o| o.pos(0)), [x, y, 0.0, 1.0]);
+		check(format!("solid VS with stream-out, vertex {} buffer 0", i),
+			run(&vs_streamout).map(|o| o.mem_writes.first().map(|w| f32x4(w.value))), [x, y, 0.0, 1.0]);
+	}
+	let color = [u32x4(SQUARE_COLOR)];
+	check("solid PS PIXEL0".to_owned(), run_ps(&ps, chip, vec![&color], &[[0.0; 4]]).map(|o| o.pixel(0)), SQUARE_COLOR);
+	check("built solid PS PIXEL0".to_owned(), run_ps(&built_ps, chip, vec![&color], &[]).map(|o| o.pixel(0)), SQUARE_COLOR);
+
+	// the blit shaders, with constants for a quad covering the target and
+	// a white YUV texel
+	let quad_vs = shader::library::quad_vs();
+	let quad = [u32x4([2.0, -2.0, 1.0, 1.0]), u32x4([-1.0, 1.0, 0.0, 0.0])];
+	for i in 0..4 {
+		let (x, y) = ((i & 1) as f32, (i >> 1) as f32);
+		check(format!("quad VS, vertex {} PARAM0", i), run_vs(&quad_vs, chip, vec![], vec![&quad], i).map(|o| o.param(0)), [x, y, 0.0, 1.0]);
+	}
+	let yuv_ps = shader::library::yuv_ps();
+	let columns: Vec<[u32; 4]> = YuvMatrix::Bt601.columns().iter().map(|&c| u32x4(c)).collect();
+	let white = |t: &shader::isa::TexInst, _: [f32; 4]| [[235.0, 128.0, 128.0][t.resource_id as usize] / 255.0, 0.0, 0.0, 1.0];
+	let mut m = Machine::new(chip);
+	m.const_buffers = vec![&columns];
+	m.params = vec![u32x4([0.5, 0.5, 0.0, 1.0])];
+	m.sampler = Some(&white);
+	check("YUV PS, white PIXEL0".to_owned(), m.run(&yuv_ps).map(|o| o.pixel(0).map(|p| p.map(|c| (c * 100.0).round() / 100.0))), [1.0; 4]);
+	ok
+}
+
+// debugfs dumps with where decoding should start, the packet rptr is in
+// and the IB the CP is in
+const RING_DUMPS: [(&'static str, &'static str, Option<u32>, Option<u32>, Option<(u64, u32)>); 2] = [
+	("radeon_ring_gfx_hang.txt", include_str!("../fixtures/debugfs/radeon_ring_gfx_hang.txt"), Some(268), Some(297), Some((0x204000, 388))),
+	("radeon_ring_gfx_idle.txt", include_str!("../fixtures/debugfs/radeon_ring_gfx_idle.txt"), Some(262128), None, None)
+];
+const FENCE_INFO_HANG: &'static str = include_str!("../fixtures/debugfs/radeon_fence_info_hang.txt");
+
+fn check_ring_dumps() -> bool {
+	let mut ok = true;
+	for &(name, text, first, stopped_at, in_ib) in RING_DUMPS.iter() {
+		match debugfs::parse_ring(text) {
+			Ok(dump) => {
+				let s = debugfs::summarize(&dump, &debugfs::decode(&dump));
+				let good = (s.first_packet, s.stopped_at, s.in_ib) == (first, stopped_at, in_ib);
+				println!("  {}: packets from {:?}, stopped at {:?}, in IB {:x?} {}", name, s.first_packet, s.stopped_at, s.in_ib,
+					if good { "ok" } else { "MISMATCH" });
+				ok &= good;
+			},
+			Err(e) => { println!("  {}: {}", name, e); ok = false; }
+		}
+	}
+	match debugfs::parse_fences(FENCE_INFO_HANG) {
+		Ok(fences) => {
+			let rings: Vec<(u32, u64, u64)> = fences.iter().map(|f| (f.ring, f.last_signaled, f.last_emitted)).collect();
+			let good = rings == [(0, 0x1234, 0x1235), (3, 0x457, 0x457)];
+			println!("  radeon_fence_info_hang.txt: {:x?} {}", rings, if good { "ok" } else { "MISMATCH" });
+			ok &= good;
+		},
+		Err(e) => { println!("  radeon_fence_info_hang.txt: {}", e); ok = false; }
+	}
+	ok
+}
+
+// Mappings always start at the beginning of the BO
+fn read_back_f32(dev: &Device, handle: u32, offset: u64, count: usize) -> Vec<f32> {
+	let mapping = bomap(dev, handle, offset + (count * 4) as u64);
+	let data = unsafe { std::slice::from_raw_parts((mapping.ptr as *const u8).offset(offset as isize) as *const f32, count) };
+	data.to_vec()
+}
+
+// False if the results are wrong or there are none to look at
+fn run_vector_add(dev: &Device, timeout: Duration) -> bool {
+	let device_id = radeon_info(dev, RADEON_INFO_DEVICE_ID) as u16;
+	let (_, family) = r600_pci_ids::pci_id_lookup(device_id).unwrap_or(("unknown", r600_pci_ids::RadeonFamily::UNKNOWN));
+	let limits = ComputeLimits::for_family(&family);
+
+	let bo = gem_create(dev, std::mem::size_of::<ComputeBOLayout>() as u64, BO_DOMAIN);
+	let kernel = ShaderObject::new(Stage::Cs, &shader::library::vector_add());
+	validate_shader("vector add", &kernel);
+	{
+		let mapping = bomap(dev, bo.handle, bo.size);
+		let layout = unsafe { &mut *(mapping.ptr as *mut ComputeBOLayout) };
+		layout.sh[..kernel.code.len()].copy_from_slice(&kernel.code);
+		for i in 0..COMPUTE_N {
+			layout.a[i] = i as f32;
+			layout.b[i] = (2 * i) as f32;
+			layout.c[i] = 0.0;
+		}
+	}
 
-	let mut busy = DrmRadeonGemBusy::default();
-	busy.handle = bo_handle;
-	loop {
-		unsafe { drm_ioctl_radeon_gem_busy(fd, &mut busy) };
-		// println!("{:?}", busy.domain);
-		::std::thread::sleep(::std::time::Duration::new(0, 1));
-		if busy.domain == 0 { break }
-		break
+	let bo_handle = bo.handle;
+	let bo_reloc = |cs: &mut CS| {
+		cs.write_label("  reloc nop");
+		cs.write(&[packet3(cs::Packet3::NOP, 0, 0), 0x00000000]);
+		cs.write_reloc(bo_handle, 0, BO_DOMAIN, 0);
+	};
+	let buffer_size = (COMPUTE_N * 4) as u32;
+	let c_offset = offset_of!(ComputeBOLayout=>c) as u32;
+
+	let mut cs = CS::default();
+	setup_compute(&mut cs, &limits);
+	evergreen_cs_setup(&mut cs, &ComputeShader {
+		shader_addr: offset_of!(ComputeBOLayout=>sh) as u32,
+		shader_size: kernel.size(),
+		num_gprs: kernel.num_gprs,
+		stack_size: kernel.stack_size,
+		lds_dwords: 0,
+		block: [shader::library::VECTOR_ADD_GROUP_SIZE, 1, 1]
+	}, &limits, &bo_reloc);
+	set_compute_buffer(&mut cs, 0, offset_of!(ComputeBOLayout=>a) as u32, buffer_size, 4, &bo_reloc);
+	set_compute_buffer(&mut cs, 1, offset_of!(ComputeBOLayout=>b) as u32, buffer_size, 4, &bo_reloc);
+	set_rat(&mut cs, 0, &rat_buffer(c_offset, buffer_size), &bo_reloc);
+	dispatch(&mut cs, 1, COMPUTE_N as u32 / shader::library::VECTOR_ADD_GROUP_SIZE, 1, 1);
+	compute_flush(&mut cs, c_offset, buffer_size, &bo_reloc);
+	cs.write_label("end");
+
+	let resets_before = reset_counter(dev);
+	if let Err(e) = cs.submit(dev) {
+		println!("CS submission failed: {}", e);
+		return false
 	}
-	//println!("BO is idle");
+	match wait_idle(dev, bo.handle, timeout, resets_before) {
+		Wait::Idle => {},
+		Wait::TimedOut => { println!("GPU still busy after {:?}", timeout); return false },
+		Wait::Reset => { println!("the GPU was reset"); return false }
+	}
+
+	let c = read_back_f32(dev, bo.handle, c_offset as u64, COMPUTE_N);
+	let mut ok = true;
+	for (i, v) in c.iter().enumerate() {
+		if *v != (3 * i) as f32 {
+			println!("c[{}] = {} (expected {})", i, v, 3 * i);
+			ok = false;
+			break
+		}
+	}
+	ok
 }
 
 enum Backend { Xcb, Wayland, Kms }
@@ -340,8 +1042,39 @@ fn main() {
 	opts.optopt("o", "write-image", "write colorbuffer to file", "FILENAME");
 	opts.optopt("b", "backend", "one of ‘xcb’, ‘wayland’ or ‘kms’", "BACKEND");
 	opts.optopt("r", "resolution", "eg. ‘640x480’ (TODO)", "RES");
+	opts.optopt("m", "msaa", "render with 2, 4 or 8 samples per pixel and resolve", "SAMPLES");
 	opts.optflag("", "info", "display results of gem info and radeon info ioctls");
 	opts.optflag("", "minimize-init-seq", "repeatedly run to find necessary packets");
+	opts.optflag("", "compute", "run a vector add kernel and check the results");
+	opts.optflag("", "streamout", "capture the square's vertices with stream-out instead of rendering");
+	opts.optflag("", "blit", "copy and composite the square and draw a YUV frame with the 2D shaders");
+	opts.optflag("", "gs", "draw through a geometry shader that passes the triangles through");
+	opts.optflag("", "mock", "use an in-process mock device that runs command streams on the reference renderer");
+	opts.optopt("", "mock-hang", "with --mock, hang the GPU on the Nth submission after opening the device", "N");
+	opts.optopt("", "timeout", "milliseconds to wait for the GPU before taking it to be hung, default 2000", "MS");
+	opts.optopt("", "record", "log every ioctl on the device to a capture file", "FILE");
+	opts.optopt("", "replay", "resubmit a capture on the device, or on the mock with --mock", "FILE");
+	opts.optopt("", "dump-capture", "list the ioctls in a capture and decode its command streams", "FILE");
+	opts.optflag("", "golden", "render the golden image scenes and compare them with their goldens, on the reference renderer with --reference");
+	opts.optmulti("", "scene", "with --golden, only this scene; one of square, msaa4, blit or gs", "NAME");
+	opts.optopt("", "tolerance", "with --golden, the per-channel tolerance instead of the scene's", "N");
+	opts.optopt("", "golden-out", "with --golden, where to write images that don't match, default target/golden", "DIR");
+	opts.optflag("", "bless", "with --golden, write the rendered images over the goldens");
+	opts.optflag("", "reference", "render on the CPU with the software reference renderer instead of the GPU");
+	opts.optopt("", "disassemble", "print the shader or shader object in FILE, eg. evergreen_shader.bin", "FILE");
+	opts.optopt("", "offset", "byte offset of the shader to disassemble", "BYTES");
+	opts.optopt("", "assemble", "assemble FILE, writing the binary to the -o file or FILE with a .bin extension", "FILE");
+	opts.optopt("", "stage", "with --assemble, write a shader object for vs, ps, gs, es, hs, ls or cs", "STAGE");
+	opts.optopt("", "validate", "check the shader object in FILE against the hardware limits and its metadata", "FILE");
+	opts.optopt("", "ring-dump", "decode a radeon_ring_gfx dump from debugfs and show where the CP stopped", "FILE");
+	opts.optopt("", "fence-info", "with --ring-dump, the radeon_fence_info dump taken with it", "FILE");
+	opts.optflag("", "check-ring-dumps", "decode the debugfs fixtures and check the results");
+	opts.optflag("", "locate-hang", "render with a marker after every labeled section and report the section the GPU stopped in");
+	opts.optflag("", "check-queries", "render the square inside occlusion and pipeline statistics queries and check their results");
+	opts.optflag("", "profile", "render with GPU timestamps around every labeled section and print how long each took");
+	opts.optopt("", "trace", "with --profile, also write the timings as Chrome trace JSON", "FILE");
+	opts.optflag("", "check-shaders", "run the solid shaders on the CPU and check their outputs");
+	opts.optflag("", "cayman", "disassemble or assemble Cayman (VLIW4) code");
 
 	let matches = match opts.parse(&args[1..]) {
 		Ok(m) => { m }
@@ -353,6 +1086,127 @@ fn main() {
 		return
 	}
 
+	let chip = if matches.opt_present("cayman") { shader::isa::Chip::Cayman } else { shader::isa::Chip::Evergreen };
+
+	if let Some(path) = matches.opt_str("assemble") {
+		let mut source = String::new();
+		fs::File::open(&path).unwrap().read_to_string(&mut source).unwrap();
+		let out = matches.opt_str("o").unwrap_or_else(||
+			std::path::Path::new(&path).with_extension("bin").to_string_lossy().into_owned());
+		let stage = matches.opt_str("stage").map(|s| Stage::from_str(&s).expect("unknown stage"));
+		match shader::asm::assemble(&source, chip) {
+			Ok(program) => fs::write(&out, match stage {
+				Some(stage) => ShaderObject::new(stage, &program).to_bytes(),
+				None => program.encode_bytes()
+			}).unwrap(),
+			Err(e) => {
+				println!("{}:{}", path, e);
+				std::process::exit(1)
+			}
+		}
+		return
+	}
+
+	if matches.opt_present("check-shaders") {
+		if !check_shaders() {
+			std::process::exit(1)
+		}
+		return
+	}
+
+	if matches.opt_present("check-ring-dumps") {
+		if !check_ring_dumps() {
+			std::process::exit(1)
+		}
+		return
+	}
+
+	if let Some(path) = matches.opt_str("ring-dump") {
+		let read = |path: &str| fs::read_to_string(path).unwrap_or_else(|e| panic!("{}: {}", path, e));
+		let dump = debugfs::parse_ring(&read(&path)).unwrap_or_else(|e| panic!("{}: {}", path, e));
+		let fences = matches.opt_str("fence-info").map_or(Vec::new(), |f|
+			debugfs::parse_fences(&read(&f)).unwrap_or_else(|e| panic!("{}: {}", f, e)));
+		debugfs::print(&dump, &fences);
+		return
+	}
+
+	if let Some(path) = matches.opt_str("validate") {
+		let object = ShaderObject::from_bytes(&fs::read(&path).unwrap()).unwrap_or_else(|e| panic!("{}: {}", path, e));
+		let problems = shader::validate::validate_object(&object, chip);
+		for p in &problems {
+			println!("{}: {}", path, p);
+		}
+		if !problems.is_empty() {
+			std::process::exit(1)
+		}
+		return
+	}
+
+	if let Some(path) = matches.opt_str("disassemble") {
+		let offset: usize = matches.opt_str("offset").map_or(0, |o| shader::asm::parse_u32(&o).expect("offset should be a number") as usize);
+		let mut bytes = Vec::new();
+		fs::File::open(path).unwrap().read_to_end(&mut bytes).unwrap();
+		if let Ok(object) = ShaderObject::from_bytes(&bytes) {
+			println!("; {:?} shader, {} GPRs, stack {}", object.stage, object.num_gprs, object.stack_size);
+			bytes = object.code;
+		}
+		assert!(offset % 8 == 0 && offset < bytes.len(), "offset should be 8 byte aligned and inside the file");
+		match shader::disasm::disassemble(&shader::disasm::words_from_bytes(&bytes[offset..]), chip) {
+			Ok(text) => print!("{}", text),
+			Err(e) => println!("disassembly failed: {}", e)
+		}
+		return
+	}
+
+	if let Some(path) = matches.opt_str("dump-capture") {
+		let records = capture::parse(&fs::read(&path).unwrap()).unwrap_or_else(|e| panic!("{}: {}", path, e));
+		device::record::dump(&records);
+		return
+	}
+
+	let timeout = Duration::from_millis(matches.opt_str("timeout").map_or(DEFAULT_TIMEOUT_MS, |t| t.parse().expect("timeout should be a number")));
+	let samples = matches.opt_str("msaa").map(|n| match n.parse() {
+		Ok(n) if MSAA_SAMPLE_COUNTS.contains(&n) => n,
+		_ => {
+			println!("--msaa takes a sample count of {:?}, not {}", MSAA_SAMPLE_COUNTS, n);
+			std::process::exit(1)
+		}
+	});
+
+	let golden_mode = matches.opt_present("golden");
+	let golden_run = |dev: Option<&Device>| {
+		let tolerance = matches.opt_str("tolerance").map(|t| t.parse().expect("tolerance should be a number"));
+		let out_dir = matches.opt_str("golden-out").unwrap_or("target/golden".to_owned());
+		if !run_golden(dev, &matches.opt_strs("scene"), &out_dir, tolerance, matches.opt_present("bless"), timeout) {
+			std::process::exit(1)
+		}
+	};
+
+	if golden_mode && matches.opt_present("reference") {
+		golden_run(None);
+		return
+	}
+
+	if matches.opt_present("reference") {
+		let streamout = matches.opt_present("streamout");
+		let blit = matches.opt_present("blit");
+		let gs = matches.opt_present("gs");
+		assert!(!matches.opt_present("msaa"), "--reference can't be combined with --msaa");
+		assert!(!(blit && streamout), "--blit can't be combined with --streamout");
+		assert!(!(gs && (streamout || blit)), "--gs can't be combined with --streamout or --blit");
+		let mem = render_reference(&RenderOptions { streamout: streamout, blit: blit, gs: gs, .. RenderOptions::default() }).unwrap_or_else(|e| panic!("reference renderer: {}", e));
+		let bo_data = unsafe { &*(mem.as_ptr() as *const BOLayout) };
+
+		println!("BO dump: {:016x}", bo_data.timestamps[0]);
+		if streamout && !check_streamout(bo_data) {
+			std::process::exit(1)
+		}
+		if let Some(path) = matches.opt_str("o") {
+			image::save_buffer(&std::path::Path::new(path.as_str()), &bo_data.cb, W, H, image::RGBA(8)).unwrap();
+		}
+		return
+	}
+
 	let backend = if let Some(backend_str) = matches.opt_str("backend") {
 		backend_from_str(backend_str.as_str()).expect("unrecognized backend")
 	}
@@ -367,25 +1221,55 @@ fn main() {
 		Backend::Kms => "/dev/dri/card0".to_owned(),
 	}};
 
-	println!("Using device {}", dev_path);
-	let mut fo_ = fs::OpenOptions::new();
-	let fo = fo_.read(true).write(true);
+	let mock = matches.opt_present("mock");
+	let mock_hang = matches.opt_str("mock-hang").map(|n| n.parse().expect("submission should be a number"));
+	if mock {
+		println!("Using the mock device");
+	} else {
+		println!("Using device {}", dev_path);
+	}
+	let capture = matches.opt_str("record").map(|path|
+		Rc::new(RefCell::new(capture::CaptureWriter::create(&path).unwrap_or_else(|e| panic!("{}: {}", path, e)))));
+	let open_device = || -> Box<Device> {
+		let dev: Box<Device> = if mock {
+			let mut mock = MockDevice::new(true);
+			mock.hang_at = mock_hang;
+			Box::new(mock)
+		} else {
+			Box::new(DrmDevice::open(&dev_path).unwrap_or_else(|e| panic!("{}: {}", dev_path, e)))
+		};
+		match capture {
+			Some(ref out) => Box::new(RecordingDevice::new(dev, out.clone())),
+			None => dev
+		}
+	};
+
+	if let Some(path) = matches.opt_str("replay") {
+		let records = capture::parse(&fs::read(&path).unwrap()).unwrap_or_else(|e| panic!("{}: {}", path, e));
+		match device::record::replay(&records, &open_device) {
+			Ok(0) => {},
+			Ok(_) => std::process::exit(1),
+			Err(e) => { println!("{}: {}", path, e); std::process::exit(1) }
+		}
+		return
+	}
+
+	if golden_mode {
+		let dev = open_device();
+		golden_run(Some(&*dev));
+		return
+	}
 
 	if matches.opt_present("info") {
 
-		let f = fo.open(dev_path).unwrap();
-		let fd = f.as_raw_fd();
+		let dev = open_device();
 
-		let mut info = DrmRadeonGemInfo::default();
-		unsafe { drm_ioctl_radeon_gem_info(fd, &mut info) };
+		let info = dev.gem_info().expect("gem_info failed");
 		println!("GART size = {}", info.gart_size);
 		println!("VRAM size = {}", info.vram_size);
 		println!("VRAM visible = {}", info.vram_visible);
 
-		let mut device_id_64: u64 = 0;
-		let mut info = DrmRadeonInfo { request: 0, pad: 0, value: &mut device_id_64 as *mut u64 as u64 };
-		unsafe { drm_ioctl_radeon_info(fd, &mut info) };
-		let device_id = device_id_64 as u16;
+		let device_id = radeon_info(&*dev, RADEON_INFO_DEVICE_ID) as u16;
 		let (name, family) = r600_pci_ids::pci_id_lookup(device_id).unwrap_or(("unknown", r600_pci_ids::RadeonFamily::UNKNOWN));
 		println!("This is a {:?} {:?} chip with PCI device id = {}",
 			family,
@@ -393,74 +1277,111 @@ fn main() {
 			device_id);
 		return
 
+	} else if matches.opt_present("compute") {
+
+		let dev = open_device();
+		if !run_vector_add(&*dev, timeout) {
+			println!("vector add: failed");
+			std::process::exit(1)
+		}
+		println!("vector add: ok");
+		return
+
+	} else if matches.opt_present("locate-hang") {
+
+		let dev = open_device();
+		let msaa = samples.map(|n| msaa_target(&*dev, n));
+		let options = RenderOptions { msaa: msaa.as_ref(), streamout: matches.opt_present("streamout"), blit: matches.opt_present("blit"), gs: matches.opt_present("gs"), .. RenderOptions::default() };
+		if !locate_hang(&*dev, &mut FrameBo::new(&*dev), &options, timeout) {
+			std::process::exit(1)
+		}
+		return
+
+	} else if matches.opt_present("check-queries") {
+
+		let dev = open_device();
+		if !check_queries(&*dev, &mut FrameBo::new(&*dev), matches.opt_present("streamout"), timeout) {
+			std::process::exit(1)
+		}
+		return
+
+	} else if matches.opt_present("profile") {
+
+		let dev = open_device();
+		let msaa = samples.map(|n| msaa_target(&*dev, n));
+		let options = RenderOptions { msaa: msaa.as_ref(), streamout: matches.opt_present("streamout"), blit: matches.opt_present("blit"), gs: matches.opt_present("gs"), .. RenderOptions::default() };
+		let trace = matches.opt_str("trace");
+		if !profile(&*dev, &mut FrameBo::new(&*dev), &options, timeout, trace.as_ref().map(|t| t.as_str())) {
+			std::process::exit(1)
+		}
+		return
+
 	} else if matches.opt_present("minimize-init-seq") {
+		let square = &GOLDEN_SCENES[0];
+		let golden_dir = std::path::Path::new(GOLDEN_DIR);
+		let expected = golden::Image::load(&golden::golden_path(golden_dir, square.golden)).unwrap();
+		let expected_mask = golden::load_mask(golden_dir, square.golden).unwrap();
 		let packets = pm4::split(&mut INITSEQ.iter().map(|a|*a));
 		let mut mask = packets.iter().map(|_| true).collect::<Vec<_>>();
-		for i in 0..packets.len() {
-
-			if packets[i].header & 0xff00 == 0x1000 { continue } // don't toggle nops individually
-			let i2 = if i+1<packets.len() && packets[i+1].header & 0xff00 == 0x1000 {
-				i+2
-			} else {
-				i+1
-			};
-
-			for m in &mut mask[i..i2] { *m = false; }
-			//mask[i] = false;
-
-			let mut compact_stream: Vec<u32> = Vec::new();
-			for j in 0..packets.len() {
-				if mask[j] {
-					print!("x");
-					compact_stream.push(packets[j].header);
-					compact_stream.extend(packets[j].words.iter().cloned());
+		// opened again after every GPU reset, with a new frame BO
+		let mut i = 0;
+		while i < packets.len() {
+			let dev = open_device();
+			let mut frame = FrameBo::new(&*dev);
+			while i < packets.len() {
+
+				if packets[i].header & 0xff00 == 0x1000 { i += 1; continue } // don't toggle nops individually
+				let i2 = if i+1<packets.len() && packets[i+1].header & 0xff00 == 0x1000 {
+					i+2
 				} else {
-					print!(" ");
+					i+1
+				};
+
+				for m in &mut mask[i..i2] { *m = false; }
+				//mask[i] = false;
+
+				let mut compact_stream: Vec<u32> = Vec::new();
+				for j in 0..packets.len() {
+					if mask[j] {
+						print!("x");
+						compact_stream.push(packets[j].header);
+						compact_stream.extend(packets[j].words.iter().cloned());
+					} else {
+						print!(" ");
+					}
+				}
+				println!("");
+
+				// VRAM comes back with what the last iteration left in it, and
+				// without the CB setup the clear doesn't happen either
+				{
+					let mapping = bomap(&*dev, frame.bo.handle, frame.bo.size);
+					let bo_data = unsafe {&mut (*(mapping.ptr as *mut BOLayout))};
+					for pixel in bo_data.cb.iter_mut() { *pixel = 0xcd; }
 				}
-			}
-			println!("");
-
-			let f = fo.open(dev_path.clone()).unwrap();
-			let fd = f.as_raw_fd();
-			let bo = gem_create(fd, std::mem::size_of::<BOLayout>() as u64, BO_DOMAIN);
-
-			{
-				let mapping = bomap(fd, bo.handle, 0, bo.size);
-				let bo_data = unsafe {&mut (*(mapping.ptr as *mut BOLayout))};
-				for pixel in bo_data.cb.iter_mut() { *pixel = 0; }
-			}
 
-			let now = std::time::SystemTime::now();
-			render(fd, bo.handle, bo.size, &compact_stream);
+				let wait = render(&*dev, &mut frame, &RenderOptions { initseq: &compact_stream, .. RenderOptions::default() }, timeout);
 
-			{
-				let mapping = bomap(fd, bo.handle, 0, bo.size);
-				let bo_data = unsafe {&(*(mapping.ptr as *const BOLayout))};
-				if true {
-					let out = format!("minimize{}.png", i);
-					image::save_buffer(&std::path::Path::new(&out), &bo_data.cb, W, H, image::RGBA(8)).unwrap();
-				}
-				let mut fail = false;
-				if now.elapsed().expect("").as_secs() > 1 {
-					//println!("fail by timeout");
-					fail = true;
-				} else {
-					for y in 10..90 {
-						for x in 10..90 {
-							let offset = ((x + y*W)*4) as usize;
-							if bo_data.cb[offset..offset+3] != [0, 255, 0] {
-								fail = true;
-								//println!("fail by pixel difference");
-								break
-							}
-						}
-						if fail { break; }
+				let mut fail = true;
+				if wait == Wait::Idle {
+					let mapping = bomap(&*dev, frame.bo.handle, frame.bo.size);
+					let bo_data = unsafe {&(*(mapping.ptr as *const BOLayout))};
+					if true {
+						let out = format!("minimize{}.png", i);
+						image::save_buffer(&std::path::Path::new(&out), &bo_data.cb, W, H, image::RGBA(8)).unwrap();
 					}
+					let actual = golden::Image::new(W, H, &bo_data.cb);
+					fail = golden::compare(&actual, &expected, expected_mask.as_ref(), square.tolerance).unwrap().is_some();
 				}
 				if fail {
 					for m in &mut mask[i..i2] { *m = true; }
 					//mask[i] = true;
 				}
+				i += 1;
+				if wait == Wait::Reset {
+					println!("reopening the device");
+					break
+				}
 			}
 		}
 
@@ -470,26 +1391,45 @@ fn main() {
 
 	} else {
 
-		let f = fo.open(dev_path).unwrap();
-		let fd = f.as_raw_fd();
-		let bo = gem_create(fd, std::mem::size_of::<BOLayout>() as u64, BO_DOMAIN);
-		render(fd, bo.handle, bo.size, &[]);
+		let dev = open_device();
+		let mut frame = FrameBo::new(&*dev);
+		let msaa = samples.map(|n| msaa_target(&*dev, n));
+		let streamout = matches.opt_present("streamout");
+		let blit = matches.opt_present("blit");
+		let gs = matches.opt_present("gs");
+		assert!(!(streamout && msaa.is_some()), "--streamout can't be combined with --msaa");
+		assert!(!(blit && (streamout || msaa.is_some())), "--blit can't be combined with --streamout or --msaa");
+		assert!(!(gs && (streamout || blit || msaa.is_some())), "--gs can't be combined with --streamout, --blit or --msaa");
+		let options = RenderOptions { msaa: msaa.as_ref(), streamout: streamout, blit: blit, gs: gs, .. RenderOptions::default() };
+		if render(&*dev, &mut frame, &options, timeout) != Wait::Idle {
+			std::process::exit(1)
+		}
 
 		{
-			let mapping = bomap(fd, bo.handle, 0, bo.size);
+			let mapping = bomap(&*dev, frame.bo.handle, frame.bo.size);
 			let bo_data = unsafe {&(*(mapping.ptr as *const BOLayout))};
 
 			println!("BO dump: {:016x}", bo_data.timestamps[0]);
 
+			if streamout {
+				if !check_streamout(bo_data) {
+					std::process::exit(1)
+				}
+				return
+			}
+
 			if let Some(path) = matches.opt_str("o") {
 				image::save_buffer(&std::path::Path::new(path.as_str()), &bo_data.cb, W, H, image::RGBA(8)).unwrap();
 			}
 
-			match backend {
-				Backend::Wayland => waylandmain(fd, bo.handle),
-				Backend::Xcb => xcbmain(fd, bo.handle),
-				Backend::Kms => kmsmain(fd, bo.handle)
-			};
+			// the mock has nothing to scan out from
+			if let Some(fd) = dev.fd() {
+				match backend {
+					Backend::Wayland => waylandmain(fd, frame.bo.handle),
+					Backend::Xcb => xcbmain(fd, frame.bo.handle),
+					Backend::Kms => kmsmain(fd, frame.bo.handle)
+				};
+			}
 		}
 	}
 }
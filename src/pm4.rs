@@ -1,7 +1,7 @@
 use std::option::Option;
 use std::iter::Iterator;
 use num;
-use cs::Packet3;
+use cs::{CS, Packet3};
 
 pub struct Packet {
 	pub header: u32,
@@ -13,7 +13,9 @@ pub fn split(stream: &mut Iterator<Item=u32>) -> Vec<Packet> {
 	while let Some(packet_header) = stream.next() {
 		let packet_type = packet_header >> 30;
 		let packet_length = ((packet_header >> 16) & 0x3fff) + 1u32;
-		if packet_type != 3u32 { panic!("can't handle"); }
+		// type 2 is a one dword filler drivers pad IBs with
+		if packet_type == 2u32 { continue }
+		if packet_type == 1u32 { panic!("can't handle"); }
 		let packet: Vec<u32> = stream.take(packet_length as usize).collect();
 		packets.push(Packet{header: packet_header, words: packet});
 	}
@@ -22,11 +24,42 @@ pub fn split(stream: &mut Iterator<Item=u32>) -> Vec<Packet> {
 
 pub fn split_and_print(stream: &[u32]) {
 	for packet in split(&mut stream.iter().map(|a|*a)) {
-		let packet_type: Option<Packet3> = num::FromPrimitive::from_u32((packet.header >> 8) & 0xff);
-		println!("{:08x} {:?}", packet.header, packet_type);
+		if packet.header >> 30 == 0 {
+			println!("{:08x} type 0, registers from {:#x}", packet.header, (packet.header & 0xffff) << 2);
+		} else {
+			let packet_type: Option<Packet3> = num::FromPrimitive::from_u32((packet.header >> 8) & 0xff);
+			println!("{:08x} {:?}", packet.header, packet_type);
+		}
 		for word in packet.words {
 			println!("{:08x}", word);
 		}
 		println!("");
 	}
 }
+
+// Prints the packets of cs.ib()[from..to] with their dword index into the
+// IB and the labels written in front of them; from should be at a packet
+pub fn print_labeled(cs: &CS, from: usize, to: usize) {
+	let ib = cs.ib();
+	let mut at = from;
+	while at < to {
+		if let Some(label) = cs.label_at(at) {
+			println!("{}:", label);
+		}
+		let header = ib[at];
+		let len = match header >> 30 {
+			0 => { println!("[{:5}] {:08x} type 0, registers from {:#x}", at, header, (header & 0xffff) << 2); ((header >> 16) & 0x3fff) as usize + 2 },
+			2 => { println!("[{:5}] {:08x} type 2 filler", at, header); 1 },
+			3 => {
+				let packet_type: Option<Packet3> = num::FromPrimitive::from_u32((header >> 8) & 0xff);
+				println!("[{:5}] {:08x} {:?}", at, header, packet_type);
+				((header >> 16) & 0x3fff) as usize + 2
+			},
+			_ => { println!("[{:5}] {:08x} type 1, can't decode further", at, header); return }
+		};
+		for k in at+1..(at+len).min(ib.len()) {
+			println!("[{:5}] {:08x}", k, ib[k]);
+		}
+		at += len;
+	}
+}
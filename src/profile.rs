@@ -0,0 +1,100 @@
+// Timings of the labeled sections of a CS from the GPU clock counter
+//
+// With MarkerKind::Timestamp markers an EOP writes the clock once the work
+// before it has left the pipeline. The CP runs ahead of the pipeline, so a
+// section's time is how much later its work drained than the work before
+// it, not how long the CP took for its packets. The clock runs at the
+// crystal frequency, RADEON_INFO_CLOCK_CRYSTAL_FREQ in kHz.
+//
+// Besides a table the timings can be written as Chrome trace JSON, to be
+// opened in chrome://tracing or Perfetto.
+
+use std::fmt::Write;
+use cs::MarkerSection;
+
+pub struct SectionTime {
+	pub label: String,
+	pub start_ns: u64, // from the first timestamp
+	pub duration_ns: Option<u64> // None when a timestamp wasn't written
+}
+
+// What the timestamp slots are filled with before the GPU writes them
+pub const NOT_WRITTEN: u64 = !0;
+
+pub fn ticks_to_ns(ticks: u64, freq_khz: u64) -> u64 {
+	(ticks as u128 * 1_000_000 / freq_khz.max(1) as u128) as u64
+}
+
+// timestamps[0] is from before the first section, timestamps[n + 1] from
+// after section n; slots the GPU didn't get to are still NOT_WRITTEN
+pub fn section_times(sections: &[MarkerSection], timestamps: &[u64], freq_khz: u64) -> Vec<SectionTime> {
+	let t0 = timestamps.get(0).cloned().unwrap_or(NOT_WRITTEN);
+	sections.iter().enumerate().map(|(n, s)| {
+		let before = timestamps.get(n).cloned().unwrap_or(NOT_WRITTEN);
+		let after = timestamps.get(n + 1).cloned().unwrap_or(NOT_WRITTEN);
+		let written = t0 != NOT_WRITTEN && before != NOT_WRITTEN && after != NOT_WRITTEN && t0 <= before && before <= after;
+		SectionTime {
+			label: s.label.clone(),
+			start_ns: if written { ticks_to_ns(before - t0, freq_khz) } else { 0 },
+			duration_ns: if written { Some(ticks_to_ns(after - before, freq_khz)) } else { None }
+		}
+	}).collect()
+}
+
+fn us(ns: u64) -> f64 {
+	ns as f64 / 1000.0
+}
+
+// Every section in order, then the time per label, the most expensive first
+pub fn print_table(times: &[SectionTime]) {
+	let total: u64 = times.iter().filter_map(|t| t.duration_ns).sum();
+	let percent = |ns: u64| if total == 0 { 0.0 } else { ns as f64 * 100.0 / total as f64 };
+	println!("{:>4} {:>12} {:>12} {:>6}  {}", "#", "start µs", "µs", "%", "section");
+	for (n, t) in times.iter().enumerate() {
+		match t.duration_ns {
+			Some(ns) => println!("{:4} {:12.3} {:12.3} {:6.1}  {}", n, us(t.start_ns), us(ns), percent(ns), t.label),
+			None => println!("{:4} {:>12} {:>12} {:>6}  {}", n, "-", "-", "", t.label)
+		}
+	}
+	println!("total {:.3} µs", us(total));
+
+	let mut by_label: Vec<(&str, usize, u64)> = Vec::new();
+	for t in times {
+		let ns = match t.duration_ns { Some(ns) => ns, None => continue };
+		match by_label.iter().position(|&(l, _, _)| l == t.label) {
+			Some(k) => { by_label[k].1 += 1; by_label[k].2 += ns; },
+			None => by_label.push((&t.label, 1, ns))
+		}
+	}
+	by_label.sort_by(|a, b| b.2.cmp(&a.2));
+	println!("");
+	println!("{:>5} {:>12} {:>6}  {}", "count", "µs", "%", "label");
+	for (label, count, ns) in by_label {
+		println!("{:5} {:12.3} {:6.1}  {}", count, us(ns), percent(ns), label);
+	}
+}
+
+fn json_string(s: &str) -> String {
+	let mut out = String::from("\"");
+	for c in s.chars() {
+		match c {
+			'"' => out.push_str("\\\""),
+			'\\' => out.push_str("\\\\"),
+			c if (c as u32) < 0x20 => write!(out, "\\u{:04x}", c as u32).unwrap(),
+			c => out.push(c)
+		}
+	}
+	out.push('"');
+	out
+}
+
+// Complete events on one track, timestamps in µs as the format wants them
+pub fn chrome_trace(times: &[SectionTime]) -> String {
+	let mut out = String::from("{\"traceEvents\":[\n");
+	let events: Vec<String> = times.iter().filter(|t| t.duration_ns.is_some()).map(|t| format!(
+		"{{\"name\":{},\"cat\":\"gpu\",\"ph\":\"X\",\"pid\":1,\"tid\":1,\"ts\":{:.3},\"dur\":{:.3}}}",
+		json_string(&t.label), us(t.start_ns), us(t.duration_ns.unwrap()))).collect();
+	out.push_str(&events.join(",\n"));
+	out.push_str("\n],\"displayTimeUnit\":\"ns\"}\n");
+	out
+}
@@ -0,0 +1,220 @@
+// Queries, see r600_query.c in mesa
+//
+// A query brackets a range of the CS with two samples of GPU counters,
+// written by EVENT_WRITE to a query BO, and its result is the difference.
+// The samples land asynchronously, so results are only read once a fence
+// emitted after the query has signaled.
+//
+// - Occlusion: ZPASS_DONE makes every DB write its count of samples that
+//   passed the depth and stencil tests, 16 bytes per DB with the end
+//   sample 8 bytes after the start. DBs that aren't there write nothing,
+//   bit 63 tells written samples apart. Counting has to be turned on in
+//   DB_COUNT_CONTROL.
+// - Pipeline statistics: SAMPLE_PIPELINESTAT writes eleven 64-bit
+//   counters, which only count between PIPELINESTAT_START and _STOP.
+// - Stream-out statistics: SAMPLE_STREAMOUTSTATS writes the primitives
+//   stream-out needed storage for and the ones it wrote, with bit 63 set.
+
+use std::ptr;
+use cs::*;
+use device::Mapping;
+use fence::{Fence, FenceSlots};
+
+pub const EVENT_TYPE_ZPASS_DONE: u32 = 0x15;
+pub const EVENT_TYPE_PIPELINESTAT_START: u32 = 0x19;
+pub const EVENT_TYPE_PIPELINESTAT_STOP: u32 = 0x1a;
+pub const EVENT_TYPE_SAMPLE_PIPELINESTAT: u32 = 0x1e;
+pub const EVENT_TYPE_SAMPLE_STREAMOUTSTATS: u32 = 0x20;
+
+// DB_COUNT_CONTROL
+const ZPASS_INCREMENT_DISABLE: u32 = 1 << 0;
+const PERFECT_ZPASS_COUNTS: u32 = 1 << 1;
+
+pub const MAX_DBS: u32 = 8;
+pub const NUM_PIPELINE_STATS: usize = 11;
+const VALID: u64 = 1 << 63;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum QueryType {
+	Occlusion,
+	PipelineStats,
+	Streamout
+}
+
+impl QueryType {
+	// Bytes for both samples
+	fn size(&self) -> u32 {
+		match *self {
+			QueryType::Occlusion => MAX_DBS * 16,
+			QueryType::PipelineStats => NUM_PIPELINE_STATS as u32 * 8 * 2,
+			QueryType::Streamout => 32
+		}
+	}
+
+	// Where the end sample goes, from the start of the query
+	fn end_offset(&self) -> u32 {
+		match *self {
+			QueryType::Occlusion => 8,
+			QueryType::PipelineStats => NUM_PIPELINE_STATS as u32 * 8,
+			QueryType::Streamout => 16
+		}
+	}
+}
+
+// The counters in the order SAMPLE_PIPELINESTAT writes them
+#[derive(Clone, Copy, PartialEq, Debug, Default)]
+pub struct PipelineStats {
+	pub ps_invocations: u64,
+	pub c_primitives: u64,
+	pub c_invocations: u64,
+	pub vs_invocations: u64,
+	pub gs_invocations: u64,
+	pub gs_primitives: u64,
+	pub ia_primitives: u64,
+	pub ia_vertices: u64,
+	pub hs_invocations: u64,
+	pub ds_invocations: u64,
+	pub cs_invocations: u64
+}
+
+impl PipelineStats {
+	pub fn from_words(w: &[u64]) -> PipelineStats {
+		PipelineStats {
+			ps_invocations: w[0], c_primitives: w[1], c_invocations: w[2], vs_invocations: w[3],
+			gs_invocations: w[4], gs_primitives: w[5], ia_primitives: w[6], ia_vertices: w[7],
+			hs_invocations: w[8], ds_invocations: w[9], cs_invocations: w[10]
+		}
+	}
+
+	pub fn to_words(&self) -> [u64; NUM_PIPELINE_STATS] {
+		[self.ps_invocations, self.c_primitives, self.c_invocations, self.vs_invocations,
+		 self.gs_invocations, self.gs_primitives, self.ia_primitives, self.ia_vertices,
+		 self.hs_invocations, self.ds_invocations, self.cs_invocations]
+	}
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum QueryResult {
+	// samples that passed
+	Occlusion(u64),
+	PipelineStats(PipelineStats),
+	Streamout { written: u64, needed: u64 }
+}
+
+#[derive(Clone, Copy)]
+pub struct Query {
+	pub kind: QueryType,
+	pub name: &'static str,
+	offset: u32 // in the BO
+}
+
+// Suballocates queries from a region of a BO, which has to be zeroed before
+// the submission. Queries are kept in the order they were begun.
+pub struct QueryPool {
+	handle: u32,
+	domain: u32,
+	offset: u32,
+	size: u32,
+	used: u32,
+	queries: Vec<Query>
+}
+
+fn sample(cs: &mut CS, event: u32, index: u32, addr: u32, handle: u32, domain: u32) {
+	cs.write(&[
+		packet3(Packet3::EVENT_WRITE, 2, 0),
+		event | event_index(index),
+		addr & !7,
+		0
+	]);
+	cs.write_reloc_nop(handle, 0, domain);
+}
+
+fn read_u64(mapping: &Mapping, at: u32) -> u64 {
+	assert!(at as usize + 8 <= mapping.size(), "query outside the mapping");
+	unsafe { ptr::read_volatile((mapping.ptr as *const u8).offset(at as isize) as *const u64) }
+}
+
+impl QueryPool {
+	pub fn new(handle: u32, domain: u32, offset: u32, size: u32) -> QueryPool {
+		assert!(offset & 7 == 0, "query pool not 8 byte aligned");
+		QueryPool { handle: handle, domain: domain, offset: offset, size: size, used: 0, queries: Vec::new() }
+	}
+
+	pub fn queries(&self) -> &[Query] {
+		&self.queries
+	}
+
+	pub fn begin(&mut self, cs: &mut CS, kind: QueryType, name: &'static str) -> Query {
+		assert!(self.used + kind.size() <= self.size, "query pool full");
+		let query = Query { kind: kind, name: name, offset: self.offset + self.used };
+		self.used += kind.size();
+		self.queries.push(query);
+
+		cs.write_label(&format!("  begin {} query", name));
+		match kind {
+			QueryType::Occlusion => {
+				cs.set_reg(0x28004, PERFECT_ZPASS_COUNTS); // DB_COUNT_CONTROL
+				sample(cs, EVENT_TYPE_ZPASS_DONE, 1, query.offset, self.handle, self.domain);
+			},
+			QueryType::PipelineStats => {
+				event_write(cs, EVENT_TYPE_PIPELINESTAT_START);
+				sample(cs, EVENT_TYPE_SAMPLE_PIPELINESTAT, 2, query.offset, self.handle, self.domain);
+			},
+			QueryType::Streamout =>
+				sample(cs, EVENT_TYPE_SAMPLE_STREAMOUTSTATS, 3, query.offset, self.handle, self.domain)
+		}
+		query
+	}
+
+	pub fn end(&self, cs: &mut CS, query: &Query) {
+		let addr = query.offset + query.kind.end_offset();
+		cs.write_label(&format!("  end {} query", query.name));
+		match query.kind {
+			QueryType::Occlusion => {
+				sample(cs, EVENT_TYPE_ZPASS_DONE, 1, addr, self.handle, self.domain);
+				cs.set_reg(0x28004, ZPASS_INCREMENT_DISABLE); // DB_COUNT_CONTROL
+			},
+			QueryType::PipelineStats => {
+				sample(cs, EVENT_TYPE_SAMPLE_PIPELINESTAT, 2, addr, self.handle, self.domain);
+				event_write(cs, EVENT_TYPE_PIPELINESTAT_STOP);
+			},
+			QueryType::Streamout =>
+				sample(cs, EVENT_TYPE_SAMPLE_STREAMOUTSTATS, 3, addr, self.handle, self.domain)
+		}
+	}
+
+	// The result once the fence has signaled, which has to come after the
+	// end of the query; None before. The mapping starts at the beginning of
+	// the BO.
+	pub fn result(&self, mapping: &Mapping, query: &Query, fences: &FenceSlots, fence: Fence) -> Option<QueryResult> {
+		if !fences.signaled(mapping, fence) {
+			return None
+		}
+		let at = |n: u32| read_u64(mapping, query.offset + n * 8);
+		let valid = |start: u64, end: u64| start & VALID != 0 && end & VALID != 0;
+		let counter = |start: u64, end: u64| (end & !VALID).wrapping_sub(start & !VALID);
+		Some(match query.kind {
+			QueryType::Occlusion => {
+				let mut passed = 0;
+				for db in 0..MAX_DBS {
+					let (start, end) = (at(db * 2), at(db * 2 + 1));
+					if valid(start, end) { passed += counter(start, end) }
+				}
+				QueryResult::Occlusion(passed)
+			},
+			QueryType::PipelineStats => {
+				let n = NUM_PIPELINE_STATS as u32;
+				let words: Vec<u64> = (0..n).map(|i| at(n + i).wrapping_sub(at(i))).collect();
+				QueryResult::PipelineStats(PipelineStats::from_words(&words))
+			},
+			QueryType::Streamout => {
+				let (needed, written) = if valid(at(0), at(2)) {
+					(counter(at(0), at(2)), counter(at(1), at(3)))
+				} else {
+					(0, 0)
+				};
+				QueryResult::Streamout { written: written, needed: needed }
+			}
+		})
+	}
+}
@@ -0,0 +1,950 @@
+// Software reference renderer: executes a command stream on the CPU against
+// a copy of the BO contents, so what the GPU produced can be compared with
+// what the stream asks for. Register writes are tracked like the CP does,
+// shaders are decoded from the BO and run with shader::interp, and
+// triangles are rasterized into color buffer 0.
+//
+// Everything lives in one BO: relocations are ignored and addresses in
+// registers and packets are offsets into mem. Covered: SET_* packets and
+// type 0 register writes, EVENT_WRITE_EOP, MEM_WRITE, CP_DMA, the
+// ZPASS_DONE and SAMPLE_PIPELINESTAT samples of queries, and
+// DRAW_INDEX_IMMD and DRAW_INDEX_AUTO of triangle lists and strips with a
+// VS and a PS, or an ES, a GS and a copy shader in front of the PS (see
+// geometry_stages), DISPATCH_DIRECT of a compute shader that stores to
+// RATs (see compute), the viewport transform, face culling, the screen,
+// window and generic scissors and cliprects, perspective correct
+// interpolation, linear 2D textures, and blending into a linear 8_8_8_8
+// UNORM or SRGB target.
+// There's no clipping, depth, stencil, multisampling or stream-out, so
+// every fragment passes and the occlusion count is the fragments written,
+// all from DB 0. What isn't handled ends up in warnings rather than failing
+// the whole stream.
+
+use std::collections::HashMap;
+use num;
+use cs::*;
+use query::*;
+use shader::disasm::words_from_bytes;
+use shader::interp::{Machine, Outputs, VertexBuffer, NUM_GPRS};
+use shader::isa::*;
+
+// How much of the BO a shader is decoded from
+const MAX_SHADER_SIZE: usize = 0x10000;
+
+#[derive(Default)]
+struct Regs(HashMap<u32, u32>);
+
+impl Regs {
+	fn get(&self, reg: u32) -> u32 { self.get_or(reg, 0) }
+	fn get_or(&self, reg: u32, default: u32) -> u32 { *self.0.get(&reg).unwrap_or(&default) }
+	fn f32(&self, reg: u32) -> f32 { f32::from_bits(self.get(reg)) }
+}
+
+pub struct Renderer<'a> {
+	pub mem: &'a mut [u8],
+	pub chip: Chip,
+	pub warnings: Vec<String>,
+	regs: Regs,
+	index_type: u32,
+	num_instances: u32,
+	clock: u64, // advances by one per dword, for EOP timestamps
+	zpass: u64, // fragments that passed, for ZPASS_DONE
+	stats: PipelineStats, // counted between PIPELINESTAT_START and _STOP
+	counting_stats: bool,
+	label: String
+}
+
+// A vertex after the viewport transform
+struct Vertex {
+	x: f64,
+	y: f64,
+	inv_w: f32,
+	params: Vec<[f32; 4]>
+}
+
+struct Fragment {
+	x: u32,
+	y: u32,
+	color: [f32; 4]
+}
+
+// Where a PS input comes from, after SPI_PS_INPUT_CNTL_n
+struct PsInput {
+	param: Option<usize>,
+	default: [f32; 4],
+	flat: bool
+}
+
+struct Texture<'m> {
+	data: &'m [u8],
+	width: u32,
+	height: u32,
+	pitch: u32,
+	format: u32,
+	degamma: bool,
+	dst_sel: [u32; 4],
+	bilinear: bool,
+	clamp: [u32; 2]
+}
+
+impl<'a> Renderer<'a> {
+	pub fn new(mem: &'a mut [u8], chip: Chip) -> Renderer<'a> {
+		Renderer {
+			mem: mem,
+			chip: chip,
+			warnings: Vec::new(),
+			regs: Regs::default(),
+			index_type: 0,
+			num_instances: 1,
+			clock: 0,
+			zpass: 0,
+			stats: PipelineStats::default(),
+			counting_stats: false,
+			label: String::new()
+		}
+	}
+
+	pub fn execute(&mut self, cs: &CS) -> Result<(), String> {
+		self.execute_ib(cs.ib(), &|i| cs.label_at(i))
+	}
+
+	// label_at names the section starting at a dword, for messages
+	pub fn execute_ib<'c>(&mut self, ib: &[u32], label_at: &Fn(usize) -> Option<&'c str>) -> Result<(), String> {
+		let mut i = 0;
+		while i < ib.len() {
+			if let Some(label) = label_at(i) {
+				if !label.starts_with(' ') { self.label = label.to_owned() }
+			}
+			let header = ib[i];
+			let count = (header >> 16 & 0x3fff) as usize + 1;
+			let body = match header >> 30 {
+				2 => { i += 1; continue } // filler
+				0 | 3 => ib.get(i+1..i+1+count).ok_or_else(|| format!("dword {}: truncated packet {:08x}", i, header))?,
+				_ => return Err(format!("dword {}: type 1 packet {:08x}", i, header))
+			};
+			if header >> 30 == 0 {
+				self.set_regs((header & 0xffff) << 2, body);
+			} else {
+				self.packet3(header, body).map_err(|e| format!("dword {} ({}): {}", i, self.label, e))?;
+			}
+			i += 1 + count;
+			self.clock += 1 + count as u64;
+		}
+		Ok(())
+	}
+
+	fn warn(&mut self, message: String) {
+		let message = if self.label.is_empty() { message } else { format!("{}: {}", self.label, message) };
+		if !self.warnings.contains(&message) {
+			self.warnings.push(message);
+		}
+	}
+
+	fn set_regs(&mut self, reg: u32, values: &[u32]) {
+		for (i, &v) in values.iter().enumerate() {
+			self.regs.0.insert(reg + 4 * i as u32, v);
+		}
+	}
+
+	fn packet3(&mut self, header: u32, body: &[u32]) -> Result<(), String> {
+		let opcode = header >> 8 & 0xff;
+		let op: Packet3 = match num::FromPrimitive::from_u32(opcode) {
+			Some(op) => op,
+			None => { self.warn(format!("unknown packet {:#x}", opcode)); return Ok(()) }
+		};
+		if let Some(base) = set_reg_base(&op) {
+			self.set_regs(base + ((arg(body, 0)? & 0xffff) << 2), &body[1..]);
+			return Ok(())
+		}
+		match op {
+			// nothing to do without caches and queues
+			Packet3::NOP | Packet3::CONTEXT_CONTROL | Packet3::CLEAR_STATE | Packet3::SURFACE_SYNC |
+			Packet3::PFP_SYNC_ME | Packet3::MODE_CONTROL | Packet3::PREAMBLE_CNTL => (),
+			Packet3::EVENT_WRITE => self.event_write(body)?,
+			Packet3::INDEX_TYPE => self.index_type = arg(body, 0)? & 3,
+			Packet3::NUM_INSTANCES => self.num_instances = arg(body, 0)?,
+			Packet3::EVENT_WRITE_EOP => {
+				let addr = arg(body, 1)? as u64 & !3 | (arg(body, 2)? as u64 & 0xff) << 32;
+				let (lo, hi) = (arg(body, 3)?, arg(body, 4)?);
+				match arg(body, 2)? >> 29 & 7 {
+					0 => (),
+					1 => self.write_u32(addr, lo)?,
+					2 => self.write_u64(addr, lo as u64 | (hi as u64) << 32)?,
+					3 => { let clock = self.clock; self.write_u64(addr, clock)? },
+					n => self.warn(format!("EVENT_WRITE_EOP with DATA_SEL {}", n))
+				}
+			},
+			Packet3::MEM_WRITE => {
+				let addr = arg(body, 0)? as u64 & !3 | (arg(body, 1)? as u64 & 0xff) << 32;
+				let (lo, hi) = (arg(body, 2)?, arg(body, 3)?);
+				if arg(body, 1)? & (1<<18) != 0 { // DATA32
+					self.write_u32(addr, lo)?;
+				} else {
+					self.write_u64(addr, lo as u64 | (hi as u64) << 32)?;
+				}
+			},
+			Packet3::CP_DMA => {
+				let dst = arg(body, 2)? as u64 | (arg(body, 3)? as u64 & 0xff) << 32;
+				let size = (arg(body, 4)? & 0x1fffff) as u64;
+				match arg(body, 1)? >> 29 & 3 {
+					0 => {
+						let src = arg(body, 0)? as u64 | (arg(body, 1)? as u64 & 0xff) << 32;
+						let data = self.range(src, size)?.to_vec();
+						self.range_mut(dst, size)?.copy_from_slice(&data);
+					},
+					2 => {
+						let word = arg(body, 0)?.to_le_bytes();
+						for (i, b) in self.range_mut(dst, size)?.iter_mut().enumerate() {
+							*b = word[i & 3];
+						}
+					},
+					n => self.warn(format!("CP_DMA with SRC_SEL {}", n))
+				}
+			},
+			Packet3::DRAW_INDEX_IMMD => {
+				let count = arg(body, 0)? as usize;
+				let data = &body[2.min(body.len())..];
+				let indices: Vec<u32> = if self.index_type == 1 {
+					data.iter().cloned().collect()
+				} else {
+					data.iter().flat_map(|&w| vec![w & 0xffff, w >> 16]).collect()
+				};
+				if indices.len() < count {
+					return Err(format!("DRAW_INDEX_IMMD of {} indices with {}", count, indices.len()))
+				}
+				self.draw(&indices[..count])?;
+			},
+			Packet3::DRAW_INDEX_AUTO => {
+				let indices: Vec<u32> = (0..arg(body, 0)?).collect();
+				self.draw(&indices)?;
+			},
+			Packet3::DISPATCH_DIRECT => {
+				let groups = [arg(body, 0)?, arg(body, 1)?, arg(body, 2)?];
+				let mut warnings = Vec::new();
+				compute(&self.regs, &mut *self.mem, self.chip, groups, &mut warnings)?;
+				for w in warnings {
+					self.warn(w);
+				}
+			},
+			op => self.warn(format!("{:?} isn't supported", op))
+		}
+		Ok(())
+	}
+
+	fn event_write(&mut self, body: &[u32]) -> Result<(), String> {
+		let addr = || -> Result<u64, String> { Ok(arg(body, 1)? as u64 & !7 | (arg(body, 2)? as u64 & 0xff) << 32) };
+		match arg(body, 0)? & 0x3f {
+			EVENT_TYPE_ZPASS_DONE => { let zpass = self.zpass; self.write_u64(addr()?, zpass | 1 << 63)? },
+			EVENT_TYPE_PIPELINESTAT_START => self.counting_stats = true,
+			EVENT_TYPE_PIPELINESTAT_STOP => self.counting_stats = false,
+			EVENT_TYPE_SAMPLE_PIPELINESTAT => {
+				let addr = addr()?;
+				for (n, &w) in self.stats.to_words().iter().enumerate() {
+					self.write_u64(addr + n as u64 * 8, w)?;
+				}
+			},
+			EVENT_TYPE_SAMPLE_STREAMOUTSTATS => self.warn("stream-out statistics aren't supported".to_owned()),
+			_ => () // nothing to do without caches and queues
+		}
+		Ok(())
+	}
+
+	fn range(&self, addr: u64, size: u64) -> Result<&[u8], String> {
+		let end = addr.checked_add(size).filter(|&e| e <= self.mem.len() as u64)
+			.ok_or_else(|| format!("{:#x}+{:#x} is outside the BO", addr, size))?;
+		Ok(&self.mem[addr as usize..end as usize])
+	}
+
+	fn range_mut(&mut self, addr: u64, size: u64) -> Result<&mut [u8], String> {
+		let end = addr.checked_add(size).filter(|&e| e <= self.mem.len() as u64)
+			.ok_or_else(|| format!("{:#x}+{:#x} is outside the BO", addr, size))?;
+		Ok(&mut self.mem[addr as usize..end as usize])
+	}
+
+	fn write_u32(&mut self, addr: u64, value: u32) -> Result<(), String> {
+		self.range_mut(addr, 4)?.copy_from_slice(&value.to_le_bytes());
+		Ok(())
+	}
+
+	fn write_u64(&mut self, addr: u64, value: u64) -> Result<(), String> {
+		self.range_mut(addr, 8)?.copy_from_slice(&value.to_le_bytes());
+		Ok(())
+	}
+
+	fn draw(&mut self, indices: &[u32]) -> Result<(), String> {
+		if self.num_instances != 1 {
+			let n = self.num_instances;
+			self.warn(format!("drawing one of {} instances", n));
+		}
+		let mut warnings = Vec::new();
+		let mut stats = PipelineStats::default();
+		let fragments = shade(&self.regs, &mut *self.mem, self.chip, indices, &mut stats, &mut warnings)?;
+		for w in warnings {
+			self.warn(w);
+		}
+		if self.counting_stats {
+			let mut words = self.stats.to_words();
+			for (w, n) in words.iter_mut().zip(stats.to_words().iter()) {
+				*w += n;
+			}
+			self.stats = PipelineStats::from_words(&words);
+		}
+		if self.regs.get(0x28004) & 1 == 0 { // DB_COUNT_CONTROL ZPASS_INCREMENT_DISABLE
+			self.zpass += fragments.len() as u64;
+		}
+		self.write_fragments(&fragments)
+	}
+
+	// The CB: blending and the format conversion of color buffer 0
+	fn write_fragments(&mut self, fragments: &[Fragment]) -> Result<(), String> {
+		let mode = self.regs.get(0x28808) >> 4 & 7; // CB_COLOR_CONTROL
+		if mode != CB_MODE_NORMAL {
+			if mode != CB_MODE_DISABLE {
+				self.warn(format!("CB mode {} isn't supported", mode));
+			}
+			return Ok(())
+		}
+		let info = self.regs.get(0x28c70); // CB_COLOR0_INFO
+		let (format, array_mode, number_type, swap) = (info >> 2 & 0x3f, info >> 8 & 0xf, info >> 12 & 7, info >> 15 & 3);
+		if format != 26 /* COLOR_8_8_8_8 */ || array_mode > 1 /* ARRAY_LINEAR_ALIGNED */ || (number_type != 0 && number_type != 6) {
+			self.warn(format!("color buffer format {}, array mode {}, number type {} isn't supported", format, array_mode, number_type));
+			return Ok(())
+		}
+		let srgb = number_type == 6;
+		let base = (self.regs.get(0x28c60) as u64) << 8; // CB_COLOR0_BASE
+		let pitch = (self.regs.get(0x28c64) as u64 + 1) * 8; // CB_COLOR0_PITCH
+		let mask = self.regs.get(0x28238) & 0xf; // CB_TARGET_MASK
+		let blend = self.regs.get(0x28780); // CB_BLEND0_CONTROL
+		let blend = if blend & (1<<30) != 0 { Some(blend) } else { None };
+		if let Some(b) = blend {
+			let factors = [b & 0x1f, b >> 8 & 0x1f, b >> 16 & 0x1f, b >> 24 & 0x1f];
+			if factors.iter().any(|&f| f > 10) || b >> 5 & 7 > 4 || b >> 21 & 7 > 4 {
+				self.warn(format!("CB_BLEND0_CONTROL {:#x} isn't supported", b));
+				return Ok(())
+			}
+		}
+		// memory byte n holds component order[n]
+		let order = match swap {
+			0 => [0, 1, 2, 3], // SWAP_STD
+			1 => [2, 1, 0, 3], // SWAP_ALT
+			2 => [3, 2, 1, 0], // SWAP_STD_REV
+			_ => [3, 0, 1, 2]  // SWAP_ALT_REV
+		};
+		for f in fragments {
+			let addr = base + (f.y as u64 * pitch + f.x as u64) * 4;
+			let pixel = self.range_mut(addr, 4)?;
+			let mut old = [0u8; 4];
+			for n in 0..4 {
+				old[order[n]] = pixel[n];
+			}
+			let to_float = |c: usize| if srgb && c < 3 { srgb_to_linear(old[c] as f32 / 255.0) } else { old[c] as f32 / 255.0 };
+			let dst = [to_float(0), to_float(1), to_float(2), to_float(3)];
+			let src = [saturate(f.color[0]), saturate(f.color[1]), saturate(f.color[2]), saturate(f.color[3])];
+			for c in 0..4 {
+				if mask & (1 << c) == 0 { continue }
+				let v = saturate(match blend {
+					Some(b) => blend_channel(b, src, dst, c),
+					None => src[c]
+				});
+				let v = if srgb && c < 3 { linear_to_srgb(v) } else { v };
+				pixel[order.iter().position(|&o| o == c).unwrap()] = (v * 255.0).round() as u8;
+			}
+		}
+		Ok(())
+	}
+}
+
+fn arg(body: &[u32], n: usize) -> Result<u32, String> {
+	body.get(n).cloned().ok_or_else(|| "packet too short".to_owned())
+}
+
+fn saturate(v: f32) -> f32 {
+	if v > 0.0 { v.min(1.0) } else { 0.0 }
+}
+
+fn srgb_to_linear(v: f32) -> f32 {
+	if v <= 0.04045 { v / 12.92 } else { ((v + 0.055) / 1.055).powf(2.4) }
+}
+
+fn linear_to_srgb(v: f32) -> f32 {
+	if v <= 0.0031308 { v * 12.92 } else { 1.055 * v.powf(1.0 / 2.4) - 0.055 }
+}
+
+fn blend_factor(factor: u32, src: [f32; 4], dst: [f32; 4], c: usize) -> f32 {
+	match factor {
+		0 => 0.0,
+		1 => 1.0,
+		2 => src[c],
+		3 => 1.0 - src[c],
+		4 => src[3],
+		5 => 1.0 - src[3],
+		6 => dst[3],
+		7 => 1.0 - dst[3],
+		8 => dst[c],
+		9 => 1.0 - dst[c],
+		_ => if c == 3 { 1.0 } else { src[3].min(1.0 - dst[3]) } // SRC_ALPHA_SATURATE
+	}
+}
+
+// CB_BLEND0_CONTROL for channel c, alpha has its own fields with SEPARATE_ALPHA_BLEND
+fn blend_channel(control: u32, src: [f32; 4], dst: [f32; 4], c: usize) -> f32 {
+	let control = if c == 3 && control & (1<<29) != 0 { control >> 16 } else { control };
+	let s = src[c] * blend_factor(control & 0x1f, src, dst, c);
+	let d = dst[c] * blend_factor(control >> 8 & 0x1f, src, dst, c);
+	match control >> 5 & 7 {
+		0 => s + d,
+		1 => s - d,
+		2 => src[c].min(dst[c]),
+		3 => src[c].max(dst[c]),
+		_ => d - s
+	}
+}
+
+// Scissor registers hold x in bits 0-14 and y in 16-30, the BR corner is exclusive
+fn scissor(regs: &Regs, tl: u32) -> [i64; 4] {
+	let (a, b) = (regs.get(tl), regs.get_or(tl + 4, 0x40004000));
+	[(a & 0x7fff) as i64, (a >> 16 & 0x7fff) as i64, (b & 0x7fff) as i64, (b >> 16 & 0x7fff) as i64]
+}
+
+fn inside(r: &[i64; 4], x: i64, y: i64) -> bool {
+	x >= r[0] && y >= r[1] && x < r[2] && y < r[3]
+}
+
+fn decode_shader(regs: &Regs, mem: &[u8], reg: u32, chip: Chip) -> Result<Program, String> {
+	let addr = (regs.get(reg) as usize) << 8;
+	if addr >= mem.len() {
+		return Err(format!("shader at {:#x} is outside the BO", addr))
+	}
+	let end = mem.len().min(addr + MAX_SHADER_SIZE);
+	Program::decode(&words_from_bytes(&mem[addr..end]), chip).map_err(|e| format!("shader at {:#x}: {}", addr, e))
+}
+
+// SQ_ALU_CONST_BUFFER_SIZE_*_n and SQ_ALU_CONST_CACHE_*_n for each slot
+fn const_buffers(regs: &Regs, mem: &[u8], size_reg: u32, cache_reg: u32) -> Result<Vec<Vec<[u32; 4]>>, String> {
+	(0..MAX_CONST_BUFFERS).map(|slot| {
+		let addr = (regs.get(cache_reg + 4*slot) as usize) << 8;
+		let size = regs.get(size_reg + 4*slot) as usize * 256;
+		let bytes = mem.get(addr..addr+size).ok_or_else(|| format!("constant buffer {} is outside the BO", slot))?;
+		Ok(words_from_bytes(bytes).chunks(4).map(|c| [c[0], c[1], c[2], c[3]]).collect())
+	}).collect()
+}
+
+// The fetch resources of a stage, by buffer id
+fn vertex_buffers<'m>(regs: &Regs, mem: &'m [u8], first: u32) -> Result<Vec<VertexBuffer<'m>>, String> {
+	(0..16).map(|id| {
+		let base = 0x30000 + 32 * (first + id);
+		if regs.get(base + 28) >> 30 != 3 {
+			return Ok(VertexBuffer { data: &[], stride: 0 })
+		}
+		let addr = regs.get(base) as usize | (regs.get(base + 8) as usize & 0xff) << 32;
+		let size = regs.get(base + 4) as usize + 1;
+		let data = mem.get(addr..addr+size).ok_or_else(|| format!("vertex buffer {} is outside the BO", id))?;
+		Ok(VertexBuffer { data: data, stride: regs.get(base + 8) >> 8 & 0x7ff })
+	}).collect()
+}
+
+impl<'m> Texture<'m> {
+	fn new(regs: &Regs, mem: &'m [u8], resource: u32, sampler: u32) -> Result<Texture<'m>, String> {
+		let base = 0x30000 + 32 * resource;
+		let word = |n: u32| regs.get(base + 4*n);
+		if word(7) >> 30 != 2 {
+			return Err(format!("resource {} isn't a texture", resource))
+		}
+		if word(0) & 7 != 1 || word(1) >> 28 > 1 {
+			return Err(format!("texture {} isn't linear 2D", resource))
+		}
+		let format = word(7) & 0x3f;
+		let bytes = match format {
+			FMT_8 => 1, FMT_8_8 => 2, FMT_8_8_8_8 => 4, FMT_32_32_32_32_FLOAT => 16,
+			_ => return Err(format!("texture format {} isn't supported", format))
+		};
+		let (pitch, width, height) = ((word(0) >> 6 & 0xfff) * 8 + 8, (word(0) >> 18) + 1, (word(1) & 0x3fff) + 1);
+		let addr = (word(2) as usize) << 8;
+		let size = (pitch * height * bytes) as usize;
+		let data = mem.get(addr..addr+size).ok_or_else(|| format!("texture {} is outside the BO", resource))?;
+		let s = regs.get(0x3c000 + 12 * sampler); // SQ_TEX_SAMPLER_WORD0
+		Ok(Texture {
+			data: data,
+			width: width,
+			height: height,
+			pitch: pitch,
+			format: format,
+			degamma: word(4) & (1<<11) != 0,
+			dst_sel: [word(4) >> 16 & 7, word(4) >> 19 & 7, word(4) >> 22 & 7, word(4) >> 25 & 7],
+			bilinear: s >> 9 & 3 != 0,
+			clamp: [s & 7, s >> 3 & 7]
+		})
+	}
+
+	// SQ_TEX_WRAP repeats, every other mode clamps like SQ_TEX_CLAMP_LAST_TEXEL
+	fn address(clamp: u32, i: i64, size: u32) -> usize {
+		if clamp == 0 {
+			i.rem_euclid(size as i64) as usize
+		} else {
+			i.max(0).min(size as i64 - 1) as usize
+		}
+	}
+
+	// Components the format doesn't have read as 0, 0, 0, 1
+	fn texel(&self, x: i64, y: i64) -> [f32; 4] {
+		let (x, y) = (Texture::address(self.clamp[0], x, self.width), Texture::address(self.clamp[1], y, self.height));
+		let o = y * self.pitch as usize + x;
+		let mut t = [0.0, 0.0, 0.0, 1.0];
+		match self.format {
+			FMT_32_32_32_32_FLOAT => {
+				let w = words_from_bytes(&self.data[o*16..o*16+16]);
+				for c in 0..4 { t[c] = f32::from_bits(w[c]) }
+			},
+			_ => {
+				let n = match self.format { FMT_8 => 1, FMT_8_8 => 2, _ => 4 };
+				for c in 0..n {
+					let v = self.data[o*n + c] as f32 / 255.0;
+					t[c] = if self.degamma && c < 3 { srgb_to_linear(v) } else { v };
+				}
+			}
+		}
+		t
+	}
+
+	fn sample(&self, inst: &TexInst, coords: [f32; 4]) -> [f32; 4] {
+		let (w, h) = (self.width as f32, self.height as f32);
+		let u = if inst.coord_type[0] { coords[0] * w } else { coords[0] };
+		let v = if inst.coord_type[1] { coords[1] * h } else { coords[1] };
+		let t = if self.bilinear {
+			let (x, y) = (u - 0.5, v - 0.5);
+			let (x0, y0) = (x.floor(), y.floor());
+			let (fx, fy) = (x - x0, y - y0);
+			let (x0, y0) = (x0 as i64, y0 as i64);
+			let (a, b, c, d) = (self.texel(x0, y0), self.texel(x0+1, y0), self.texel(x0, y0+1), self.texel(x0+1, y0+1));
+			let mut t = [0.0; 4];
+			for i in 0..4 {
+				t[i] = (a[i] * (1.0 - fx) + b[i] * fx) * (1.0 - fy) + (c[i] * (1.0 - fx) + d[i] * fx) * fy;
+			}
+			t
+		} else {
+			self.texel(u.floor() as i64, v.floor() as i64)
+		};
+		let mut out = [0.0; 4];
+		for c in 0..4 {
+			out[c] = match self.dst_sel[c] {
+				s @ SEL_X ..= SEL_W => t[s as usize],
+				SEL_1 => 1.0,
+				_ => 0.0
+			};
+		}
+		out
+	}
+}
+
+// The PS inputs per SPI_PS_INPUT_CNTL_n, matched against SPI_VS_OUT_ID_n
+fn ps_inputs(regs: &Regs, vs_params: usize) -> Vec<PsInput> {
+	let num_interp = regs.get(0x286cc) & 0x3f; // SPI_PS_IN_CONTROL_0
+	(0..num_interp).map(|n| {
+		let cntl = regs.get(0x28644 + 4*n);
+		let semantic = cntl & 0xff;
+		PsInput {
+			param: (0..vs_params).find(|&p| regs.get(0x2861c + 4 * (p as u32 / 4)) >> (8 * (p % 4)) & 0xff == semantic),
+			default: match cntl >> 8 & 3 {
+				0 => [0.0, 0.0, 0.0, 0.0],
+				1 => [0.0, 0.0, 0.0, 1.0],
+				2 => [1.0, 1.0, 1.0, 0.0],
+				_ => [1.0, 1.0, 1.0, 1.0]
+			},
+			flat: cntl & (1<<10) != 0
+		}
+	}).collect()
+}
+
+// Runs the VS for each index, or the ES, GS and copy shader when there's a
+// GS, sets up the triangles and runs the PS for every pixel they cover,
+// counting what the pipeline statistics count
+fn shade(regs: &Regs, mem: &mut [u8], chip: Chip, indices: &[u32], stats: &mut PipelineStats, warnings: &mut Vec<String>) -> Result<Vec<Fragment>, String> {
+	let clip_cntl = regs.get(0x28810); // PA_CL_CLIP_CNTL
+	let prim_type = regs.get(0x8958); // VGT_PRIMITIVE_TYPE
+	let n = indices.len();
+	let triangles: Vec<[usize; 3]> = match prim_type {
+		DI_PT_TRILIST => (0..n/3).map(|t| [3*t, 3*t+1, 3*t+2]).collect(),
+		DI_PT_TRISTRIP => strip(0, n),
+		_ => {
+			warnings.push(format!("primitive type {:#x} isn't supported", prim_type));
+			return Ok(Vec::new())
+		}
+	};
+	let index_offset = regs.get(0x28408); // VGT_INDX_OFFSET
+	let indices: Vec<u32> = indices.iter().map(|i| i.wrapping_add(index_offset)).collect();
+	let mut distinct = indices.clone();
+	distinct.sort();
+	distinct.dedup();
+	stats.ia_vertices += n as u64;
+	stats.ia_primitives += triangles.len() as u64;
+	stats.vs_invocations += distinct.len() as u64;
+	if clip_cntl & (1<<22) != 0 { // DX_RASTERIZATION_KILL
+		return Ok(Vec::new())
+	}
+
+	let (vertices, triangles) = if regs.get(0x28b54) & (1<<5) != 0 { // VGT_SHADER_STAGES_EN GS_EN
+		geometry_stages(regs, mem, chip, &indices, &triangles, stats, warnings)?
+	} else {
+		vertex_stage(regs, mem, chip, &indices, &triangles)?
+	};
+	stats.c_invocations += triangles.len() as u64;
+	rasterize(regs, mem, chip, &vertices, &triangles, stats, warnings)
+}
+
+// Triangles of a strip of n vertices starting at first, every other one
+// flipped to keep the winding
+fn strip(first: usize, n: usize) -> Vec<[usize; 3]> {
+	(0..n.saturating_sub(2)).map(|t| {
+		let v = first + t;
+		if t % 2 == 0 { [v, v+1, v+2] } else { [v+1, v, v+2] }
+	}).collect()
+}
+
+// SPI_VS_OUT_CONFIG
+fn vs_params(regs: &Regs) -> usize {
+	(regs.get(0x286c4) >> 1 & 0x1f) as usize + 1
+}
+
+// Runs a vertex shader once per distinct index, returns the results and
+// which of them each index got
+fn per_vertex<T, F: FnMut(u32) -> Result<T, String>>(indices: &[u32], mut run: F) -> Result<(Vec<T>, Vec<usize>), String> {
+	let mut results = Vec::new();
+	let mut slots: HashMap<u32, usize> = HashMap::new();
+	let mut order = Vec::new();
+	for &index in indices {
+		if let Some(&slot) = slots.get(&index) {
+			order.push(slot);
+			continue
+		}
+		slots.insert(index, results.len());
+		order.push(results.len());
+		results.push(run(index)?);
+	}
+	Ok((results, order))
+}
+
+// The vertex a VS or copy shader exported, after the viewport transform
+fn transform(regs: &Regs, out: &Outputs, params: usize) -> Option<Vertex> {
+	let vte = regs.get(0x28818); // PA_CL_VTE_CNTL
+	let vport = |i: u32| regs.f32(0x2843c + 4*i); // PA_CL_VPORT_XSCALE_0 ..
+	let p = out.pos(0)?;
+	let (mut x, mut y) = (p[0], p[1]);
+	let inv_w = if vte & (1<<10) != 0 { 1.0 / p[3] } else { p[3] }; // VTX_W0_FMT
+	if vte & (1<<8) == 0 { x *= inv_w; y *= inv_w; } // VTX_XY_FMT
+	if vte & 1 != 0 { x *= vport(0) }
+	if vte & 2 != 0 { x += vport(1) }
+	if vte & 4 != 0 { y *= vport(2) }
+	if vte & 8 != 0 { y += vport(3) }
+	// snapped to the 1/256th pixel grid of PA_SU_VTX_CNTL
+	Some(Vertex {
+		x: (x as f64 * 256.0).round() / 256.0,
+		y: (y as f64 * 256.0).round() / 256.0,
+		inv_w: inv_w,
+		params: (0..params as u32).map(|p| out.param(p).unwrap_or([0.0; 4])).collect()
+	})
+}
+
+// The VS, once per distinct index. Returns the vertices and the triangles
+// as indices into them.
+fn vertex_stage(regs: &Regs, mem: &[u8], chip: Chip, indices: &[u32], triangles: &[[usize; 3]]) -> Result<(Vec<Vertex>, Vec<[usize; 3]>), String> {
+	let vs = decode_shader(regs, mem, 0x2885c, chip)?; // SQ_PGM_START_VS
+	let consts = const_buffers(regs, mem, 0x28180, 0x28980)?;
+	let params = vs_params(regs);
+	let mut m = Machine::new(chip);
+	m.const_buffers = consts.iter().map(|c| &c[..]).collect();
+	m.vertex_buffers = vertex_buffers(regs, mem, FETCH_RESOURCE_OFFSET_VS)?;
+	let (vertices, order) = per_vertex(indices, |index| {
+		m.gprs = vec![[0; 4]; NUM_GPRS];
+		m.gprs[0] = [index, 0, 0, 0];
+		let out = m.run(&vs).map_err(|e| format!("VS, vertex {}: {}", index, e))?;
+		transform(regs, &out, params).ok_or_else(|| format!("VS, vertex {}: no POS0 export", index))
+	})?;
+	Ok((vertices, triangles.iter().map(|t| [order[t[0]], order[t[1]], order[t[2]]]).collect()))
+}
+
+// A memory write: byte address, value and component mask
+type Store = (usize, [u32; 4], u32);
+
+// SQ_*_RING_BASE and SQ_*_RING_SIZE as the ring's address and size
+fn ring(regs: &Regs, base_reg: u32) -> (usize, usize) {
+	((regs.get(base_reg) as usize) << 8, (regs.get(base_reg + 4) as usize) << 8)
+}
+
+// The MEM_RING writes of a shader whose data starts at dword at of the ring
+fn ring_writes(out: &Outputs, ring: (usize, usize), at: u32, name: &str) -> Result<Vec<Store>, String> {
+	out.mem_writes.iter().filter(|w| w.inst == CF_INST_MEM_RING).map(|w| {
+		let offset = (at + w.array_base + w.index) as usize * 4;
+		if offset + 16 > ring.1 {
+			return Err(format!("the draw doesn't fit into the {} ring", name))
+		}
+		Ok((ring.0 + offset, w.value, w.comp_mask))
+	}).collect()
+}
+
+fn store(mem: &mut [u8], writes: &[Store]) -> Result<(), String> {
+	for &(addr, value, mask) in writes {
+		for c in (0..4).filter(|c| mask >> c & 1 != 0) {
+			let at = addr + 4*c;
+			let dst = mem.get_mut(at..at+4).ok_or_else(|| format!("memory write at {:#x} is outside the BO", at))?;
+			dst.copy_from_slice(&value[c].to_le_bytes());
+		}
+	}
+	Ok(())
+}
+
+// The compute shader on the LS stage once per thread of every group, with
+// the thread's id in the group in R0.xyz and the group's in R1.xyz. Inputs
+// are the fetch resources of the CS range; STORE_RAW to a RAT writes the
+// dword at index_gpr.x of the color buffer with the RAT bit. The stores
+// land once all threads have run, so threads don't see each other's.
+fn compute(regs: &Regs, mem: &mut [u8], chip: Chip, groups: [u32; 3], warnings: &mut Vec<String>) -> Result<(), String> {
+	let cs = decode_shader(regs, mem, 0x288d0, chip)?; // SQ_PGM_START_LS
+	let block = [regs.get(0x286ec), regs.get(0x286f0), regs.get(0x286f4)]; // SPI_COMPUTE_NUM_THREAD_X ..
+	let ids = |n: [u32; 3]| (0..n[0] * n[1] * n[2]).map(move |i| [i % n[0], i / n[0] % n[1], i / n[0] / n[1]]);
+	let mut stores = Vec::new();
+	{
+		let mut m = Machine::new(chip);
+		m.vertex_buffers = vertex_buffers(regs, mem, FETCH_RESOURCE_OFFSET_CS)?;
+		for group in ids(groups) {
+			for thread in ids(block) {
+				m.gprs = vec![[0; 4]; NUM_GPRS];
+				m.gprs[0] = [thread[0], thread[1], thread[2], 0];
+				m.gprs[1] = [group[0], group[1], group[2], 0];
+				let out = m.run(&cs).map_err(|e| format!("CS, group {:?}, thread {:?}: {}", group, thread, e))?;
+				for w in &out.mem_writes {
+					let (rat, op) = (w.array_base & 0xf, w.array_base >> 4);
+					if (w.inst != CF_INST_MEM_RAT && w.inst != CF_INST_MEM_RAT_CACHELESS) || op != RAT_INST_STORE_RAW {
+						warnings.push(format!("memory write {} with RAT instruction {} isn't supported", w.inst, op));
+						continue
+					}
+					let r = 0x28c60 + rat * 0x3c; // CB_COLOR0_BASE of the RAT
+					if regs.get(r + 0x10) & (1<<26) == 0 { // CB_COLOR0_INFO RAT
+						return Err(format!("color buffer {} isn't a RAT", rat))
+					}
+					stores.push((((regs.get(r) as usize) << 8) + w.index as usize * 4, w.value, w.comp_mask));
+				}
+			}
+		}
+	}
+	store(mem, &stores)
+}
+
+// The ES once per distinct index, writing to the ESGS ring, the GS once
+// per triangle, reading the ES's vertices from there and writing its own
+// to the GSVS ring, and the copy shader on the VS stage once per vertex the
+// GS emitted, reading it back. The hardware streams through the rings,
+// here every draw starts at their beginning and has to fit: ES vertex n is
+// at n * SQ_ESGS_RING_ITEMSIZE and GS invocation n at
+// n * SQ_GSVS_RING_ITEMSIZE, with its vertex k another
+// k * SQ_GS_VERT_ITEMSIZE further. Sizes and offsets are in dwords, the GS
+// gets those of its vertices in R0.x, R0.y and R0.w and the copy shader
+// that of its vertex in R0.x. The ES shares the VS constants and fetch
+// resources. Covers scenario G with triangle strips out, stream 0 only.
+fn geometry_stages(regs: &Regs, mem: &mut [u8], chip: Chip, indices: &[u32], triangles: &[[usize; 3]],
+                   stats: &mut PipelineStats, warnings: &mut Vec<String>) -> Result<(Vec<Vertex>, Vec<[usize; 3]>), String> {
+	let mode = regs.get(0x28a40) & 7; // VGT_GS_MODE
+	let out_prim = regs.get(0x28a6c); // VGT_GS_OUT_PRIM_TYPE
+	if mode != 3 /* GS_SCENARIO_G */ || out_prim != GsOutPrim::TriStrip as u32 {
+		warnings.push(format!("GS mode {} with output primitive type {} isn't supported", mode, out_prim));
+		return Ok((Vec::new(), Vec::new()))
+	}
+	let (esgs, gsvs) = (ring(regs, 0x8c40), ring(regs, 0x8c48)); // SQ_ESGS_RING_BASE, SQ_GSVS_RING_BASE
+	let es_item = regs.get(0x28900); // SQ_ESGS_RING_ITEMSIZE
+	let gs_item = regs.get(0x28904); // SQ_GSVS_RING_ITEMSIZE
+	let vert_item = regs.get(0x2891c); // SQ_GS_VERT_ITEMSIZE
+	let max_vert_out = regs.get(0x28b38); // VGT_GS_MAX_VERT_OUT
+	let vs_consts = const_buffers(regs, mem, 0x28180, 0x28980)?;
+
+	let es = decode_shader(regs, mem, 0x2888c, chip)?; // SQ_PGM_START_ES
+	let mut writes = Vec::new();
+	let order = {
+		let mut m = Machine::new(chip);
+		m.const_buffers = vs_consts.iter().map(|c| &c[..]).collect();
+		m.vertex_buffers = vertex_buffers(regs, mem, FETCH_RESOURCE_OFFSET_VS)?;
+		per_vertex(indices, |index| {
+			m.gprs = vec![[0; 4]; NUM_GPRS];
+			m.gprs[0] = [index, 0, 0, 0];
+			let out = m.run(&es).map_err(|e| format!("ES, vertex {}: {}", index, e))?;
+			let slot = writes.len() as u32;
+			writes.push(ring_writes(&out, esgs, slot * es_item, "ESGS")?);
+			Ok(())
+		})?.1
+	};
+	store(mem, &writes.concat())?;
+
+	let gs = decode_shader(regs, mem, 0x28874, chip)?; // SQ_PGM_START_GS
+	let gs_consts = const_buffers(regs, mem, 0x281c0, 0x289c0)?;
+	let mut writes = Vec::new();
+	let mut strips = Vec::new(); // dword offset of the first vertex and the count
+	{
+		let mut m = Machine::new(chip);
+		m.const_buffers = gs_consts.iter().map(|c| &c[..]).collect();
+		m.vertex_buffers = vertex_buffers(regs, mem, FETCH_RESOURCE_OFFSET_GS)?;
+		for (p, t) in triangles.iter().enumerate() {
+			let offset = |i: usize| order[t[i]] as u32 * es_item;
+			m.gprs = vec![[0; 4]; NUM_GPRS];
+			m.gprs[0] = [offset(0), offset(1), p as u32, offset(2)];
+			let out = m.run(&gs).map_err(|e| format!("GS, primitive {}: {}", p, e))?;
+			let base = p as u32 * gs_item;
+			writes.extend(ring_writes(&out, gsvs, base, "GSVS")?);
+			if out.strips.iter().sum::<u32>() > max_vert_out {
+				warnings.push(format!("GS, primitive {}: more than {} vertices emitted", p, max_vert_out));
+			}
+			let mut first = base;
+			for &n in &out.strips {
+				strips.push((first, n));
+				first += n * vert_item;
+			}
+		}
+	}
+	stats.gs_invocations += triangles.len() as u64;
+	store(mem, &writes)?;
+
+	let vs = decode_shader(regs, mem, 0x2885c, chip)?; // SQ_PGM_START_VS
+	let params = vs_params(regs);
+	let mut m = Machine::new(chip);
+	m.const_buffers = vs_consts.iter().map(|c| &c[..]).collect();
+	m.vertex_buffers = vertex_buffers(regs, mem, FETCH_RESOURCE_OFFSET_VS)?;
+	let mut vertices = Vec::new();
+	let mut out_triangles = Vec::new();
+	for &(first, n) in &strips {
+		out_triangles.extend(strip(vertices.len(), n as usize));
+		for k in 0..n {
+			let offset = first + k * vert_item;
+			m.gprs = vec![[0; 4]; NUM_GPRS];
+			m.gprs[0] = [offset, 0, 0, 0];
+			let out = m.run(&vs).map_err(|e| format!("copy shader, offset {}: {}", offset, e))?;
+			vertices.push(transform(regs, &out, params).ok_or_else(|| format!("copy shader, offset {}: no POS0 export", offset))?);
+		}
+	}
+	stats.gs_primitives += out_triangles.len() as u64;
+	Ok((vertices, out_triangles))
+}
+
+// Sets up the triangles, given as indices into vertices, and runs the PS
+// for every pixel they cover
+fn rasterize(regs: &Regs, mem: &[u8], chip: Chip, vertices: &[Vertex], triangles: &[[usize; 3]],
+             stats: &mut PipelineStats, warnings: &mut Vec<String>) -> Result<Vec<Fragment>, String> {
+	let clip_cntl = regs.get(0x28810); // PA_CL_CLIP_CNTL
+	let vte = regs.get(0x28818); // PA_CL_VTE_CNTL
+	let ps = decode_shader(regs, mem, 0x28840, chip)?; // SQ_PGM_START_PS
+	let ps_consts = const_buffers(regs, mem, 0x28140, 0x28940)?;
+
+	// the rasterizer state
+	let mode_cntl = regs.get(0x28814); // PA_SU_SC_MODE_CNTL
+	let center = if regs.get_or(0x28c08, 1) & 1 != 0 { 0.5 } else { 0.0 }; // PA_SU_VTX_CNTL PIX_CENTER
+	let mut bounds = [0i64, 0, 0x4000, 0x4000];
+	for &tl in &[0x28030, 0x28204, 0x28240] { // PA_SC_SCREEN_SCISSOR_TL, WINDOW and GENERIC
+		let s = scissor(regs, tl);
+		bounds = [bounds[0].max(s[0]), bounds[1].max(s[1]), bounds[2].min(s[2]), bounds[3].min(s[3])];
+	}
+	let rule = regs.get_or(0x2820c, 0xffff) & 0xffff; // PA_SC_CLIPRECT_RULE
+	let cliprects: Vec<[i64; 4]> = (0..4).map(|i| scissor(regs, 0x28210 + 8*i)).collect();
+	let inputs = ps_inputs(regs, vs_params(regs));
+	if regs.get(0x286cc) & (1<<8) != 0 {
+		warnings.push("the PS position input isn't supported".to_owned());
+	}
+
+	// the pixel shader, with the textures it samples
+	let mut textures = HashMap::new();
+	for inst in &ps.cf {
+		if let CfInst::Fetch(_, ref fetches) = *inst {
+			for f in fetches {
+				if let FetchInst::Tex(ref t) = *f {
+					let key = (t.resource_id, t.sampler_id);
+					if !textures.contains_key(&key) {
+						let tex = Texture::new(regs, mem, FETCH_RESOURCE_OFFSET_PS + t.resource_id, SAMPLER_OFFSET_PS + t.sampler_id)?;
+						textures.insert(key, tex);
+					}
+				}
+			}
+		}
+	}
+	let sampler = |t: &TexInst, coords: [f32; 4]| textures.get(&(t.resource_id, t.sampler_id)).map_or([0.0; 4], |tex| tex.sample(t, coords));
+	let mut m = Machine::new(chip);
+	m.const_buffers = ps_consts.iter().map(|c| &c[..]).collect();
+	m.sampler = Some(&sampler);
+	// pixels with the same inputs get the same color, which makes solid fills cheap
+	let mut last: Option<(Vec<[u32; 4]>, Option<[f32; 4]>)> = None;
+
+	let mut fragments = Vec::new();
+	for t in triangles {
+		let provoking = if mode_cntl & (1<<19) != 0 { t[2] } else { t[0] }; // PROVOKING_VTX_LAST
+		let mut v = [&vertices[t[0]], &vertices[t[1]], &vertices[t[2]]];
+		if clip_cntl & (1<<16) == 0 && vte & (1<<10) != 0 && v.iter().any(|v| !(v.inv_w > 0.0)) {
+			warnings.push("skipped primitives crossing W = 0, clipping isn't supported".to_owned());
+			continue
+		}
+		stats.c_primitives += 1;
+		let edge = |a: &Vertex, b: &Vertex, x: f64, y: f64| (b.x - a.x) * (y - a.y) - (b.y - a.y) * (x - a.x);
+		let area = edge(v[0], v[1], v[2].x, v[2].y);
+		if area == 0.0 { continue }
+		// clockwise on screen when the area is positive, y points down
+		let front = (area > 0.0) == (mode_cntl & 4 != 0); // FACE
+		if (front && mode_cntl & 1 != 0) || (!front && mode_cntl & 2 != 0) { continue } // CULL_FRONT, CULL_BACK
+		if area < 0.0 { v.swap(1, 2) }
+		let area = area.abs();
+
+		let x0 = (v.iter().map(|v| v.x).fold(f64::MAX, f64::min) - center).ceil().max(bounds[0] as f64) as i64;
+		let y0 = (v.iter().map(|v| v.y).fold(f64::MAX, f64::min) - center).ceil().max(bounds[1] as f64) as i64;
+		let x1 = (v.iter().map(|v| v.x).fold(f64::MIN, f64::max) - center).floor().min(bounds[2] as f64 - 1.0) as i64;
+		let y1 = (v.iter().map(|v| v.y).fold(f64::MIN, f64::max) - center).floor().min(bounds[3] as f64 - 1.0) as i64;
+		for y in y0..y1+1 {
+			for x in x0..x1+1 {
+				if rule != 0xffff {
+					let code = cliprects.iter().enumerate().fold(0, |c, (i, r)| c | (inside(r, x, y) as u32) << i);
+					if rule >> code & 1 == 0 { continue }
+				}
+				let (px, py) = (x as f64 + center, y as f64 + center);
+				// top-left rule: pixels on an edge belong to top and left edges
+				let mut l = [0.0; 3];
+				let mut covered = true;
+				for i in 0..3 {
+					let (a, b) = (v[(i+1)%3], v[(i+2)%3]);
+					let e = edge(a, b, px, py);
+					let (dx, dy) = (b.x - a.x, b.y - a.y);
+					covered &= e > 0.0 || (e == 0.0 && (dy < 0.0 || (dy == 0.0 && dx > 0.0)));
+					l[i] = e / area;
+				}
+				if !covered { continue }
+				stats.ps_invocations += 1;
+
+				let b: Vec<f32> = (0..3).map(|i| l[i] as f32 * v[i].inv_w).collect();
+				let sum: f32 = b.iter().sum();
+				let params: Vec<[u32; 4]> = inputs.iter().map(|input| {
+					let value = match input.param {
+						Some(p) if input.flat => vertices[provoking].params[p],
+						Some(p) => {
+							let mut value = [0.0; 4];
+							for c in 0..4 {
+								value[c] = (0..3).map(|i| b[i] * v[i].params[p][c]).sum::<f32>() / sum;
+							}
+							value
+						},
+						None => input.default
+					};
+					[value[0].to_bits(), value[1].to_bits(), value[2].to_bits(), value[3].to_bits()]
+				}).collect();
+
+				let color = match last {
+					Some((ref p, color)) if *p == params => color,
+					_ => {
+						m.gprs = vec![[0; 4]; NUM_GPRS];
+						m.params = params.clone();
+						let out = m.run(&ps).map_err(|e| format!("PS at {}, {}: {}", x, y, e))?;
+						let color = if out.killed { None } else { out.pixel(0) };
+						if !out.killed && color.is_none() {
+							warnings.push("the PS doesn't export color 0".to_owned());
+						}
+						last = Some((params, color));
+						color
+					}
+				};
+				if let Some(color) = color {
+					fragments.push(Fragment { x: x as u32, y: y as u32, color: color });
+				}
+			}
+		}
+	}
+	Ok(fragments)
+}
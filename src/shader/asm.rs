@@ -0,0 +1,698 @@
+// Assembler for the syntax printed by disasm.rs
+//
+// Lines starting in the first column are CF instructions or labels
+// ("name:"), indented lines belong to the clause of the CF instruction
+// above them. ';' starts a comment. The first instruction of an ALU group
+// carries the group number, the number itself is not checked:
+//
+//     ALU KC0[CB0:0-15] BARRIER
+//          0 x: MUL R0.x, R1.x, KC0[0].x
+//            t: RECIP_IEEE R0.y, 0x40000000
+//          1 x: ADD R0.x, PV.x, PS
+//
+// Literals are written as hex numbers in place of the operand and are
+// allocated to the group's literal slots in order of appearance. Clause
+// addresses and counts are filled in by Program::encode, flow control
+// instructions take either a CF address or a label as ADDR.
+
+use shader::isa::*;
+
+// Largest clauses mesa emits, the count fields allow more
+pub const MAX_ALU_CLAUSE_SLOTS: u32 = 128;
+pub const MAX_FETCH_CLAUSE_INSTS: usize = 16;
+
+pub fn parse_u32(s: &str) -> Option<u32> {
+	if s.starts_with("0x") {
+		u32::from_str_radix(&s[2..], 16).ok()
+	} else {
+		s.parse().ok()
+	}
+}
+
+fn number(s: &str, what: &str) -> Result<u32, String> {
+	parse_u32(s).ok_or_else(|| format!("bad {} '{}'", what, s))
+}
+
+fn bounded(s: &str, what: &str, limit: u32) -> Result<u32, String> {
+	let n = number(s, what)?;
+	if n >= limit {
+		return Err(format!("{} {} out of range, must be below {}", what, n, limit))
+	}
+	Ok(n)
+}
+
+fn chan(c: char) -> Result<u32, String> {
+	match c {
+		'x' => Ok(0), 'y' => Ok(1), 'z' => Ok(2), 'w' => Ok(3),
+		_ => Err(format!("bad channel '{}'", c))
+	}
+}
+
+fn single_chan(s: &str) -> Result<u32, String> {
+	let mut chars = s.chars();
+	match (chars.next(), chars.next()) {
+		(Some(c), None) => chan(c),
+		_ => Err(format!("expected one channel, got '{}'", s))
+	}
+}
+
+fn swizzle(s: &str) -> Result<[u32; 4], String> {
+	let sel: Vec<u32> = s.chars().map(|c| match c {
+		'x' => Ok(SEL_X), 'y' => Ok(SEL_Y), 'z' => Ok(SEL_Z), 'w' => Ok(SEL_W),
+		'0' => Ok(SEL_0), '1' => Ok(SEL_1), '_' => Ok(SEL_MASK),
+		_ => Err(format!("bad swizzle '{}'", s))
+	}).collect::<Result<_, _>>()?;
+	if sel.len() != 4 {
+		return Err(format!("swizzle '{}' needs four components", s))
+	}
+	Ok([sel[0], sel[1], sel[2], sel[3]])
+}
+
+// "R12[AR]" -> (12, true)
+fn gpr(s: &str) -> Result<(u32, bool), String> {
+	let (base, rel) = if s.ends_with("[AR]") { (&s[..s.len()-4], true) } else { (s, false) };
+	if !base.starts_with('R') {
+		return Err(format!("expected a register, got '{}'", s))
+	}
+	Ok((bounded(&base[1..], "register", 128)?, rel))
+}
+
+// "R1.xy01" -> (1, false, "xy01")
+fn gpr_swizzle(s: &str) -> Result<(u32, bool, &str), String> {
+	match s.rfind('.') {
+		Some(dot) => {
+			let (n, rel) = gpr(&s[..dot])?;
+			Ok((n, rel, &s[dot+1..]))
+		},
+		None => Err(format!("expected register.swizzle, got '{}'", s))
+	}
+}
+
+// "NAME" or "NAME:value"
+fn split_flag(flag: &str) -> (&str, &str) {
+	match flag.find(':') {
+		Some(colon) => (&flag[..colon], &flag[colon+1..]),
+		None => (flag, "")
+	}
+}
+
+fn lookup(table: &[(u32, &'static str)], raw_prefix: &str, name: &str) -> Option<u32> {
+	value_of(table, name).or_else(|| {
+		if name.starts_with(raw_prefix) { parse_u32(&name[raw_prefix.len()..]) } else { None }
+	})
+}
+
+fn alu_src(s: &str, literals: &mut Vec<u32>, kcache: &[Kcache; 2]) -> Result<AluSrc, String> {
+	let mut src = AluSrc::default();
+	// named constants first, "-1" isn't a negated "1"
+	if let Some(sel) = value_of(ALU_SRC_NAMES, s) {
+		src.sel = sel;
+		return Ok(src)
+	}
+	let mut s = s;
+	if s.starts_with('-') {
+		src.neg = true;
+		s = &s[1..];
+	}
+	if s.len() >= 2 && s.starts_with('|') && s.ends_with('|') {
+		src.abs = true;
+		s = &s[1..s.len()-1];
+	}
+	if let Some(sel) = value_of(ALU_SRC_NAMES, s) {
+		src.sel = sel;
+		return Ok(src)
+	}
+	if s.starts_with("0x") {
+		let value = number(s, "literal")?;
+		let index = match literals.iter().position(|&l| l == value) {
+			Some(index) => index,
+			None => {
+				if literals.len() == 4 {
+					return Err("more than four literals in one group".to_owned())
+				}
+				literals.push(value);
+				literals.len() - 1
+			}
+		};
+		src.sel = ALU_SRC_LITERAL;
+		src.chan = index as u32;
+		return Ok(src)
+	}
+
+	let dot = s.rfind('.').ok_or_else(|| format!("bad operand '{}'", s))?;
+	src.chan = single_chan(&s[dot+1..])?;
+	let mut base = &s[..dot];
+	if base.ends_with("[AR]") {
+		src.rel = true;
+		base = &base[..base.len()-4];
+	}
+
+	if base == "PV" {
+		src.sel = ALU_SRC_PV;
+	} else if base == "L" {
+		if src.chan as usize >= literals.len() {
+			literals.resize(src.chan as usize + 1, 0);
+		}
+		src.sel = ALU_SRC_LITERAL;
+	} else if base.starts_with("Param") {
+		src.sel = ALU_SRC_PARAM_BASE + bounded(&base[5..], "parameter", 32)?;
+	} else if base.starts_with("SRC") {
+		src.sel = bounded(&base[3..], "source select", 512)?;
+	} else if base.starts_with("KC") && base.ends_with(']') {
+		let open = base.find('[').ok_or_else(|| format!("bad constant '{}'", s))?;
+		let bank = bounded(&base[2..open], "kcache bank", 4)?;
+		let index = bounded(&base[open+1..base.len()-1], "constant", 32)?;
+		if bank < 2 {
+			let lock = &kcache[bank as usize];
+			let size = match lock.mode {
+				KCACHE_NOP => return Err(format!("KC{} used but not locked by the ALU clause", bank)),
+				KCACHE_LOCK_2 => 32,
+				_ => 16
+			};
+			if index >= size {
+				return Err(format!("KC{}[{}] outside the {} locked constants", bank, index, size))
+			}
+			src.sel = if bank == 0 { ALU_SRC_KCACHE0_BASE } else { ALU_SRC_KCACHE1_BASE } + index;
+		} else {
+			src.sel = 256 + (bank - 2) * 32 + index;
+		}
+	} else {
+		let (n, _) = gpr(base)?;
+		src.sel = n;
+	}
+	Ok(src)
+}
+
+fn op(name: &str) -> Result<(u32, bool, usize), String> {
+	if let Some(op) = lookup(OP3_NAMES, "OP3_", name) {
+		return Ok((op, true, 3))
+	}
+	if let Some((op, nsrc)) = op2_by_name(name) {
+		return Ok((op, false, nsrc as usize))
+	}
+	if name.starts_with("OP2_") {
+		return Ok((number(&name[4..], "opcode")?, false, 2))
+	}
+	Err(format!("unknown ALU instruction '{}'", name))
+}
+
+// Operand list: comma separated, then space separated flags
+fn operands_and_flags<'a, 'b>(tokens: &'b [&'a str], count: usize) -> Result<(Vec<&'a str>, &'b [&'a str]), String> {
+	if tokens.len() < count {
+		return Err(format!("expected {} operands", count))
+	}
+	let mut operands = Vec::new();
+	for (i, t) in tokens[..count].iter().enumerate() {
+		// the last one may have a comma too, before the flags
+		if i + 1 < count && !t.ends_with(',') {
+			return Err(format!("expected ',' after '{}'", t))
+		}
+		operands.push(t.trim_end_matches(','));
+	}
+	Ok((operands, &tokens[count..]))
+}
+
+struct Group {
+	slots: Vec<u32>,
+	insts: Vec<AluInst>,
+	literals: Vec<u32>
+}
+
+fn alu_inst(tokens: &[&str], group: &mut Group, kcache: &[Kcache; 2], chip: Chip) -> Result<(), String> {
+	let slot = match tokens.get(0).cloned() {
+		Some("x:") => 0, Some("y:") => 1, Some("z:") => 2, Some("w:") => 3,
+		Some("t:") if chip == Chip::Evergreen => 4,
+		Some(t) => return Err(format!("expected a slot, got '{}'", t)),
+		None => return Err("expected a slot".to_owned())
+	};
+	let name = tokens.get(1).ok_or("expected an instruction")?;
+	let (opcode, op3, nsrc) = op(name)?;
+	let (operands, flags) = operands_and_flags(&tokens[2..], nsrc + 1)?;
+
+	let mut inst = AluInst::default();
+	inst.op = opcode;
+	inst.op3 = op3;
+	inst.write = true;
+
+	let (dst, dst_chan) = match operands[0].rfind('.') {
+		Some(dot) => (&operands[0][..dot], single_chan(&operands[0][dot+1..])?),
+		None => return Err(format!("bad destination '{}'", operands[0]))
+	};
+	inst.dst_chan = dst_chan;
+	if dst == "__" {
+		if op3 {
+			return Err("OP3 instructions always write their destination".to_owned())
+		}
+		inst.write = false;
+	} else {
+		let (n, rel) = gpr(dst)?;
+		inst.dst_gpr = n;
+		inst.dst_rel = rel;
+	}
+	for i in 0..nsrc {
+		inst.src[i] = alu_src(operands[i+1], &mut group.literals, kcache)?;
+		if op3 && inst.src[i].abs {
+			return Err("OP3 instructions have no absolute value modifier".to_owned())
+		}
+	}
+	// unused sources repeat the channel of the first, like mesa does
+	for i in nsrc.max(1)..(if op3 { 3 } else { 2 }) {
+		inst.src[i].chan = inst.src[0].chan;
+	}
+
+	for flag in flags {
+		let (name, value) = split_flag(flag);
+		match name {
+			"CLAMP" => inst.clamp = true,
+			"OMOD" if !op3 => inst.omod = bounded(value, "OMOD", 4)?,
+			"UPDATE_EXEC_MASK" if !op3 => inst.update_exec_mask = true,
+			"UPDATE_PRED" if !op3 => inst.update_pred = true,
+			"PRED_SEL_ZERO" => inst.pred_sel = 2,
+			"PRED_SEL_ONE" => inst.pred_sel = 3,
+			"PRED_SEL" => inst.pred_sel = bounded(value, "PRED_SEL", 4)?,
+			"INDEX_MODE" => inst.index_mode = bounded(value, "INDEX_MODE", 8)?,
+			"BS" => inst.bank_swizzle = bounded(value, "bank swizzle", 6)?,
+			_ => return Err(format!("unknown flag '{}' for {}", flag, tokens[1]))
+		}
+	}
+
+	// slot rules
+	if let Some(&prev) = group.slots.last() {
+		if slot <= prev {
+			return Err("slots of a group must be in x, y, z, w, t order".to_owned())
+		}
+	}
+	if slot < 4 && slot != inst.dst_chan {
+		return Err(format!("slot {} can't write channel {}", "xyzw".as_bytes()[slot as usize] as char,
+			"xyzw".as_bytes()[inst.dst_chan as usize] as char))
+	}
+	if chip == Chip::Evergreen && !op3 {
+		if slot == 4 && is_vector_only(inst.op) {
+			return Err(format!("{} can't run in the trans slot", tokens[1]))
+		}
+		if slot != 4 && is_trans_only(inst.op) {
+			return Err(format!("{} only runs in the trans slot", tokens[1]))
+		}
+	}
+	group.slots.push(slot);
+	group.insts.push(inst);
+	Ok(())
+}
+
+fn vtx_inst(name: &str, tokens: &[&str]) -> Result<VtxInst, String> {
+	let mut v = VtxInst::default();
+	v.inst = lookup(VC_NAMES, "VC_INST_", name).ok_or_else(|| format!("unknown fetch instruction '{}'", name))?;
+	let (operands, flags) = operands_and_flags(tokens, 2)?;
+	let (dst, dst_rel, dst_sel) = gpr_swizzle(operands[0])?;
+	let (src, src_rel, src_sel) = gpr_swizzle(operands[1])?;
+	v.dst_gpr = dst;
+	v.dst_rel = dst_rel;
+	v.dst_sel = swizzle(dst_sel)?;
+	v.src_gpr = src;
+	v.src_rel = src_rel;
+	v.src_sel_x = single_chan(src_sel)?;
+	for flag in flags {
+		let (name, value) = split_flag(flag);
+		match name {
+			"BUFFER" => v.buffer_id = bounded(value, "buffer id", 256)?,
+			"INSTANCE_DATA" => v.fetch_type = VTX_FETCH_INSTANCE_DATA,
+			"NO_INDEX_OFFSET" => v.fetch_type = VTX_FETCH_NO_INDEX_OFFSET,
+			"FETCH_TYPE" => v.fetch_type = bounded(value, "fetch type", 4)?,
+			"FORMAT" => v.data_format = match value_of(FMT_NAMES, value) {
+				Some(format) => format,
+				None => bounded(value, "format", 64)?
+			},
+			"NUM" => v.num_format_all = match value {
+				"NORM" => NUM_FORMAT_NORM,
+				"INT" => NUM_FORMAT_INT,
+				"SCALED" => NUM_FORMAT_SCALED,
+				_ => bounded(value, "number format", 4)?
+			},
+			"SIGNED" => v.format_comp_all = true,
+			"SRF_MODE" => v.srf_mode_all = true,
+			"USE_CONST_FIELDS" => v.use_const_fields = true,
+			"MFC" => v.mega_fetch_count = bounded(value, "mega fetch count", 64)?,
+			"MEGA_FETCH" => v.mega_fetch = true,
+			"OFFSET" => v.offset = bounded(value, "offset", 1 << 16)?,
+			"ENDIAN" => v.endian_swap = bounded(value, "endian swap", 4)?,
+			"CONST_BUF_NO_STRIDE" => v.const_buf_no_stride = true,
+			"WHOLE_QUAD" => v.fetch_whole_quad = true,
+			"ALT_CONST" => v.alt_const = true,
+			"INDEX_MODE" => v.buffer_index_mode = bounded(value, "index mode", 4)?,
+			_ => return Err(format!("unknown flag '{}' for {}", flag, name))
+		}
+	}
+	Ok(v)
+}
+
+fn tex_inst(name: &str, tokens: &[&str]) -> Result<TexInst, String> {
+	let mut t = TexInst::default();
+	t.inst = lookup(TEX_NAMES, "TEX_INST_", name).ok_or_else(|| format!("unknown texture instruction '{}'", name))?;
+	let (operands, flags) = operands_and_flags(tokens, 2)?;
+	let (dst, dst_rel, dst_sel) = gpr_swizzle(operands[0])?;
+	let (src, src_rel, src_sel) = gpr_swizzle(operands[1])?;
+	t.dst_gpr = dst;
+	t.dst_rel = dst_rel;
+	t.dst_sel = swizzle(dst_sel)?;
+	t.src_gpr = src;
+	t.src_rel = src_rel;
+	t.src_sel = swizzle(src_sel)?;
+	for flag in flags {
+		let (name, value) = split_flag(flag);
+		match name {
+			"RID" => t.resource_id = bounded(value, "resource id", 256)?,
+			"SID" => t.sampler_id = bounded(value, "sampler id", 32)?,
+			"CT" => {
+				if value.len() != 4 || !value.chars().all(|c| c == 'N' || c == 'U') {
+					return Err(format!("bad coordinate types '{}'", value))
+				}
+				for (i, c) in value.chars().enumerate() { t.coord_type[i] = c == 'N' }
+			},
+			"OFFSET" => {
+				let offsets: Vec<&str> = value.split(',').collect();
+				if offsets.len() != 3 {
+					return Err(format!("bad texel offset '{}'", value))
+				}
+				for i in 0..3 { t.offset[i] = bounded(offsets[i], "texel offset", 32)? }
+			},
+			"LOD_BIAS" => t.lod_bias = bounded(value, "LOD bias", 128)?,
+			"MOD" => t.inst_mod = bounded(value, "instruction modifier", 4)?,
+			"WHOLE_QUAD" => t.fetch_whole_quad = true,
+			"ALT_CONST" => t.alt_const = true,
+			"RESOURCE_INDEX_MODE" => t.resource_index_mode = bounded(value, "index mode", 4)?,
+			"SAMPLER_INDEX_MODE" => t.sampler_index_mode = bounded(value, "index mode", 4)?,
+			_ => return Err(format!("unknown flag '{}' for {}", flag, name))
+		}
+	}
+	Ok(t)
+}
+
+fn raw_inst(tokens: &[&str]) -> Result<[u32; 4], String> {
+	if tokens.len() != 4 {
+		return Err("RAW takes four dwords".to_owned())
+	}
+	let mut w = [0; 4];
+	for i in 0..4 { w[i] = number(tokens[i], "dword")? }
+	Ok(w)
+}
+
+// "KC0[CB1:32-47]" or with ":LOOP" before the bracket
+fn kcache_lock(flag: &str) -> Result<(usize, Kcache), String> {
+	let bad = || format!("bad constant lock '{}'", flag);
+	let index = match &flag[..4] { "KC0[" => 0, "KC1[" => 1, _ => return Err(bad()) };
+	let inner = flag[4..].trim_end_matches(']');
+	let (inner, mode) = if inner.ends_with(":LOOP") {
+		(&inner[..inner.len()-5], KCACHE_LOCK_LOOP_INDEX)
+	} else {
+		(inner, KCACHE_LOCK_1)
+	};
+	if !inner.starts_with("CB") { return Err(bad()) }
+	let colon = inner.find(':').ok_or_else(bad)?;
+	let dash = inner.find('-').ok_or_else(bad)?;
+	let bank = bounded(&inner[2..colon], "constant buffer", 16)?;
+	let first = number(&inner[colon+1..dash], "constant")?;
+	let last = number(&inner[dash+1..], "constant")?;
+	if first % 16 != 0 || first / 16 >= 256 {
+		return Err(format!("constant lock must start at a multiple of 16 below 4096, not {}", first))
+	}
+	let mode = match (last + 1).checked_sub(first) {
+		Some(16) => mode,
+		Some(32) if mode == KCACHE_LOCK_1 => KCACHE_LOCK_2,
+		_ => return Err(format!("constant lock '{}' must cover 16 or 32 constants", flag))
+	};
+	Ok((index, Kcache { bank: bank, mode: mode, addr: first / 16 }))
+}
+
+struct Assembler {
+	chip: Chip,
+	cf: Vec<CfInst>,
+	labels: Vec<(String, u32)>,
+	fixups: Vec<(usize, String, usize)>, // cf index, label, line
+	group: Option<Group>
+}
+
+impl Assembler {
+	fn finish_group(&mut self) {
+		if let Some(group) = self.group.take() {
+			if let Some(&mut CfInst::Alu(_, ref mut groups)) = self.cf.last_mut() {
+				groups.push(AluGroup { insts: group.insts, literals: group.literals });
+			}
+		}
+	}
+
+	fn cf_line(&mut self, tokens: &[&str], line: usize) -> Result<(), String> {
+		self.finish_group();
+		let name = tokens[0];
+		let flags = &tokens[1..];
+
+		if let Some(inst) = value_of(CF_ALU_NAMES, name) {
+			let mut c = CfAluWord::default();
+			c.inst = inst;
+			for flag in flags {
+				match split_flag(flag) {
+					("ALT_CONST", _) => c.alt_const = true,
+					("WHOLE_QUAD_MODE", _) => c.whole_quad_mode = true,
+					("BARRIER", _) => c.barrier = true,
+					_ if flag.starts_with("KC") => {
+						let (index, lock) = kcache_lock(flag)?;
+						c.kcache[index] = lock;
+					},
+					_ => return Err(format!("unknown flag '{}' for {}", flag, name))
+				}
+			}
+			self.cf.push(CfInst::Alu(c, Vec::new()));
+			return Ok(())
+		}
+
+		let inst = lookup(CF_NAMES, "CF_INST_", name).ok_or_else(|| format!("unknown CF instruction '{}'", name))?;
+		if inst >= CF_INST_MEM_STREAM0_BUF0 {
+			let e = self.export(inst, tokens)?;
+			self.cf.push(CfInst::Export(e));
+			return Ok(())
+		}
+
+		let mut c = CfWord::default();
+		c.inst = inst;
+		c.count = 1;
+		let clause = is_fetch_clause(inst);
+		for flag in flags {
+			let (flag_name, value) = split_flag(flag);
+			match flag_name {
+				"ADDR" if !clause => {
+					match parse_u32(value) {
+						Some(addr) => c.addr = addr,
+						None => self.fixups.push((self.cf.len(), value.to_owned(), line))
+					}
+				},
+				"COUNT" if !clause => c.count = bounded(value, "count", 65)?,
+				"POP" => c.pop_count = bounded(value, "pop count", 8)?,
+				"CF_CONST" => c.cf_const = bounded(value, "CF constant", 32)?,
+				"COND" => c.cond = match value {
+					"ACTIVE" => CF_COND_ACTIVE,
+					"FALSE" => CF_COND_FALSE,
+					"BOOL" => CF_COND_BOOL,
+					"NOT_BOOL" => CF_COND_NOT_BOOL,
+					_ => return Err(format!("bad condition '{}'", value))
+				},
+				"JTS" => c.jumptable_sel = bounded(value, "jump table select", 8)?,
+				"VALID_PIXEL_MODE" => c.valid_pixel_mode = true,
+				"WHOLE_QUAD_MODE" => c.whole_quad_mode = true,
+				"BARRIER" => c.barrier = true,
+				"END_OF_PROGRAM" => c.end_of_program = true,
+				_ => return Err(format!("unknown flag '{}' for {}", flag, name))
+			}
+		}
+		self.cf.push(if clause { CfInst::Fetch(c, Vec::new()) } else { CfInst::Cf(c) });
+		Ok(())
+	}
+
+	fn export(&self, inst: u32, tokens: &[&str]) -> Result<CfExportWord, String> {
+		let mut e = CfExportWord::default();
+		e.inst = inst;
+		e.burst_count = 1;
+		let flags;
+		if is_swizzled_export(inst) {
+			if tokens.len() < 3 {
+				return Err(format!("{} needs a target and a register", tokens[0]))
+			}
+			let target = tokens[1];
+			let (ty, index) = if target.starts_with("PIXEL") {
+				(EXPORT_PIXEL, &target[5..])
+			} else if target.starts_with("POS") {
+				(EXPORT_POS, &target[3..])
+			} else if target.starts_with("PARAM") {
+				(EXPORT_PARAM, &target[5..])
+			} else {
+				return Err(format!("bad export target '{}'", target))
+			};
+			e.ty = ty;
+			e.array_base = if ty == EXPORT_POS {
+				let n: i32 = index.parse().map_err(|_| format!("bad export target '{}'", target))?;
+				(ARRAY_BASE_POS0 as i32 + n) as u32
+			} else {
+				number(index, "export target")?
+			};
+			let (n, rel, sel) = gpr_swizzle(tokens[2])?;
+			e.rw_gpr = n;
+			e.rw_rel = rel;
+			e.sel = swizzle(sel)?;
+			flags = &tokens[3..];
+		} else {
+			if tokens.len() < 3 {
+				return Err(format!("{} needs a type and a register", tokens[0]))
+			}
+			e.ty = match tokens[1] {
+				"WRITE" => EXPORT_WRITE,
+				"WRITE_IND" => EXPORT_WRITE_IND,
+				"WRITE_ACK" => EXPORT_WRITE_ACK,
+				"WRITE_IND_ACK" => EXPORT_WRITE_IND_ACK,
+				t => return Err(format!("bad memory export type '{}'", t))
+			};
+			let (n, rel, mask) = gpr_swizzle(tokens[2])?;
+			e.rw_gpr = n;
+			e.rw_rel = rel;
+			if mask.len() != 4 {
+				return Err(format!("bad component mask '{}'", mask))
+			}
+			for (i, c) in mask.chars().enumerate() {
+				match c {
+					'_' => (),
+					c if chan(c) == Ok(i as u32) => e.comp_mask |= 1 << i,
+					_ => return Err(format!("bad component mask '{}'", mask))
+				}
+			}
+			flags = &tokens[3..];
+		}
+		for flag in flags {
+			let (name, value) = split_flag(flag);
+			match name {
+				"INDEX" => e.index_gpr = gpr(value)?.0,
+				"ARRAY_BASE" => e.array_base = bounded(value, "array base", 1 << 13)?,
+				"ARRAY_SIZE" if !is_swizzled_export(inst) => e.array_size = bounded(value, "array size", 1 << 12)?,
+				"ELEM_SIZE" => e.elem_size = bounded(value, "element size", 4)?,
+				"BURST" => {
+					e.burst_count = number(value, "burst count")?;
+					if e.burst_count < 1 || e.burst_count > 16 {
+						return Err(format!("burst count {} must be 1 to 16", e.burst_count))
+					}
+				},
+				"MARK" => e.mark = true,
+				"VALID_PIXEL_MODE" => e.valid_pixel_mode = true,
+				"BARRIER" => e.barrier = true,
+				"END_OF_PROGRAM" => e.end_of_program = true,
+				_ => return Err(format!("unknown flag '{}' for {}", flag, tokens[0]))
+			}
+		}
+		Ok(e)
+	}
+
+	fn clause_line(&mut self, tokens: &[&str]) -> Result<(), String> {
+		let alu_kcache = match self.cf.last() {
+			Some(&CfInst::Alu(ref c, _)) => Some(c.kcache),
+			_ => None
+		};
+		if let Some(kcache) = alu_kcache {
+			let mut tokens = tokens;
+			if tokens[0].chars().all(|c| c.is_digit(10)) {
+				self.finish_group();
+				self.group = Some(Group { slots: Vec::new(), insts: Vec::new(), literals: Vec::new() });
+				tokens = &tokens[1..];
+			}
+			let chip = self.chip;
+			return match self.group {
+				Some(ref mut group) => alu_inst(tokens, group, &kcache, chip),
+				None => Err("the first instruction of a group needs a group number".to_owned())
+			}
+		}
+		match self.cf.last_mut() {
+			Some(&mut CfInst::Fetch(ref c, ref mut fetches)) => {
+				let name = tokens[0];
+				let fetch = if name == "RAW" {
+					FetchInst::Raw(raw_inst(&tokens[1..])?)
+				} else {
+					match c.inst {
+						CF_INST_VC | CF_INST_VC_ACK => FetchInst::Vtx(vtx_inst(name, &tokens[1..])?),
+						CF_INST_TC | CF_INST_TC_ACK => FetchInst::Tex(tex_inst(name, &tokens[1..])?),
+						_ => return Err("GDS clauses only take RAW instructions".to_owned())
+					}
+				};
+				if fetches.len() == MAX_FETCH_CLAUSE_INSTS {
+					return Err(format!("more than {} instructions in a fetch clause", MAX_FETCH_CLAUSE_INSTS))
+				}
+				fetches.push(fetch);
+				Ok(())
+			},
+			_ => Err("clause instruction outside of a clause".to_owned())
+		}
+	}
+
+	fn finish(mut self) -> Result<Program, String> {
+		self.finish_group();
+		for (index, label, line) in self.fixups.drain(..) {
+			let addr = match self.labels.iter().find(|l| l.0 == label) {
+				Some(l) => l.1,
+				None => return Err(format!("line {}: unknown label '{}'", line, label))
+			};
+			if let CfInst::Cf(ref mut c) = self.cf[index] { c.addr = addr }
+		}
+		let mut end = false;
+		for (i, inst) in self.cf.iter().enumerate() {
+			match *inst {
+				CfInst::Alu(_, ref groups) => {
+					let slots: u32 = groups.iter().map(|g| g.slots()).sum();
+					if groups.is_empty() {
+						return Err(format!("ALU clause {} is empty", i))
+					}
+					if slots > MAX_ALU_CLAUSE_SLOTS {
+						return Err(format!("ALU clause {} takes {} slots, more than {}", i, slots, MAX_ALU_CLAUSE_SLOTS))
+					}
+				},
+				CfInst::Fetch(_, ref fetches) if fetches.is_empty() =>
+					return Err(format!("fetch clause {} is empty", i)),
+				CfInst::Cf(ref c) => end |= c.end_of_program || (self.chip == Chip::Cayman && c.inst == CF_INST_END),
+				CfInst::Export(ref e) => end |= e.end_of_program,
+				_ => ()
+			}
+		}
+		if !end {
+			return Err("no instruction ends the program".to_owned())
+		}
+		Ok(Program { cf: self.cf })
+	}
+}
+
+pub fn assemble(source: &str, chip: Chip) -> Result<Program, String> {
+	let mut asm = Assembler {
+		chip: chip,
+		cf: Vec::new(),
+		labels: Vec::new(),
+		fixups: Vec::new(),
+		group: None
+	};
+	for (i, text) in source.lines().enumerate() {
+		let line = i + 1;
+		let code = match text.find(';') { Some(c) => &text[..c], None => text };
+		let tokens: Vec<&str> = code.split_whitespace().collect();
+		if tokens.is_empty() { continue }
+
+		let indented = code.starts_with(char::is_whitespace);
+		let result = if !indented && tokens.len() == 1 && tokens[0].ends_with(':') {
+			let label = tokens[0].trim_end_matches(':');
+			if asm.labels.iter().any(|l| l.0 == label) {
+				Err(format!("label '{}' defined twice", label))
+			} else {
+				asm.finish_group();
+				asm.labels.push((label.to_owned(), asm.cf.len() as u32));
+				Ok(())
+			}
+		} else if indented {
+			asm.clause_line(&tokens)
+		} else {
+			asm.cf_line(&tokens, line)
+		};
+		if let Err(e) = result {
+			return Err(format!("line {}: {}", line, e))
+		}
+	}
+	asm.finish()
+}
@@ -0,0 +1,650 @@
+// Builds Evergreen shaders from vec4 expressions instead of hand written
+// ALU groups. Every operation becomes a node producing a vec4 in a GPR;
+// program() drops unused nodes and channels, puts the nodes into fetch and
+// ALU clauses, allocates registers and appends the exports.
+//
+//	let mut b = ShaderBuilder::new(Stage::Ps);
+//	let uv = b.param(0);
+//	let texel = b.sample(0, 0, uv.swizzle("xy00"));
+//	let tint = b.uniform(0, 0);
+//	let color = b.mul(texel, tint);
+//	b.export_color(0, color);
+//	let ps = b.program()?;
+//
+// A VS starts with the vertex index in R0.x and a PS with the perspective
+// barycentrics in R0.xy, so R0 is never allocated. Uniforms are read
+// through the kcache, each shader can use constants 0..31 of up to two
+// constant buffers.
+
+use shader::isa::*;
+use shader::asm::{MAX_ALU_CLAUSE_SLOTS, MAX_FETCH_CLAUSE_INSTS};
+use shader::object::Stage;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Src {
+	Node(usize),
+	Gpr(u32),
+	Uniform(u32, u32), // buffer, constant
+	Literal([u32; 4])
+}
+
+// An operand: a vec4 with a swizzle (SEL_X..SEL_W, SEL_0, SEL_1), which
+// costs nothing in the instruction that reads it.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Value {
+	src: Src,
+	swizzle: [u32; 4]
+}
+
+const XYZW: [u32; 4] = [SEL_X, SEL_Y, SEL_Z, SEL_W];
+
+impl Value {
+	fn new(src: Src) -> Value {
+		Value { src: src, swizzle: XYZW }
+	}
+
+	// Swizzles from a string of "xyzw01", eg. "xy01" or "www"; the last
+	// component is repeated for short strings.
+	pub fn swizzle(&self, s: &str) -> Value {
+		assert!(!s.is_empty() && s.len() <= 4, "bad swizzle {}", s);
+		let mut v = *self;
+		let sels: Vec<u32> = s.chars().map(|c| match c {
+			'x' => SEL_X, 'y' => SEL_Y, 'z' => SEL_Z, 'w' => SEL_W, '0' => SEL_0, '1' => SEL_1,
+			_ => panic!("bad swizzle {}", s)
+		}).collect();
+		for i in 0..4 {
+			let sel = sels[i.min(sels.len() - 1)];
+			v.swizzle[i] = if sel < 4 { self.swizzle[sel as usize] } else { sel };
+		}
+		v
+	}
+
+	pub fn x(&self) -> Value { self.swizzle("x") }
+	pub fn y(&self) -> Value { self.swizzle("y") }
+
+	fn node(&self) -> Option<usize> {
+		if let Src::Node(n) = self.src { Some(n) } else { None }
+	}
+}
+
+#[derive(Clone, Debug)]
+enum Op {
+	Alu(u32, Vec<Value>, bool), // OP2 with CLAMP
+	Alu3(u32, [Value; 3]),
+	Interp(u32), // parameter
+	Vertex { buffer_id: u32, offset: u32, data_format: u32, components: u32 },
+	Sample { resource_id: u32, sampler_id: u32, coords: Value }
+}
+
+impl Op {
+	fn operands(&self) -> Vec<Value> {
+		match *self {
+			Op::Alu(_, ref srcs, _) => srcs.clone(),
+			Op::Alu3(_, srcs) => srcs.to_vec(),
+			Op::Sample { coords, .. } => vec![coords],
+			_ => Vec::new()
+		}
+	}
+
+	fn is_fetch(&self) -> bool {
+		match *self {
+			Op::Vertex { .. } | Op::Sample { .. } => true,
+			_ => false
+		}
+	}
+}
+
+struct ExportValue {
+	ty: u32,
+	array_base: u32,
+	value: Value
+}
+
+pub struct ShaderBuilder {
+	stage: Stage,
+	nodes: Vec<Op>,
+	exports: Vec<ExportValue>
+}
+
+fn bits(v: [f32; 4]) -> [u32; 4] {
+	[v[0].to_bits(), v[1].to_bits(), v[2].to_bits(), v[3].to_bits()]
+}
+
+fn is_inline_constant(v: u32) -> bool {
+	v == 0 || v == 1.0f32.to_bits() || v == 0.5f32.to_bits()
+}
+
+impl ShaderBuilder {
+	pub fn new(stage: Stage) -> ShaderBuilder {
+		assert!(stage == Stage::Vs || stage == Stage::Ps, "only vertex and pixel shaders can be built");
+		ShaderBuilder { stage: stage, nodes: Vec::new(), exports: Vec::new() }
+	}
+
+	fn push(&mut self, op: Op) -> Value {
+		self.nodes.push(op);
+		Value::new(Src::Node(self.nodes.len() - 1))
+	}
+
+	// Inputs
+
+	// The vertex index, as an integer in x
+	pub fn vertex_index(&self) -> Value {
+		assert!(self.stage == Stage::Vs);
+		Value::new(Src::Gpr(0)).swizzle("x000")
+	}
+
+	// components (1..4) values of data_format from fetch buffer buffer_id,
+	// missing components read as 0, 0, 0, 1
+	pub fn vertex_input(&mut self, buffer_id: u32, offset: u32, data_format: u32, components: u32) -> Value {
+		assert!(self.stage == Stage::Vs);
+		assert!(components >= 1 && components <= 4);
+		self.push(Op::Vertex { buffer_id: buffer_id, offset: offset, data_format: data_format, components: components })
+	}
+
+	// Parameter n of the PS, interpolated with the perspective barycentrics
+	pub fn param(&mut self, n: u32) -> Value {
+		assert!(self.stage == Stage::Ps);
+		self.push(Op::Interp(n))
+	}
+
+	pub fn uniform(&self, buffer: u32, index: u32) -> Value {
+		assert!(index < 32, "only constants 0..31 can be read");
+		Value::new(Src::Uniform(buffer, index))
+	}
+
+	pub fn constant(&self, v: [f32; 4]) -> Value {
+		Value::new(Src::Literal(bits(v)))
+	}
+
+	// Texture resource resource_id sampled with normalized coordinates
+	pub fn sample(&mut self, resource_id: u32, sampler_id: u32, coords: Value) -> Value {
+		let coords = self.in_gpr(coords);
+		self.push(Op::Sample { resource_id: resource_id, sampler_id: sampler_id, coords: coords })
+	}
+
+	// Operations, componentwise unless noted
+
+	// Operands that one ALU group can't read together are copied first:
+	// more than four distinct literals, or more than one vec4 uniform.
+	fn alu(&mut self, op: u32, srcs: &[Value]) -> Value {
+		let srcs = self.fit_group(srcs);
+		self.push(Op::Alu(op, srcs, false))
+	}
+
+	fn alu3(&mut self, op: u32, srcs: [Value; 3]) -> Value {
+		let s = self.fit_group(&srcs);
+		self.push(Op::Alu3(op, [s[0], s[1], s[2]]))
+	}
+
+	fn fit_group(&mut self, srcs: &[Value]) -> Vec<Value> {
+		let mut literals = Vec::new();
+		let mut uniforms = 0;
+		let mut out = Vec::new();
+		for &s in srcs {
+			let fits = match s.src {
+				Src::Literal(l) => {
+					let mut merged = literals.clone();
+					for &sel in &s.swizzle {
+						if sel < 4 && !is_inline_constant(l[sel as usize]) && !merged.contains(&l[sel as usize]) {
+							merged.push(l[sel as usize]);
+						}
+					}
+					if merged.len() <= 4 { literals = merged; true } else { false }
+				},
+				Src::Uniform(..) => { uniforms += 1; uniforms <= 1 },
+				_ => true
+			};
+			out.push(if fits { s } else { self.mov(s) });
+		}
+		out
+	}
+
+	// Values not in a GPR are copied into one, for fetches and exports
+	fn in_gpr(&mut self, v: Value) -> Value {
+		match v.src {
+			Src::Node(_) | Src::Gpr(_) => v,
+			_ => self.mov(v)
+		}
+	}
+
+	pub fn mov(&mut self, a: Value) -> Value { self.alu(OP2_MOV, &[a]) }
+	pub fn mul(&mut self, a: Value, b: Value) -> Value { self.alu(OP2_MUL_IEEE, &[a, b]) }
+	pub fn floor(&mut self, a: Value) -> Value { self.alu(OP2_FLOOR, &[a]) }
+	// Signed integers, like the vertex index, to floats
+	pub fn int_to_float(&mut self, a: Value) -> Value { self.alu(OP2_INT_TO_FLT, &[a]) }
+
+	// a * b + c
+	pub fn mad(&mut self, a: Value, b: Value, c: Value) -> Value { self.alu3(OP3_MULADD_IEEE, [a, b, c]) }
+
+	// Clamped to [0, 1]
+	pub fn saturate(&mut self, a: Value) -> Value {
+		let srcs = self.fit_group(&[a]);
+		self.push(Op::Alu(OP2_MOV, srcs, true))
+	}
+
+	// Outputs
+
+	pub fn export_position(&mut self, v: Value) {
+		assert!(self.stage == Stage::Vs);
+		let v = self.in_gpr(v);
+		self.exports.push(ExportValue { ty: EXPORT_POS, array_base: ARRAY_BASE_POS0, value: v });
+	}
+
+	pub fn export_param(&mut self, n: u32, v: Value) {
+		assert!(self.stage == Stage::Vs);
+		let v = self.in_gpr(v);
+		self.exports.push(ExportValue { ty: EXPORT_PARAM, array_base: n, value: v });
+	}
+
+	pub fn export_color(&mut self, n: u32, v: Value) {
+		assert!(self.stage == Stage::Ps);
+		let v = self.in_gpr(v);
+		self.exports.push(ExportValue { ty: EXPORT_PIXEL, array_base: n, value: v });
+	}
+}
+
+// GPRs 124..127 are left free, mesa uses them as clause temporaries
+const MAX_GPR: u32 = 123;
+
+// Bytes of one element of a vertex format
+fn format_bytes(data_format: u32) -> Option<u32> {
+	Some(match data_format {
+		FMT_8 => 1,
+		FMT_16 | FMT_16_FLOAT | FMT_8_8 => 2,
+		FMT_32 | FMT_32_FLOAT | FMT_16_16 | FMT_16_16_FLOAT | FMT_8_8_8_8 => 4,
+		FMT_32_32 | FMT_32_32_FLOAT | FMT_16_16_16_16 | FMT_16_16_16_16_FLOAT => 8,
+		FMT_32_32_32 | FMT_32_32_32_FLOAT => 12,
+		FMT_32_32_32_32 | FMT_32_32_32_32_FLOAT => 16,
+		_ => return None
+	})
+}
+
+// Where things ended up, for lowering the nodes
+struct Lowering {
+	gpr: Vec<u32>,
+	used: Vec<u32>,         // channel mask of each node
+	kcache: Vec<(u32, u32)> // (buffer, KCACHE_LOCK_*) for KC0 and KC1
+}
+
+impl Lowering {
+	fn src(&self, v: &Value, c: usize, literals: &mut Vec<u32>) -> AluSrc {
+		let mut s = AluSrc::default();
+		let sel = v.swizzle[c];
+		if sel == SEL_0 {
+			s.sel = ALU_SRC_0;
+			return s
+		} else if sel == SEL_1 {
+			s.sel = ALU_SRC_1;
+			return s
+		}
+		s.chan = sel;
+		match v.src {
+			Src::Node(n) => s.sel = self.gpr[n],
+			Src::Gpr(r) => s.sel = r,
+			Src::Uniform(buffer, index) => {
+				let k = self.kcache.iter().position(|&(b, _)| b == buffer).unwrap();
+				s.sel = [ALU_SRC_KCACHE0_BASE, ALU_SRC_KCACHE1_BASE][k] + index;
+			},
+			Src::Literal(l) => {
+				let x = l[sel as usize];
+				s.chan = 0;
+				s.sel = if x == 0 {
+					ALU_SRC_0
+				} else if x == 1.0f32.to_bits() {
+					ALU_SRC_1
+				} else if x == 0.5f32.to_bits() {
+					ALU_SRC_0_5
+				} else {
+					s.chan = match literals.iter().position(|&y| y == x) {
+						Some(i) => i as u32,
+						None => { literals.push(x); literals.len() as u32 - 1 }
+					};
+					ALU_SRC_LITERAL
+				};
+			}
+		}
+		s
+	}
+
+	fn inst(&self, op: u32, op3: bool, srcs: &[Value], dst: u32, c: usize, literals: &mut Vec<u32>) -> AluInst {
+		let mut inst = AluInst {
+			op: op,
+			op3: op3,
+			dst_gpr: dst,
+			dst_chan: c as u32,
+			write: true,
+			..AluInst::default()
+		};
+		for (i, v) in srcs.iter().enumerate() {
+			inst.src[i] = self.src(v, c, literals);
+		}
+		// unused sources repeat the channel of src0, like the assembler
+		for i in srcs.len()..3 {
+			inst.src[i].chan = inst.src[0].chan;
+		}
+		inst
+	}
+
+	fn alu_groups(&self, n: usize, op: &Op) -> Vec<AluGroup> {
+		let dst = self.gpr[n];
+		let used = self.used[n];
+		let chans: Vec<usize> = (0..4).filter(|c| used >> c & 1 != 0).collect();
+		let mut groups = Vec::new();
+		match *op {
+			Op::Alu(op, ref srcs, clamp) if is_trans_only(op) => {
+				// one trans slot per group
+				for &c in &chans {
+					let mut literals = Vec::new();
+					let mut inst = self.inst(op, false, srcs, dst, c, &mut literals);
+					inst.clamp = clamp;
+					groups.push(AluGroup { insts: vec![inst], literals: literals });
+				}
+			},
+			Op::Alu(op, ref srcs, clamp) => {
+				let mut g = AluGroup::default();
+				for &c in &chans {
+					let mut inst = self.inst(op, false, srcs, dst, c, &mut g.literals);
+					inst.clamp = clamp;
+					g.insts.push(inst);
+				}
+				groups.push(g);
+			},
+			Op::Alu3(op, ref srcs) => {
+				let mut g = AluGroup::default();
+				for &c in &chans {
+					let inst = self.inst(op, true, srcs, dst, c, &mut g.literals);
+					g.insts.push(inst);
+				}
+				groups.push(g);
+			},
+			Op::Interp(param) => {
+				// like mesa: INTERP_ZW writes z and w, INTERP_XY x and y,
+				// all four slots read j, i, j, i from R0
+				for &(op, mask) in &[(OP2_INTERP_ZW, 0xc), (OP2_INTERP_XY, 0x3)] {
+					if used & mask == 0 { continue }
+					let mut g = AluGroup::default();
+					for c in 0..4 {
+						let mut src = [AluSrc::default(); 3];
+						src[0] = AluSrc { sel: 0, chan: if c % 2 == 0 { 1 } else { 0 }, ..AluSrc::default() };
+						src[1] = AluSrc { sel: ALU_SRC_PARAM_BASE + param, ..AluSrc::default() };
+						let write = (mask & used) >> c & 1 != 0;
+						g.insts.push(AluInst {
+							op: op,
+							src: src,
+							dst_gpr: if write { dst } else { 0 },
+							dst_chan: c,
+							write: write,
+							bank_swizzle: 5, // VEC_210
+							..AluInst::default()
+						});
+					}
+					groups.push(g);
+				}
+			},
+			_ => unreachable!()
+		}
+		groups
+	}
+
+	fn fetch(&self, n: usize, op: &Op) -> Result<FetchInst, String> {
+		let mut dst_sel = [SEL_MASK; 4];
+		match *op {
+			Op::Vertex { buffer_id, offset, data_format, components } => {
+				let bytes = format_bytes(data_format)
+					.ok_or_else(|| format!("vertex format {} is not supported", data_format))?;
+				for c in 0..4 {
+					if self.used[n] >> c & 1 == 0 { continue }
+					dst_sel[c] = if (c as u32) < components { c as u32 } else if c == 3 { SEL_1 } else { SEL_0 };
+				}
+				Ok(FetchInst::Vtx(VtxInst {
+					inst: VC_INST_FETCH,
+					buffer_id: buffer_id,
+					src_gpr: 0,
+					src_sel_x: SEL_X,
+					mega_fetch_count: bytes - 1,
+					dst_gpr: self.gpr[n],
+					dst_sel: dst_sel,
+					data_format: data_format,
+					num_format_all: NUM_FORMAT_SCALED,
+					format_comp_all: true,
+					offset: offset,
+					mega_fetch: true,
+					..VtxInst::default()
+				}))
+			},
+			Op::Sample { resource_id, sampler_id, coords } => {
+				for c in 0..4 {
+					if self.used[n] >> c & 1 != 0 { dst_sel[c] = c as u32 }
+				}
+				Ok(FetchInst::Tex(TexInst {
+					inst: TEX_INST_SAMPLE,
+					resource_id: resource_id,
+					sampler_id: sampler_id,
+					src_gpr: match coords.src { Src::Node(c) => self.gpr[c], Src::Gpr(r) => r, _ => unreachable!() },
+					src_sel: coords.swizzle,
+					dst_gpr: self.gpr[n],
+					dst_sel: dst_sel,
+					coord_type: [true; 4],
+					..TexInst::default()
+				}))
+			},
+			_ => unreachable!()
+		}
+	}
+}
+
+impl ShaderBuilder {
+	// Channels of each node something reads, zero for dead nodes
+	fn used_channels(&self) -> Vec<u32> {
+		let mut used = vec![0u32; self.nodes.len()];
+		fn mark(used: &mut Vec<u32>, v: &Value, chans: u32) {
+			if let Some(n) = v.node() {
+				for c in 0..4 {
+					if chans >> c & 1 != 0 && v.swizzle[c] < 4 {
+						used[n] |= 1 << v.swizzle[c];
+					}
+				}
+			}
+		}
+		for e in &self.exports {
+			mark(&mut used, &e.value, 0xf);
+		}
+		for n in (0..self.nodes.len()).rev() {
+			if used[n] == 0 { continue }
+			let chans = match self.nodes[n] {
+				Op::Sample { .. } => 0xf,
+				_ => used[n]
+			};
+			for v in self.nodes[n].operands() {
+				mark(&mut used, &v, chans);
+			}
+		}
+		used
+	}
+
+	// Fetches that are ready go first, then every ALU node that is ready
+	// or becomes ready in the same clause, until all nodes are placed.
+	// Fetches in one clause don't read each other's results.
+	fn schedule(&self, used: &[u32]) -> Vec<Vec<usize>> {
+		let n = self.nodes.len();
+		let mut done: Vec<bool> = used.iter().map(|&u| u == 0).collect();
+		let mut clauses = Vec::new();
+		let ready = |i: usize, done: &[bool]| !done[i] &&
+			self.nodes[i].operands().iter().all(|v| v.node().map_or(true, |d| done[d]));
+		loop {
+			let mut progress = false;
+			for &vertex in &[true, false] {
+				let batch: Vec<usize> = (0..n).filter(|&i| ready(i, &done) && self.nodes[i].is_fetch() &&
+					vertex == if let Op::Vertex { .. } = self.nodes[i] { true } else { false }).collect();
+				for chunk in batch.chunks(MAX_FETCH_CLAUSE_INSTS) {
+					clauses.push(chunk.to_vec());
+				}
+				for &i in &batch { done[i] = true }
+				progress |= !batch.is_empty();
+			}
+			let mut alu = Vec::new();
+			for i in 0..n {
+				if ready(i, &done) && !self.nodes[i].is_fetch() {
+					done[i] = true;
+					alu.push(i);
+				}
+			}
+			if !alu.is_empty() {
+				clauses.push(alu);
+				progress = true;
+			}
+			if !progress { break }
+		}
+		clauses
+	}
+
+	// Linear scan over the schedule. A register is reused once its last
+	// reader has run; an ALU node that fits one group can take over the
+	// register of an operand it reads for the last time, since groups read
+	// before they write. Readers in fetch clauses count as the clause end.
+	fn allocate(&self, clauses: &[Vec<usize>], used: &[u32]) -> Result<Vec<u32>, String> {
+		let n = self.nodes.len();
+		let mut pos = vec![0; n];
+		let mut clause_end = vec![0; n];
+		let mut p = 0;
+		for clause in clauses {
+			for &i in clause {
+				pos[i] = p;
+				p += 1;
+			}
+			for &i in clause {
+				clause_end[i] = p - 1;
+			}
+		}
+		let mut last_use = vec![0; n];
+		for clause in clauses {
+			for &i in clause {
+				let at = if self.nodes[i].is_fetch() { clause_end[i] } else { pos[i] };
+				for v in self.nodes[i].operands() {
+					if let Some(d) = v.node() {
+						last_use[d] = last_use[d].max(at);
+					}
+				}
+			}
+		}
+		for e in &self.exports {
+			if let Some(d) = e.value.node() {
+				last_use[d] = ::std::usize::MAX;
+			}
+		}
+
+		let mut gpr = vec![0; n];
+		let mut free: Vec<u32> = (1..MAX_GPR + 1).rev().collect();
+		let mut live: Vec<usize> = Vec::new();
+		for clause in clauses {
+			for &i in clause {
+				let one_group = match self.nodes[i] {
+					Op::Alu(op, _, _) => !is_trans_only(op) || used[i].count_ones() == 1,
+					Op::Alu3(..) => true,
+					_ => false
+				};
+				live.retain(|&j| {
+					let dead = last_use[j] < pos[i] || one_group && last_use[j] == pos[i];
+					if dead { free.push(gpr[j]) }
+					!dead
+				});
+				free.sort_by(|a, b| b.cmp(a));
+				gpr[i] = free.pop().ok_or("the shader needs more GPRs than there are")?;
+				live.push(i);
+			}
+		}
+		Ok(gpr)
+	}
+
+	// The two constant buffers the kcache locks, with enough lines for the
+	// constants read from them
+	fn kcache(&self, used: &[u32]) -> Result<Vec<(u32, u32)>, String> {
+		let mut kcache: Vec<(u32, u32)> = Vec::new();
+		for (i, op) in self.nodes.iter().enumerate() {
+			if used[i] == 0 { continue }
+			for v in op.operands() {
+				if let Src::Uniform(buffer, index) = v.src {
+					let mode = if index < 16 { KCACHE_LOCK_1 } else { KCACHE_LOCK_2 };
+					match kcache.iter().position(|&(b, _)| b == buffer) {
+						Some(k) => kcache[k].1 = kcache[k].1.max(mode),
+						None => kcache.push((buffer, mode))
+					}
+				}
+			}
+		}
+		if kcache.len() > 2 {
+			return Err(format!("uniforms from {} constant buffers, at most 2 can be used", kcache.len()))
+		}
+		Ok(kcache)
+	}
+
+	pub fn program(&self) -> Result<Program, String> {
+		let used = self.used_channels();
+		let clauses = self.schedule(&used);
+		let lowering = Lowering {
+			gpr: self.allocate(&clauses, &used)?,
+			kcache: self.kcache(&used)?,
+			used: used
+		};
+		let mut kcache = [Kcache::default(); 2];
+		for (k, &(buffer, mode)) in lowering.kcache.iter().enumerate() {
+			kcache[k] = Kcache { bank: buffer, mode: mode, addr: 0 };
+		}
+
+		let mut cf = Vec::new();
+		for clause in &clauses {
+			let first = &self.nodes[clause[0]];
+			if first.is_fetch() {
+				let inst = if let Op::Vertex { .. } = *first { CF_INST_VC } else { CF_INST_TC };
+				let mut fetches = Vec::new();
+				for &i in clause {
+					fetches.push(lowering.fetch(i, &self.nodes[i])?);
+				}
+				cf.push(CfInst::Fetch(CfWord { inst: inst, barrier: true, ..CfWord::default() }, fetches));
+				continue
+			}
+			// split where a clause would get too long
+			let alu = CfAluWord { inst: CF_INST_ALU, kcache: kcache, barrier: true, ..CfAluWord::default() };
+			let mut groups: Vec<AluGroup> = Vec::new();
+			let mut slots = 0;
+			for &i in clause {
+				for g in lowering.alu_groups(i, &self.nodes[i]) {
+					if slots + g.slots() > MAX_ALU_CLAUSE_SLOTS {
+						cf.push(CfInst::Alu(alu, groups));
+						groups = Vec::new();
+						slots = 0;
+					}
+					slots += g.slots();
+					groups.push(g);
+				}
+			}
+			cf.push(CfInst::Alu(alu, groups));
+		}
+
+		let mut exports: Vec<&ExportValue> = Vec::new();
+		for &ty in &[EXPORT_POS, EXPORT_PARAM, EXPORT_PIXEL] {
+			exports.extend(self.exports.iter().filter(|e| e.ty == ty));
+		}
+		if self.stage == Stage::Vs && !exports.iter().any(|e| e.ty == EXPORT_POS) {
+			return Err("the vertex shader doesn't export a position".to_owned())
+		}
+		if self.stage == Stage::Ps && exports.is_empty() {
+			return Err("the pixel shader doesn't export a color".to_owned())
+		}
+		for (i, e) in exports.iter().enumerate() {
+			let last_of_type = exports[i+1..].iter().all(|f| f.ty != e.ty);
+			cf.push(CfInst::Export(CfExportWord {
+				inst: if last_of_type { CF_INST_EXPORT_DONE } else { CF_INST_EXPORT },
+				ty: e.ty,
+				array_base: e.array_base,
+				rw_gpr: match e.value.src { Src::Node(n) => lowering.gpr[n], Src::Gpr(r) => r, _ => unreachable!() },
+				sel: e.value.swizzle,
+				burst_count: 1,
+				end_of_program: i + 1 == exports.len(),
+				barrier: true,
+				..CfExportWord::default()
+			}));
+		}
+		Ok(Program { cf: cf })
+	}
+}
@@ -0,0 +1,397 @@
+// Disassembler producing text in the syntax accepted by asm.rs
+//
+// The layout follows r600_bytecode_disasm in mesa: CF instructions at the
+// start of a line, clause contents indented below the CF instruction that
+// runs them. Clause addresses and sizes are implied by the order of the
+// listing, so they only appear in the trailing comments, together with the
+// dword offset and raw encoding of each instruction.
+//
+//     VC BARRIER                              ; 0000 00000004 80800000 @4 1
+//         FETCH R1.xy01, R0.x, BUFFER:0 FORMAT:32_32_FLOAT NUM:SCALED SIGNED MFC:7 MEGA_FETCH
+//     ALU KC0[CB0:0-15] BARRIER
+//          0 x: MOV R0.x, KC0[0].x
+//            y: MUL R0.y, R1.y, 0x3f000000    ; 0.5
+//     EXPORT_DONE POS0 R1.xyzw BARRIER END_OF_PROGRAM
+
+use shader::isa::*;
+
+const CHAN: [char; 4] = ['x', 'y', 'z', 'w'];
+const SLOT: [char; 5] = ['x', 'y', 'z', 'w', 't'];
+const SWIZZLE: [char; 8] = ['x', 'y', 'z', 'w', '0', '1', '?', '_'];
+
+const COMMENT_COLUMN: usize = 48;
+
+fn line(out: &mut String, text: String, comment: String) {
+	out.push_str(&text);
+	if !comment.is_empty() {
+		let width = text.chars().map(|c| if c == '\t' { 4 } else { 1 }).sum::<usize>();
+		for _ in width..COMMENT_COLUMN { out.push(' '); }
+		out.push_str(" ; ");
+		out.push_str(&comment);
+	}
+	out.push('\n');
+}
+
+fn raw(at: usize, words: &[u32]) -> String {
+	let mut s = format!("{:04}", at);
+	for w in words { s.push_str(&format!(" {:08x}", w)); }
+	s
+}
+
+fn swizzle(sel: &[u32]) -> String {
+	sel.iter().map(|&s| SWIZZLE[s as usize & 7]).collect()
+}
+
+fn gpr(sel: u32, rel: bool) -> String {
+	if rel { format!("R{}[AR]", sel) } else { format!("R{}", sel) }
+}
+
+fn kcache_lock(index: u32, k: &Kcache) -> String {
+	let size = if k.mode == KCACHE_LOCK_1 { 16 } else { 32 };
+	let start = k.addr * 16;
+	let mut s = format!("KC{}[CB{}:{}-{}", index, k.bank, start, start + size - 1);
+	if k.mode == KCACHE_LOCK_LOOP_INDEX { s.push_str(":LOOP") }
+	s.push(']');
+	s
+}
+
+fn alu_src(src: &AluSrc, literals: &[u32]) -> String {
+	let chan = CHAN[src.chan as usize];
+	let rel = if src.rel { "[AR]" } else { "" };
+	let mut s = if src.sel < 128 {
+		format!("R{}{}.{}", src.sel, rel, chan)
+	} else if src.sel < 160 {
+		format!("KC0[{}]{}.{}", src.sel - ALU_SRC_KCACHE0_BASE, rel, chan)
+	} else if src.sel < 192 {
+		format!("KC1[{}]{}.{}", src.sel - ALU_SRC_KCACHE1_BASE, rel, chan)
+	} else if src.sel == ALU_SRC_LITERAL {
+		match literals.get(src.chan as usize) {
+			Some(value) => format!("0x{:08x}", value),
+			None => format!("L.{}", chan)
+		}
+	} else if src.sel == ALU_SRC_PV {
+		format!("PV.{}", chan)
+	} else if src.sel >= 256 && src.sel < 320 {
+		format!("KC{}[{}]{}.{}", 2 + (src.sel - 256) / 32, src.sel % 32, rel, chan)
+	} else if src.sel >= ALU_SRC_PARAM_BASE {
+		format!("Param{}.{}", src.sel - ALU_SRC_PARAM_BASE, chan)
+	} else {
+		match name_of(ALU_SRC_NAMES, src.sel) {
+			Some(name) => name.to_owned(),
+			None => format!("SRC{}.{}", src.sel, chan)
+		}
+	};
+	if src.abs { s = format!("|{}|", s) }
+	if src.neg { s = format!("-{}", s) }
+	s
+}
+
+fn op_name(inst: &AluInst) -> (String, usize) {
+	if inst.op3 {
+		match name_of(OP3_NAMES, inst.op) {
+			Some(name) => (name.to_owned(), 3),
+			None => (format!("OP3_{}", inst.op), 3)
+		}
+	} else {
+		match op2_info(inst.op) {
+			Some((name, n)) => (name.to_owned(), n as usize),
+			None => (format!("OP2_{}", inst.op), 2)
+		}
+	}
+}
+
+fn alu_inst(inst: &AluInst, literals: &[u32]) -> (String, String) {
+	let (name, nsrc) = op_name(inst);
+	let mut s = format!("{} ", name);
+	let chan = CHAN[inst.dst_chan as usize];
+	if inst.write {
+		s.push_str(&format!("{}.{}", gpr(inst.dst_gpr, inst.dst_rel), chan));
+	} else {
+		s.push_str(&format!("__.{}", chan));
+	}
+	let mut comment = Vec::new();
+	for src in &inst.src[..nsrc] {
+		s.push_str(", ");
+		s.push_str(&alu_src(src, literals));
+		if src.sel == ALU_SRC_LITERAL {
+			if let Some(&value) = literals.get(src.chan as usize) {
+				comment.push(format!("{:?}", f32::from_bits(value)));
+			}
+		}
+	}
+	if inst.clamp { s.push_str(" CLAMP") }
+	if inst.omod != 0 { s.push_str(&format!(" OMOD:{}", inst.omod)) }
+	if inst.update_exec_mask { s.push_str(" UPDATE_EXEC_MASK") }
+	if inst.update_pred { s.push_str(" UPDATE_PRED") }
+	match inst.pred_sel {
+		0 => (),
+		2 => s.push_str(" PRED_SEL_ZERO"),
+		3 => s.push_str(" PRED_SEL_ONE"),
+		n => s.push_str(&format!(" PRED_SEL:{}", n))
+	}
+	if inst.index_mode != 0 { s.push_str(&format!(" INDEX_MODE:{}", inst.index_mode)) }
+	if inst.bank_swizzle != 0 { s.push_str(&format!(" BS:{}", inst.bank_swizzle)) }
+	(s, comment.join(" "))
+}
+
+fn vtx_inst(v: &VtxInst) -> String {
+	let mut s = match name_of(VC_NAMES, v.inst) {
+		Some(name) => name.to_owned(),
+		None => format!("VC_INST_{}", v.inst)
+	};
+	s.push_str(&format!(" {}.{}, {}.{}, BUFFER:{}",
+		gpr(v.dst_gpr, v.dst_rel), swizzle(&v.dst_sel),
+		gpr(v.src_gpr, v.src_rel), CHAN[v.src_sel_x as usize],
+		v.buffer_id));
+	match v.fetch_type {
+		VTX_FETCH_VERTEX_DATA => (),
+		VTX_FETCH_INSTANCE_DATA => s.push_str(" INSTANCE_DATA"),
+		VTX_FETCH_NO_INDEX_OFFSET => s.push_str(" NO_INDEX_OFFSET"),
+		n => s.push_str(&format!(" FETCH_TYPE:{}", n))
+	}
+	match name_of(FMT_NAMES, v.data_format) {
+		Some(name) => s.push_str(&format!(" FORMAT:{}", name)),
+		None => s.push_str(&format!(" FORMAT:{}", v.data_format))
+	}
+	s.push_str(match v.num_format_all {
+		NUM_FORMAT_NORM => " NUM:NORM",
+		NUM_FORMAT_INT => " NUM:INT",
+		NUM_FORMAT_SCALED => " NUM:SCALED",
+		_ => " NUM:3"
+	});
+	if v.format_comp_all { s.push_str(" SIGNED") }
+	if v.srf_mode_all { s.push_str(" SRF_MODE") }
+	if v.use_const_fields { s.push_str(" USE_CONST_FIELDS") }
+	s.push_str(&format!(" MFC:{}", v.mega_fetch_count));
+	if v.mega_fetch { s.push_str(" MEGA_FETCH") }
+	if v.offset != 0 { s.push_str(&format!(" OFFSET:{}", v.offset)) }
+	if v.endian_swap != 0 { s.push_str(&format!(" ENDIAN:{}", v.endian_swap)) }
+	if v.const_buf_no_stride { s.push_str(" CONST_BUF_NO_STRIDE") }
+	if v.fetch_whole_quad { s.push_str(" WHOLE_QUAD") }
+	if v.alt_const { s.push_str(" ALT_CONST") }
+	if v.buffer_index_mode != 0 { s.push_str(&format!(" INDEX_MODE:{}", v.buffer_index_mode)) }
+	s
+}
+
+fn tex_inst(t: &TexInst) -> String {
+	let mut s = match name_of(TEX_NAMES, t.inst) {
+		Some(name) => name.to_owned(),
+		None => format!("TEX_INST_{}", t.inst)
+	};
+	s.push_str(&format!(" {}.{}, {}.{}, RID:{} SID:{} CT:{}",
+		gpr(t.dst_gpr, t.dst_rel), swizzle(&t.dst_sel),
+		gpr(t.src_gpr, t.src_rel), swizzle(&t.src_sel),
+		t.resource_id, t.sampler_id,
+		t.coord_type.iter().map(|&n| if n { 'N' } else { 'U' }).collect::<String>()));
+	if t.offset != [0, 0, 0] {
+		s.push_str(&format!(" OFFSET:{},{},{}", t.offset[0], t.offset[1], t.offset[2]))
+	}
+	if t.lod_bias != 0 { s.push_str(&format!(" LOD_BIAS:{}", t.lod_bias)) }
+	if t.inst_mod != 0 { s.push_str(&format!(" MOD:{}", t.inst_mod)) }
+	if t.fetch_whole_quad { s.push_str(" WHOLE_QUAD") }
+	if t.alt_const { s.push_str(" ALT_CONST") }
+	if t.resource_index_mode != 0 { s.push_str(&format!(" RESOURCE_INDEX_MODE:{}", t.resource_index_mode)) }
+	if t.sampler_index_mode != 0 { s.push_str(&format!(" SAMPLER_INDEX_MODE:{}", t.sampler_index_mode)) }
+	s
+}
+
+fn cf_name(table: &[(u32, &'static str)], inst: u32) -> String {
+	match name_of(table, inst) {
+		Some(name) => name.to_owned(),
+		None => format!("CF_INST_{}", inst)
+	}
+}
+
+fn cf_flags(s: &mut String, vpm: bool, wqm: bool, barrier: bool, eop: bool) {
+	if vpm { s.push_str(" VALID_PIXEL_MODE") }
+	if wqm { s.push_str(" WHOLE_QUAD_MODE") }
+	if barrier { s.push_str(" BARRIER") }
+	if eop { s.push_str(" END_OF_PROGRAM") }
+}
+
+fn label(addr: u32) -> String {
+	format!("cf_{}", addr)
+}
+
+fn cf_word(c: &CfWord, clause: bool, labels: &[u32]) -> String {
+	let mut s = cf_name(CF_NAMES, c.inst);
+	if !clause {
+		if is_flow_control(c.inst) && labels.contains(&c.addr) {
+			s.push_str(&format!(" ADDR:{}", label(c.addr)))
+		} else if c.addr != 0 {
+			s.push_str(&format!(" ADDR:{}", c.addr))
+		}
+		if c.count != 1 { s.push_str(&format!(" COUNT:{}", c.count)) }
+	}
+	if c.pop_count != 0 { s.push_str(&format!(" POP:{}", c.pop_count)) }
+	if c.cf_const != 0 { s.push_str(&format!(" CF_CONST:{}", c.cf_const)) }
+	match c.cond {
+		CF_COND_ACTIVE => (),
+		CF_COND_FALSE => s.push_str(" COND:FALSE"),
+		CF_COND_BOOL => s.push_str(" COND:BOOL"),
+		_ => s.push_str(" COND:NOT_BOOL")
+	}
+	if c.jumptable_sel != 0 { s.push_str(&format!(" JTS:{}", c.jumptable_sel)) }
+	cf_flags(&mut s, c.valid_pixel_mode, c.whole_quad_mode, c.barrier, c.end_of_program);
+	s
+}
+
+fn cf_alu_word(c: &CfAluWord) -> String {
+	let mut s = cf_name(CF_ALU_NAMES, c.inst);
+	for (i, k) in c.kcache.iter().enumerate() {
+		if k.mode != KCACHE_NOP { s.push_str(&format!(" {}", kcache_lock(i as u32, k))) }
+	}
+	if c.alt_const { s.push_str(" ALT_CONST") }
+	cf_flags(&mut s, false, c.whole_quad_mode, c.barrier, false);
+	s
+}
+
+fn export_target(e: &CfExportWord) -> String {
+	match e.ty {
+		EXPORT_PIXEL => format!("PIXEL{}", e.array_base),
+		EXPORT_POS => format!("POS{}", e.array_base as i32 - ARRAY_BASE_POS0 as i32),
+		EXPORT_PARAM => format!("PARAM{}", e.array_base),
+		_ => format!("TYPE{}:{}", e.ty, e.array_base)
+	}
+}
+
+fn cf_export_word(e: &CfExportWord) -> (String, String) {
+	let mut s = cf_name(CF_NAMES, e.inst);
+	let mut comment = String::new();
+	if is_swizzled_export(e.inst) {
+		s.push_str(&format!(" {} {}.{}", export_target(e), gpr(e.rw_gpr, e.rw_rel), swizzle(&e.sel)));
+		if e.index_gpr != 0 { s.push_str(&format!(" INDEX:R{}", e.index_gpr)) }
+	} else {
+		let mask: String = (0..4).map(|i| if e.comp_mask & (1 << i) != 0 { CHAN[i] } else { '_' }).collect();
+		s.push_str(match e.ty {
+			EXPORT_WRITE => " WRITE",
+			EXPORT_WRITE_IND => " WRITE_IND",
+			EXPORT_WRITE_ACK => " WRITE_ACK",
+			_ => " WRITE_IND_ACK"
+		});
+		s.push_str(&format!(" {}.{}", gpr(e.rw_gpr, e.rw_rel), mask));
+		if e.ty & 1 != 0 || e.index_gpr != 0 { s.push_str(&format!(" INDEX:R{}", e.index_gpr)) }
+		s.push_str(&format!(" ARRAY_BASE:{} ARRAY_SIZE:{}", e.array_base, e.array_size));
+		if e.inst == CF_INST_MEM_RAT || e.inst == CF_INST_MEM_RAT_CACHELESS {
+			comment = format!("RAT{} inst {}", e.array_base & 0xf, (e.array_base >> 4) & 0x3f);
+		}
+	}
+	if e.elem_size != 0 { s.push_str(&format!(" ELEM_SIZE:{}", e.elem_size)) }
+	if e.burst_count != 1 { s.push_str(&format!(" BURST:{}", e.burst_count)) }
+	if e.mark { s.push_str(" MARK") }
+	cf_flags(&mut s, e.valid_pixel_mode, false, e.barrier, e.end_of_program);
+	(s, comment)
+}
+
+// words is the encoding of program, cf_base its first CF address and
+// clause_addrs where its clauses start, only used for the comments.
+// Branch targets in labels get a label line.
+fn listing(out: &mut String, program: &Program, words: &[u32], cf_base: usize, clause_addrs: &[Option<u32>], labels: &[u32], chip: Chip) {
+
+	for (i, inst) in program.cf.iter().enumerate() {
+		let at = (cf_base + i) * 2;
+		let cf_raw = raw(at, &words[at..at+2]);
+		if labels.contains(&((cf_base + i) as u32)) {
+			out.push_str(&format!("{}:\n", label((cf_base + i) as u32)));
+		}
+		match *inst {
+			CfInst::Cf(ref c) => line(out, cf_word(c, false, labels), cf_raw),
+			CfInst::Export(ref e) => {
+				let (s, comment) = cf_export_word(e);
+				let comment = if comment.is_empty() { cf_raw } else { format!("{} {}", cf_raw, comment) };
+				line(out, s, comment)
+			},
+			CfInst::Fetch(ref c, ref fetches) => {
+				let addr = clause_addrs[i].unwrap() as usize;
+				line(out, cf_word(c, true, labels), format!("{} @{} {}", cf_raw, addr, fetches.len()));
+				for (j, f) in fetches.iter().enumerate() {
+					let at = addr * 2 + j * 4;
+					let text = match *f {
+						FetchInst::Vtx(ref v) => vtx_inst(v),
+						FetchInst::Tex(ref t) => tex_inst(t),
+						FetchInst::Raw(w) => format!("RAW 0x{:08x} 0x{:08x} 0x{:08x} 0x{:08x}", w[0], w[1], w[2], w[3])
+					};
+					line(out, format!("\t{}", text), raw(at, &words[at..at+4]));
+				}
+			},
+			CfInst::Alu(ref c, ref groups) => {
+				let addr = clause_addrs[i].unwrap() as usize;
+				let slots: u32 = groups.iter().map(|g| g.slots()).sum();
+				line(out, cf_alu_word(c), format!("{} @{} {}", cf_raw, addr, slots));
+				let mut at = addr * 2;
+				for (j, g) in groups.iter().enumerate() {
+					let slot_of = assign_slots(&g.insts, chip);
+					for (k, inst) in g.insts.iter().enumerate() {
+						let (text, comment) = alu_inst(inst, &g.literals);
+						let prefix = if k == 0 { format!("{:2}", j) } else { "  ".to_owned() };
+						let r = raw(at, &words[at..at+2]);
+						line(out, format!("\t{} {}: {}", prefix, SLOT[slot_of[k] as usize], text),
+							if comment.is_empty() { r } else { format!("{} {}", r, comment) });
+						at += 2;
+					}
+					at += ((g.literals.len() + 1) & !1) as usize;
+				}
+			}
+		}
+	}
+}
+
+fn branch_targets(programs: &[(u32, Program)]) -> Vec<u32> {
+	let mut targets = Vec::new();
+	for &(_, ref program) in programs {
+		for inst in &program.cf {
+			if let CfInst::Cf(ref c) = *inst {
+				if is_flow_control(c.inst) && !targets.contains(&c.addr) &&
+					programs.iter().any(|&(s, ref p)| c.addr >= s && c.addr < s + p.cf.len() as u32) {
+					targets.push(c.addr);
+				}
+			}
+		}
+	}
+	targets
+}
+
+fn clause_addrs(program: &Program) -> Vec<Option<u32>> {
+	program.cf.iter().map(|inst| match *inst {
+		CfInst::Fetch(ref c, _) => Some(c.addr),
+		CfInst::Alu(ref c, _) => Some(c.addr),
+		_ => None
+	}).collect()
+}
+
+// Lists the program at the start of words, followed by the subroutines
+// it calls.
+pub fn disassemble(words: &[u32], chip: Chip) -> Result<String, String> {
+	let mut programs = vec![(0, Program::decode(words, chip)?)];
+	let mut next = 0;
+	while next < programs.len() {
+		let calls: Vec<u32> = programs[next].1.cf.iter().filter_map(|inst| match *inst {
+			CfInst::Cf(ref c) if c.inst == CF_INST_CALL => Some(c.addr),
+			_ => None
+		}).collect();
+		for target in calls {
+			if programs.iter().any(|&(start, ref p)| target >= start && target < start + p.cf.len() as u32) {
+				continue
+			}
+			programs.push((target, Program::decode_at(words, target, chip)?));
+		}
+		next += 1;
+	}
+
+	let labels = branch_targets(&programs);
+	let mut out = String::new();
+	for (i, &(start, ref program)) in programs.iter().enumerate() {
+		if i > 0 {
+			out.push_str(&format!("\n; subroutine at CF {}\n", start));
+		}
+		listing(&mut out, program, words, start as usize, &clause_addrs(program), &labels, chip);
+	}
+	Ok(out)
+}
+
+pub fn words_from_bytes(bytes: &[u8]) -> Vec<u32> {
+	bytes.chunks(4)
+		.filter(|c| c.len() == 4)
+		.map(|c| c[0] as u32 | (c[1] as u32) << 8 | (c[2] as u32) << 16 | (c[3] as u32) << 24)
+		.collect()
+}
@@ -0,0 +1,735 @@
+// Runs a decoded program for a single thread on the CPU, so shaders can be
+// checked without a GPU. Registers hold raw 32 bit values, float ops
+// reinterpret them. Control flow is followed for the one thread: the
+// active mask is a bool and the stack holds the saved ones.
+//
+// Covered: ALU clauses (OP2 and OP3 ALU ops without relative addressing,
+// PV/PS forwarding, kcache 0 and 1, literals, predicates and KILL), VTX
+// fetches from simulated vertex buffers, TEX through a caller supplied
+// sampler, swizzled and memory exports, JUMP/ELSE/PUSH/POP and DX10 loops
+// in the CF program, and the EMIT_VERTEX and CUT_VERTEX of a GS.
+
+use shader::isa::*;
+
+pub const NUM_GPRS: usize = 128;
+
+// Loops are cut off after this many iterations
+const MAX_LOOP_ITERATIONS: u32 = 0x10000;
+
+// A vertex buffer resource: element i starts at i * stride
+pub struct VertexBuffer<'a> {
+	pub data: &'a [u8],
+	pub stride: u32
+}
+
+// An export to the position, parameter or pixel cache. Components the
+// swizzle masks out are 0 and clear in mask.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Export {
+	pub ty: u32,
+	pub array_base: u32,
+	pub value: [u32; 4],
+	pub mask: u32
+}
+
+impl Export {
+	pub fn f32(&self) -> [f32; 4] {
+		f32x4(self.value)
+	}
+}
+
+// A memory export (stream-out, ring, scratch or RAT). index is index_gpr.x
+// for the _IND types, 0 otherwise.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct MemWrite {
+	pub inst: u32,
+	pub ty: u32,
+	pub array_base: u32,
+	pub index: u32,
+	pub value: [u32; 4],
+	pub comp_mask: u32
+}
+
+#[derive(Clone, Default, Debug)]
+pub struct Outputs {
+	pub exports: Vec<Export>,
+	pub mem_writes: Vec<MemWrite>,
+	pub strips: Vec<u32>, // vertices a GS emitted, split where it cut
+	pub killed: bool
+}
+
+impl Outputs {
+	fn find(&self, ty: u32, index: u32) -> Option<[f32; 4]> {
+		self.exports.iter().rev().find(|e| e.ty == ty && e.array_base == index).map(|e| e.f32())
+	}
+	pub fn pos(&self, index: u32) -> Option<[f32; 4]> { self.find(EXPORT_POS, ARRAY_BASE_POS0 + index) }
+	pub fn param(&self, index: u32) -> Option<[f32; 4]> { self.find(EXPORT_PARAM, index) }
+	pub fn pixel(&self, index: u32) -> Option<[f32; 4]> { self.find(EXPORT_PIXEL, index) }
+}
+
+pub fn f32x4(v: [u32; 4]) -> [f32; 4] {
+	[f32::from_bits(v[0]), f32::from_bits(v[1]), f32::from_bits(v[2]), f32::from_bits(v[3])]
+}
+
+pub fn u32x4(v: [f32; 4]) -> [u32; 4] {
+	[v[0].to_bits(), v[1].to_bits(), v[2].to_bits(), v[3].to_bits()]
+}
+
+// The inputs and state of one thread. Constant buffers are indexed by
+// bank, vertex buffers by fetch buffer id. Param n reads params[n], which
+// should hold the already interpolated values for a PS.
+pub struct Machine<'a> {
+	pub chip: Chip,
+	pub gprs: Vec<[u32; 4]>,
+	pub const_buffers: Vec<&'a [[u32; 4]]>,
+	pub vertex_buffers: Vec<VertexBuffer<'a>>,
+	pub params: Vec<[u32; 4]>,
+	pub bools: u32,
+	pub sampler: Option<&'a Fn(&TexInst, [f32; 4]) -> [f32; 4]>
+}
+
+struct LoopFrame {
+	active: bool,
+	iterations: u32
+}
+
+struct Thread {
+	active: bool,
+	pred: bool,
+	pv: [u32; 5], // PV.xyzw and PS from the previous group
+	stack: Vec<bool>,
+	loops: Vec<LoopFrame>,
+	cut: bool, // the next emitted vertex starts a strip
+	out: Outputs
+}
+
+impl<'a> Machine<'a> {
+	pub fn new(chip: Chip) -> Machine<'a> {
+		Machine {
+			chip: chip,
+			gprs: vec![[0; 4]; NUM_GPRS],
+			const_buffers: Vec::new(),
+			vertex_buffers: Vec::new(),
+			params: Vec::new(),
+			bools: 0,
+			sampler: None
+		}
+	}
+
+	pub fn run(&mut self, program: &Program) -> Result<Outputs, String> {
+		let mut t = Thread {
+			active: true,
+			pred: false,
+			pv: [0; 5],
+			stack: Vec::new(),
+			loops: Vec::new(),
+			cut: true,
+			out: Outputs::default()
+		};
+		let mut pc = 0;
+		while pc < program.cf.len() {
+			let mut next = pc + 1;
+			match program.cf[pc] {
+				CfInst::Cf(ref c) => {
+					if let Some(target) = self.flow(&mut t, c)? {
+						next = target as usize;
+					}
+					if c.end_of_program { break }
+				},
+				CfInst::Fetch(ref c, ref fetches) => {
+					if t.active && self.cond(c) {
+						for f in fetches {
+							self.fetch(f)?;
+						}
+					}
+					if c.end_of_program { break }
+				},
+				CfInst::Alu(ref c, ref groups) => {
+					match c.inst {
+						CF_INST_ALU_PUSH_BEFORE => t.stack.push(t.active),
+						CF_INST_ALU | CF_INST_ALU_POP_AFTER | CF_INST_ALU_POP2_AFTER |
+						CF_INST_ALU_BREAK | CF_INST_ALU_CONTINUE | CF_INST_ALU_ELSE_AFTER => {},
+						_ => return Err(format!("{} is not supported", name_of(CF_ALU_NAMES, c.inst).unwrap_or("ALU_EXTENDED")))
+					}
+					if t.active {
+						for g in groups {
+							self.group(&mut t, c, g)?;
+						}
+					}
+					match c.inst {
+						CF_INST_ALU_POP_AFTER => pop(&mut t, 1),
+						CF_INST_ALU_POP2_AFTER => pop(&mut t, 2),
+						CF_INST_ALU_ELSE_AFTER => t.active = self.outer(&t, 0) && !t.active,
+						_ => {}
+					}
+				},
+				CfInst::Export(ref e) => {
+					if t.active {
+						self.export(&mut t, e);
+					}
+					if e.end_of_program { break }
+				}
+			}
+			pc = next;
+		}
+		Ok(t.out)
+	}
+
+	// CF_CONST selects a boolean constant for COND_BOOL and COND_NOT_BOOL
+	fn cond(&self, c: &CfWord) -> bool {
+		match c.cond {
+			CF_COND_FALSE => false,
+			CF_COND_BOOL => self.bools >> c.cf_const & 1 != 0,
+			CF_COND_NOT_BOOL => self.bools >> c.cf_const & 1 == 0,
+			_ => true
+		}
+	}
+
+	// The active state outside the innermost branch, after popping
+	fn outer(&self, t: &Thread, popped: u32) -> bool {
+		if popped > 0 { t.active } else { *t.stack.last().unwrap_or(&true) }
+	}
+
+	// Returns where to continue if the instruction jumps
+	fn flow(&self, t: &mut Thread, c: &CfWord) -> Result<Option<u32>, String> {
+		let pass = t.active && self.cond(c);
+		Ok(match c.inst {
+			CF_INST_NOP => None,
+			CF_INST_PUSH => {
+				t.stack.push(t.active);
+				if pass { None } else { Some(c.addr) }
+			},
+			CF_INST_POP => {
+				pop(t, c.pop_count);
+				None
+			},
+			CF_INST_JUMP => {
+				if pass { None } else { pop(t, c.pop_count); Some(c.addr) }
+			},
+			CF_INST_ELSE => {
+				let taken = t.active;
+				pop(t, c.pop_count);
+				t.active = self.outer(t, c.pop_count) && !taken && self.cond(c);
+				if t.active { None } else { Some(c.addr) }
+			},
+			CF_INST_LOOP_START_DX10 | CF_INST_LOOP_START_NO_AL => {
+				t.loops.push(LoopFrame { active: t.active, iterations: 0 });
+				if pass { None } else { Some(c.addr) }
+			},
+			CF_INST_LOOP_END => {
+				let frame = t.loops.last_mut().ok_or("LOOP_END without LOOP_START")?;
+				frame.iterations += 1;
+				if frame.iterations >= MAX_LOOP_ITERATIONS {
+					return Err(format!("loop didn't end after {} iterations", MAX_LOOP_ITERATIONS))
+				}
+				if pass {
+					Some(c.addr)
+				} else {
+					t.active = frame.active;
+					t.loops.pop();
+					None
+				}
+			},
+			CF_INST_LOOP_BREAK => {
+				if pass { t.active = false; Some(c.addr) } else { None }
+			},
+			CF_INST_LOOP_CONTINUE => {
+				if pass { Some(c.addr) } else { None }
+			},
+			CF_INST_KILL => {
+				if pass { t.out.killed = true; t.active = false; }
+				None
+			},
+			CF_INST_EMIT_VERTEX | CF_INST_EMIT_CUT_VERTEX | CF_INST_CUT_VERTEX => {
+				if pass {
+					if c.inst != CF_INST_CUT_VERTEX {
+						if t.cut { t.out.strips.push(0) }
+						*t.out.strips.last_mut().unwrap() += 1;
+					}
+					t.cut = c.inst != CF_INST_EMIT_VERTEX;
+				}
+				None
+			},
+			CF_INST_WAIT_ACK | CF_INST_TC_ACK | CF_INST_VC_ACK | CF_INST_GLOBAL_WAVE_SYNC => None,
+			CF_INST_END => None,
+			_ => return Err(format!("{} is not supported", name_of(CF_NAMES, c.inst).unwrap_or("CF instruction")))
+		})
+	}
+
+	fn export(&self, t: &mut Thread, e: &CfExportWord) {
+		for i in 0..e.burst_count.max(1) {
+			let r = self.gprs[(e.rw_gpr + i) as usize];
+			if is_swizzled_export(e.inst) {
+				let mut value = [0; 4];
+				let mut mask = 0;
+				for c in 0..4 {
+					value[c] = match e.sel[c] {
+						s @ SEL_X ..= SEL_W => r[s as usize],
+						SEL_1 => 1.0f32.to_bits(),
+						_ => 0
+					};
+					if e.sel[c] != SEL_MASK { mask |= 1 << c }
+				}
+				t.out.exports.push(Export { ty: e.ty, array_base: e.array_base + i, value: value, mask: mask });
+			} else {
+				let index = if e.ty & 1 != 0 { self.gprs[e.index_gpr as usize][0] } else { 0 };
+				t.out.mem_writes.push(MemWrite {
+					inst: e.inst,
+					ty: e.ty,
+					array_base: e.array_base + i,
+					index: index,
+					value: r,
+					comp_mask: e.comp_mask
+				});
+			}
+		}
+	}
+
+	fn fetch(&mut self, f: &FetchInst) -> Result<(), String> {
+		let (dst_gpr, dst_sel, value) = match *f {
+			FetchInst::Vtx(ref v) => {
+				if v.inst != VC_INST_FETCH {
+					return Err(format!("{} is not supported", name_of(VC_NAMES, v.inst).unwrap_or("VTX instruction")))
+				}
+				if v.src_rel || v.dst_rel || v.use_const_fields {
+					return Err("relative and constant field fetches are not supported".to_owned())
+				}
+				let index = self.gprs[v.src_gpr as usize][v.src_sel_x as usize];
+				(v.dst_gpr, v.dst_sel, self.vertex(v, index)?)
+			},
+			FetchInst::Tex(ref x) => {
+				if x.src_rel || x.dst_rel {
+					return Err("relative texture fetches are not supported".to_owned())
+				}
+				let sampler = self.sampler.ok_or("TEX fetch without a sampler")?;
+				let r = self.gprs[x.src_gpr as usize];
+				let mut coords = [0.0; 4];
+				for c in 0..4 {
+					coords[c] = match x.src_sel[c] {
+						s @ SEL_X ..= SEL_W => f32::from_bits(r[s as usize]),
+						SEL_1 => 1.0,
+						_ => 0.0
+					};
+				}
+				(x.dst_gpr, x.dst_sel, u32x4(sampler(x, coords)))
+			},
+			FetchInst::Raw(_) => return Err("GDS clauses are not supported".to_owned())
+		};
+		let dst = &mut self.gprs[dst_gpr as usize];
+		let old = *dst;
+		for c in 0..4 {
+			dst[c] = match dst_sel[c] {
+				s @ SEL_X ..= SEL_W => value[s as usize],
+				SEL_0 => 0,
+				SEL_1 => value_one(f),
+				_ => old[c]
+			};
+		}
+		Ok(())
+	}
+
+	// Components a format doesn't have read as 0, 0, 0, 1
+	fn vertex(&self, v: &VtxInst, index: u32) -> Result<[u32; 4], String> {
+		let vb = self.vertex_buffers.get(v.buffer_id as usize)
+			.ok_or_else(|| format!("no vertex buffer {}", v.buffer_id))?;
+		let (count, bits) = match v.data_format {
+			FMT_8 => (1, 8), FMT_8_8 => (2, 8), FMT_8_8_8_8 => (4, 8),
+			FMT_16 | FMT_16_FLOAT => (1, 16), FMT_16_16 | FMT_16_16_FLOAT => (2, 16),
+			FMT_16_16_16_16 | FMT_16_16_16_16_FLOAT => (4, 16),
+			FMT_32 | FMT_32_FLOAT => (1, 32), FMT_32_32 | FMT_32_32_FLOAT => (2, 32),
+			FMT_32_32_32 | FMT_32_32_32_FLOAT => (3, 32), FMT_32_32_32_32 | FMT_32_32_32_32_FLOAT => (4, 32),
+			_ => return Err(format!("vertex format {} is not supported", v.data_format))
+		};
+		let float = match v.data_format {
+			FMT_16_FLOAT | FMT_16_16_FLOAT | FMT_16_16_16_16_FLOAT |
+			FMT_32_FLOAT | FMT_32_32_FLOAT | FMT_32_32_32_FLOAT | FMT_32_32_32_32_FLOAT => true,
+			_ => false
+		};
+		let start = index as usize * vb.stride as usize + v.offset as usize;
+		let size = count * bits / 8;
+		if start + size > vb.data.len() {
+			return Err(format!("fetch of {} bytes at {} is outside vertex buffer {}", size, start, v.buffer_id))
+		}
+		let int = v.num_format_all == NUM_FORMAT_INT;
+		let mut out = [0, 0, 0, if int { 1 } else { 1.0f32.to_bits() }];
+		for c in 0..count {
+			let at = start + c * bits / 8;
+			let mut raw = 0u32;
+			for b in 0..bits / 8 {
+				raw |= (vb.data[at + b] as u32) << (b * 8);
+			}
+			out[c] = if float {
+				if bits == 16 { f16_to_f32(raw as u16).to_bits() } else { raw }
+			} else {
+				convert(raw, bits as u32, v.num_format_all, v.format_comp_all)
+			};
+		}
+		Ok(out)
+	}
+
+	fn kcache(&self, c: &CfAluWord, k: usize, index: u32) -> Result<u32, String> {
+		let kc = &c.kcache[k];
+		if kc.mode == KCACHE_NOP {
+			return Err(format!("KC{} read without a locked kcache", k))
+		}
+		let cb = self.const_buffers.get(kc.bank as usize)
+			.ok_or_else(|| format!("no constant buffer {}", kc.bank))?;
+		let n = (kc.addr * 16 + index / 4) as usize;
+		cb.get(n).map(|v| v[(index & 3) as usize])
+			.ok_or_else(|| format!("constant {} is outside constant buffer {}", n, kc.bank))
+	}
+
+	fn src(&self, c: &CfAluWord, g: &AluGroup, pv: &[u32; 5], s: &AluSrc, op3: bool) -> Result<u32, String> {
+		if s.rel {
+			return Err("relative addressing is not supported".to_owned())
+		}
+		let chan = s.chan as usize;
+		let v = match s.sel {
+			0 ..= 127 => self.gprs[s.sel as usize][chan],
+			128 ..= 159 => self.kcache(c, 0, (s.sel - ALU_SRC_KCACHE0_BASE) * 4 + s.chan)?,
+			160 ..= 191 => self.kcache(c, 1, (s.sel - ALU_SRC_KCACHE1_BASE) * 4 + s.chan)?,
+			ALU_SRC_0 => 0,
+			ALU_SRC_1 => 1.0f32.to_bits(),
+			ALU_SRC_1_INT => 1,
+			ALU_SRC_M_1_INT => !0,
+			ALU_SRC_0_5 => 0.5f32.to_bits(),
+			ALU_SRC_LITERAL => *g.literals.get(chan).ok_or("literal out of range")?,
+			ALU_SRC_PV => pv[chan],
+			ALU_SRC_PS => pv[4],
+			_ if s.sel >= ALU_SRC_PARAM_BASE => {
+				let n = (s.sel - ALU_SRC_PARAM_BASE) as usize;
+				self.params.get(n).ok_or_else(|| format!("no parameter {}", n))?[chan]
+			},
+			_ => return Err(format!("ALU source {} is not supported", s.sel))
+		};
+		// abs is applied before neg, and only exists on OP2
+		let mut f = v;
+		if s.abs && !op3 { f &= 0x7fffffff }
+		if s.neg { f ^= 0x80000000 }
+		Ok(f)
+	}
+
+	fn group(&mut self, t: &mut Thread, c: &CfAluWord, g: &AluGroup) -> Result<(), String> {
+		let slots = assign_slots(&g.insts, self.chip);
+		let pv = t.pv;
+		let mut results = Vec::with_capacity(g.insts.len());
+		let mut dot = 0.0f32;
+		for i in &g.insts {
+			if i.dst_rel {
+				return Err("relative addressing is not supported".to_owned())
+			}
+			let nsrc = if i.op3 { 3 } else { op2_info(i.op).map_or(2, |x| x.1) as usize };
+			let mut src = [0u32; 3];
+			for n in 0..nsrc {
+				src[n] = self.src(c, g, &pv, &i.src[n], i.op3)?;
+			}
+			let skip = match i.pred_sel {
+				2 => t.pred, // PRED_SEL_ZERO
+				3 => !t.pred, // PRED_SEL_ONE
+				_ => false
+			};
+			let r = if i.op3 { op3(i.op, src)? } else { self.op2(t, i, src)? };
+			if !i.op3 && (i.op == OP2_DOT4 || i.op == OP2_DOT4_IEEE) {
+				dot += f32::from_bits(r);
+			}
+			results.push((r, skip));
+		}
+		let mut next_pv = [0; 5];
+		for (n, i) in g.insts.iter().enumerate() {
+			let (mut r, skip) = results[n];
+			if !i.op3 && (i.op == OP2_DOT4 || i.op == OP2_DOT4_IEEE) {
+				r = dot.to_bits();
+			}
+			if float_result(i) {
+				r = output_modifiers(i, r);
+			}
+			next_pv[slots[n] as usize] = r;
+			if skip || !(i.op3 || i.write) { continue }
+			self.gprs[i.dst_gpr as usize][i.dst_chan as usize] = r;
+		}
+		t.pv = next_pv;
+		Ok(())
+	}
+
+	fn op2(&self, t: &mut Thread, i: &AluInst, s: [u32; 3]) -> Result<u32, String> {
+		let a = f32::from_bits(s[0]);
+		let b = f32::from_bits(s[1]);
+		let (ia, ib) = (s[0] as i32, s[1] as i32);
+		let f = |x: f32| x.to_bits();
+		let set = |x: bool| if x { 1.0f32.to_bits() } else { 0 };
+		let set_dx10 = |x: bool| if x { !0 } else { 0 };
+		Ok(match i.op {
+			OP2_ADD => f(a + b),
+			OP2_MUL | OP2_MUL_IEEE => f(legacy_mul(i.op == OP2_MUL, a, b)),
+			OP2_MAX | OP2_MAX_DX10 => f(a.max(b)),
+			OP2_MIN | OP2_MIN_DX10 => f(a.min(b)),
+			OP2_SETE => set(a == b),
+			OP2_SETGT => set(a > b),
+			OP2_SETGE => set(a >= b),
+			OP2_SETNE => set(a != b),
+			OP2_SETE_DX10 => set_dx10(a == b),
+			OP2_SETGT_DX10 => set_dx10(a > b),
+			OP2_SETGE_DX10 => set_dx10(a >= b),
+			OP2_SETNE_DX10 => set_dx10(a != b),
+			OP2_FRACT => f(a - a.floor()),
+			OP2_TRUNC => f(a.trunc()),
+			OP2_CEIL => f(a.ceil()),
+			OP2_RNDNE => f(round_even(a)),
+			OP2_FLOOR => f(a.floor()),
+			OP2_ASHR_INT => (ia >> (s[1] & 31)) as u32,
+			OP2_LSHR_INT => s[0] >> (s[1] & 31),
+			OP2_LSHL_INT => s[0] << (s[1] & 31),
+			OP2_MOV => s[0],
+			OP2_NOP => 0,
+			OP2_PRED_SETE | OP2_PRED_SETGT | OP2_PRED_SETGE | OP2_PRED_SETNE |
+			OP2_PRED_SETE_PUSH | OP2_PRED_SETGT_PUSH | OP2_PRED_SETGE_PUSH | OP2_PRED_SETNE_PUSH |
+			OP2_PRED_SETE_INT | OP2_PRED_SETGT_INT | OP2_PRED_SETGE_INT | OP2_PRED_SETNE_INT |
+			OP2_PRED_SETE_PUSH_INT | OP2_PRED_SETGT_PUSH_INT | OP2_PRED_SETGE_PUSH_INT |
+			OP2_PRED_SETNE_PUSH_INT | OP2_PRED_SETLT_PUSH_INT | OP2_PRED_SETLE_PUSH_INT |
+			OP2_PRED_SETGT_UINT | OP2_PRED_SETGE_UINT | OP2_PRED_SET_INV | OP2_PRED_SET_CLR |
+			OP2_PRED_SET_RESTORE | OP2_PRED_SET_POP => {
+				let p = match i.op {
+					OP2_PRED_SETE | OP2_PRED_SETE_PUSH => a == b,
+					OP2_PRED_SETGT | OP2_PRED_SETGT_PUSH => a > b,
+					OP2_PRED_SETGE | OP2_PRED_SETGE_PUSH => a >= b,
+					OP2_PRED_SETNE | OP2_PRED_SETNE_PUSH => a != b,
+					OP2_PRED_SETE_INT | OP2_PRED_SETE_PUSH_INT => ia == ib,
+					OP2_PRED_SETGT_INT | OP2_PRED_SETGT_PUSH_INT => ia > ib,
+					OP2_PRED_SETGE_INT | OP2_PRED_SETGE_PUSH_INT => ia >= ib,
+					OP2_PRED_SETNE_INT | OP2_PRED_SETNE_PUSH_INT => ia != ib,
+					OP2_PRED_SETLT_PUSH_INT => ia < ib,
+					OP2_PRED_SETLE_PUSH_INT => ia <= ib,
+					OP2_PRED_SETGT_UINT => s[0] > s[1],
+					OP2_PRED_SETGE_UINT => s[0] >= s[1],
+					OP2_PRED_SET_INV => a != 1.0,
+					OP2_PRED_SET_CLR => false,
+					OP2_PRED_SET_RESTORE => a == 0.0,
+					_ => a > 1.0 // PRED_SET_POP: pops src0 - 1 levels if positive
+				};
+				if i.update_pred { t.pred = p }
+				if i.update_exec_mask { t.active = p }
+				match i.op {
+					OP2_PRED_SET_INV => f(if a == 1.0 { 0.0 } else if a == 0.0 { 1.0 } else { a }),
+					OP2_PRED_SET_POP => f(if a > 0.0 { a - 1.0 } else { 0.0 }),
+					OP2_PRED_SET_RESTORE => s[0],
+					_ => set(p)
+				}
+			},
+			OP2_KILLE | OP2_KILLGT | OP2_KILLGE | OP2_KILLNE |
+			OP2_KILLE_INT | OP2_KILLGT_INT | OP2_KILLGE_INT | OP2_KILLNE_INT |
+			OP2_KILLGT_UINT | OP2_KILLGE_UINT => {
+				let kill = match i.op {
+					OP2_KILLE => a == b,
+					OP2_KILLGT => a > b,
+					OP2_KILLGE => a >= b,
+					OP2_KILLNE => a != b,
+					OP2_KILLE_INT => ia == ib,
+					OP2_KILLGT_INT => ia > ib,
+					OP2_KILLGE_INT => ia >= ib,
+					OP2_KILLNE_INT => ia != ib,
+					OP2_KILLGT_UINT => s[0] > s[1],
+					_ => s[0] >= s[1]
+				};
+				if kill { t.out.killed = true; t.active = false; }
+				set(kill)
+			},
+			OP2_AND_INT => s[0] & s[1],
+			OP2_OR_INT => s[0] | s[1],
+			OP2_XOR_INT => s[0] ^ s[1],
+			OP2_NOT_INT => !s[0],
+			OP2_ADD_INT => s[0].wrapping_add(s[1]),
+			OP2_SUB_INT => s[0].wrapping_sub(s[1]),
+			OP2_MAX_INT => ia.max(ib) as u32,
+			OP2_MIN_INT => ia.min(ib) as u32,
+			OP2_MAX_UINT => s[0].max(s[1]),
+			OP2_MIN_UINT => s[0].min(s[1]),
+			OP2_SETE_INT => set_dx10(s[0] == s[1]),
+			OP2_SETGT_INT => set_dx10(ia > ib),
+			OP2_SETGE_INT => set_dx10(ia >= ib),
+			OP2_SETNE_INT => set_dx10(s[0] != s[1]),
+			OP2_SETGT_UINT => set_dx10(s[0] > s[1]),
+			OP2_SETGE_UINT => set_dx10(s[0] >= s[1]),
+			OP2_FLT_TO_INT => a.trunc() as i32 as u32,
+			OP2_FLT_TO_INT_FLOOR => a.floor() as i32 as u32,
+			OP2_FLT_TO_INT_RPI => (a + 0.5).floor() as i32 as u32,
+			OP2_FLT_TO_UINT => a.trunc() as u32,
+			OP2_INT_TO_FLT => f(ia as f32),
+			OP2_UINT_TO_FLT => f(s[0] as f32),
+			OP2_BFREV_INT => s[0].reverse_bits(),
+			OP2_BCNT_INT => s[0].count_ones(),
+			OP2_FFBH_UINT => if s[0] == 0 { !0 } else { s[0].leading_zeros() },
+			OP2_FFBL_INT => if s[0] == 0 { !0 } else { s[0].trailing_zeros() },
+			OP2_BFM_INT => (((1u64 << (s[0] & 31)) - 1) as u32) << (s[1] & 31),
+			OP2_UBYTE0_FLT => f((s[0] & 0xff) as f32),
+			OP2_UBYTE1_FLT => f((s[0] >> 8 & 0xff) as f32),
+			OP2_UBYTE2_FLT => f((s[0] >> 16 & 0xff) as f32),
+			OP2_UBYTE3_FLT => f((s[0] >> 24) as f32),
+			OP2_FLT16_TO_FLT32 => f(f16_to_f32(s[0] as u16)),
+			OP2_EXP_IEEE => f(a.exp2()),
+			OP2_LOG_CLAMPED => f(if a == 0.0 { ::std::f32::MIN } else { a.log2() }),
+			OP2_LOG_IEEE => f(a.log2()),
+			OP2_RECIP_CLAMPED | OP2_RECIP_FF => f(clamp_inf(1.0 / a, i.op == OP2_RECIP_FF)),
+			OP2_RECIP_IEEE => f(1.0 / a),
+			OP2_RECIPSQRT_CLAMPED | OP2_RECIPSQRT_FF => f(clamp_inf(1.0 / a.sqrt(), i.op == OP2_RECIPSQRT_FF)),
+			OP2_RECIPSQRT_IEEE => f(1.0 / a.sqrt()),
+			OP2_SQRT_IEEE => f(a.sqrt()),
+			// the angle is in revolutions, shaders premultiply by 1/2pi
+			OP2_SIN => f((a * 2.0 * ::std::f32::consts::PI).sin()),
+			OP2_COS => f((a * 2.0 * ::std::f32::consts::PI).cos()),
+			OP2_MULLO_INT | OP2_MULLO_UINT => s[0].wrapping_mul(s[1]),
+			OP2_MULHI_INT => ((ia as i64 * ib as i64) >> 32) as u32,
+			OP2_MULHI_UINT => ((s[0] as u64 * s[1] as u64) >> 32) as u32,
+			OP2_MUL_UINT24 => (s[0] & 0xffffff).wrapping_mul(s[1] & 0xffffff),
+			OP2_MULHI_UINT24 => (((s[0] & 0xffffff) as u64 * (s[1] & 0xffffff) as u64) >> 32) as u32,
+			OP2_RECIP_UINT => if s[0] == 0 { !0 } else { ((1u64 << 32) / s[0] as u64) as u32 },
+			OP2_DOT4 | OP2_DOT4_IEEE => f(legacy_mul(i.op == OP2_DOT4, a, b)),
+			OP2_DOT_IEEE => f(a * b),
+			OP2_MAX4 => f(a),
+			// the attribute is already interpolated, src1 selects it
+			OP2_INTERP_XY | OP2_INTERP_ZW | OP2_INTERP_X | OP2_INTERP_Z => {
+				let n = i.src[1].sel.checked_sub(ALU_SRC_PARAM_BASE).ok_or("INTERP without a parameter")?;
+				self.params.get(n as usize).ok_or_else(|| format!("no parameter {}", n))?[i.dst_chan as usize]
+			},
+			OP2_INTERP_LOAD_P0 => s[0],
+			_ => return Err(format!("{} is not supported", op2_info(i.op).map_or("ALU instruction", |x| x.0)))
+		})
+	}
+}
+
+fn op3(op: u32, s: [u32; 3]) -> Result<u32, String> {
+	let a = f32::from_bits(s[0]);
+	let b = f32::from_bits(s[1]);
+	let c = f32::from_bits(s[2]);
+	let f = |x: f32| x.to_bits();
+	Ok(match op {
+		OP3_MULADD => f(legacy_mul(true, a, b) + c),
+		OP3_MULADD_M2 => f((legacy_mul(true, a, b) + c) * 2.0),
+		OP3_MULADD_M4 => f((legacy_mul(true, a, b) + c) * 4.0),
+		OP3_MULADD_D2 => f((legacy_mul(true, a, b) + c) / 2.0),
+		OP3_MULADD_IEEE => f(a * b + c),
+		OP3_FMA => f(a.mul_add(b, c)),
+		OP3_CNDE => if a == 0.0 { s[1] } else { s[2] },
+		OP3_CNDGT => if a > 0.0 { s[1] } else { s[2] },
+		OP3_CNDGE => if a >= 0.0 { s[1] } else { s[2] },
+		OP3_CNDE_INT => if s[0] == 0 { s[1] } else { s[2] },
+		OP3_CNDGT_INT => if (s[0] as i32) > 0 { s[1] } else { s[2] },
+		OP3_CNDGE_INT => if (s[0] as i32) >= 0 { s[1] } else { s[2] },
+		OP3_BFE_UINT | OP3_BFE_INT => {
+			let (offset, width) = (s[1] & 31, s[2] & 31);
+			if width == 0 { return Ok(0) }
+			let v = s[0] >> offset & ((1u64 << width) - 1) as u32;
+			if op == OP3_BFE_INT && offset + width < 32 {
+				((v << (32 - width)) as i32 >> (32 - width)) as u32
+			} else { v }
+		},
+		OP3_BFI_INT => (s[0] & s[1]) | (!s[0] & s[2]),
+		OP3_MULADD_UINT24 => (s[0] & 0xffffff).wrapping_mul(s[1] & 0xffffff).wrapping_add(s[2]),
+		_ => return Err(format!("{} is not supported", name_of(OP3_NAMES, op).unwrap_or("OP3 instruction")))
+	})
+}
+
+// The bottom of the stack is the initial, active state. An if/else that
+// pops in both ELSE and the final POP pops once more than it pushed.
+fn pop(t: &mut Thread, count: u32) {
+	for _ in 0..count {
+		t.active = t.stack.pop().unwrap_or(true);
+	}
+}
+
+// DX9 style multiplies give 0 for 0 * anything, including inf and nan
+fn legacy_mul(legacy: bool, a: f32, b: f32) -> f32 {
+	if legacy && (a == 0.0 || b == 0.0) { 0.0 } else { a * b }
+}
+
+// _CLAMPED gives the largest float for infinity, _FF zero
+fn clamp_inf(x: f32, ff: bool) -> f32 {
+	if !x.is_infinite() { x } else if ff { 0.0f32.copysign(x) } else { ::std::f32::MAX.copysign(x) }
+}
+
+fn round_even(a: f32) -> f32 {
+	let r = a.round();
+	if (a - a.trunc()).abs() == 0.5 && r % 2.0 != 0.0 { r - a.signum() } else { r }
+}
+
+// Whether CLAMP and OMOD apply to an instruction's result
+fn float_result(i: &AluInst) -> bool {
+	if i.op3 {
+		match i.op {
+			OP3_MULADD ..= OP3_MULADD_IEEE | OP3_FMA | OP3_CNDE | OP3_CNDGT | OP3_CNDGE => true,
+			_ => false
+		}
+	} else {
+		match i.op {
+			OP2_ADD ..= OP2_MIN_DX10 | OP2_SETE ..= OP2_SETNE | OP2_FRACT ..= OP2_FLOOR | OP2_MOV |
+			OP2_EXP_IEEE ..= OP2_COS | OP2_INT_TO_FLT | OP2_UINT_TO_FLT | OP2_DOT4 | OP2_DOT4_IEEE |
+			OP2_UBYTE0_FLT ..= OP2_UBYTE3_FLT | OP2_INTERP_XY ..= OP2_INTERP_Z => true,
+			_ => false
+		}
+	}
+}
+
+fn output_modifiers(i: &AluInst, r: u32) -> u32 {
+	let mut x = f32::from_bits(r);
+	if !i.op3 {
+		x = match i.omod { 1 => x * 2.0, 2 => x * 4.0, 3 => x / 2.0, _ => x };
+	}
+	if i.clamp {
+		x = if x > 1.0 { 1.0 } else if x >= 0.0 { x } else { 0.0 };
+	}
+	if !i.op3 && i.omod == 0 && !i.clamp { r } else { x.to_bits() }
+}
+
+// SEL_1 gives integer 1 for integer fetches
+fn value_one(f: &FetchInst) -> u32 {
+	match *f {
+		FetchInst::Vtx(ref v) if v.num_format_all == NUM_FORMAT_INT => 1,
+		_ => 1.0f32.to_bits()
+	}
+}
+
+// One fixed point component to the register value
+fn convert(raw: u32, bits: u32, num_format: u32, signed: bool) -> u32 {
+	let max = if bits == 32 { !0u32 } else { (1u32 << bits) - 1 };
+	let value = if signed && bits < 32 && raw >> (bits - 1) & 1 != 0 {
+		(raw | !max) as i32
+	} else {
+		raw as i32
+	};
+	match num_format {
+		NUM_FORMAT_INT => if signed { value as u32 } else { raw },
+		NUM_FORMAT_SCALED => if signed { value as f32 } else { raw as f32 }.to_bits(),
+		_ => if signed {
+			(value as f32 / (max >> 1) as f32).max(-1.0)
+		} else {
+			raw as f32 / max as f32
+		}.to_bits()
+	}
+}
+
+pub fn f16_to_f32(h: u16) -> f32 {
+	let sign = if h & 0x8000 != 0 { -1.0 } else { 1.0 };
+	let exp = (h >> 10 & 0x1f) as i32;
+	let mant = (h & 0x3ff) as f32;
+	sign * match exp {
+		0 => mant * (2.0f32).powi(-24),
+		31 => if mant == 0.0 { ::std::f32::INFINITY } else { ::std::f32::NAN },
+		_ => (1.0 + mant / 1024.0) * (2.0f32).powi(exp - 15)
+	}
+}
+
+// R0.x holds the vertex index when a VS starts
+pub fn run_vs(program: &Program, chip: Chip, vertex_buffers: Vec<VertexBuffer>, const_buffers: Vec<&[[u32; 4]]>, vertex: u32) -> Result<Outputs, String> {
+	let mut m = Machine::new(chip);
+	m.vertex_buffers = vertex_buffers;
+	m.const_buffers = const_buffers;
+	m.gprs[0] = [vertex, 0, 0, 0];
+	m.run(program)
+}
+
+// params holds one interpolated vec4 per PS input
+pub fn run_ps(program: &Program, chip: Chip, const_buffers: Vec<&[[u32; 4]]>, params: &[[f32; 4]]) -> Result<Outputs, String> {
+	let mut m = Machine::new(chip);
+	m.const_buffers = const_buffers;
+	m.params = params.iter().map(|&p| u32x4(p)).collect();
+	m.run(program)
+}
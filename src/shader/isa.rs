@@ -0,0 +1,1293 @@
+#![allow(dead_code)]
+
+// A shader is a control flow (CF) program followed by the clauses it
+// executes. CF instructions are 64 bits, ALU instructions are 64 bits and
+// may be followed by up to four literal dwords (padded to 64 bits), fetch
+// instructions are 128 bits and need 128 bit alignment. Clause addresses
+// are in units of 64 bits.
+
+// CF_INST of CF_WORD1
+pub const CF_INST_NOP: u32 = 0;
+pub const CF_INST_TC: u32 = 1;
+pub const CF_INST_VC: u32 = 2;
+pub const CF_INST_GDS: u32 = 3;
+pub const CF_INST_LOOP_START: u32 = 4;
+pub const CF_INST_LOOP_END: u32 = 5;
+pub const CF_INST_LOOP_START_DX10: u32 = 6;
+pub const CF_INST_LOOP_START_NO_AL: u32 = 7;
+pub const CF_INST_LOOP_CONTINUE: u32 = 8;
+pub const CF_INST_LOOP_BREAK: u32 = 9;
+pub const CF_INST_JUMP: u32 = 10;
+pub const CF_INST_PUSH: u32 = 11;
+pub const CF_INST_ELSE: u32 = 13;
+pub const CF_INST_POP: u32 = 14;
+pub const CF_INST_CALL: u32 = 18;
+pub const CF_INST_CALL_FS: u32 = 19;
+pub const CF_INST_RETURN: u32 = 20;
+pub const CF_INST_EMIT_VERTEX: u32 = 21;
+pub const CF_INST_EMIT_CUT_VERTEX: u32 = 22;
+pub const CF_INST_CUT_VERTEX: u32 = 23;
+pub const CF_INST_KILL: u32 = 24;
+pub const CF_INST_WAIT_ACK: u32 = 26;
+pub const CF_INST_TC_ACK: u32 = 27;
+pub const CF_INST_VC_ACK: u32 = 28;
+pub const CF_INST_JUMPTABLE: u32 = 29;
+pub const CF_INST_GLOBAL_WAVE_SYNC: u32 = 30;
+pub const CF_INST_HALT: u32 = 31;
+pub const CF_INST_END: u32 = 32; // cayman
+
+// CF_INST of CF_ALLOC_EXPORT_WORD1
+pub const CF_INST_MEM_STREAM0_BUF0: u32 = 64; // .. MEM_STREAM3_BUF3 = 79
+pub const CF_INST_MEM_WRITE_SCRATCH: u32 = 80;
+pub const CF_INST_MEM_RING: u32 = 82;
+pub const CF_INST_EXPORT: u32 = 83;
+pub const CF_INST_EXPORT_DONE: u32 = 84;
+pub const CF_INST_MEM_EXPORT: u32 = 85;
+pub const CF_INST_MEM_RAT: u32 = 86;
+pub const CF_INST_MEM_RAT_CACHELESS: u32 = 87;
+pub const CF_INST_MEM_RING1: u32 = 88;
+pub const CF_INST_MEM_RING2: u32 = 89;
+pub const CF_INST_MEM_RING3: u32 = 90;
+pub const CF_INST_MEM_EXPORT_COMBINED: u32 = 91;
+pub const CF_INST_MEM_RAT_COMBINED_CACHELESS: u32 = 92;
+
+// CF_INST of CF_ALU_WORD1
+pub const CF_INST_ALU: u32 = 8;
+pub const CF_INST_ALU_PUSH_BEFORE: u32 = 9;
+pub const CF_INST_ALU_POP_AFTER: u32 = 10;
+pub const CF_INST_ALU_POP2_AFTER: u32 = 11;
+pub const CF_INST_ALU_EXTENDED: u32 = 12;
+pub const CF_INST_ALU_CONTINUE: u32 = 13;
+pub const CF_INST_ALU_BREAK: u32 = 14;
+pub const CF_INST_ALU_ELSE_AFTER: u32 = 15;
+
+pub const CF_COND_ACTIVE: u32 = 0;
+pub const CF_COND_FALSE: u32 = 1;
+pub const CF_COND_BOOL: u32 = 2;
+pub const CF_COND_NOT_BOOL: u32 = 3;
+
+pub const KCACHE_NOP: u32 = 0;
+pub const KCACHE_LOCK_1: u32 = 1;
+pub const KCACHE_LOCK_2: u32 = 2;
+pub const KCACHE_LOCK_LOOP_INDEX: u32 = 3;
+
+// TYPE of CF_ALLOC_EXPORT_WORD0
+pub const EXPORT_PIXEL: u32 = 0;
+pub const EXPORT_POS: u32 = 1;
+pub const EXPORT_PARAM: u32 = 2;
+pub const EXPORT_WRITE: u32 = 0;
+pub const EXPORT_WRITE_IND: u32 = 1;
+pub const EXPORT_WRITE_ACK: u32 = 2;
+pub const EXPORT_WRITE_IND_ACK: u32 = 3;
+
+pub const ARRAY_BASE_POS0: u32 = 60;
+pub const ARRAY_BASE_PIXEL_Z: u32 = 61;
+
+// RAT_INST of CF_ALLOC_EXPORT_WORD0_RAT
+pub const RAT_INST_NOP: u32 = 0;
+pub const RAT_INST_STORE_TYPED: u32 = 1;
+pub const RAT_INST_STORE_RAW: u32 = 2;
+pub const RAT_INST_STORE_RAW_FDENORM: u32 = 3;
+
+// Component selects for exports and fetches
+pub const SEL_X: u32 = 0;
+pub const SEL_Y: u32 = 1;
+pub const SEL_Z: u32 = 2;
+pub const SEL_W: u32 = 3;
+pub const SEL_0: u32 = 4;
+pub const SEL_1: u32 = 5;
+pub const SEL_MASK: u32 = 7;
+
+// ALU source selects other than GPRs (0..127) and kcache constants
+pub const ALU_SRC_KCACHE0_BASE: u32 = 128;
+pub const ALU_SRC_KCACHE1_BASE: u32 = 160;
+pub const ALU_SRC_LDS_OQ_A: u32 = 219;
+pub const ALU_SRC_LDS_OQ_B: u32 = 220;
+pub const ALU_SRC_LDS_OQ_A_POP: u32 = 221;
+pub const ALU_SRC_LDS_OQ_B_POP: u32 = 222;
+pub const ALU_SRC_LDS_DIRECT_A: u32 = 223;
+pub const ALU_SRC_LDS_DIRECT_B: u32 = 224;
+pub const ALU_SRC_TIME_HI: u32 = 227;
+pub const ALU_SRC_TIME_LO: u32 = 228;
+pub const ALU_SRC_MASK_HI: u32 = 229;
+pub const ALU_SRC_MASK_LO: u32 = 230;
+pub const ALU_SRC_HW_WAVE_ID: u32 = 231;
+pub const ALU_SRC_SIMD_ID: u32 = 232;
+pub const ALU_SRC_SE_ID: u32 = 233;
+pub const ALU_SRC_HW_THREADGRP_ID: u32 = 234;
+pub const ALU_SRC_WAVE_ID_IN_GRP: u32 = 235;
+pub const ALU_SRC_NUM_THREADGRP_WAVES: u32 = 236;
+pub const ALU_SRC_HW_ALU_ODD: u32 = 237;
+pub const ALU_SRC_LOOP_IDX: u32 = 238;
+pub const ALU_SRC_PARAM_BASE_ADDR: u32 = 240;
+pub const ALU_SRC_NEW_PRIM_MASK: u32 = 241;
+pub const ALU_SRC_PRIM_MASK_HI: u32 = 242;
+pub const ALU_SRC_PRIM_MASK_LO: u32 = 243;
+pub const ALU_SRC_1_DBL_L: u32 = 244;
+pub const ALU_SRC_1_DBL_M: u32 = 245;
+pub const ALU_SRC_0_5_DBL_L: u32 = 246;
+pub const ALU_SRC_0_5_DBL_M: u32 = 247;
+pub const ALU_SRC_0: u32 = 248;
+pub const ALU_SRC_1: u32 = 249;
+pub const ALU_SRC_1_INT: u32 = 250;
+pub const ALU_SRC_M_1_INT: u32 = 251;
+pub const ALU_SRC_0_5: u32 = 252;
+pub const ALU_SRC_LITERAL: u32 = 253;
+pub const ALU_SRC_PV: u32 = 254;
+pub const ALU_SRC_PS: u32 = 255;
+pub const ALU_SRC_KCACHE2_BASE: u32 = 256;
+pub const ALU_SRC_KCACHE3_BASE: u32 = 288;
+pub const ALU_SRC_PARAM_BASE: u32 = 448;
+
+// ALU_INST of ALU_WORD1_OP2
+pub const OP2_ADD: u32 = 0x00;
+pub const OP2_MUL: u32 = 0x01;
+pub const OP2_MUL_IEEE: u32 = 0x02;
+pub const OP2_MAX: u32 = 0x03;
+pub const OP2_MIN: u32 = 0x04;
+pub const OP2_MAX_DX10: u32 = 0x05;
+pub const OP2_MIN_DX10: u32 = 0x06;
+pub const OP2_SETE: u32 = 0x08;
+pub const OP2_SETGT: u32 = 0x09;
+pub const OP2_SETGE: u32 = 0x0a;
+pub const OP2_SETNE: u32 = 0x0b;
+pub const OP2_SETE_DX10: u32 = 0x0c;
+pub const OP2_SETGT_DX10: u32 = 0x0d;
+pub const OP2_SETGE_DX10: u32 = 0x0e;
+pub const OP2_SETNE_DX10: u32 = 0x0f;
+pub const OP2_FRACT: u32 = 0x10;
+pub const OP2_TRUNC: u32 = 0x11;
+pub const OP2_CEIL: u32 = 0x12;
+pub const OP2_RNDNE: u32 = 0x13;
+pub const OP2_FLOOR: u32 = 0x14;
+pub const OP2_ASHR_INT: u32 = 0x15;
+pub const OP2_LSHR_INT: u32 = 0x16;
+pub const OP2_LSHL_INT: u32 = 0x17;
+pub const OP2_MOV: u32 = 0x19;
+pub const OP2_NOP: u32 = 0x1a;
+pub const OP2_PRED_SETGT_UINT: u32 = 0x1e;
+pub const OP2_PRED_SETGE_UINT: u32 = 0x1f;
+pub const OP2_PRED_SETE: u32 = 0x20;
+pub const OP2_PRED_SETGT: u32 = 0x21;
+pub const OP2_PRED_SETGE: u32 = 0x22;
+pub const OP2_PRED_SETNE: u32 = 0x23;
+pub const OP2_PRED_SET_INV: u32 = 0x24;
+pub const OP2_PRED_SET_POP: u32 = 0x25;
+pub const OP2_PRED_SET_CLR: u32 = 0x26;
+pub const OP2_PRED_SET_RESTORE: u32 = 0x27;
+pub const OP2_PRED_SETE_PUSH: u32 = 0x28;
+pub const OP2_PRED_SETGT_PUSH: u32 = 0x29;
+pub const OP2_PRED_SETGE_PUSH: u32 = 0x2a;
+pub const OP2_PRED_SETNE_PUSH: u32 = 0x2b;
+pub const OP2_KILLE: u32 = 0x2c;
+pub const OP2_KILLGT: u32 = 0x2d;
+pub const OP2_KILLGE: u32 = 0x2e;
+pub const OP2_KILLNE: u32 = 0x2f;
+pub const OP2_AND_INT: u32 = 0x30;
+pub const OP2_OR_INT: u32 = 0x31;
+pub const OP2_XOR_INT: u32 = 0x32;
+pub const OP2_NOT_INT: u32 = 0x33;
+pub const OP2_ADD_INT: u32 = 0x34;
+pub const OP2_SUB_INT: u32 = 0x35;
+pub const OP2_MAX_INT: u32 = 0x36;
+pub const OP2_MIN_INT: u32 = 0x37;
+pub const OP2_MAX_UINT: u32 = 0x38;
+pub const OP2_MIN_UINT: u32 = 0x39;
+pub const OP2_SETE_INT: u32 = 0x3a;
+pub const OP2_SETGT_INT: u32 = 0x3b;
+pub const OP2_SETGE_INT: u32 = 0x3c;
+pub const OP2_SETNE_INT: u32 = 0x3d;
+pub const OP2_SETGT_UINT: u32 = 0x3e;
+pub const OP2_SETGE_UINT: u32 = 0x3f;
+pub const OP2_KILLGT_UINT: u32 = 0x40;
+pub const OP2_KILLGE_UINT: u32 = 0x41;
+pub const OP2_PRED_SETE_INT: u32 = 0x42;
+pub const OP2_PRED_SETGT_INT: u32 = 0x43;
+pub const OP2_PRED_SETGE_INT: u32 = 0x44;
+pub const OP2_PRED_SETNE_INT: u32 = 0x45;
+pub const OP2_KILLE_INT: u32 = 0x46;
+pub const OP2_KILLGT_INT: u32 = 0x47;
+pub const OP2_KILLGE_INT: u32 = 0x48;
+pub const OP2_KILLNE_INT: u32 = 0x49;
+pub const OP2_PRED_SETE_PUSH_INT: u32 = 0x4a;
+pub const OP2_PRED_SETGT_PUSH_INT: u32 = 0x4b;
+pub const OP2_PRED_SETGE_PUSH_INT: u32 = 0x4c;
+pub const OP2_PRED_SETNE_PUSH_INT: u32 = 0x4d;
+pub const OP2_PRED_SETLT_PUSH_INT: u32 = 0x4e;
+pub const OP2_PRED_SETLE_PUSH_INT: u32 = 0x4f;
+pub const OP2_FLT_TO_INT: u32 = 0x50;
+pub const OP2_BFREV_INT: u32 = 0x51;
+pub const OP2_ADDC_UINT: u32 = 0x52;
+pub const OP2_SUBB_UINT: u32 = 0x53;
+pub const OP2_GROUP_BARRIER: u32 = 0x54;
+pub const OP2_GROUP_SEQ_BEGIN: u32 = 0x55;
+pub const OP2_GROUP_SEQ_END: u32 = 0x56;
+pub const OP2_SET_MODE: u32 = 0x57;
+pub const OP2_SET_CF_IDX0: u32 = 0x58;
+pub const OP2_SET_CF_IDX1: u32 = 0x59;
+pub const OP2_SET_LDS_SIZE: u32 = 0x5a;
+pub const OP2_EXP_IEEE: u32 = 0x81;
+pub const OP2_LOG_CLAMPED: u32 = 0x82;
+pub const OP2_LOG_IEEE: u32 = 0x83;
+pub const OP2_RECIP_CLAMPED: u32 = 0x84;
+pub const OP2_RECIP_FF: u32 = 0x85;
+pub const OP2_RECIP_IEEE: u32 = 0x86;
+pub const OP2_RECIPSQRT_CLAMPED: u32 = 0x87;
+pub const OP2_RECIPSQRT_FF: u32 = 0x88;
+pub const OP2_RECIPSQRT_IEEE: u32 = 0x89;
+pub const OP2_SQRT_IEEE: u32 = 0x8a;
+pub const OP2_SIN: u32 = 0x8d;
+pub const OP2_COS: u32 = 0x8e;
+pub const OP2_MULLO_INT: u32 = 0x8f;
+pub const OP2_MULHI_INT: u32 = 0x90;
+pub const OP2_MULLO_UINT: u32 = 0x91;
+pub const OP2_MULHI_UINT: u32 = 0x92;
+pub const OP2_RECIP_INT: u32 = 0x93;
+pub const OP2_RECIP_UINT: u32 = 0x94;
+pub const OP2_FLT_TO_UINT: u32 = 0x9a;
+pub const OP2_INT_TO_FLT: u32 = 0x9b;
+pub const OP2_UINT_TO_FLT: u32 = 0x9c;
+pub const OP2_BFM_INT: u32 = 0xa0;
+pub const OP2_FLT32_TO_FLT16: u32 = 0xa2;
+pub const OP2_FLT16_TO_FLT32: u32 = 0xa3;
+pub const OP2_UBYTE0_FLT: u32 = 0xa4;
+pub const OP2_UBYTE1_FLT: u32 = 0xa5;
+pub const OP2_UBYTE2_FLT: u32 = 0xa6;
+pub const OP2_UBYTE3_FLT: u32 = 0xa7;
+pub const OP2_BCNT_INT: u32 = 0xaa;
+pub const OP2_FFBH_UINT: u32 = 0xab;
+pub const OP2_FFBL_INT: u32 = 0xac;
+pub const OP2_FFBH_INT: u32 = 0xad;
+pub const OP2_FLT_TO_UINT4: u32 = 0xae;
+pub const OP2_DOT_IEEE: u32 = 0xaf;
+pub const OP2_FLT_TO_INT_RPI: u32 = 0xb0;
+pub const OP2_FLT_TO_INT_FLOOR: u32 = 0xb1;
+pub const OP2_MULHI_UINT24: u32 = 0xb2;
+pub const OP2_MBCNT_32HI_INT: u32 = 0xb3;
+pub const OP2_OFFSET_TO_FLT: u32 = 0xb4;
+pub const OP2_MUL_UINT24: u32 = 0xb5;
+pub const OP2_BCNT_ACCUM_PREV_INT: u32 = 0xb6;
+pub const OP2_MBCNT_32LO_ACCUM_PREV_INT: u32 = 0xb7;
+pub const OP2_DOT4: u32 = 0xbe;
+pub const OP2_DOT4_IEEE: u32 = 0xbf;
+pub const OP2_CUBE: u32 = 0xc0;
+pub const OP2_MAX4: u32 = 0xc1;
+pub const OP2_INTERP_XY: u32 = 0xd6;
+pub const OP2_INTERP_ZW: u32 = 0xd7;
+pub const OP2_INTERP_X: u32 = 0xd8;
+pub const OP2_INTERP_Z: u32 = 0xd9;
+pub const OP2_STORE_FLAGS: u32 = 0xda;
+pub const OP2_LOAD_STORE_FLAGS: u32 = 0xdb;
+pub const OP2_INTERP_LOAD_P0: u32 = 0xe0;
+pub const OP2_INTERP_LOAD_P10: u32 = 0xe1;
+pub const OP2_INTERP_LOAD_P20: u32 = 0xe2;
+
+// ALU_INST of ALU_WORD1_OP3
+pub const OP3_BFE_UINT: u32 = 0x04;
+pub const OP3_BFE_INT: u32 = 0x05;
+pub const OP3_BFI_INT: u32 = 0x06;
+pub const OP3_FMA: u32 = 0x07;
+pub const OP3_MULADD_UINT24: u32 = 0x10;
+pub const OP3_CNDNE_64: u32 = 0x0d;
+pub const OP3_MULADD: u32 = 0x14;
+pub const OP3_MULADD_M2: u32 = 0x15;
+pub const OP3_MULADD_M4: u32 = 0x16;
+pub const OP3_MULADD_D2: u32 = 0x17;
+pub const OP3_MULADD_IEEE: u32 = 0x18;
+pub const OP3_CNDE: u32 = 0x19;
+pub const OP3_CNDGT: u32 = 0x1a;
+pub const OP3_CNDGE: u32 = 0x1b;
+pub const OP3_CNDE_INT: u32 = 0x1c;
+pub const OP3_CNDGT_INT: u32 = 0x1d;
+pub const OP3_CNDGE_INT: u32 = 0x1e;
+pub const OP3_MUL_LIT: u32 = 0x1f;
+
+// VC_INST of VTX_WORD0
+pub const VC_INST_FETCH: u32 = 0;
+pub const VC_INST_SEMANTIC: u32 = 1;
+pub const VC_INST_GET_BUFFER_RESINFO: u32 = 14;
+
+pub const VTX_FETCH_VERTEX_DATA: u32 = 0;
+pub const VTX_FETCH_INSTANCE_DATA: u32 = 1;
+pub const VTX_FETCH_NO_INDEX_OFFSET: u32 = 2;
+
+// TEX_INST of TEX_WORD0
+pub const TEX_INST_LD: u32 = 3;
+pub const TEX_INST_GET_TEXTURE_RESINFO: u32 = 4;
+pub const TEX_INST_GET_NUMBER_OF_SAMPLES: u32 = 5;
+pub const TEX_INST_GET_LOD: u32 = 6;
+pub const TEX_INST_GET_GRADIENTS_H: u32 = 7;
+pub const TEX_INST_GET_GRADIENTS_V: u32 = 8;
+pub const TEX_INST_SET_TEXTURE_OFFSETS: u32 = 9;
+pub const TEX_INST_KEEP_GRADIENTS: u32 = 10;
+pub const TEX_INST_SET_GRADIENTS_H: u32 = 11;
+pub const TEX_INST_SET_GRADIENTS_V: u32 = 12;
+pub const TEX_INST_PASS: u32 = 13;
+pub const TEX_INST_SAMPLE: u32 = 16;
+pub const TEX_INST_SAMPLE_L: u32 = 17;
+pub const TEX_INST_SAMPLE_LB: u32 = 18;
+pub const TEX_INST_SAMPLE_LZ: u32 = 19;
+pub const TEX_INST_SAMPLE_G: u32 = 20;
+pub const TEX_INST_SAMPLE_C: u32 = 24;
+pub const TEX_INST_SAMPLE_C_L: u32 = 25;
+pub const TEX_INST_SAMPLE_C_LB: u32 = 26;
+pub const TEX_INST_SAMPLE_C_LZ: u32 = 27;
+pub const TEX_INST_SAMPLE_C_G: u32 = 28;
+
+// DATA_FORMAT of fetch instructions and resources
+pub const FMT_8: u32 = 1;
+pub const FMT_16: u32 = 5;
+pub const FMT_16_FLOAT: u32 = 6;
+pub const FMT_8_8: u32 = 7;
+pub const FMT_32: u32 = 13;
+pub const FMT_32_FLOAT: u32 = 14;
+pub const FMT_16_16: u32 = 15;
+pub const FMT_16_16_FLOAT: u32 = 16;
+pub const FMT_8_8_8_8: u32 = 26;
+pub const FMT_32_32: u32 = 29;
+pub const FMT_32_32_FLOAT: u32 = 30;
+pub const FMT_16_16_16_16: u32 = 31;
+pub const FMT_16_16_16_16_FLOAT: u32 = 32;
+pub const FMT_32_32_32_32: u32 = 34;
+pub const FMT_32_32_32_32_FLOAT: u32 = 35;
+pub const FMT_32_32_32: u32 = 47;
+pub const FMT_32_32_32_FLOAT: u32 = 48;
+
+pub const NUM_FORMAT_NORM: u32 = 0;
+pub const NUM_FORMAT_INT: u32 = 1;
+pub const NUM_FORMAT_SCALED: u32 = 2;
+
+fn bit(b: bool) -> u32 { if b {1} else {0} }
+
+// CF_WORD0 / CF_WORD1, for everything but ALU clauses and exports
+#[derive(Clone, Copy, Default, Debug, PartialEq)]
+pub struct CfWord {
+	pub inst: u32,
+	pub addr: u32,
+	pub jumptable_sel: u32,
+	pub pop_count: u32,
+	pub cf_const: u32,
+	pub cond: u32,
+	pub count: u32, // number of instructions in the clause, not minus one
+	pub valid_pixel_mode: bool,
+	pub end_of_program: bool,
+	pub whole_quad_mode: bool,
+	pub barrier: bool
+}
+
+impl CfWord {
+	pub fn encode(&self) -> [u32; 2] {
+		[
+			(self.addr & 0xffffff) |
+			((self.jumptable_sel & 7) << 24),
+			(self.pop_count & 7) |
+			((self.cf_const & 0x1f) << 3) |
+			((self.cond & 3) << 8) |
+			((if self.count > 0 {self.count - 1} else {0} & 0x3f) << 10) |
+			(bit(self.valid_pixel_mode) << 20) |
+			(bit(self.end_of_program) << 21) |
+			((self.inst & 0xff) << 22) |
+			(bit(self.whole_quad_mode) << 30) |
+			(bit(self.barrier) << 31)
+		]
+	}
+}
+
+#[derive(Clone, Copy, Default, Debug, PartialEq)]
+pub struct Kcache {
+	pub bank: u32,
+	pub mode: u32,
+	pub addr: u32 // in units of 16 constants
+}
+
+// CF_ALU_WORD0 / CF_ALU_WORD1
+#[derive(Clone, Copy, Default, Debug, PartialEq)]
+pub struct CfAluWord {
+	pub inst: u32,
+	pub addr: u32,
+	pub kcache: [Kcache; 2],
+	pub count: u32, // in 64 bit slots including literals, not minus one
+	pub alt_const: bool,
+	pub whole_quad_mode: bool,
+	pub barrier: bool
+}
+
+impl CfAluWord {
+	pub fn encode(&self) -> [u32; 2] {
+		[
+			(self.addr & 0x3fffff) |
+			((self.kcache[0].bank & 0xf) << 22) |
+			((self.kcache[1].bank & 0xf) << 26) |
+			((self.kcache[0].mode & 3) << 30),
+			(self.kcache[1].mode & 3) |
+			((self.kcache[0].addr & 0xff) << 2) |
+			((self.kcache[1].addr & 0xff) << 10) |
+			((if self.count > 0 {self.count - 1} else {0} & 0x7f) << 18) |
+			(bit(self.alt_const) << 25) |
+			((self.inst & 0xf) << 26) |
+			(bit(self.whole_quad_mode) << 30) |
+			(bit(self.barrier) << 31)
+		]
+	}
+}
+
+// CF_ALLOC_EXPORT_WORD0 / CF_ALLOC_EXPORT_WORD1_{SWIZ,BUF}. Exports to
+// the pixel, position and parameter caches use SWIZ, memory exports BUF.
+// For MEM_RAT*, array_base holds RAT_ID | RAT_INST << 4 | RAT_INDEX_MODE << 11.
+#[derive(Clone, Copy, Default, Debug, PartialEq)]
+pub struct CfExportWord {
+	pub inst: u32,
+	pub ty: u32,
+	pub array_base: u32,
+	pub rw_gpr: u32,
+	pub rw_rel: bool,
+	pub index_gpr: u32,
+	pub elem_size: u32,
+	pub sel: [u32; 4],    // SWIZ
+	pub array_size: u32,  // BUF
+	pub comp_mask: u32,   // BUF
+	pub burst_count: u32, // not minus one
+	pub valid_pixel_mode: bool,
+	pub end_of_program: bool,
+	pub mark: bool,
+	pub barrier: bool
+}
+
+pub fn is_swizzled_export(inst: u32) -> bool {
+	inst == CF_INST_EXPORT || inst == CF_INST_EXPORT_DONE
+}
+
+impl CfExportWord {
+	pub fn encode(&self) -> [u32; 2] {
+		let w1 = if is_swizzled_export(self.inst) {
+			(self.sel[0] & 7) |
+			((self.sel[1] & 7) << 3) |
+			((self.sel[2] & 7) << 6) |
+			((self.sel[3] & 7) << 9)
+		} else {
+			(self.array_size & 0xfff) |
+			((self.comp_mask & 0xf) << 12)
+		};
+		[
+			(self.array_base & 0x1fff) |
+			((self.ty & 3) << 13) |
+			((self.rw_gpr & 0x7f) << 15) |
+			(bit(self.rw_rel) << 22) |
+			((self.index_gpr & 0x7f) << 23) |
+			((self.elem_size & 3) << 30),
+			w1 |
+			((if self.burst_count > 0 {self.burst_count - 1} else {0} & 0xf) << 16) |
+			(bit(self.valid_pixel_mode) << 20) |
+			(bit(self.end_of_program) << 21) |
+			((self.inst & 0xff) << 22) |
+			(bit(self.mark) << 30) |
+			(bit(self.barrier) << 31)
+		]
+	}
+}
+
+#[derive(Clone, Copy, Default, Debug, PartialEq)]
+pub struct AluSrc {
+	pub sel: u32,
+	pub rel: bool,
+	pub chan: u32,
+	pub neg: bool,
+	pub abs: bool // OP2 only, src0 and src1
+}
+
+// ALU_WORD0 / ALU_WORD1_{OP2,OP3}
+#[derive(Clone, Copy, Default, Debug, PartialEq)]
+pub struct AluInst {
+	pub op: u32,
+	pub op3: bool,
+	pub src: [AluSrc; 3],
+	pub dst_gpr: u32,
+	pub dst_rel: bool,
+	pub dst_chan: u32,
+	pub write: bool, // WRITE_MASK, OP2 only, OP3 always writes
+	pub clamp: bool,
+	pub omod: u32,
+	pub update_exec_mask: bool,
+	pub update_pred: bool,
+	pub pred_sel: u32,
+	pub bank_swizzle: u32,
+	pub index_mode: u32,
+	pub last: bool
+}
+
+impl AluInst {
+	pub fn encode(&self) -> [u32; 2] {
+		let s = &self.src;
+		let w0 =
+			(s[0].sel & 0x1ff) |
+			(bit(s[0].rel) << 9) |
+			((s[0].chan & 3) << 10) |
+			(bit(s[0].neg) << 12) |
+			((s[1].sel & 0x1ff) << 13) |
+			(bit(s[1].rel) << 22) |
+			((s[1].chan & 3) << 23) |
+			(bit(s[1].neg) << 25) |
+			((self.index_mode & 7) << 26) |
+			((self.pred_sel & 3) << 29) |
+			(bit(self.last) << 31);
+		let w1_common =
+			((self.bank_swizzle & 7) << 18) |
+			((self.dst_gpr & 0x7f) << 21) |
+			(bit(self.dst_rel) << 28) |
+			((self.dst_chan & 3) << 29) |
+			(bit(self.clamp) << 31);
+		let w1 = if self.op3 {
+			(s[2].sel & 0x1ff) |
+			(bit(s[2].rel) << 9) |
+			((s[2].chan & 3) << 10) |
+			(bit(s[2].neg) << 12) |
+			((self.op & 0x1f) << 13)
+		} else {
+			bit(s[0].abs) |
+			(bit(s[1].abs) << 1) |
+			(bit(self.update_exec_mask) << 2) |
+			(bit(self.update_pred) << 3) |
+			(bit(self.write) << 4) |
+			((self.omod & 3) << 5) |
+			((self.op & 0x7ff) << 7)
+		};
+		[w0, w1 | w1_common]
+	}
+}
+
+// An instruction group: up to five ALU instructions executed together,
+// the last one has `last` set, followed by the literals they use.
+#[derive(Clone, Default, Debug, PartialEq)]
+pub struct AluGroup {
+	pub insts: Vec<AluInst>,
+	pub literals: Vec<u32>
+}
+
+impl AluGroup {
+	// literal dwords are padded to a multiple of two
+	pub fn slots(&self) -> u32 {
+		self.insts.len() as u32 + (self.literals.len() as u32 + 1) / 2
+	}
+	pub fn encode(&self, out: &mut Vec<u32>) {
+		let n = self.insts.len();
+		for (i, inst) in self.insts.iter().enumerate() {
+			let mut inst = *inst;
+			inst.last = i + 1 == n;
+			out.extend_from_slice(&inst.encode());
+		}
+		out.extend_from_slice(&self.literals);
+		if self.literals.len() % 2 == 1 {
+			out.push(0);
+		}
+	}
+}
+
+// VTX_WORD0 / VTX_WORD1_GPR / VTX_WORD2 (+ padding)
+#[derive(Clone, Copy, Default, Debug, PartialEq)]
+pub struct VtxInst {
+	pub inst: u32,
+	pub fetch_type: u32,
+	pub fetch_whole_quad: bool,
+	pub buffer_id: u32,
+	pub src_gpr: u32,
+	pub src_rel: bool,
+	pub src_sel_x: u32,
+	pub mega_fetch_count: u32, // bytes minus one
+	pub dst_gpr: u32,
+	pub dst_rel: bool,
+	pub dst_sel: [u32; 4],
+	pub use_const_fields: bool,
+	pub data_format: u32,
+	pub num_format_all: u32,
+	pub format_comp_all: bool, // signed
+	pub srf_mode_all: bool,
+	pub offset: u32,
+	pub endian_swap: u32,
+	pub const_buf_no_stride: bool,
+	pub mega_fetch: bool,
+	pub alt_const: bool,
+	pub buffer_index_mode: u32
+}
+
+impl VtxInst {
+	pub fn encode(&self) -> [u32; 4] {
+		[
+			(self.inst & 0x1f) |
+			((self.fetch_type & 3) << 5) |
+			(bit(self.fetch_whole_quad) << 7) |
+			((self.buffer_id & 0xff) << 8) |
+			((self.src_gpr & 0x7f) << 16) |
+			(bit(self.src_rel) << 23) |
+			((self.src_sel_x & 3) << 24) |
+			((self.mega_fetch_count & 0x3f) << 26),
+			(self.dst_gpr & 0x7f) |
+			(bit(self.dst_rel) << 7) |
+			((self.dst_sel[0] & 7) << 9) |
+			((self.dst_sel[1] & 7) << 12) |
+			((self.dst_sel[2] & 7) << 15) |
+			((self.dst_sel[3] & 7) << 18) |
+			(bit(self.use_const_fields) << 21) |
+			((self.data_format & 0x3f) << 22) |
+			((self.num_format_all & 3) << 28) |
+			(bit(self.format_comp_all) << 30) |
+			(bit(self.srf_mode_all) << 31),
+			(self.offset & 0xffff) |
+			((self.endian_swap & 3) << 16) |
+			(bit(self.const_buf_no_stride) << 18) |
+			(bit(self.mega_fetch) << 19) |
+			(bit(self.alt_const) << 20) |
+			((self.buffer_index_mode & 3) << 21),
+			0
+		]
+	}
+}
+
+// TEX_WORD0 / TEX_WORD1 / TEX_WORD2 (+ padding)
+#[derive(Clone, Copy, Default, Debug, PartialEq)]
+pub struct TexInst {
+	pub inst: u32,
+	pub inst_mod: u32,
+	pub fetch_whole_quad: bool,
+	pub resource_id: u32,
+	pub src_gpr: u32,
+	pub src_rel: bool,
+	pub alt_const: bool,
+	pub resource_index_mode: u32,
+	pub sampler_index_mode: u32,
+	pub dst_gpr: u32,
+	pub dst_rel: bool,
+	pub dst_sel: [u32; 4],
+	pub lod_bias: u32,
+	pub coord_type: [bool; 4], // normalized
+	pub offset: [u32; 3],
+	pub sampler_id: u32,
+	pub src_sel: [u32; 4]
+}
+
+impl TexInst {
+	pub fn encode(&self) -> [u32; 4] {
+		[
+			(self.inst & 0x1f) |
+			((self.inst_mod & 3) << 5) |
+			(bit(self.fetch_whole_quad) << 7) |
+			((self.resource_id & 0xff) << 8) |
+			((self.src_gpr & 0x7f) << 16) |
+			(bit(self.src_rel) << 23) |
+			(bit(self.alt_const) << 24) |
+			((self.resource_index_mode & 3) << 25) |
+			((self.sampler_index_mode & 3) << 27),
+			(self.dst_gpr & 0x7f) |
+			(bit(self.dst_rel) << 7) |
+			((self.dst_sel[0] & 7) << 9) |
+			((self.dst_sel[1] & 7) << 12) |
+			((self.dst_sel[2] & 7) << 15) |
+			((self.dst_sel[3] & 7) << 18) |
+			((self.lod_bias & 0x7f) << 21) |
+			(bit(self.coord_type[0]) << 28) |
+			(bit(self.coord_type[1]) << 29) |
+			(bit(self.coord_type[2]) << 30) |
+			(bit(self.coord_type[3]) << 31),
+			(self.offset[0] & 0x1f) |
+			((self.offset[1] & 0x1f) << 5) |
+			((self.offset[2] & 0x1f) << 10) |
+			((self.sampler_id & 0x1f) << 15) |
+			((self.src_sel[0] & 7) << 20) |
+			((self.src_sel[1] & 7) << 23) |
+			((self.src_sel[2] & 7) << 26) |
+			((self.src_sel[3] & 7) << 29),
+			0
+		]
+	}
+}
+
+// Raw is used for GDS clauses, which aren't decoded
+#[derive(Clone, Debug, PartialEq)]
+pub enum FetchInst {
+	Vtx(VtxInst),
+	Tex(TexInst),
+	Raw([u32; 4])
+}
+
+impl FetchInst {
+	pub fn encode(&self) -> [u32; 4] {
+		match *self {
+			FetchInst::Vtx(ref v) => v.encode(),
+			FetchInst::Tex(ref t) => t.encode(),
+			FetchInst::Raw(w) => w
+		}
+	}
+}
+
+// One CF instruction together with the clause it executes, if any. The
+// addr and count fields of clause instructions are filled in by layout.
+#[derive(Clone, Debug, PartialEq)]
+pub enum CfInst {
+	Cf(CfWord),
+	Fetch(CfWord, Vec<FetchInst>),
+	Alu(CfAluWord, Vec<AluGroup>),
+	Export(CfExportWord)
+}
+
+#[derive(Clone, Default, Debug, PartialEq)]
+pub struct Program {
+	pub cf: Vec<CfInst>
+}
+
+impl Program {
+	// CF program first, then the clauses in CF order. Fetch clauses are
+	// aligned to 128 bits.
+	pub fn encode(&self) -> Vec<u32> {
+		let mut clauses: Vec<u32> = Vec::new();
+		let cf_dwords = self.cf.len() * 2;
+		let mut cf: Vec<u32> = Vec::with_capacity(cf_dwords);
+
+		for inst in &self.cf {
+			match *inst {
+				CfInst::Cf(ref w) => cf.extend_from_slice(&w.encode()),
+				CfInst::Export(ref w) => cf.extend_from_slice(&w.encode()),
+				CfInst::Fetch(ref w, ref fetches) => {
+					if (cf_dwords + clauses.len()) % 4 != 0 {
+						clauses.extend_from_slice(&[0, 0]);
+					}
+					let mut w = *w;
+					w.addr = ((cf_dwords + clauses.len()) / 2) as u32;
+					w.count = fetches.len() as u32;
+					for f in fetches {
+						clauses.extend_from_slice(&f.encode());
+					}
+					cf.extend_from_slice(&w.encode());
+				},
+				CfInst::Alu(ref w, ref groups) => {
+					let mut w = *w;
+					w.addr = ((cf_dwords + clauses.len()) / 2) as u32;
+					w.count = groups.iter().map(|g| g.slots()).sum();
+					for g in groups {
+						g.encode(&mut clauses);
+					}
+					cf.extend_from_slice(&w.encode());
+				}
+			}
+		}
+		cf.extend(clauses);
+		cf
+	}
+
+	pub fn encode_bytes(&self) -> Vec<u8> {
+		let mut bytes = Vec::new();
+		for w in self.encode() {
+			bytes.extend_from_slice(&w.to_le_bytes());
+		}
+		bytes
+	}
+}
+
+// Decoding
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Chip {
+	Evergreen, // VLIW5, with a trans slot
+	Cayman     // VLIW4
+}
+
+fn flag(w: u32, b: u32) -> bool { (w >> b) & 1 != 0 }
+
+impl CfWord {
+	pub fn decode(w: [u32; 2]) -> CfWord {
+		CfWord {
+			inst: (w[1] >> 22) & 0xff,
+			addr: w[0] & 0xffffff,
+			jumptable_sel: (w[0] >> 24) & 7,
+			pop_count: w[1] & 7,
+			cf_const: (w[1] >> 3) & 0x1f,
+			cond: (w[1] >> 8) & 3,
+			count: ((w[1] >> 10) & 0x3f) + 1,
+			valid_pixel_mode: flag(w[1], 20),
+			end_of_program: flag(w[1], 21),
+			whole_quad_mode: flag(w[1], 30),
+			barrier: flag(w[1], 31)
+		}
+	}
+}
+
+impl CfAluWord {
+	pub fn decode(w: [u32; 2]) -> CfAluWord {
+		CfAluWord {
+			inst: (w[1] >> 26) & 0xf,
+			addr: w[0] & 0x3fffff,
+			kcache: [
+				Kcache { bank: (w[0] >> 22) & 0xf, mode: (w[0] >> 30) & 3, addr: (w[1] >> 2) & 0xff },
+				Kcache { bank: (w[0] >> 26) & 0xf, mode: w[1] & 3, addr: (w[1] >> 10) & 0xff }
+			],
+			count: ((w[1] >> 18) & 0x7f) + 1,
+			alt_const: flag(w[1], 25),
+			whole_quad_mode: flag(w[1], 30),
+			barrier: flag(w[1], 31)
+		}
+	}
+}
+
+impl CfExportWord {
+	pub fn decode(w: [u32; 2]) -> CfExportWord {
+		let inst = (w[1] >> 22) & 0xff;
+		let swizzled = is_swizzled_export(inst);
+		CfExportWord {
+			inst: inst,
+			ty: (w[0] >> 13) & 3,
+			array_base: w[0] & 0x1fff,
+			rw_gpr: (w[0] >> 15) & 0x7f,
+			rw_rel: flag(w[0], 22),
+			index_gpr: (w[0] >> 23) & 0x7f,
+			elem_size: (w[0] >> 30) & 3,
+			sel: if swizzled {
+				[w[1] & 7, (w[1] >> 3) & 7, (w[1] >> 6) & 7, (w[1] >> 9) & 7]
+			} else {
+				[0; 4]
+			},
+			array_size: if swizzled { 0 } else { w[1] & 0xfff },
+			comp_mask: if swizzled { 0 } else { (w[1] >> 12) & 0xf },
+			burst_count: ((w[1] >> 16) & 0xf) + 1,
+			valid_pixel_mode: flag(w[1], 20),
+			end_of_program: flag(w[1], 21),
+			mark: flag(w[1], 30),
+			barrier: flag(w[1], 31)
+		}
+	}
+}
+
+pub enum CfKind { Normal, Alu, Export }
+
+pub fn cf_kind(w1: u32) -> CfKind {
+	if flag(w1, 29) {
+		CfKind::Alu
+	} else if (w1 >> 22) & 0xff >= CF_INST_MEM_STREAM0_BUF0 {
+		CfKind::Export
+	} else {
+		CfKind::Normal
+	}
+}
+
+impl AluInst {
+	pub fn decode(w: [u32; 2]) -> AluInst {
+		let op3 = (w[1] >> 15) & 7 != 0;
+		let src = |sel_shift: u32, w: u32, abs: bool| AluSrc {
+			sel: (w >> sel_shift) & 0x1ff,
+			rel: flag(w, sel_shift + 9),
+			chan: (w >> (sel_shift + 10)) & 3,
+			neg: flag(w, sel_shift + 12),
+			abs: abs
+		};
+		AluInst {
+			op: if op3 { (w[1] >> 13) & 0x1f } else { (w[1] >> 7) & 0x7ff },
+			op3: op3,
+			src: [
+				src(0, w[0], !op3 && flag(w[1], 0)),
+				src(13, w[0], !op3 && flag(w[1], 1)),
+				if op3 { src(0, w[1], false) } else { AluSrc::default() }
+			],
+			dst_gpr: (w[1] >> 21) & 0x7f,
+			dst_rel: flag(w[1], 28),
+			dst_chan: (w[1] >> 29) & 3,
+			write: op3 || flag(w[1], 4),
+			clamp: flag(w[1], 31),
+			omod: if op3 { 0 } else { (w[1] >> 5) & 3 },
+			update_exec_mask: !op3 && flag(w[1], 2),
+			update_pred: !op3 && flag(w[1], 3),
+			pred_sel: (w[0] >> 29) & 3,
+			bank_swizzle: (w[1] >> 18) & 7,
+			index_mode: (w[0] >> 26) & 7,
+			last: flag(w[0], 31)
+		}
+	}
+
+	// Number of literal dwords this instruction needs
+	pub fn literals_used(&self) -> u32 {
+		let n = if self.op3 { 3 } else { 2 };
+		self.src[..n].iter()
+			.filter(|s| s.sel == ALU_SRC_LITERAL)
+			.map(|s| s.chan + 1)
+			.max().unwrap_or(0)
+	}
+}
+
+impl VtxInst {
+	pub fn decode(w: [u32; 4]) -> VtxInst {
+		VtxInst {
+			inst: w[0] & 0x1f,
+			fetch_type: (w[0] >> 5) & 3,
+			fetch_whole_quad: flag(w[0], 7),
+			buffer_id: (w[0] >> 8) & 0xff,
+			src_gpr: (w[0] >> 16) & 0x7f,
+			src_rel: flag(w[0], 23),
+			src_sel_x: (w[0] >> 24) & 3,
+			mega_fetch_count: (w[0] >> 26) & 0x3f,
+			dst_gpr: w[1] & 0x7f,
+			dst_rel: flag(w[1], 7),
+			dst_sel: [(w[1] >> 9) & 7, (w[1] >> 12) & 7, (w[1] >> 15) & 7, (w[1] >> 18) & 7],
+			use_const_fields: flag(w[1], 21),
+			data_format: (w[1] >> 22) & 0x3f,
+			num_format_all: (w[1] >> 28) & 3,
+			format_comp_all: flag(w[1], 30),
+			srf_mode_all: flag(w[1], 31),
+			offset: w[2] & 0xffff,
+			endian_swap: (w[2] >> 16) & 3,
+			const_buf_no_stride: flag(w[2], 18),
+			mega_fetch: flag(w[2], 19),
+			alt_const: flag(w[2], 20),
+			buffer_index_mode: (w[2] >> 21) & 3
+		}
+	}
+}
+
+impl TexInst {
+	pub fn decode(w: [u32; 4]) -> TexInst {
+		TexInst {
+			inst: w[0] & 0x1f,
+			inst_mod: (w[0] >> 5) & 3,
+			fetch_whole_quad: flag(w[0], 7),
+			resource_id: (w[0] >> 8) & 0xff,
+			src_gpr: (w[0] >> 16) & 0x7f,
+			src_rel: flag(w[0], 23),
+			alt_const: flag(w[0], 24),
+			resource_index_mode: (w[0] >> 25) & 3,
+			sampler_index_mode: (w[0] >> 27) & 3,
+			dst_gpr: w[1] & 0x7f,
+			dst_rel: flag(w[1], 7),
+			dst_sel: [(w[1] >> 9) & 7, (w[1] >> 12) & 7, (w[1] >> 15) & 7, (w[1] >> 18) & 7],
+			lod_bias: (w[1] >> 21) & 0x7f,
+			coord_type: [flag(w[1], 28), flag(w[1], 29), flag(w[1], 30), flag(w[1], 31)],
+			offset: [w[2] & 0x1f, (w[2] >> 5) & 0x1f, (w[2] >> 10) & 0x1f],
+			sampler_id: (w[2] >> 15) & 0x1f,
+			src_sel: [(w[2] >> 20) & 7, (w[2] >> 23) & 7, (w[2] >> 26) & 7, (w[2] >> 29) & 7]
+		}
+	}
+}
+
+fn words2(words: &[u32], at: usize) -> Result<[u32; 2], String> {
+	if at + 2 > words.len() {
+		return Err(format!("shader ends inside the instruction at dword {}", at))
+	}
+	Ok([words[at], words[at+1]])
+}
+
+fn words4(words: &[u32], at: usize) -> Result<[u32; 4], String> {
+	if at + 4 > words.len() {
+		return Err(format!("shader ends inside the instruction at dword {}", at))
+	}
+	Ok([words[at], words[at+1], words[at+2], words[at+3]])
+}
+
+pub fn decode_alu_clause(words: &[u32], addr: u32, count: u32) -> Result<Vec<AluGroup>, String> {
+	let mut groups = Vec::new();
+	let mut group = AluGroup::default();
+	let mut slot = 0;
+	while slot < count {
+		let inst = AluInst::decode(words2(words, (addr + slot) as usize * 2)?);
+		slot += 1;
+		let last = inst.last;
+		group.insts.push(inst);
+		if last {
+			let n = group.insts.iter().map(|i| i.literals_used()).max().unwrap_or(0);
+			let at = (addr + slot) as usize * 2;
+			if at + n as usize > words.len() {
+				return Err(format!("shader ends inside the literals at dword {}", at))
+			}
+			group.literals = words[at..at + n as usize].to_vec();
+			slot += (n + 1) / 2;
+			groups.push(group);
+			group = AluGroup::default();
+		} else if group.insts.len() == 5 {
+			return Err(format!("ALU group at slot {} has no LAST bit", addr + slot - 5))
+		}
+	}
+	if !group.insts.is_empty() {
+		return Err(format!("ALU clause at {} ends inside a group", addr))
+	}
+	Ok(groups)
+}
+
+pub fn decode_fetch_clause(words: &[u32], cf_inst: u32, addr: u32, count: u32) -> Result<Vec<FetchInst>, String> {
+	let mut fetches = Vec::new();
+	for i in 0..count {
+		let w = words4(words, (addr * 2 + i * 4) as usize)?;
+		fetches.push(match cf_inst {
+			CF_INST_VC | CF_INST_VC_ACK => FetchInst::Vtx(VtxInst::decode(w)),
+			CF_INST_TC | CF_INST_TC_ACK => FetchInst::Tex(TexInst::decode(w)),
+			_ => FetchInst::Raw(w)
+		});
+	}
+	Ok(fetches)
+}
+
+pub fn is_fetch_clause(cf_inst: u32) -> bool {
+	match cf_inst {
+		CF_INST_TC | CF_INST_VC | CF_INST_GDS | CF_INST_TC_ACK | CF_INST_VC_ACK => true,
+		_ => false
+	}
+}
+
+// CF instructions whose ADDR is a CF address rather than a clause
+pub fn is_flow_control(cf_inst: u32) -> bool {
+	match cf_inst {
+		CF_INST_LOOP_START ..= CF_INST_ELSE | CF_INST_CALL => true,
+		_ => false
+	}
+}
+
+impl Program {
+	// Reads CF instructions from the start of words until END_OF_PROGRAM
+	// (or CF_END on Cayman), together with the clauses they execute.
+	pub fn decode(words: &[u32], chip: Chip) -> Result<Program, String> {
+		Program::decode_at(words, 0, chip)
+	}
+
+	// Same starting at CF address start, which also stops at RETURN for
+	// reading subroutines. Clause addresses are relative to words.
+	pub fn decode_at(words: &[u32], start: u32, chip: Chip) -> Result<Program, String> {
+		let mut cf = Vec::new();
+		let mut at = start as usize * 2;
+		loop {
+			let w = words2(words, at)?;
+			at += 2;
+			let (inst, end) = match cf_kind(w[1]) {
+				CfKind::Alu => {
+					let c = CfAluWord::decode(w);
+					(CfInst::Alu(c, decode_alu_clause(words, c.addr, c.count)?), false)
+				},
+				CfKind::Export => {
+					let e = CfExportWord::decode(w);
+					(CfInst::Export(e), e.end_of_program)
+				},
+				CfKind::Normal => {
+					let c = CfWord::decode(w);
+					let end = c.end_of_program || c.inst == CF_INST_RETURN ||
+						(chip == Chip::Cayman && c.inst == CF_INST_END);
+					if is_fetch_clause(c.inst) {
+						(CfInst::Fetch(c, decode_fetch_clause(words, c.inst, c.addr, c.count)?), end)
+					} else {
+						(CfInst::Cf(c), end)
+					}
+				}
+			};
+			cf.push(inst);
+			if end { break }
+		}
+		Ok(Program { cf: cf })
+	}
+
+	// Where each CF instruction's clause starts, in 64 bit units, as laid
+	// out by encode.
+	pub fn layout(&self) -> Vec<Option<u32>> {
+		let mut addrs = Vec::new();
+		let mut at = self.cf.len() as u32;
+		for inst in &self.cf {
+			match *inst {
+				CfInst::Fetch(_, ref fetches) => {
+					at = (at + 1) & !1;
+					addrs.push(Some(at));
+					at += fetches.len() as u32 * 2;
+				},
+				CfInst::Alu(_, ref groups) => {
+					addrs.push(Some(at));
+					at += groups.iter().map(|g| g.slots()).sum::<u32>();
+				},
+				_ => addrs.push(None)
+			}
+		}
+		addrs
+	}
+}
+
+// Trans unit only ops, on Cayman these are executed by the vector slots
+pub fn is_trans_only(op: u32) -> bool {
+	match op {
+		OP2_EXP_IEEE ..= OP2_RECIP_UINT | OP2_FLT_TO_UINT | OP2_INT_TO_FLT | OP2_UINT_TO_FLT => true,
+		_ => false
+	}
+}
+
+pub fn is_vector_only(op: u32) -> bool {
+	match op {
+		OP2_DOT4 | OP2_DOT4_IEEE | OP2_CUBE | OP2_MAX4 |
+		OP2_INTERP_XY ..= OP2_LOAD_STORE_FLAGS | OP2_INTERP_LOAD_P0 ..= OP2_INTERP_LOAD_P20 => true,
+		_ => false
+	}
+}
+
+// Slot of each instruction of a group: 0..3 for x..w, 4 for trans
+pub fn assign_slots(insts: &[AluInst], chip: Chip) -> Vec<u32> {
+	let mut taken = [false; 5];
+	insts.iter().map(|i| {
+		let vector = i.dst_chan as usize;
+		let slot = if chip == Chip::Cayman || (!taken[vector] && (i.op3 || !is_trans_only(i.op))) {
+			vector
+		} else {
+			4
+		};
+		taken[slot] = true;
+		slot as u32
+	}).collect()
+}
+
+// Names
+
+pub const CF_NAMES: &'static [(u32, &'static str)] = &[
+	(CF_INST_NOP, "NOP"), (CF_INST_TC, "TC"), (CF_INST_VC, "VC"), (CF_INST_GDS, "GDS"),
+	(CF_INST_LOOP_START, "LOOP_START"), (CF_INST_LOOP_END, "LOOP_END"),
+	(CF_INST_LOOP_START_DX10, "LOOP_START_DX10"), (CF_INST_LOOP_START_NO_AL, "LOOP_START_NO_AL"),
+	(CF_INST_LOOP_CONTINUE, "LOOP_CONTINUE"), (CF_INST_LOOP_BREAK, "LOOP_BREAK"),
+	(CF_INST_JUMP, "JUMP"), (CF_INST_PUSH, "PUSH"), (CF_INST_ELSE, "ELSE"), (CF_INST_POP, "POP"),
+	(CF_INST_CALL, "CALL"), (CF_INST_CALL_FS, "CALL_FS"), (CF_INST_RETURN, "RETURN"),
+	(CF_INST_EMIT_VERTEX, "EMIT_VERTEX"), (CF_INST_EMIT_CUT_VERTEX, "EMIT_CUT_VERTEX"),
+	(CF_INST_CUT_VERTEX, "CUT_VERTEX"), (CF_INST_KILL, "KILL"), (CF_INST_WAIT_ACK, "WAIT_ACK"),
+	(CF_INST_TC_ACK, "TC_ACK"), (CF_INST_VC_ACK, "VC_ACK"), (CF_INST_JUMPTABLE, "JUMPTABLE"),
+	(CF_INST_GLOBAL_WAVE_SYNC, "GLOBAL_WAVE_SYNC"), (CF_INST_HALT, "HALT"), (CF_INST_END, "CF_END"),
+	(64, "MEM_STREAM0_BUF0"), (65, "MEM_STREAM0_BUF1"), (66, "MEM_STREAM0_BUF2"), (67, "MEM_STREAM0_BUF3"),
+	(68, "MEM_STREAM1_BUF0"), (69, "MEM_STREAM1_BUF1"), (70, "MEM_STREAM1_BUF2"), (71, "MEM_STREAM1_BUF3"),
+	(72, "MEM_STREAM2_BUF0"), (73, "MEM_STREAM2_BUF1"), (74, "MEM_STREAM2_BUF2"), (75, "MEM_STREAM2_BUF3"),
+	(76, "MEM_STREAM3_BUF0"), (77, "MEM_STREAM3_BUF1"), (78, "MEM_STREAM3_BUF2"), (79, "MEM_STREAM3_BUF3"),
+	(CF_INST_MEM_WRITE_SCRATCH, "MEM_WRITE_SCRATCH"), (CF_INST_MEM_RING, "MEM_RING"),
+	(CF_INST_EXPORT, "EXPORT"), (CF_INST_EXPORT_DONE, "EXPORT_DONE"), (CF_INST_MEM_EXPORT, "MEM_EXPORT"),
+	(CF_INST_MEM_RAT, "MEM_RAT"), (CF_INST_MEM_RAT_CACHELESS, "MEM_RAT_CACHELESS"),
+	(CF_INST_MEM_RING1, "MEM_RING1"), (CF_INST_MEM_RING2, "MEM_RING2"), (CF_INST_MEM_RING3, "MEM_RING3"),
+	(CF_INST_MEM_EXPORT_COMBINED, "MEM_EXPORT_COMBINED"),
+	(CF_INST_MEM_RAT_COMBINED_CACHELESS, "MEM_RAT_COMBINED_CACHELESS")
+];
+
+pub const CF_ALU_NAMES: &'static [(u32, &'static str)] = &[
+	(CF_INST_ALU, "ALU"), (CF_INST_ALU_PUSH_BEFORE, "ALU_PUSH_BEFORE"),
+	(CF_INST_ALU_POP_AFTER, "ALU_POP_AFTER"), (CF_INST_ALU_POP2_AFTER, "ALU_POP2_AFTER"),
+	(CF_INST_ALU_EXTENDED, "ALU_EXTENDED"), (CF_INST_ALU_CONTINUE, "ALU_CONTINUE"),
+	(CF_INST_ALU_BREAK, "ALU_BREAK"), (CF_INST_ALU_ELSE_AFTER, "ALU_ELSE_AFTER")
+];
+
+// (opcode, name, number of sources)
+pub const OP2_NAMES: &'static [(u32, &'static str, u32)] = &[
+	(OP2_ADD, "ADD", 2), (OP2_MUL, "MUL", 2), (OP2_MUL_IEEE, "MUL_IEEE", 2), (OP2_MAX, "MAX", 2),
+	(OP2_MIN, "MIN", 2), (OP2_MAX_DX10, "MAX_DX10", 2), (OP2_MIN_DX10, "MIN_DX10", 2),
+	(OP2_SETE, "SETE", 2), (OP2_SETGT, "SETGT", 2), (OP2_SETGE, "SETGE", 2), (OP2_SETNE, "SETNE", 2),
+	(OP2_SETE_DX10, "SETE_DX10", 2), (OP2_SETGT_DX10, "SETGT_DX10", 2),
+	(OP2_SETGE_DX10, "SETGE_DX10", 2), (OP2_SETNE_DX10, "SETNE_DX10", 2),
+	(OP2_FRACT, "FRACT", 1), (OP2_TRUNC, "TRUNC", 1), (OP2_CEIL, "CEIL", 1), (OP2_RNDNE, "RNDNE", 1),
+	(OP2_FLOOR, "FLOOR", 1), (OP2_ASHR_INT, "ASHR_INT", 2), (OP2_LSHR_INT, "LSHR_INT", 2),
+	(OP2_LSHL_INT, "LSHL_INT", 2), (OP2_MOV, "MOV", 1), (OP2_NOP, "NOP", 0),
+	(OP2_PRED_SETGT_UINT, "PRED_SETGT_UINT", 2), (OP2_PRED_SETGE_UINT, "PRED_SETGE_UINT", 2),
+	(OP2_PRED_SETE, "PRED_SETE", 2), (OP2_PRED_SETGT, "PRED_SETGT", 2), (OP2_PRED_SETGE, "PRED_SETGE", 2),
+	(OP2_PRED_SETNE, "PRED_SETNE", 2), (OP2_PRED_SET_INV, "PRED_SET_INV", 1),
+	(OP2_PRED_SET_POP, "PRED_SET_POP", 2), (OP2_PRED_SET_CLR, "PRED_SET_CLR", 0),
+	(OP2_PRED_SET_RESTORE, "PRED_SET_RESTORE", 1), (OP2_PRED_SETE_PUSH, "PRED_SETE_PUSH", 2),
+	(OP2_PRED_SETGT_PUSH, "PRED_SETGT_PUSH", 2), (OP2_PRED_SETGE_PUSH, "PRED_SETGE_PUSH", 2),
+	(OP2_PRED_SETNE_PUSH, "PRED_SETNE_PUSH", 2), (OP2_KILLE, "KILLE", 2), (OP2_KILLGT, "KILLGT", 2),
+	(OP2_KILLGE, "KILLGE", 2), (OP2_KILLNE, "KILLNE", 2), (OP2_AND_INT, "AND_INT", 2),
+	(OP2_OR_INT, "OR_INT", 2), (OP2_XOR_INT, "XOR_INT", 2), (OP2_NOT_INT, "NOT_INT", 1),
+	(OP2_ADD_INT, "ADD_INT", 2), (OP2_SUB_INT, "SUB_INT", 2), (OP2_MAX_INT, "MAX_INT", 2),
+	(OP2_MIN_INT, "MIN_INT", 2), (OP2_MAX_UINT, "MAX_UINT", 2), (OP2_MIN_UINT, "MIN_UINT", 2),
+	(OP2_SETE_INT, "SETE_INT", 2), (OP2_SETGT_INT, "SETGT_INT", 2), (OP2_SETGE_INT, "SETGE_INT", 2),
+	(OP2_SETNE_INT, "SETNE_INT", 2), (OP2_SETGT_UINT, "SETGT_UINT", 2), (OP2_SETGE_UINT, "SETGE_UINT", 2),
+	(OP2_KILLGT_UINT, "KILLGT_UINT", 2), (OP2_KILLGE_UINT, "KILLGE_UINT", 2),
+	(OP2_PRED_SETE_INT, "PRED_SETE_INT", 2), (OP2_PRED_SETGT_INT, "PRED_SETGT_INT", 2),
+	(OP2_PRED_SETGE_INT, "PRED_SETGE_INT", 2), (OP2_PRED_SETNE_INT, "PRED_SETNE_INT", 2),
+	(OP2_KILLE_INT, "KILLE_INT", 2), (OP2_KILLGT_INT, "KILLGT_INT", 2), (OP2_KILLGE_INT, "KILLGE_INT", 2),
+	(OP2_KILLNE_INT, "KILLNE_INT", 2), (OP2_PRED_SETE_PUSH_INT, "PRED_SETE_PUSH_INT", 2),
+	(OP2_PRED_SETGT_PUSH_INT, "PRED_SETGT_PUSH_INT", 2), (OP2_PRED_SETGE_PUSH_INT, "PRED_SETGE_PUSH_INT", 2),
+	(OP2_PRED_SETNE_PUSH_INT, "PRED_SETNE_PUSH_INT", 2), (OP2_PRED_SETLT_PUSH_INT, "PRED_SETLT_PUSH_INT", 2),
+	(OP2_PRED_SETLE_PUSH_INT, "PRED_SETLE_PUSH_INT", 2), (OP2_FLT_TO_INT, "FLT_TO_INT", 1),
+	(OP2_BFREV_INT, "BFREV_INT", 1), (OP2_ADDC_UINT, "ADDC_UINT", 2), (OP2_SUBB_UINT, "SUBB_UINT", 2),
+	(OP2_GROUP_BARRIER, "GROUP_BARRIER", 0), (OP2_GROUP_SEQ_BEGIN, "GROUP_SEQ_BEGIN", 0),
+	(OP2_GROUP_SEQ_END, "GROUP_SEQ_END", 0), (OP2_SET_MODE, "SET_MODE", 2),
+	(OP2_SET_CF_IDX0, "SET_CF_IDX0", 1), (OP2_SET_CF_IDX1, "SET_CF_IDX1", 1),
+	(OP2_SET_LDS_SIZE, "SET_LDS_SIZE", 1), (OP2_EXP_IEEE, "EXP_IEEE", 1),
+	(OP2_LOG_CLAMPED, "LOG_CLAMPED", 1), (OP2_LOG_IEEE, "LOG_IEEE", 1),
+	(OP2_RECIP_CLAMPED, "RECIP_CLAMPED", 1), (OP2_RECIP_FF, "RECIP_FF", 1),
+	(OP2_RECIP_IEEE, "RECIP_IEEE", 1), (OP2_RECIPSQRT_CLAMPED, "RECIPSQRT_CLAMPED", 1),
+	(OP2_RECIPSQRT_FF, "RECIPSQRT_FF", 1), (OP2_RECIPSQRT_IEEE, "RECIPSQRT_IEEE", 1),
+	(OP2_SQRT_IEEE, "SQRT_IEEE", 1), (OP2_SIN, "SIN", 1), (OP2_COS, "COS", 1),
+	(OP2_MULLO_INT, "MULLO_INT", 2), (OP2_MULHI_INT, "MULHI_INT", 2),
+	(OP2_MULLO_UINT, "MULLO_UINT", 2), (OP2_MULHI_UINT, "MULHI_UINT", 2),
+	(OP2_RECIP_INT, "RECIP_INT", 1), (OP2_RECIP_UINT, "RECIP_UINT", 1),
+	(OP2_FLT_TO_UINT, "FLT_TO_UINT", 1), (OP2_INT_TO_FLT, "INT_TO_FLT", 1),
+	(OP2_UINT_TO_FLT, "UINT_TO_FLT", 1), (OP2_BFM_INT, "BFM_INT", 2),
+	(OP2_FLT32_TO_FLT16, "FLT32_TO_FLT16", 1), (OP2_FLT16_TO_FLT32, "FLT16_TO_FLT32", 1),
+	(OP2_UBYTE0_FLT, "UBYTE0_FLT", 1), (OP2_UBYTE1_FLT, "UBYTE1_FLT", 1),
+	(OP2_UBYTE2_FLT, "UBYTE2_FLT", 1), (OP2_UBYTE3_FLT, "UBYTE3_FLT", 1),
+	(OP2_BCNT_INT, "BCNT_INT", 1), (OP2_FFBH_UINT, "FFBH_UINT", 1), (OP2_FFBL_INT, "FFBL_INT", 1),
+	(OP2_FFBH_INT, "FFBH_INT", 1), (OP2_FLT_TO_UINT4, "FLT_TO_UINT4", 1), (OP2_DOT_IEEE, "DOT_IEEE", 2),
+	(OP2_FLT_TO_INT_RPI, "FLT_TO_INT_RPI", 1), (OP2_FLT_TO_INT_FLOOR, "FLT_TO_INT_FLOOR", 1),
+	(OP2_MULHI_UINT24, "MULHI_UINT24", 2), (OP2_MBCNT_32HI_INT, "MBCNT_32HI_INT", 1),
+	(OP2_OFFSET_TO_FLT, "OFFSET_TO_FLT", 1), (OP2_MUL_UINT24, "MUL_UINT24", 2),
+	(OP2_BCNT_ACCUM_PREV_INT, "BCNT_ACCUM_PREV_INT", 1),
+	(OP2_MBCNT_32LO_ACCUM_PREV_INT, "MBCNT_32LO_ACCUM_PREV_INT", 1),
+	(OP2_DOT4, "DOT4", 2), (OP2_DOT4_IEEE, "DOT4_IEEE", 2), (OP2_CUBE, "CUBE", 2), (OP2_MAX4, "MAX4", 1),
+	(OP2_INTERP_XY, "INTERP_XY", 2), (OP2_INTERP_ZW, "INTERP_ZW", 2), (OP2_INTERP_X, "INTERP_X", 2),
+	(OP2_INTERP_Z, "INTERP_Z", 2), (OP2_STORE_FLAGS, "STORE_FLAGS", 1),
+	(OP2_LOAD_STORE_FLAGS, "LOAD_STORE_FLAGS", 1), (OP2_INTERP_LOAD_P0, "INTERP_LOAD_P0", 1),
+	(OP2_INTERP_LOAD_P10, "INTERP_LOAD_P10", 1), (OP2_INTERP_LOAD_P20, "INTERP_LOAD_P20", 1)
+];
+
+pub const OP3_NAMES: &'static [(u32, &'static str)] = &[
+	(OP3_BFE_UINT, "BFE_UINT"), (OP3_BFE_INT, "BFE_INT"), (OP3_BFI_INT, "BFI_INT"), (OP3_FMA, "FMA"),
+	(OP3_CNDNE_64, "CNDNE_64"), (OP3_MULADD_UINT24, "MULADD_UINT24"), (OP3_MULADD, "MULADD"),
+	(OP3_MULADD_M2, "MULADD_M2"), (OP3_MULADD_M4, "MULADD_M4"), (OP3_MULADD_D2, "MULADD_D2"),
+	(OP3_MULADD_IEEE, "MULADD_IEEE"), (OP3_CNDE, "CNDE"), (OP3_CNDGT, "CNDGT"), (OP3_CNDGE, "CNDGE"),
+	(OP3_CNDE_INT, "CNDE_INT"), (OP3_CNDGT_INT, "CNDGT_INT"), (OP3_CNDGE_INT, "CNDGE_INT"),
+	(OP3_MUL_LIT, "MUL_LIT")
+];
+
+pub const VC_NAMES: &'static [(u32, &'static str)] = &[
+	(VC_INST_FETCH, "FETCH"), (VC_INST_SEMANTIC, "SEMANTIC"),
+	(VC_INST_GET_BUFFER_RESINFO, "GET_BUFFER_RESINFO")
+];
+
+pub const TEX_NAMES: &'static [(u32, &'static str)] = &[
+	(TEX_INST_LD, "LD"), (TEX_INST_GET_TEXTURE_RESINFO, "GET_TEXTURE_RESINFO"),
+	(TEX_INST_GET_NUMBER_OF_SAMPLES, "GET_NUMBER_OF_SAMPLES"), (TEX_INST_GET_LOD, "GET_LOD"),
+	(TEX_INST_GET_GRADIENTS_H, "GET_GRADIENTS_H"), (TEX_INST_GET_GRADIENTS_V, "GET_GRADIENTS_V"),
+	(TEX_INST_SET_TEXTURE_OFFSETS, "SET_TEXTURE_OFFSETS"), (TEX_INST_KEEP_GRADIENTS, "KEEP_GRADIENTS"),
+	(TEX_INST_SET_GRADIENTS_H, "SET_GRADIENTS_H"), (TEX_INST_SET_GRADIENTS_V, "SET_GRADIENTS_V"),
+	(TEX_INST_PASS, "PASS"), (TEX_INST_SAMPLE, "SAMPLE"), (TEX_INST_SAMPLE_L, "SAMPLE_L"),
+	(TEX_INST_SAMPLE_LB, "SAMPLE_LB"), (TEX_INST_SAMPLE_LZ, "SAMPLE_LZ"), (TEX_INST_SAMPLE_G, "SAMPLE_G"),
+	(TEX_INST_SAMPLE_C, "SAMPLE_C"), (TEX_INST_SAMPLE_C_L, "SAMPLE_C_L"),
+	(TEX_INST_SAMPLE_C_LB, "SAMPLE_C_LB"), (TEX_INST_SAMPLE_C_LZ, "SAMPLE_C_LZ"),
+	(TEX_INST_SAMPLE_C_G, "SAMPLE_C_G")
+];
+
+pub const FMT_NAMES: &'static [(u32, &'static str)] = &[
+	(FMT_8, "8"), (FMT_16, "16"), (FMT_16_FLOAT, "16_FLOAT"), (FMT_8_8, "8_8"), (FMT_32, "32"),
+	(FMT_32_FLOAT, "32_FLOAT"), (FMT_16_16, "16_16"), (FMT_16_16_FLOAT, "16_16_FLOAT"),
+	(FMT_8_8_8_8, "8_8_8_8"), (FMT_32_32, "32_32"), (FMT_32_32_FLOAT, "32_32_FLOAT"),
+	(FMT_16_16_16_16, "16_16_16_16"), (FMT_16_16_16_16_FLOAT, "16_16_16_16_FLOAT"),
+	(FMT_32_32_32_32, "32_32_32_32"), (FMT_32_32_32_32_FLOAT, "32_32_32_32_FLOAT"),
+	(FMT_32_32_32, "32_32_32"), (FMT_32_32_32_FLOAT, "32_32_32_FLOAT")
+];
+
+pub const ALU_SRC_NAMES: &'static [(u32, &'static str)] = &[
+	(ALU_SRC_LDS_OQ_A, "LDS_OQ_A"), (ALU_SRC_LDS_OQ_B, "LDS_OQ_B"),
+	(ALU_SRC_LDS_OQ_A_POP, "LDS_OQ_A_POP"), (ALU_SRC_LDS_OQ_B_POP, "LDS_OQ_B_POP"),
+	(ALU_SRC_LDS_DIRECT_A, "LDS_DIRECT_A"), (ALU_SRC_LDS_DIRECT_B, "LDS_DIRECT_B"),
+	(ALU_SRC_TIME_HI, "TIME_HI"), (ALU_SRC_TIME_LO, "TIME_LO"), (ALU_SRC_MASK_HI, "MASK_HI"),
+	(ALU_SRC_MASK_LO, "MASK_LO"), (ALU_SRC_HW_WAVE_ID, "HW_WAVE_ID"), (ALU_SRC_SIMD_ID, "SIMD_ID"),
+	(ALU_SRC_SE_ID, "SE_ID"), (ALU_SRC_HW_THREADGRP_ID, "HW_THREADGRP_ID"),
+	(ALU_SRC_WAVE_ID_IN_GRP, "WAVE_ID_IN_GRP"), (ALU_SRC_NUM_THREADGRP_WAVES, "NUM_THREADGRP_WAVES"),
+	(ALU_SRC_HW_ALU_ODD, "HW_ALU_ODD"), (ALU_SRC_LOOP_IDX, "LOOP_IDX"),
+	(ALU_SRC_PARAM_BASE_ADDR, "PARAM_BASE_ADDR"), (ALU_SRC_NEW_PRIM_MASK, "NEW_PRIM_MASK"),
+	(ALU_SRC_PRIM_MASK_HI, "PRIM_MASK_HI"), (ALU_SRC_PRIM_MASK_LO, "PRIM_MASK_LO"),
+	(ALU_SRC_1_DBL_L, "1_DBL_L"), (ALU_SRC_1_DBL_M, "1_DBL_M"),
+	(ALU_SRC_0_5_DBL_L, "0_5_DBL_L"), (ALU_SRC_0_5_DBL_M, "0_5_DBL_M"),
+	(ALU_SRC_0, "0"), (ALU_SRC_1, "1.0"), (ALU_SRC_1_INT, "1"), (ALU_SRC_M_1_INT, "-1"),
+	(ALU_SRC_0_5, "0.5"), (ALU_SRC_PS, "PS")
+];
+
+pub fn name_of(table: &[(u32, &'static str)], value: u32) -> Option<&'static str> {
+	table.iter().find(|e| e.0 == value).map(|e| e.1)
+}
+
+pub fn value_of(table: &[(u32, &'static str)], name: &str) -> Option<u32> {
+	table.iter().find(|e| e.1 == name).map(|e| e.0)
+}
+
+pub fn op2_info(op: u32) -> Option<(&'static str, u32)> {
+	OP2_NAMES.iter().find(|e| e.0 == op).map(|e| (e.1, e.2))
+}
+
+pub fn op2_by_name(name: &str) -> Option<(u32, u32)> {
+	OP2_NAMES.iter().find(|e| e.1 == name).map(|e| (e.0, e.2))
+}
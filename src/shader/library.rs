@@ -0,0 +1,208 @@
+// Shaders and kernels that come with the program, built directly from ISA
+// structures or with the shader builder
+
+use shader::isa::*;
+use shader::builder::ShaderBuilder;
+use shader::object::Stage;
+
+fn gpr(sel: u32, chan: u32) -> AluSrc {
+	AluSrc { sel: sel, chan: chan, ..AluSrc::default() }
+}
+
+fn literal(chan: u32) -> AluSrc {
+	AluSrc { sel: ALU_SRC_LITERAL, chan: chan, ..AluSrc::default() }
+}
+
+fn op2(op: u32, dst_gpr: u32, dst_chan: u32, src0: AluSrc, src1: AluSrc) -> AluInst {
+	AluInst {
+		op: op,
+		src: [src0, src1, AluSrc::default()],
+		dst_gpr: dst_gpr,
+		dst_chan: dst_chan,
+		write: true,
+		..AluInst::default()
+	}
+}
+
+fn group(insts: Vec<AluInst>, literals: Vec<u32>) -> AluGroup {
+	AluGroup { insts: insts, literals: literals }
+}
+
+// Fetches one dword per thread from a buffer, indexed by src_gpr.x
+fn fetch_dword(buffer_id: u32, src_gpr: u32, dst_gpr: u32) -> FetchInst {
+	FetchInst::Vtx(VtxInst {
+		inst: VC_INST_FETCH,
+		fetch_type: VTX_FETCH_NO_INDEX_OFFSET,
+		buffer_id: buffer_id,
+		src_gpr: src_gpr,
+		src_sel_x: SEL_X,
+		mega_fetch_count: 3,
+		dst_gpr: dst_gpr,
+		dst_sel: [SEL_X, SEL_MASK, SEL_MASK, SEL_MASK],
+		data_format: FMT_32_FLOAT,
+		num_format_all: NUM_FORMAT_SCALED,
+		mega_fetch: true,
+		..VtxInst::default()
+	})
+}
+
+pub const VECTOR_ADD_GROUP_SIZE: u32 = 64;
+
+// c[i] = a[i] + b[i] for float buffers a (fetch buffer 0), b (fetch buffer
+// 1) and c (RAT 0). The thread group size is baked into the index
+// computation, so dispatch groups of VECTOR_ADD_GROUP_SIZE threads.
+//
+// On entry R0.x is the thread id within the group and R1.x the group id.
+pub fn vector_add() -> Program {
+	let index = vec![
+		// R2.x = (R1.x << 6) + R0.x
+		group(vec![op2(OP2_LSHL_INT, 2, 0, gpr(1, 0), literal(0))],
+			vec![VECTOR_ADD_GROUP_SIZE.trailing_zeros()]),
+		group(vec![op2(OP2_ADD_INT, 2, 0, gpr(2, 0), gpr(0, 0))], vec![])
+	];
+	let add = vec![
+		group(vec![op2(OP2_ADD, 3, 0, gpr(3, 0), gpr(4, 0))], vec![])
+	];
+	let store = CfExportWord {
+		inst: CF_INST_MEM_RAT_CACHELESS,
+		ty: EXPORT_WRITE_IND,
+		array_base: 0 | (RAT_INST_STORE_RAW << 4), // RAT 0
+		rw_gpr: 3,
+		index_gpr: 2,
+		comp_mask: 1,
+		burst_count: 1,
+		end_of_program: true,
+		barrier: true,
+		..CfExportWord::default()
+	};
+	Program { cf: vec![
+		CfInst::Alu(CfAluWord { inst: CF_INST_ALU, barrier: true, ..CfAluWord::default() }, index),
+		CfInst::Fetch(CfWord { inst: CF_INST_VC, barrier: true, ..CfWord::default() }, vec![
+			fetch_dword(0, 2, 3),
+			fetch_dword(1, 2, 4)
+		]),
+		CfInst::Alu(CfAluWord { inst: CF_INST_ALU, barrier: true, ..CfAluWord::default() }, add),
+		CfInst::Export(store)
+	]}
+}
+
+fn export(inst: u32, ty: u32, array_base: u32, gpr: u32, end_of_program: bool) -> CfInst {
+	CfInst::Export(CfExportWord {
+		inst: inst,
+		ty: ty,
+		array_base: array_base,
+		rw_gpr: gpr,
+		sel: [SEL_X, SEL_Y, SEL_Z, SEL_W],
+		burst_count: 1,
+		end_of_program: end_of_program,
+		barrier: true,
+		..CfExportWord::default()
+	})
+}
+
+// Writes all four components of a GPR to the current vertex of stream 0
+pub fn streamout_export(buffer: u32, gpr: u32, dword_offset: u32) -> CfInst {
+	CfInst::Export(CfExportWord {
+		inst: CF_INST_MEM_STREAM0_BUF0 + buffer,
+		ty: EXPORT_WRITE,
+		array_base: dword_offset,
+		rw_gpr: gpr,
+		elem_size: 3,
+		array_size: 0xfff,
+		comp_mask: 0xf,
+		burst_count: 1,
+		barrier: true,
+		..CfExportWord::default()
+	})
+}
+
+// The solid VS from evergreen_shader.bin (position from fetch buffer 0 to
+// POS0, R0 to PARAM0), which also writes the position to stream-out
+// buffer 0.
+pub fn solid_vs_streamout() -> Program {
+	let fetch = FetchInst::Vtx(VtxInst {
+		inst: VC_INST_FETCH,
+		src_gpr: 0,
+		src_sel_x: SEL_X,
+		mega_fetch_count: 7,
+		dst_gpr: 1,
+		dst_sel: [SEL_X, SEL_Y, SEL_0, SEL_1],
+		data_format: FMT_32_32_FLOAT,
+		num_format_all: NUM_FORMAT_SCALED,
+		mega_fetch: true,
+		..VtxInst::default()
+	});
+	Program { cf: vec![
+		CfInst::Fetch(CfWord { inst: CF_INST_VC, barrier: true, ..CfWord::default() }, vec![fetch]),
+		streamout_export(0, 1, 0),
+		export(CF_INST_EXPORT_DONE, EXPORT_POS, ARRAY_BASE_POS0, 1, false),
+		export(CF_INST_EXPORT_DONE, EXPORT_PARAM, 0, 0, true)
+	]}
+}
+
+// Shaders for the 2D operations in blit.rs. They all draw a quad as a
+// triangle strip of vertex indices 0..3 without any vertex buffer.
+
+fn build(name: &str, b: &ShaderBuilder) -> Program {
+	b.program().unwrap_or_else(|e| panic!("{}: {}", name, e))
+}
+
+// Corner i of the quad is (i & 1, i >> 1), scaled and offset per component
+// by VS constants 0 and 1 into a position (xy) and a texture coordinate
+// (zw), which goes to PARAM0.
+pub fn quad_vs() -> Program {
+	let mut b = ShaderBuilder::new(Stage::Vs);
+	let index = b.vertex_index();
+	let i = b.int_to_float(index.x());
+	let halves = b.constant([1.0, 0.5, 1.0, 0.5]);
+	let scaled = b.mul(i, halves);
+	let f = b.floor(scaled);                     // (i, i >> 1, i, i >> 1)
+	let mask = b.constant([1.0, 0.0, 1.0, 0.0]);
+	let i_x = b.mul(f.x(), mask);                // (i, 0, i, 0)
+	let steps = b.constant([-2.0, 1.0, -2.0, 1.0]);
+	let corner = b.mad(f.y(), steps, i_x);       // (i & 1, i >> 1, i & 1, i >> 1)
+	let scale = b.uniform(0, 0);
+	let offset = b.uniform(0, 1);
+	let out = b.mad(corner, scale, offset);
+	b.export_position(out.swizzle("xy01"));
+	b.export_param(0, out.swizzle("zw01"));
+	build("quad VS", &b)
+}
+
+// Texture 0 at PARAM0
+pub fn copy_ps() -> Program {
+	let mut b = ShaderBuilder::new(Stage::Ps);
+	let uv = b.param(0);
+	let texel = b.sample(0, 0, uv.swizzle("xy00"));
+	b.export_color(0, texel);
+	build("copy PS", &b)
+}
+
+// Texture 0 at PARAM0 times PS constant 0
+pub fn texture_ps() -> Program {
+	let mut b = ShaderBuilder::new(Stage::Ps);
+	let uv = b.param(0);
+	let texel = b.sample(0, 0, uv.swizzle("xy00"));
+	let tint = b.uniform(0, 0);
+	let color = b.mul(texel, tint);
+	b.export_color(0, color);
+	build("texture PS", &b)
+}
+
+// Planar YUV from textures 0 (Y), 1 (U) and 2 (V), converted with
+// rgba = Y * c0 + U * c1 + V * c2 + c3 from PS constants 0..3
+pub fn yuv_ps() -> Program {
+	let mut b = ShaderBuilder::new(Stage::Ps);
+	let uv = b.param(0);
+	let coords = uv.swizzle("xy00");
+	let y = b.sample(0, 0, coords);
+	let u = b.sample(1, 0, coords);
+	let v = b.sample(2, 0, coords);
+	let c: Vec<_> = (0..4).map(|i| b.uniform(0, i)).collect();
+	let rgba = b.mad(y.x(), c[0], c[3]);
+	let rgba = b.mad(u.x(), c[1], rgba);
+	let rgba = b.mad(v.x(), c[2], rgba);
+	let rgba = b.saturate(rgba);
+	b.export_color(0, rgba);
+	build("YUV PS", &b)
+}
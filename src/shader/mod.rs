@@ -0,0 +1,11 @@
+// Evergreen shader bytecode, see the "Evergreen Family Instruction Set
+// Architecture" document and r600_asm.c / eg_sq.h in mesa.
+
+pub mod isa;
+pub mod disasm;
+pub mod asm;
+pub mod object;
+pub mod interp;
+pub mod builder;
+pub mod library;
+pub mod validate;
@@ -0,0 +1,341 @@
+// Shader objects: code plus what the register setup needs to know about it
+//
+// The metadata is derived from the program when the object is built, so a
+// shader and the SQ_PGM_* / SPI_* values programmed for it can't disagree.
+// Objects can be stored as files: a header of little endian dwords
+// followed by the code.
+//
+//     "EGSO" version stage num_gprs stack_size
+//     pos_exports pixel_exports z_export params streamout_buffers
+//     inputs const_buffers num_fetches
+//     num_fetches * (buffer_id offset data_format num_format signed dst_gpr dst_sel)
+//     code_size code
+
+use shader::isa::*;
+
+pub const OBJECT_MAGIC: u32 = 0x4f534745; // "EGSO"
+pub const OBJECT_VERSION: u32 = 1;
+
+// SQ_PGM_START_* holds bits 8 and up of the address
+pub const SHADER_ALIGN: usize = 256;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Stage { Vs, Ps, Gs, Es, Hs, Ls, Cs }
+
+const STAGES: [Stage; 7] = [Stage::Vs, Stage::Ps, Stage::Gs, Stage::Es, Stage::Hs, Stage::Ls, Stage::Cs];
+
+impl Stage {
+	pub fn from_str(s: &str) -> Option<Stage> {
+		match s {
+			"vs" => Some(Stage::Vs),
+			"ps" => Some(Stage::Ps),
+			"gs" => Some(Stage::Gs),
+			"es" => Some(Stage::Es),
+			"hs" => Some(Stage::Hs),
+			"ls" => Some(Stage::Ls),
+			"cs" => Some(Stage::Cs),
+			_ => None
+		}
+	}
+}
+
+// One vertex fetch of the shader, in fetch order
+#[derive(Clone, Copy, Default, PartialEq, Debug)]
+pub struct FetchInput {
+	pub buffer_id: u32,
+	pub offset: u32,
+	pub data_format: u32,
+	pub num_format: u32,
+	pub signed: bool,
+	pub dst_gpr: u32,
+	pub dst_sel: [u32; 4]
+}
+
+#[derive(Clone, Copy, Default, PartialEq, Debug)]
+pub struct Exports {
+	pub pos: u32,       // mask of POS0..3
+	pub pixel: u32,     // mask of color targets
+	pub z: bool,        // PIXEL61
+	pub params: u32,    // number of PARAM exports
+	pub streamout: u32  // mask of stream-out buffers written
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct ShaderObject {
+	pub stage: Stage,
+	pub num_gprs: u32,
+	pub stack_size: u32,      // in entries of 4 elements
+	pub exports: Exports,
+	pub inputs: u32,          // interpolated parameters read by a PS
+	pub fetches: Vec<FetchInput>,
+	pub const_buffers: u32,   // mask of constant buffers locked by ALU clauses
+	pub code: Vec<u8>
+}
+
+pub fn gprs_used(program: &Program) -> u32 {
+	let mut top = 0;
+	{
+		let mut gpr = |n: u32| top = top.max(n + 1);
+		for inst in &program.cf {
+			match *inst {
+				CfInst::Alu(_, ref groups) => for g in groups {
+					for i in &g.insts {
+						if i.write || i.op3 { gpr(i.dst_gpr) }
+						let n = if i.op3 { 3 } else { 2 };
+						for s in &i.src[..n] {
+							if s.sel < 128 { gpr(s.sel) }
+						}
+					}
+				},
+				CfInst::Fetch(_, ref fetches) => for f in fetches {
+					match *f {
+						FetchInst::Vtx(ref v) => { gpr(v.src_gpr); gpr(v.dst_gpr) },
+						FetchInst::Tex(ref t) => { gpr(t.src_gpr); gpr(t.dst_gpr) },
+						FetchInst::Raw(_) => ()
+					}
+				},
+				CfInst::Export(ref e) => {
+					gpr(e.rw_gpr + e.burst_count - 1);
+					gpr(e.index_gpr);
+				},
+				CfInst::Cf(_) => ()
+			}
+		}
+	}
+	// the hardware initializes R0 with the vertex index or barycentrics
+	top.max(1)
+}
+
+// Deepest use of the control flow stack, counted like r600_asm.c: a loop
+// takes a whole entry of 4 elements, a push one element.
+pub fn stack_entries(program: &Program) -> u32 {
+	let (mut pushes, mut loops, mut max) = (0i32, 0i32, 0i32);
+	for inst in &program.cf {
+		match *inst {
+			CfInst::Cf(ref c) => match c.inst {
+				CF_INST_PUSH => pushes += 1,
+				CF_INST_POP => pushes -= c.pop_count as i32,
+				CF_INST_LOOP_START | CF_INST_LOOP_START_DX10 | CF_INST_LOOP_START_NO_AL => loops += 1,
+				CF_INST_LOOP_END => loops -= 1,
+				_ => ()
+			},
+			CfInst::Alu(ref c, _) => match c.inst {
+				CF_INST_ALU_PUSH_BEFORE => pushes += 1,
+				CF_INST_ALU_POP_AFTER => pushes -= 1,
+				CF_INST_ALU_POP2_AFTER => pushes -= 2,
+				_ => ()
+			},
+			_ => ()
+		}
+		max = max.max(loops * 4 + pushes);
+	}
+	(max.max(0) as u32 + 3) / 4
+}
+
+pub fn exports(program: &Program) -> Exports {
+	let mut exports = Exports::default();
+	for inst in &program.cf {
+		if let CfInst::Export(ref e) = *inst {
+			if e.inst >= CF_INST_MEM_STREAM0_BUF0 && e.inst < CF_INST_MEM_WRITE_SCRATCH {
+				exports.streamout |= 1 << ((e.inst - CF_INST_MEM_STREAM0_BUF0) & 3);
+			}
+			if !is_swizzled_export(e.inst) { continue }
+			for base in e.array_base..e.array_base + e.burst_count {
+				match e.ty {
+					EXPORT_PIXEL if base == 61 => exports.z = true,
+					EXPORT_PIXEL if base < 8 => exports.pixel |= 1 << base,
+					EXPORT_POS if base >= ARRAY_BASE_POS0 && base < ARRAY_BASE_POS0 + 4 =>
+						exports.pos |= 1 << (base - ARRAY_BASE_POS0),
+					EXPORT_PARAM => exports.params = exports.params.max(base + 1),
+					_ => ()
+				}
+			}
+		}
+	}
+	exports
+}
+
+fn inputs(program: &Program) -> u32 {
+	let mut inputs = 0;
+	for inst in &program.cf {
+		if let CfInst::Alu(_, ref groups) = *inst {
+			for i in groups.iter().flat_map(|g| g.insts.iter()) {
+				for s in &i.src[..if i.op3 { 3 } else { 2 }] {
+					if s.sel >= ALU_SRC_PARAM_BASE {
+						inputs = inputs.max(s.sel - ALU_SRC_PARAM_BASE + 1);
+					}
+				}
+			}
+		}
+	}
+	inputs
+}
+
+fn fetches(program: &Program) -> Vec<FetchInput> {
+	let mut inputs = Vec::new();
+	for inst in &program.cf {
+		if let CfInst::Fetch(_, ref fetches) = *inst {
+			for f in fetches {
+				if let FetchInst::Vtx(ref v) = *f {
+					if v.inst != VC_INST_FETCH { continue }
+					inputs.push(FetchInput {
+						buffer_id: v.buffer_id,
+						offset: v.offset,
+						data_format: v.data_format,
+						num_format: v.num_format_all,
+						signed: v.format_comp_all,
+						dst_gpr: v.dst_gpr,
+						dst_sel: v.dst_sel
+					});
+				}
+			}
+		}
+	}
+	inputs
+}
+
+fn const_buffers(program: &Program) -> u32 {
+	let mut mask = 0;
+	for inst in &program.cf {
+		if let CfInst::Alu(ref c, _) = *inst {
+			for k in &c.kcache {
+				if k.mode != KCACHE_NOP { mask |= 1 << k.bank }
+			}
+		}
+	}
+	mask
+}
+
+fn read_u32(bytes: &[u8], at: &mut usize) -> Result<u32, String> {
+	if *at + 4 > bytes.len() {
+		return Err("shader object truncated".to_owned())
+	}
+	let b = &bytes[*at..*at+4];
+	*at += 4;
+	Ok(b[0] as u32 | (b[1] as u32) << 8 | (b[2] as u32) << 16 | (b[3] as u32) << 24)
+}
+
+fn push_u32(out: &mut Vec<u8>, v: u32) {
+	out.extend_from_slice(&[v as u8, (v >> 8) as u8, (v >> 16) as u8, (v >> 24) as u8]);
+}
+
+impl ShaderObject {
+	pub fn new(stage: Stage, program: &Program) -> ShaderObject {
+		ShaderObject {
+			stage: stage,
+			num_gprs: gprs_used(program),
+			stack_size: stack_entries(program),
+			exports: exports(program),
+			inputs: inputs(program),
+			fetches: fetches(program),
+			const_buffers: const_buffers(program),
+			code: program.encode_bytes()
+		}
+	}
+
+	pub fn size(&self) -> u32 {
+		self.code.len() as u32
+	}
+
+	// SQ_PGM_EXPORTS_PS
+	pub fn ps_exports(&self) -> u32 {
+		let colors = 32 - self.exports.pixel.leading_zeros();
+		(colors << 1) | self.exports.z as u32
+	}
+
+	// VS_EXPORT_COUNT of SPI_VS_OUT_CONFIG, one less than the parameters
+	pub fn vs_export_count(&self) -> u32 {
+		self.exports.params.max(1) - 1
+	}
+
+	pub fn to_bytes(&self) -> Vec<u8> {
+		let mut out = Vec::new();
+		let stage = STAGES.iter().position(|&s| s == self.stage).unwrap() as u32;
+		for &v in &[OBJECT_MAGIC, OBJECT_VERSION, stage, self.num_gprs, self.stack_size,
+			self.exports.pos, self.exports.pixel, self.exports.z as u32, self.exports.params,
+			self.exports.streamout, self.inputs, self.const_buffers, self.fetches.len() as u32] {
+			push_u32(&mut out, v);
+		}
+		for f in &self.fetches {
+			let dst_sel = f.dst_sel[0] | f.dst_sel[1] << 3 | f.dst_sel[2] << 6 | f.dst_sel[3] << 9;
+			for &v in &[f.buffer_id, f.offset, f.data_format, f.num_format, f.signed as u32, f.dst_gpr, dst_sel] {
+				push_u32(&mut out, v);
+			}
+		}
+		push_u32(&mut out, self.code.len() as u32);
+		out.extend_from_slice(&self.code);
+		out
+	}
+
+	pub fn from_bytes(bytes: &[u8]) -> Result<ShaderObject, String> {
+		let at = &mut 0;
+		if read_u32(bytes, at)? != OBJECT_MAGIC {
+			return Err("not a shader object".to_owned())
+		}
+		let version = read_u32(bytes, at)?;
+		if version != OBJECT_VERSION {
+			return Err(format!("shader object version {} unsupported", version))
+		}
+		let stage = *STAGES.get(read_u32(bytes, at)? as usize).ok_or("bad shader stage")?;
+		let num_gprs = read_u32(bytes, at)?;
+		let stack_size = read_u32(bytes, at)?;
+		let exports = Exports {
+			pos: read_u32(bytes, at)?,
+			pixel: read_u32(bytes, at)?,
+			z: read_u32(bytes, at)? != 0,
+			params: read_u32(bytes, at)?,
+			streamout: read_u32(bytes, at)?
+		};
+		let inputs = read_u32(bytes, at)?;
+		let const_buffers = read_u32(bytes, at)?;
+		let num_fetches = read_u32(bytes, at)?;
+		let mut fetches = Vec::new();
+		for _ in 0..num_fetches {
+			let mut f = FetchInput::default();
+			f.buffer_id = read_u32(bytes, at)?;
+			f.offset = read_u32(bytes, at)?;
+			f.data_format = read_u32(bytes, at)?;
+			f.num_format = read_u32(bytes, at)?;
+			f.signed = read_u32(bytes, at)? != 0;
+			f.dst_gpr = read_u32(bytes, at)?;
+			let dst_sel = read_u32(bytes, at)?;
+			f.dst_sel = [dst_sel & 7, (dst_sel >> 3) & 7, (dst_sel >> 6) & 7, (dst_sel >> 9) & 7];
+			fetches.push(f);
+		}
+		let code_size = read_u32(bytes, at)? as usize;
+		if *at + code_size != bytes.len() {
+			return Err("shader object size doesn't match its code size".to_owned())
+		}
+		Ok(ShaderObject {
+			stage: stage,
+			num_gprs: num_gprs,
+			stack_size: stack_size,
+			exports: exports,
+			inputs: inputs,
+			fetches: fetches,
+			const_buffers: const_buffers,
+			code: bytes[*at..].to_vec()
+		})
+	}
+}
+
+// A shader at an offset into a buffer of shaders
+pub struct PlacedShader {
+	pub object: ShaderObject,
+	pub offset: usize
+}
+
+// Packs shaders one after the other, aligned for SQ_PGM_START_*
+#[derive(Default)]
+pub struct ShaderHeap {
+	pub code: Vec<u8>
+}
+
+impl ShaderHeap {
+	pub fn add(&mut self, object: ShaderObject) -> PlacedShader {
+		let offset = (self.code.len() + SHADER_ALIGN - 1) & !(SHADER_ALIGN - 1);
+		self.code.resize(offset, 0);
+		self.code.extend_from_slice(&object.code);
+		PlacedShader { object: object, offset: offset }
+	}
+}
@@ -0,0 +1,209 @@
+// Static checks of shaders against the Evergreen limits and against what
+// gets programmed for them. Most of these mistakes don't fault on the GPU:
+// too few GPRs or stack entries corrupt other threads, a wrong export count
+// or a missing END_OF_PROGRAM hangs the SPI or the sequencer.
+
+use shader::disasm::words_from_bytes;
+use shader::isa::*;
+use shader::object::{exports, gprs_used, stack_entries, ShaderObject, Stage};
+
+// GPRs 124..127 are the clause temporaries
+pub const MAX_GPRS: u32 = 124;
+pub const MAX_ALU_CLAUSE_SLOTS: u32 = 128;
+pub const MAX_FETCH_CLAUSE_INSTS: usize = 16;
+pub const MAX_LITERALS: usize = 4;
+
+// The register values a shader runs with: SQ_PGM_RESOURCES_* and, for the
+// PS and VS, SQ_PGM_EXPORTS_PS and SPI_VS_OUT_CONFIG
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct PgmConfig {
+	pub num_gprs: u32,
+	pub stack_size: u32,
+	pub sq_pgm_exports_ps: u32,
+	pub spi_vs_out_config: u32
+}
+
+impl PgmConfig {
+	// What evergreen_*_setup programs for object
+	pub fn of(object: &ShaderObject) -> PgmConfig {
+		PgmConfig {
+			num_gprs: object.num_gprs,
+			stack_size: object.stack_size,
+			sq_pgm_exports_ps: object.ps_exports(),
+			spi_vs_out_config: object.vs_export_count() << 1
+		}
+	}
+}
+
+// Decodes the code of object and checks it against its own metadata
+pub fn validate_object(object: &ShaderObject, chip: Chip) -> Vec<String> {
+	match Program::decode(&words_from_bytes(&object.code), chip) {
+		Ok(program) => validate(&program, object.stage, &PgmConfig::of(object), chip),
+		Err(e) => vec![format!("can't decode the code, is END_OF_PROGRAM missing? ({})", e)]
+	}
+}
+
+// Returns one message per problem, nothing for a good shader
+pub fn validate(program: &Program, stage: Stage, config: &PgmConfig, chip: Chip) -> Vec<String> {
+	let mut problems = Vec::new();
+	check_resources(program, config, &mut problems);
+	check_clauses(program, chip, &mut problems);
+	check_end_of_program(program, &mut problems);
+	match stage {
+		Stage::Vs => check_vs_exports(program, config, &mut problems),
+		Stage::Ps => check_ps_exports(program, config, &mut problems),
+		_ => ()
+	}
+	problems
+}
+
+fn check_resources(program: &Program, config: &PgmConfig, problems: &mut Vec<String>) {
+	let gprs = gprs_used(program);
+	if gprs > config.num_gprs {
+		problems.push(format!("uses {} GPRs but NUM_GPRS is {}", gprs, config.num_gprs));
+	}
+	if config.num_gprs > MAX_GPRS {
+		problems.push(format!("NUM_GPRS {} is above the limit of {}", config.num_gprs, MAX_GPRS));
+	}
+
+	let stack = stack_entries(program);
+	if stack > config.stack_size {
+		problems.push(format!("needs {} stack entries but STACK_SIZE is {}", stack, config.stack_size));
+	}
+	let mut depth = 0i32;
+	for (n, inst) in program.cf.iter().enumerate() {
+		depth += match *inst {
+			CfInst::Cf(ref c) if c.inst == CF_INST_PUSH => 1,
+			CfInst::Cf(ref c) if c.inst == CF_INST_POP => -(c.pop_count as i32),
+			CfInst::Alu(ref c, _) if c.inst == CF_INST_ALU_PUSH_BEFORE => 1,
+			CfInst::Alu(ref c, _) if c.inst == CF_INST_ALU_POP_AFTER => -1,
+			CfInst::Alu(ref c, _) if c.inst == CF_INST_ALU_POP2_AFTER => -2,
+			_ => 0
+		};
+		if depth < 0 {
+			problems.push(format!("CF {}: pops more than was pushed", n));
+			depth = 0;
+		}
+	}
+}
+
+fn check_clauses(program: &Program, chip: Chip, problems: &mut Vec<String>) {
+	let max_insts = if chip == Chip::Cayman { 4 } else { 5 };
+	for (n, inst) in program.cf.iter().enumerate() {
+		match *inst {
+			CfInst::Alu(ref c, ref groups) => {
+				let slots: u32 = groups.iter().map(|g| g.slots()).sum();
+				if slots > MAX_ALU_CLAUSE_SLOTS {
+					problems.push(format!("CF {}: ALU clause of {} slots, at most {} fit", n, slots, MAX_ALU_CLAUSE_SLOTS));
+				}
+				for (i, g) in groups.iter().enumerate() {
+					check_group(g, c, max_insts, &format!("CF {} group {}", n, i), problems);
+				}
+			},
+			CfInst::Fetch(_, ref fetches) => {
+				if fetches.len() > MAX_FETCH_CLAUSE_INSTS {
+					problems.push(format!("CF {}: fetch clause of {} instructions, at most {} fit",
+						n, fetches.len(), MAX_FETCH_CLAUSE_INSTS));
+				}
+			},
+			_ => ()
+		}
+	}
+}
+
+fn check_group(g: &AluGroup, clause: &CfAluWord, max_insts: usize, at: &str, problems: &mut Vec<String>) {
+	if g.insts.is_empty() || g.insts.len() > max_insts {
+		problems.push(format!("{}: {} instructions", at, g.insts.len()));
+	}
+	if g.literals.len() > MAX_LITERALS {
+		problems.push(format!("{}: {} literals, at most {} fit", at, g.literals.len(), MAX_LITERALS));
+	}
+	let used = g.insts.iter().map(|i| i.literals_used()).max().unwrap_or(0);
+	if used as usize > g.literals.len() {
+		problems.push(format!("{}: reads literal {} but has {}", at, used - 1, g.literals.len()));
+	}
+	for i in &g.insts {
+		if (i.write || i.op3) && i.dst_gpr >= 128 {
+			problems.push(format!("{}: writes R{}", at, i.dst_gpr));
+		}
+		for s in &i.src[..if i.op3 { 3 } else { 2 }] {
+			let (bank, index) = match s.sel {
+				ALU_SRC_KCACHE0_BASE ..= 159 => (0, s.sel - ALU_SRC_KCACHE0_BASE),
+				ALU_SRC_KCACHE1_BASE ..= 191 => (1, s.sel - ALU_SRC_KCACHE1_BASE),
+				_ => continue
+			};
+			let locked = match clause.kcache[bank].mode {
+				KCACHE_LOCK_1 => 16,
+				KCACHE_LOCK_2 | KCACHE_LOCK_LOOP_INDEX => 32,
+				_ => 0
+			};
+			if index >= locked {
+				problems.push(format!("{}: reads KC{}[{}] outside the locked constants", at, bank, index));
+			}
+		}
+	}
+}
+
+fn check_end_of_program(program: &Program, problems: &mut Vec<String>) {
+	let n = program.cf.len();
+	for (i, inst) in program.cf.iter().enumerate() {
+		let end = match *inst {
+			CfInst::Cf(ref c) | CfInst::Fetch(ref c, _) => c.end_of_program,
+			CfInst::Export(ref e) => e.end_of_program,
+			CfInst::Alu(..) => false // ALU clauses can't end the program
+		};
+		if end && i + 1 != n {
+			problems.push(format!("CF {}: END_OF_PROGRAM before the last instruction", i));
+		}
+		if !end && i + 1 == n {
+			problems.push("the last CF instruction doesn't have END_OF_PROGRAM set".to_owned());
+		}
+	}
+	if n == 0 {
+		problems.push("no CF instructions".to_owned());
+	}
+}
+
+// The last export of each type has to be EXPORT_DONE
+fn check_export_done(program: &Program, ty: u32, what: &str, problems: &mut Vec<String>) {
+	let last = program.cf.iter().filter_map(|inst| match *inst {
+		CfInst::Export(ref e) if is_swizzled_export(e.inst) && e.ty == ty => Some(e.inst),
+		_ => None
+	}).last();
+	if last == Some(CF_INST_EXPORT) {
+		problems.push(format!("the last {} export isn't EXPORT_DONE", what));
+	}
+}
+
+fn check_vs_exports(program: &Program, config: &PgmConfig, problems: &mut Vec<String>) {
+	let e = exports(program);
+	if e.pos & 1 == 0 {
+		problems.push("the VS doesn't export POS0".to_owned());
+	}
+	let count = (config.spi_vs_out_config >> 1 & 0x1f) + 1;
+	if e.params > 0 && e.params != count {
+		problems.push(format!("the VS exports {} parameters but SPI_VS_OUT_CONFIG says {}", e.params, count));
+	}
+	check_export_done(program, EXPORT_POS, "position", problems);
+	check_export_done(program, EXPORT_PARAM, "parameter", problems);
+}
+
+fn check_ps_exports(program: &Program, config: &PgmConfig, problems: &mut Vec<String>) {
+	let e = exports(program);
+	let colors = 32 - e.pixel.leading_zeros();
+	let (config_colors, config_z) = (config.sq_pgm_exports_ps >> 1 & 0xf, config.sq_pgm_exports_ps & 1 != 0);
+	if colors != config_colors {
+		problems.push(format!("the PS exports {} colors but SQ_PGM_EXPORTS_PS says {}", colors, config_colors));
+	}
+	if e.pixel != 0 && e.pixel + 1 != 1 << colors {
+		problems.push(format!("the PS leaves gaps between color exports (mask {:#x})", e.pixel));
+	}
+	if e.z != config_z {
+		problems.push(format!("the PS {} Z but SQ_PGM_EXPORTS_PS {}",
+			if e.z { "exports" } else { "doesn't export" }, if config_z { "expects it" } else { "doesn't" }));
+	}
+	if e.pixel == 0 && !e.z {
+		problems.push("the PS doesn't export anything".to_owned());
+	}
+	check_export_done(program, EXPORT_PIXEL, "pixel", problems);
+}
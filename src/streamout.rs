@@ -0,0 +1,93 @@
+// Stream-out (transform feedback), see r600_streamout.c in mesa
+//
+// The VS writes vertices to stream-out buffers with MEM_STREAM exports. The
+// VGT tracks how far each buffer is filled, and can store that size into a
+// BO when stream-out ends.
+
+use cs::*;
+
+pub const MAX_STREAMOUT_BUFFERS: usize = 4;
+
+const EVENT_TYPE_SO_VGTSTREAMOUT_FLUSH: u32 = 0x1f;
+const WAIT_REG_MEM_EQUAL: u32 = 3;
+
+// STRMOUT_BUFFER_UPDATE control
+const STORE_BUFFER_FILLED_SIZE: u32 = 1 << 0;
+const OFFSET_FROM_PACKET: u32 = 0 << 1;
+const OFFSET_NONE: u32 = 3 << 1;
+fn select_buffer(n: u32) -> u32 { n << 8 }
+
+pub struct StreamoutBuffer {
+	pub offset: u32, // 256 byte aligned
+	pub size: u32,
+	pub stride: u32, // bytes per vertex
+	pub filled_size_offset: u32 // where streamout_end stores the filled size in bytes
+}
+
+// Only stream 0 is used. Buffer i is enabled by bit i of buffer_mask, zero
+// disables stream-out.
+pub fn set_streamout_enable(cs: &mut CS, buffer_mask: u32) {
+	cs.set_reg_n(0x28b94, 2);
+	cs.emit(if buffer_mask != 0 {1} else {0}); // VGT_STRMOUT_CONFIG // STREAMOUT_0_EN
+	cs.emit(buffer_mask);                      // VGT_STRMOUT_BUFFER_CONFIG // STREAM_0_BUFFER_EN
+}
+
+// Waits until the VGT has written back its buffer offsets
+fn flush_vgt_streamout(cs: &mut CS) {
+	cs.set_reg(0x84fc, 0); // CP_STRMOUT_CNTL
+	cs.write(&[packet3(Packet3::EVENT_WRITE, 0, 0), EVENT_TYPE_SO_VGTSTREAMOUT_FLUSH]);
+	cs.write(&[
+		packet3(Packet3::WAIT_REG_MEM, 5, 0),
+		WAIT_REG_MEM_EQUAL,
+		0x84fc >> 2, // CP_STRMOUT_CNTL
+		0,
+		1, // reference: OFFSET_UPDATE_DONE
+		1, // mask
+		4  // poll interval
+	]);
+}
+
+pub fn streamout_begin(cs: &mut CS, buffers: &[StreamoutBuffer], bo_reloc: &Fn(&mut CS) -> ()) {
+	cs.write_label("streamout begin");
+	assert!(buffers.len() <= MAX_STREAMOUT_BUFFERS, "too many stream-out buffers");
+	set_streamout_enable(cs, (1 << buffers.len()) - 1);
+	flush_vgt_streamout(cs);
+
+	for (i, b) in buffers.iter().enumerate() {
+		let r = 0x28ad0 + 16 * i as u32;
+		assert!(b.offset & 0xff == 0, "stream-out buffer not 256 byte aligned");
+		cs.set_reg_n(r, 3);
+		cs.emit(b.size >> 2);   // VGT_STRMOUT_BUFFER_SIZE_n // in dwords
+		cs.emit(b.stride >> 2); // VGT_STRMOUT_VTX_STRIDE_n // in dwords
+		cs.emit(b.offset >> 8); // VGT_STRMOUT_BUFFER_BASE_n
+		bo_reloc(cs);
+
+		cs.write(&[
+			packet3(Packet3::STRMOUT_BUFFER_UPDATE, 4, 0),
+			select_buffer(i as u32) | OFFSET_FROM_PACKET,
+			0,
+			0,
+			0, // start at the beginning of the buffer
+			0
+		]);
+	}
+}
+
+// Stores the filled size of every buffer and disables stream-out
+pub fn streamout_end(cs: &mut CS, buffers: &[StreamoutBuffer], bo_reloc: &Fn(&mut CS) -> ()) {
+	cs.write_label("streamout end");
+	flush_vgt_streamout(cs);
+
+	for (i, b) in buffers.iter().enumerate() {
+		cs.write(&[
+			packet3(Packet3::STRMOUT_BUFFER_UPDATE, 4, 0),
+			select_buffer(i as u32) | OFFSET_NONE | STORE_BUFFER_FILLED_SIZE,
+			b.filled_size_offset, // lower 32 bits of address
+			0,                    // upper 32-39
+			0,
+			0
+		]);
+		bo_reloc(cs);
+	}
+	set_streamout_enable(cs, 0);
+}